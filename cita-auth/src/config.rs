@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::txpool::{PackageStrategy, PriorityConfig};
+use cita_metrics::MetricsConfig;
+
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct Config {
     pub count_per_batch: usize,
@@ -19,7 +22,16 @@ pub struct Config {
     pub tx_verify_thread_num: usize,
     pub tx_verify_cache_size: usize,
     pub tx_pool_limit: usize,
+    // pending transactions of each sender, 0 for unlimited
+    #[serde(default)]
+    pub tx_pool_sender_limit: usize,
+    #[serde(default)]
+    pub package_strategy: PackageStrategy,
     pub wal_enable: bool,
+    #[serde(default)]
+    pub priority: PriorityConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 impl Config {
@@ -41,7 +53,14 @@ mod tests {
         tx_verify_thread_num = 4
         tx_verify_cache_size = 100000
         tx_pool_limit = 50000
+        tx_pool_sender_limit = 1000
+        package_strategy = "fair"
         wal_enable = true
+        [priority]
+        senders = ["0x0000000000000000000000000000000000000001"]
+        [metrics]
+        enable = true
+        port = 9101
         "#;
 
         let mut tmpfile: NamedTempFile = NamedTempFile::new().unwrap();
@@ -54,6 +73,12 @@ mod tests {
         assert_eq!(4, value.tx_verify_thread_num);
         assert_eq!(100000, value.tx_verify_cache_size);
         assert_eq!(50000, value.tx_pool_limit);
+        assert_eq!(1000, value.tx_pool_sender_limit);
+        assert_eq!(PackageStrategy::Fair, value.package_strategy);
         assert_eq!(true, value.wal_enable);
+        assert_eq!(1, value.priority.senders.len());
+        assert!(value.priority.contracts.is_empty());
+        assert_eq!(true, value.metrics.enable);
+        assert_eq!(9101, value.metrics.port);
     }
 }
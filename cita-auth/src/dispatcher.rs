@@ -13,30 +13,59 @@
 // limitations under the License.
 
 use crate::handler::SysConfigInfo;
+use crate::transaction_verify::Error;
+use crate::txpool::{tx_to, PackageStrategy, Packer, PoolIndex, PoolStatus, PoolTx, PriorityLanes};
 use crate::txwal::TxWal;
 use cita_types::traits::LowerHex;
 use cita_types::{Address, H256};
-use libproto::blockchain::{AccountGasLimit, BlockBody, BlockTxs, SignedTransaction};
+use crypto::{pubkey_to_address, PubKey};
+use libproto::blockchain::{AccountGasLimit, BlockBody, BlockTxs, SignedTransaction, Transaction};
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::Message;
 use libproto::TryInto;
 use pubsub::channel::Sender;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::Into;
 use std::thread;
 use tx_pool;
 
+/// How a new transaction gets into the pool.
+#[derive(Debug, PartialEq)]
+pub enum Submitted {
+    Added,
+    /// Replaced the pending transaction with the same nonce.
+    Replaced(H256),
+    /// Cancelled the pending transaction with the same nonce, and added itself.
+    Cancelled(H256),
+}
+
 pub struct Dispatcher {
     txs_pool: RefCell<tx_pool::Pool>,
+    pool_index: RefCell<PoolIndex>,
+    // in a proposal of the current height, they can't be replaced any more
+    proposed: RefCell<HashSet<H256>>,
+    // the replaced or cancelled transactions, and the ones taking their place
+    superseded: RefCell<HashMap<H256, H256>>,
+    package_strategy: PackageStrategy,
+    priority_lanes: PriorityLanes,
     wal: TxWal,
     wal_enable: bool,
 }
 
 impl Dispatcher {
-    pub fn new(wal_enable: bool) -> Self {
+    pub fn new(
+        wal_enable: bool,
+        package_strategy: PackageStrategy,
+        priority_lanes: PriorityLanes,
+    ) -> Self {
         let mut dispatch = Dispatcher {
             txs_pool: RefCell::new(tx_pool::Pool::new(0)),
+            pool_index: RefCell::new(PoolIndex::new()),
+            proposed: RefCell::new(HashSet::new()),
+            superseded: RefCell::new(HashMap::new()),
+            package_strategy,
+            priority_lanes,
             wal: TxWal::new("/txwal"),
             wal_enable,
         };
@@ -57,6 +86,9 @@ impl Dispatcher {
     /// Clean transaction pool and regenerate an pool cache db
     pub fn clear_txs_pool(&mut self, package_limit: usize) {
         self.txs_pool = RefCell::new(tx_pool::Pool::new(package_limit));
+        self.pool_index.borrow_mut().clear();
+        self.proposed.borrow_mut().clear();
+        self.superseded.borrow_mut().clear();
         self.wal.regenerate("/txwal");
     }
 
@@ -64,6 +96,42 @@ impl Dispatcher {
         self.txs_pool.borrow().len()
     }
 
+    /// How many transactions of the sender are in the pool.
+    pub fn sender_pending(&self, sender: &Address) -> usize {
+        self.pool_index.borrow().sender_count(sender)
+    }
+
+    pub fn is_nonce_pending(&self, sender: &Address, nonce: &str) -> bool {
+        self.pool_index.borrow().find(sender, nonce).is_some()
+    }
+
+    pub fn pool_status(&self) -> PoolStatus {
+        self.pool_index.borrow().status()
+    }
+
+    /// Pending transactions grouped by sender, of the sender only if given.
+    pub fn pool_content(&self, sender: Option<&Address>) -> BTreeMap<String, Vec<PoolTx>> {
+        let pool = self.txs_pool.borrow();
+        let index = self.pool_index.borrow();
+        index
+            .content(sender)
+            .into_iter()
+            .map(|(sender, hashes)| {
+                let txs = hashes
+                    .iter()
+                    .filter_map(|hash| pool.get(hash).and_then(|tx| index.pool_tx(tx)))
+                    .collect();
+                (format!("0x{}", sender.lower_hex()), txs)
+            })
+            .collect()
+    }
+
+    pub fn pool_transaction(&self, hash: &H256) -> Option<PoolTx> {
+        let pool = self.txs_pool.borrow();
+        pool.get(hash)
+            .and_then(|tx| self.pool_index.borrow().pool_tx(tx))
+    }
+
     /// package a block with new transactions,
     /// send to cita-bft
     pub fn proposal_tx_list(
@@ -123,6 +191,9 @@ impl Dispatcher {
         trace!("add tx {} to pool", tx.get_tx_hash().lower_hex());
         let txs_pool = &mut self.txs_pool.borrow_mut();
         let success = txs_pool.enqueue(tx.clone());
+        if success {
+            self.index_tx(tx);
+        }
         if self.wal_enable {
             if success {
                 self.wal.write(tx);
@@ -136,6 +207,114 @@ impl Dispatcher {
         success
     }
 
+    /// Add a new transaction, which may replace or cancel the pending
+    /// transaction of the same sender and nonce.
+    ///
+    /// The replacement must have a higher quota limit. There is no price of
+    /// a transaction to compare, the quota price is the same for all of them.
+    /// An empty call from the sender to itself cancels the pending one, and
+    /// takes its place in the pool, so the nonce is used by an empty call.
+    ///
+    /// A pending transaction which is already in a proposal can't be
+    /// replaced, and a replacement is dropped from the pool when the
+    /// transaction it replaced is committed anyway, see `commit_txs`.
+    /// Only this pool is checked: the original may still be proposed by a
+    /// node which has not received the replacement, then the replacement is
+    /// dropped when the original is committed.
+    pub fn submit_tx(&self, tx: &SignedTransaction) -> Result<Submitted, Error> {
+        // Check the duplication first, so the pending one is never removed
+        // without the new one being added.
+        if self
+            .txs_pool
+            .borrow()
+            .get(&H256::from_slice(tx.get_tx_hash()))
+            .is_some()
+        {
+            return Err(Error::Dup);
+        }
+        let sender = pubkey_to_address(&PubKey::from_slice(tx.get_signer()));
+        let transaction = tx.get_transaction_with_sig().get_transaction();
+        let pending = self
+            .pool_index
+            .borrow()
+            .find(&sender, transaction.get_nonce())
+            .filter(|hash| hash.as_ref() != tx.get_tx_hash());
+
+        let submitted = if let Some(pending) = pending {
+            if self.proposed.borrow().contains(&pending) {
+                return Err(Error::NonceTaken);
+            }
+            if is_cancellation(transaction, &sender) {
+                self.del_txs_from_pool_with_hash(&vec![pending].into_iter().collect());
+                Submitted::Cancelled(pending)
+            } else {
+                let pending_quota = self
+                    .txs_pool
+                    .borrow()
+                    .get(&pending)
+                    .map(|tx| tx.get_transaction_with_sig().get_transaction().get_quota())
+                    .unwrap_or(0);
+                if transaction.get_quota() <= pending_quota {
+                    return Err(Error::QuotaNotHigher);
+                }
+                self.del_txs_from_pool_with_hash(&vec![pending].into_iter().collect());
+                Submitted::Replaced(pending)
+            }
+        } else {
+            Submitted::Added
+        };
+
+        if !self.add_tx_to_pool(tx) {
+            return Err(Error::Dup);
+        }
+        match submitted {
+            Submitted::Replaced(pending) | Submitted::Cancelled(pending) => {
+                let hash = H256::from_slice(tx.get_tx_hash());
+                let mut superseded = self.superseded.borrow_mut();
+                // A replacement of a replacement takes the place of both.
+                for replacement in superseded.values_mut() {
+                    if *replacement == pending {
+                        *replacement = hash;
+                    }
+                }
+                superseded.insert(pending, hash);
+            }
+            Submitted::Added => {}
+        }
+        Ok(submitted)
+    }
+
+    /// Mark the transactions of a proposal of the current height.
+    pub fn mark_proposed(&self, hashes: &[H256]) {
+        self.proposed.borrow_mut().extend(hashes.iter().cloned());
+    }
+
+    /// Remove the transactions of a committed block from the pool, with the
+    /// replacements of the committed ones, as their nonces are used.
+    ///
+    /// Returns the dropped replacements.
+    pub fn commit_txs(&self, hashes: &HashSet<H256>) -> Vec<H256> {
+        let dropped: Vec<H256> = {
+            let mut superseded = self.superseded.borrow_mut();
+            hashes
+                .iter()
+                .filter_map(|hash| superseded.remove(hash))
+                .filter(|hash| !hashes.contains(hash))
+                .collect()
+        };
+        let mut removed = hashes.clone();
+        removed.extend(dropped.iter().cloned());
+        self.del_txs_from_pool_with_hash(&removed);
+
+        // Forget the replacements which are not in the pool any more.
+        let pool = self.txs_pool.borrow();
+        self.superseded
+            .borrow_mut()
+            .retain(|_, replacement| pool.get(replacement).is_some());
+        self.proposed.borrow_mut().clear();
+        dropped
+    }
+
     // TODO: Wal shoud be inside pool
     pub fn add_txs_to_pool(&self, txs: Vec<SignedTransaction>) {
         let txs_pool = &mut self.txs_pool.borrow_mut();
@@ -146,6 +325,9 @@ impl Dispatcher {
                 txs_pool.enqueue(tx.clone())
             })
             .collect();
+        for tx in &added {
+            self.index_tx(tx);
+        }
         if self.wal_enable {
             self.wal.write_batch(&added);
         }
@@ -173,19 +355,51 @@ impl Dispatcher {
         version: u32,
     ) -> Vec<SignedTransaction> {
         let txs_pool = &mut self.txs_pool.borrow_mut();
-        txs_pool.package(
-            height,
-            block_quota_limit,
-            account_quota_limit,
-            check_quota,
-            *admin_address,
-            version,
-        )
+        let txs =
+            if self.package_strategy == PackageStrategy::Fifo && self.priority_lanes.is_empty() {
+                txs_pool.package(
+                    height,
+                    block_quota_limit,
+                    account_quota_limit,
+                    check_quota,
+                    *admin_address,
+                    version,
+                )
+            } else {
+                // Take all the valid transactions, then package them by ourselves.
+                let txs = txs_pool.package(
+                    height,
+                    u64::max_value(),
+                    account_quota_limit.clone(),
+                    false,
+                    *admin_address,
+                    version,
+                );
+                Packer::new(
+                    block_quota_limit,
+                    &account_quota_limit,
+                    check_quota,
+                    *admin_address,
+                )
+                .take_with_lanes(txs, &self.priority_lanes, self.package_strategy)
+            };
+        // Outdated transactions are dropped by the pool when packaging.
+        self.pool_index
+            .borrow_mut()
+            .retain(|hash| txs_pool.get(hash).is_some());
+        self.proposed
+            .borrow_mut()
+            .extend(txs.iter().map(|tx| H256::from_slice(tx.get_tx_hash())));
+        txs
     }
 
     pub fn del_txs_from_pool_with_hash(&self, txs: &HashSet<H256>) {
         {
             self.txs_pool.borrow_mut().update_with_hash(txs);
+            let mut pool_index = self.pool_index.borrow_mut();
+            for hash in txs {
+                pool_index.remove(hash);
+            }
         }
         if self.wal_enable {
             let mut wal = self.wal.clone();
@@ -203,8 +417,51 @@ impl Dispatcher {
         let len = txs.len();
         let mut pool = self.txs_pool.borrow_mut();
         for tx in txs {
-            pool.enqueue(tx);
+            if pool.enqueue(tx.clone()) {
+                self.index_tx(&tx);
+            }
         }
         len
     }
+
+    fn index_tx(&self, tx: &SignedTransaction) {
+        let sender = pubkey_to_address(&PubKey::from_slice(tx.get_signer()));
+        let nonce = tx.get_transaction_with_sig().get_transaction().get_nonce();
+        self.pool_index
+            .borrow_mut()
+            .insert(H256::from_slice(tx.get_tx_hash()), sender, nonce);
+    }
+}
+
+// A cancellation is a call without data and value from the sender to itself.
+fn is_cancellation(tx: &Transaction, sender: &Address) -> bool {
+    tx_to(tx) == Some(*sender) && tx.get_data().is_empty() && tx.get_value().iter().all(|b| *b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_cancellation;
+    use cita_types::traits::LowerHex;
+    use cita_types::Address;
+    use libproto::blockchain::Transaction;
+
+    #[test]
+    fn test_is_cancellation() {
+        let sender = Address::from(1);
+        let mut tx = Transaction::new();
+        tx.set_to_v1(sender.to_vec());
+        tx.set_value(vec![0; 32]);
+        assert!(is_cancellation(&tx, &sender));
+        assert!(!is_cancellation(&tx, &Address::from(2)));
+
+        tx.set_to_v1(Vec::new());
+        tx.set_to(sender.lower_hex());
+        assert!(is_cancellation(&tx, &sender));
+
+        tx.set_data(vec![1]);
+        assert!(!is_cancellation(&tx, &sender));
+        tx.set_data(Vec::new());
+        tx.set_value(vec![1]);
+        assert!(!is_cancellation(&tx, &sender));
+    }
 }
@@ -14,9 +14,13 @@
 
 use crate::block_txn::{BlockTxnMessage, BlockTxnReq};
 use crate::block_verify::BlockVerify;
-use crate::dispatcher::Dispatcher;
+use crate::dispatcher::{Dispatcher, Submitted};
 use crate::history::HistoryHeights;
+use crate::metrics;
 use crate::transaction_verify::Error;
+use crate::txpool::{
+    PoolQuery, PoolReply, PoolTxState, GET_POOL_CONTENT, GET_POOL_STATUS, GET_POOL_TRANSACTION,
+};
 use cita_types::traits::LowerHex;
 use cita_types::{clean_0x, Address, H256, U256};
 use crypto::{pubkey_to_address, PubKey, Sign, Signature, SIGNATURE_BYTES_LEN};
@@ -27,7 +31,7 @@ use libproto::blockchain::{AccountGasLimit, SignedTransaction, Transaction};
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::snapshot::{Cmd, Resp, SnapshotReq, SnapshotResp};
 use libproto::{
-    BlackList, BlockTxHashes, BlockTxHashesReq, BlockTxn, Crypto, GetBlockTxn, Message,
+    BlackList, BlockTxHashes, BlockTxHashesReq, BlockTxn, Crypto, GetBlockTxn, Message, MsgClass,
     OperateType, Origin, Request, Response, UnverifiedTransaction, VerifyBlockReq, VerifyTxReq,
 };
 use libproto::{TryFrom, TryInto};
@@ -36,6 +40,7 @@ use pubsub::channel::{Receiver, Sender};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rayon::ThreadPoolBuilder;
 use serde_json;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::convert::Into;
 use std::str::FromStr;
@@ -43,12 +48,17 @@ use std::time::Duration;
 use util::BLOCKLIMIT;
 
 const TX_OK: &str = "OK";
+// Rejected transactions remembered for `getPoolTransaction`
+const REJECTED_TXS_CACHE_SIZE: usize = 10_000;
 // Paid for every non-zero byte of data or code for a transaction
 const G_TX_DATA_NON_ZERO: usize = 68;
 // Paid for every transaction
 const G_TRANSACTION: usize = 21000;
 // Paid for contract create
 const G_CREATE: usize = 32000;
+/// The hashes of the transactions added to the pool, from jsonrpc or from
+/// the network, for the `newPendingTransactions` subscriptions of jsonrpc.
+pub const PENDING_TXS: &str = "auth.pending_txs";
 
 // verify signature
 pub fn verify_tx_sig(crypto: Crypto, hash: &H256, sig_bytes: &[u8]) -> Result<Vec<u8>, ()> {
@@ -94,12 +104,17 @@ pub struct MsgHandler {
     dispatcher: Dispatcher,
     tx_request: Sender<Request>,
     tx_pool_limit: usize,
+    tx_pool_sender_limit: usize,
     is_snapshot: bool,
     black_list_cache: HashMap<Address, i8>,
     is_need_proposal_new_block: bool,
     config_info: SysConfigInfo,
     block_txn_req: Option<BlockTxnReq>,
     verify_block_req: Option<VerifyBlockReq>,
+    // why the recent transactions are rejected
+    rejected_txs: RefCell<LruCache<H256, Error>>,
+    // added to the pool by the message being handled
+    pending_txs: RefCell<Vec<H256>>,
 }
 
 impl MsgHandler {
@@ -109,6 +124,7 @@ impl MsgHandler {
         dispatcher: Dispatcher,
         tx_request: Sender<Request>,
         tx_pool_limit: usize,
+        tx_pool_sender_limit: usize,
         tx_verify_thread_num: usize,
         tx_verify_cache_size: usize,
     ) -> Self {
@@ -126,6 +142,7 @@ impl MsgHandler {
             dispatcher,
             tx_request,
             tx_pool_limit,
+            tx_pool_sender_limit,
             is_snapshot: false,
             black_list_cache: HashMap::new(),
             is_need_proposal_new_block: false,
@@ -138,6 +155,8 @@ impl MsgHandler {
             },
             block_txn_req: None,
             verify_block_req: None,
+            rejected_txs: RefCell::new(LruCache::new(REJECTED_TXS_CACHE_SIZE)),
+            pending_txs: RefCell::new(Vec::new()),
         }
     }
 
@@ -152,9 +171,29 @@ impl MsgHandler {
         self.tx_pool_limit != 0 && tx_count + self.dispatcher.tx_pool_len() > self.tx_pool_limit
     }
 
+    // Replacing a pending transaction doesn't add to the sender.
+    fn is_sender_limited(&self, signed_tx: &SignedTransaction) -> bool {
+        if self.tx_pool_sender_limit == 0 {
+            return false;
+        }
+        let sender = pubkey_to_address(&PubKey::from(signed_tx.get_signer()));
+        let nonce = signed_tx
+            .get_transaction_with_sig()
+            .get_transaction()
+            .get_nonce();
+        self.dispatcher.sender_pending(&sender) >= self.tx_pool_sender_limit
+            && !self.dispatcher.is_nonce_pending(&sender, nonce)
+    }
+
     #[allow(unknown_lints, clippy::option_option)] // TODO clippy
     fn get_ret_from_cache(&self, tx_hash: &H256) -> Option<Option<Vec<u8>>> {
-        self.cache.peek(tx_hash).cloned()
+        let ret = self.cache.peek(tx_hash).cloned();
+        if ret.is_some() {
+            metrics::VERIFY_CACHE_HITS.inc();
+        } else {
+            metrics::VERIFY_CACHE_MISSES.inc();
+        }
+        ret
     }
 
     fn save_ret_to_cache(&mut self, tx_hash: H256, option_pubkey: Option<Vec<u8>>) {
@@ -352,6 +391,15 @@ impl MsgHandler {
             .unwrap();
     }
 
+    /// Reply the failure if the transaction is from jsonrpc,
+    /// and remember why it is rejected for `getPoolTransaction`.
+    fn publish_tx_rejected(&self, is_local: bool, request_id: Vec<u8>, tx_hash: H256, ret: Error) {
+        if is_local {
+            self.publish_tx_failed_result(request_id, &ret);
+        }
+        self.rejected_txs.borrow_mut().put(tx_hash, ret);
+    }
+
     fn publish_tx_success_result(&self, request_id: Vec<u8>, tx_hash: H256) {
         let mut response = Response::new();
         response.set_request_id(request_id);
@@ -369,6 +417,26 @@ impl MsgHandler {
             .unwrap();
     }
 
+    fn publish_pending_txs(&self) {
+        let hashes: Vec<String> = self
+            .pending_txs
+            .borrow_mut()
+            .drain(..)
+            .map(|hash| format!("0x{}", hash.lower_hex()))
+            .collect();
+        if hashes.is_empty() {
+            return;
+        }
+        let msg = Message::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(serde_json::to_vec(&hashes).unwrap()),
+        );
+        self.tx_pub
+            .send((PENDING_TXS.to_owned(), msg.try_into().unwrap()))
+            .unwrap();
+    }
+
     fn forward_request(&self, tx_req: Request) {
         let _ = self.tx_request.send(tx_req);
     }
@@ -464,6 +532,7 @@ impl MsgHandler {
                     if let Some(newtx_req) = msg.take_request() {
                         let is_local = rounting_key.is_sub_module(SubModules::Jsonrpc);
                         self.deal_request(is_local, newtx_req);
+                        self.publish_pending_txs();
                     } else {
                         error!("Can not get request from message {:?}.", msg);
                     }
@@ -498,6 +567,13 @@ impl MsgHandler {
                         self.deal_signed_proposal(msg);
                     }
                 }
+                routing_key!(Jsonrpc >> RawBytes) => {
+                    if let Some(query) = msg.take_raw_bytes() {
+                        self.deal_pool_query(&query);
+                    } else {
+                        error!("Can not get pool query from message {:?}.", msg);
+                    }
+                }
                 routing_key!(Net >> BlockTxn) => {
                     if !self.is_ready() || self.verify_block_req.is_none() {
                         info!("Net >> BlockTxn: auth is not ready");
@@ -533,6 +609,8 @@ impl MsgHandler {
 
             // process message from MQ
             self.process_msg();
+
+            metrics::TX_POOL_SIZE.set(self.dispatcher.tx_pool_len() as i64);
         }
     }
 
@@ -587,7 +665,11 @@ impl MsgHandler {
             let hash = H256::from_slice(data);
             tx_hashes_h256.insert(hash);
         }
-        self.dispatcher.del_txs_from_pool_with_hash(&tx_hashes_h256);
+        let dropped = self.dispatcher.commit_txs(&tx_hashes_h256);
+        let mut rejected_txs = self.rejected_txs.borrow_mut();
+        for hash in dropped {
+            rejected_txs.put(hash, Error::NonceTaken);
+        }
 
         // update history_hashes
         for i in old_min_height..self.history_heights.min_height() {
@@ -647,11 +729,11 @@ impl MsgHandler {
 
             if self.is_flow_control(batch_new_tx.len()) {
                 trace!("flow control ...");
-                if is_local {
-                    for tx_req in batch_new_tx.iter() {
-                        let request_id = tx_req.get_request_id().to_vec();
-                        self.publish_tx_failed_result(request_id, &Error::Busy);
-                    }
+                for tx_req in batch_new_tx.iter() {
+                    let request_id = tx_req.get_request_id().to_vec();
+                    let req = tx_req.get_un_tx().tx_verify_req_msg();
+                    let tx_hash = H256::from_slice(req.get_tx_hash());
+                    self.publish_tx_rejected(is_local, request_id, tx_hash, Error::Busy);
                 }
                 return;
             }
@@ -663,10 +745,8 @@ impl MsgHandler {
                 let tx_hash = H256::from_slice(req.get_tx_hash());
                 if let Some(option_pubkey) = self.get_ret_from_cache(&tx_hash) {
                     if option_pubkey.is_none() {
-                        if is_local {
-                            let request_id = tx_req.get_request_id().to_vec();
-                            self.publish_tx_failed_result(request_id, &Error::BadSig);
-                        }
+                        let request_id = tx_req.get_request_id().to_vec();
+                        self.publish_tx_rejected(is_local, request_id, tx_hash, Error::BadSig);
                         continue;
                     }
                     let mut new_req = req.clone();
@@ -700,10 +780,8 @@ impl MsgHandler {
                         v.0.set_signer(pubkey);
                     }
                 } else if let Some(ref mut v) = requests.get_mut(&tx_hash) {
-                    if is_local {
-                        let request_id = v.1.get_request_id().to_vec();
-                        self.publish_tx_failed_result(request_id, &Error::BadSig);
-                    }
+                    let request_id = v.1.get_request_id().to_vec();
+                    self.publish_tx_rejected(is_local, request_id, tx_hash, Error::BadSig);
                     v.2 = false;
                 }
             }
@@ -712,34 +790,28 @@ impl MsgHandler {
             requests
                 .into_iter()
                 .filter(|(_tx_hash, (_req, _tx_req, flag))| *flag)
-                .filter(|(_tx_hash, (ref req, ref tx_req, _flag))| {
+                .filter(|(tx_hash, (ref req, ref tx_req, _flag))| {
                     if let Err(e) = self.verify_black_list(&req) {
-                        if is_local {
-                            let request_id = tx_req.get_request_id().to_vec();
-                            self.publish_tx_failed_result(request_id, &e);
-                        }
+                        let request_id = tx_req.get_request_id().to_vec();
+                        self.publish_tx_rejected(is_local, request_id, *tx_hash, e);
                         false
                     } else {
                         true
                     }
                 })
-                .filter(|(_tx_hash, (ref _req, ref tx_req, _flag))| {
+                .filter(|(tx_hash, (ref _req, ref tx_req, _flag))| {
                     if let Err(e) = self.verify_request(tx_req) {
-                        if is_local {
-                            let request_id = tx_req.get_request_id().to_vec();
-                            self.publish_tx_failed_result(request_id, &e);
-                        }
+                        let request_id = tx_req.get_request_id().to_vec();
+                        self.publish_tx_rejected(is_local, request_id, *tx_hash, e);
                         false
                     } else {
                         true
                     }
                 })
-                .filter(|(_tx_hash, (ref req, ref tx_req, _flag))| {
+                .filter(|(tx_hash, (ref req, ref tx_req, _flag))| {
                     if let Err(e) = self.verify_tx_req(&req) {
-                        if is_local {
-                            let request_id = tx_req.get_request_id().to_vec();
-                            self.publish_tx_failed_result(request_id, &e);
-                        }
+                        let request_id = tx_req.get_request_id().to_vec();
+                        self.publish_tx_rejected(is_local, request_id, *tx_hash, e);
                         false
                     } else {
                         true
@@ -750,17 +822,7 @@ impl MsgHandler {
                     signed_tx.set_transaction_with_sig(tx_req.get_un_tx().clone());
                     signed_tx.set_signer(req.get_signer().to_vec());
                     signed_tx.set_tx_hash(tx_hash.to_vec());
-                    let request_id = tx_req.get_request_id().to_vec();
-                    if self.dispatcher.add_tx_to_pool(&signed_tx) {
-                        if is_local {
-                            self.publish_tx_success_result(request_id, tx_hash);
-                        }
-                        // new tx need forward to other nodes
-                        self.forward_request(tx_req.clone());
-                    } else if is_local {
-                        // dup with transaction in tx pool
-                        self.publish_tx_failed_result(request_id, &Error::Dup);
-                    }
+                    self.submit_tx(is_local, tx_req.clone(), &signed_tx);
                 });
         } else if newtx_req.has_un_tx() {
             trace!("get single new tx request from Jsonrpc");
@@ -772,19 +834,18 @@ impl MsgHandler {
                 }
                 return;
             }
+            let mut req = newtx_req.get_un_tx().tx_verify_req_msg();
+            let tx_hash = H256::from_slice(req.get_tx_hash());
             if self.is_flow_control(1) {
                 trace!("flow control ...");
-                if is_local {
-                    self.publish_tx_failed_result(request_id, &Error::Busy);
-                }
+                self.publish_tx_rejected(is_local, request_id, tx_hash, Error::Busy);
                 return;
             }
-            let mut req = newtx_req.get_un_tx().tx_verify_req_msg();
             // verify with cache
-            let tx_hash = H256::from_slice(req.get_tx_hash());
             if let Some(option_pubkey) = self.get_ret_from_cache(&tx_hash) {
                 if option_pubkey.is_none() {
                     self.publish_tx_failed_result(request_id, &Error::BadSig);
+                    self.rejected_txs.borrow_mut().put(tx_hash, Error::BadSig);
                     return;
                 }
                 req.set_signer(option_pubkey.unwrap());
@@ -800,9 +861,7 @@ impl MsgHandler {
                         req.set_signer(pubkey);
                     }
                     Err(_) => {
-                        if is_local {
-                            self.publish_tx_failed_result(request_id, &Error::BadSig);
-                        }
+                        self.publish_tx_rejected(is_local, request_id, tx_hash, Error::BadSig);
                         return;
                     }
                 }
@@ -810,24 +869,18 @@ impl MsgHandler {
 
             // black verify
             if let Err(e) = self.verify_black_list(&req) {
-                if is_local {
-                    self.publish_tx_failed_result(request_id, &e);
-                }
+                self.publish_tx_rejected(is_local, request_id, tx_hash, e);
                 return;
             }
 
             if let Err(e) = self.verify_request(&newtx_req) {
-                if is_local {
-                    self.publish_tx_failed_result(request_id, &e);
-                }
+                self.publish_tx_rejected(is_local, request_id, tx_hash, e);
                 return;
             }
 
             // other verify
             if let Err(e) = self.verify_tx_req(&req) {
-                if is_local {
-                    self.publish_tx_failed_result(request_id, &e);
-                }
+                self.publish_tx_rejected(is_local, request_id, tx_hash, e);
                 return;
             }
 
@@ -836,16 +889,45 @@ impl MsgHandler {
             signed_tx.set_transaction_with_sig(newtx_req.get_un_tx().clone());
             signed_tx.set_signer(req.get_signer().to_vec());
             signed_tx.set_tx_hash(tx_hash.to_vec());
-            if self.dispatcher.add_tx_to_pool(&signed_tx) {
+            self.submit_tx(is_local, newtx_req, &signed_tx);
+        }
+    }
+
+    fn submit_tx(&self, is_local: bool, tx_req: Request, signed_tx: &SignedTransaction) {
+        let request_id = tx_req.get_request_id().to_vec();
+        let tx_hash = H256::from_slice(signed_tx.get_tx_hash());
+        if self.is_sender_limited(signed_tx) {
+            trace!("sender limit of tx {:?}", tx_hash);
+            self.publish_tx_rejected(is_local, request_id, tx_hash, Error::TooManyPending);
+            return;
+        }
+        match self.dispatcher.submit_tx(signed_tx) {
+            Ok(submitted) => {
+                let mut rejected_txs = self.rejected_txs.borrow_mut();
+                rejected_txs.pop(&tx_hash);
+                match submitted {
+                    Submitted::Added => {}
+                    Submitted::Replaced(hash) => {
+                        rejected_txs.put(hash, Error::Replaced);
+                    }
+                    Submitted::Cancelled(hash) => {
+                        rejected_txs.put(hash, Error::Cancelled);
+                    }
+                }
                 if is_local {
                     self.publish_tx_success_result(request_id, tx_hash);
                 }
+                self.pending_txs.borrow_mut().push(tx_hash);
                 // new tx need forward to other nodes
-                self.forward_request(newtx_req);
-            } else if is_local {
+                self.forward_request(tx_req);
+            }
+            Err(Error::Dup) => {
                 // dup with transaction in tx pool
-                self.publish_tx_failed_result(request_id, &Error::Dup);
+                if is_local {
+                    self.publish_tx_failed_result(request_id, &Error::Dup);
+                }
             }
+            Err(e) => self.publish_tx_rejected(is_local, request_id, tx_hash, e),
         }
     }
 
@@ -904,6 +986,78 @@ impl MsgHandler {
         }
     }
 
+    fn deal_pool_query(&self, query: &[u8]) {
+        let query: PoolQuery = match serde_json::from_slice(query) {
+            Ok(query) => query,
+            Err(e) => {
+                warn!("receive invalid pool query: {:?}", e);
+                return;
+            }
+        };
+        trace!("pool query {:?}", query);
+
+        let param = |index: usize| -> Result<Option<&str>, String> {
+            match query.params.get(index) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(serde_json::Value::String(s)) => Ok(Some(clean_0x(s))),
+                Some(v) => Err(format!("invalid param {}", v)),
+            }
+        };
+        let result = match query.method.as_str() {
+            GET_POOL_STATUS => {
+                serde_json::to_value(self.dispatcher.pool_status()).map_err(|e| e.to_string())
+            }
+            GET_POOL_CONTENT => param(0)
+                .and_then(|sender| match sender {
+                    Some(s) => Address::from_str(s)
+                        .map(Some)
+                        .map_err(|_| format!("invalid address {}", s)),
+                    None => Ok(None),
+                })
+                .and_then(|sender| {
+                    serde_json::to_value(self.dispatcher.pool_content(sender.as_ref()))
+                        .map_err(|e| e.to_string())
+                }),
+            GET_POOL_TRANSACTION => param(0)
+                .and_then(|hash| hash.ok_or_else(|| "missing transaction hash".to_owned()))
+                .and_then(|hash| H256::from_str(hash).map_err(|_| format!("invalid hash {}", hash)))
+                .and_then(|hash| {
+                    let state = if let Some(tx) = self.dispatcher.pool_transaction(&hash) {
+                        PoolTxState::Pending { transaction: tx }
+                    } else if let Some(e) = self.rejected_txs.borrow_mut().get(&hash) {
+                        PoolTxState::Rejected {
+                            reason: format!("{:?}", e),
+                        }
+                    } else {
+                        PoolTxState::Unknown
+                    };
+                    serde_json::to_value(state).map_err(|e| e.to_string())
+                }),
+            method => Err(format!("unknown method {}", method)),
+        };
+
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(e) => (None, Some(e)),
+        };
+        let reply = PoolReply {
+            request_id: query.request_id.clone(),
+            result,
+            error,
+        };
+        let msg = Message::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(serde_json::to_vec(&reply).unwrap()),
+        );
+        self.tx_pub
+            .send((
+                routing_key!(Auth >> RawBytes).into(),
+                msg.try_into().unwrap(),
+            ))
+            .unwrap();
+    }
+
     fn deal_get_block_txn(&mut self, get_block_txn: &mut GetBlockTxn, origin: Origin) {
         let short_ids: Vec<H256> = get_block_txn
             .get_short_ids()
@@ -942,6 +1096,7 @@ impl MsgHandler {
             if tx_hashes.is_empty() {
                 return;
             };
+            self.dispatcher.mark_proposed(&tx_hashes);
 
             // Check tx hash in cache
             for tx_hash in tx_hashes.clone() {
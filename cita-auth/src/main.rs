@@ -28,6 +28,7 @@
 //!     | auth  | Chain     | BlockTxHashes     |
 //!     | auth  | Executor  | BlackList         |
 //!     | auth  | Jsonrpc   | RequestNewTxBatch |
+//!     | auth  | Jsonrpc   | RawBytes          |
 //!     | auth  | Net       | Request           |
 //!     | auth  | Snapshot  | SnapshotReq       |
 //!     | auth  | Executor  | Miscellaneous     |
@@ -41,6 +42,7 @@
 //!     | auth  | Auth      | Chain     | BlockTxHashesReq |
 //!     | auth  | Auth      | Consensus | VerifyBlockResp  |
 //!     | auth  | Auth      | Jsonrpc   | Response         |
+//!     | auth  | Auth      | Jsonrpc   | RawBytes         |
 //!     | auth  | Auth      | Net       | Request          |
 //!     | auth  | Auth      | Consensus | BlockTxs         |
 //!     | auth  | Auth      | Snapshot  | SnapshotResp     |
@@ -48,6 +50,9 @@
 //!     | auth  | Auth      | Net       | GetBlockTxn      |
 //!     | auth  | Auth      | Net       | BlockTxn         |
 //!
+//!     The hashes of the transactions added to the pool, from jsonrpc or from
+//!     the network, are published as JSON on the plain key `auth.pending_txs`.
+//!
 //! ### Key behavior
 //!
 //! the key struct:
@@ -70,6 +75,8 @@ extern crate cita_crypto as crypto;
 extern crate libproto;
 #[macro_use]
 extern crate cita_logger as logger;
+#[macro_use]
+extern crate lazy_static;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
@@ -90,6 +97,7 @@ use libproto::router::{MsgType, RoutingKey, SubModules};
 use pubsub::channel;
 use pubsub::start_pubsub;
 use std::thread;
+use txpool::PriorityLanes;
 use util::set_panic_handler;
 
 pub mod batch_forward;
@@ -99,7 +107,9 @@ pub mod config;
 pub mod dispatcher;
 pub mod handler;
 pub mod history;
+mod metrics;
 mod transaction_verify;
+pub mod txpool;
 pub mod txwal;
 
 include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
@@ -130,8 +140,11 @@ fn main() {
     let tx_verify_thread_num = config.tx_verify_thread_num;
     let tx_verify_cache_size = config.tx_verify_cache_size;
     let tx_pool_limit = config.tx_pool_limit;
+    let tx_pool_sender_limit = config.tx_pool_sender_limit;
     let wal_enable = config.wal_enable;
 
+    cita_metrics::start_server(config.metrics);
+
     // Start publish and subcribe message from MQ.
     // The CITA system runs in a logic nodes, and it contains some components
     // which we called micro-service at their running time.
@@ -146,6 +159,7 @@ fn main() {
             Chain >> BlockTxHashes,
             Executor >> BlackList,
             Jsonrpc >> RequestNewTxBatch,
+            Jsonrpc >> RawBytes,
             Net >> Request,
             Snapshot >> SnapshotReq,
             Executor >> Miscellaneous,
@@ -165,7 +179,11 @@ fn main() {
         batch_forward.run();
     });
 
-    let dispatcher = Dispatcher::new(wal_enable);
+    let dispatcher = Dispatcher::new(
+        wal_enable,
+        config.package_strategy,
+        PriorityLanes::new(&config.priority),
+    );
 
     // handle message from MQ
     let mut msg_handler = MsgHandler::new(
@@ -174,6 +192,7 @@ fn main() {
         dispatcher,
         tx_request,
         tx_pool_limit,
+        tx_pool_sender_limit,
         tx_verify_thread_num,
         tx_verify_cache_size,
     );
@@ -0,0 +1,28 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cita_metrics::{int_counter, int_gauge, IntCounter, IntGauge};
+
+lazy_static! {
+    pub static ref TX_POOL_SIZE: IntGauge =
+        int_gauge("auth_tx_pool_size", "Number of transactions in the pool");
+    pub static ref VERIFY_CACHE_HITS: IntCounter = int_counter(
+        "auth_verify_cache_hits_total",
+        "Signature verifications served by the cache"
+    );
+    pub static ref VERIFY_CACHE_MISSES: IntCounter = int_counter(
+        "auth_verify_cache_misses_total",
+        "Signature verifications missed the cache"
+    );
+}
@@ -28,6 +28,16 @@ pub enum Error {
     Forbidden,
     InvalidValue,
     InvalidVersion,
+    // The quota limit of the replacement is not higher than the pending one
+    QuotaNotHigher,
+    // The transaction with the same nonce is already proposed or committed
+    NonceTaken,
+    // Replaced by another transaction with the same nonce
+    Replaced,
+    // Cancelled by its sender
+    Cancelled,
+    // The sender has too many transactions in the pool
+    TooManyPending,
 }
 
 impl fmt::Display for Error {
@@ -45,6 +55,11 @@ impl fmt::Display for Error {
             Forbidden => write!(f, "Forbidden"),
             InvalidValue => write!(f, "InvalidValue"),
             InvalidVersion => write!(f, "InvalidVersion"),
+            QuotaNotHigher => write!(f, "QuotaNotHigher"),
+            NonceTaken => write!(f, "NonceTaken"),
+            Replaced => write!(f, "Replaced"),
+            Cancelled => write!(f, "Cancelled"),
+            TooManyPending => write!(f, "TooManyPending"),
         }
     }
 }
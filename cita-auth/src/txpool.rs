@@ -0,0 +1,592 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Introspection of the transaction pool.
+//!
+//! `tx_pool::Pool` can only be looked up by hash, so the sender, the nonce
+//! and the receiving time of every pending transaction are kept in a
+//! `PoolIndex` beside it.
+//!
+//! Jsonrpc sends a `PoolQuery` in a `RawBytes` message, and auth replies
+//! with a `PoolReply` in the same way.
+//!
+//! The transactions in the priority lanes are packaged first. With the
+//! `fair` package strategy, the rest of the quota is shared among the
+//! senders round by round, instead of in the order of the pool.
+
+use cita_types::traits::LowerHex;
+use cita_types::{clean_0x, Address, H256};
+use crypto::{pubkey_to_address, PubKey};
+use libproto::blockchain::{AccountGasLimit, SignedTransaction, Transaction};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::time::Instant;
+
+pub const GET_POOL_STATUS: &str = "getPoolStatus";
+pub const GET_POOL_CONTENT: &str = "getPoolContent";
+pub const GET_POOL_TRANSACTION: &str = "getPoolTransaction";
+
+#[derive(Debug, Clone)]
+struct PoolEntry {
+    sender: Address,
+    nonce: String,
+    received: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct PoolIndex {
+    entries: HashMap<H256, PoolEntry>,
+    senders: HashMap<Address, usize>,
+    nonces: HashMap<(Address, String), H256>,
+}
+
+impl PoolIndex {
+    pub fn new() -> Self {
+        PoolIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, hash: H256, sender: Address, nonce: &str) {
+        if self.entries.contains_key(&hash) {
+            return;
+        }
+        *self.senders.entry(sender).or_insert(0) += 1;
+        // An empty nonce can't identify a transaction.
+        if !nonce.is_empty() {
+            self.nonces.insert((sender, nonce.to_owned()), hash);
+        }
+        self.entries.insert(
+            hash,
+            PoolEntry {
+                sender,
+                nonce: nonce.to_owned(),
+                received: Instant::now(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, hash: &H256) {
+        if let Some(entry) = self.entries.remove(hash) {
+            let sender = entry.sender;
+            let key = (sender, entry.nonce);
+            if self.nonces.get(&key) == Some(hash) {
+                self.nonces.remove(&key);
+            }
+            let empty = match self.senders.get_mut(&sender) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if empty {
+                self.senders.remove(&sender);
+            }
+        }
+    }
+
+    /// Drop the entries of transactions which are not in the pool any more,
+    /// e.g. the outdated ones dropped when packaging.
+    pub fn retain<F>(&mut self, mut in_pool: F)
+    where
+        F: FnMut(&H256) -> bool,
+    {
+        let removed: Vec<H256> = self
+            .entries
+            .keys()
+            .filter(|hash| !in_pool(hash))
+            .cloned()
+            .collect();
+        for hash in removed {
+            self.remove(&hash);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.senders.clear();
+        self.nonces.clear();
+    }
+
+    /// The pending transaction of the sender with the nonce.
+    pub fn find(&self, sender: &Address, nonce: &str) -> Option<H256> {
+        self.nonces.get(&(*sender, nonce.to_owned())).cloned()
+    }
+
+    pub fn sender_count(&self, sender: &Address) -> usize {
+        self.senders.get(sender).cloned().unwrap_or(0)
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            pending: self.entries.len(),
+            senders: self
+                .senders
+                .iter()
+                .map(|(sender, count)| (format!("0x{}", sender.lower_hex()), *count))
+                .collect(),
+            oldest_tx_age: self
+                .entries
+                .values()
+                .map(|entry| entry.received.elapsed().as_secs())
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// The hashes of the pending transactions, grouped by sender.
+    pub fn content(&self, sender: Option<&Address>) -> BTreeMap<Address, Vec<H256>> {
+        let mut content: BTreeMap<Address, Vec<H256>> = BTreeMap::new();
+        for (hash, entry) in &self.entries {
+            if sender.map(|s| *s == entry.sender).unwrap_or(true) {
+                content.entry(entry.sender).or_default().push(*hash);
+            }
+        }
+        content
+    }
+
+    pub fn pool_tx(&self, tx: &SignedTransaction) -> Option<PoolTx> {
+        let hash = H256::from_slice(tx.get_tx_hash());
+        self.entries.get(&hash).map(|entry| {
+            let transaction = tx.get_transaction_with_sig().get_transaction();
+            PoolTx {
+                hash: format!("0x{}", hash.lower_hex()),
+                from: format!("0x{}", entry.sender.lower_hex()),
+                nonce: transaction.get_nonce().to_owned(),
+                quota: transaction.get_quota(),
+                valid_until_block: transaction.get_valid_until_block(),
+                age: entry.received.elapsed().as_secs(),
+            }
+        })
+    }
+}
+
+/// How the transactions of a proposal are taken from the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageStrategy {
+    /// In the order of the pool.
+    Fifo,
+    /// One transaction of each sender in turn.
+    Fair,
+}
+
+impl Default for PackageStrategy {
+    fn default() -> Self {
+        PackageStrategy::Fifo
+    }
+}
+
+/// Transactions to be packaged first, by their senders or destinations.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PriorityConfig {
+    pub senders: Vec<String>,
+    pub contracts: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct PriorityLanes {
+    senders: HashSet<Address>,
+    contracts: HashSet<Address>,
+}
+
+impl PriorityLanes {
+    pub fn new(config: &PriorityConfig) -> Self {
+        let parse = |addrs: &[String]| {
+            addrs
+                .iter()
+                .filter_map(|addr| match Address::from_str(clean_0x(addr)) {
+                    Ok(addr) => Some(addr),
+                    Err(_) => {
+                        warn!("invalid priority address {}", addr);
+                        None
+                    }
+                })
+                .collect()
+        };
+        PriorityLanes {
+            senders: parse(&config.senders),
+            contracts: parse(&config.contracts),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty() && self.contracts.is_empty()
+    }
+
+    pub fn contains(&self, tx: &SignedTransaction) -> bool {
+        let sender = pubkey_to_address(&PubKey::from_slice(tx.get_signer()));
+        self.senders.contains(&sender)
+            || tx_to(tx.get_transaction_with_sig().get_transaction())
+                .map(|to| self.contracts.contains(&to))
+                .unwrap_or(false)
+    }
+}
+
+/// The destination of a transaction, `None` for contract creation.
+pub fn tx_to(tx: &Transaction) -> Option<Address> {
+    if tx.get_to_v1().is_empty() {
+        Address::from_str(clean_0x(tx.get_to())).ok()
+    } else if tx.get_to_v1().len() == 20 {
+        Some(Address::from_slice(tx.get_to_v1()))
+    } else {
+        None
+    }
+}
+
+/// Packages the transactions of a proposal within the block and account quota limits.
+pub struct Packer<'a> {
+    block_quota_left: u64,
+    account_quota_limit: &'a AccountGasLimit,
+    account_quota_left: HashMap<Address, u64>,
+    check_quota: bool,
+    admin_address: Option<Address>,
+}
+
+impl<'a> Packer<'a> {
+    pub fn new(
+        block_quota_limit: u64,
+        account_quota_limit: &'a AccountGasLimit,
+        check_quota: bool,
+        admin_address: Option<Address>,
+    ) -> Self {
+        Packer {
+            block_quota_left: block_quota_limit,
+            account_quota_limit,
+            account_quota_left: HashMap::new(),
+            check_quota,
+            admin_address,
+        }
+    }
+
+    /// Take the transactions of the priority lanes first in the order given,
+    /// then the others by the strategy.
+    pub fn take_with_lanes(
+        &mut self,
+        txs: Vec<SignedTransaction>,
+        lanes: &PriorityLanes,
+        strategy: PackageStrategy,
+    ) -> Vec<SignedTransaction> {
+        let (priority_txs, txs): (Vec<_>, Vec<_>) =
+            txs.into_iter().partition(|tx| lanes.contains(tx));
+        let mut packaged = self.take_in_order(priority_txs);
+        packaged.extend(match strategy {
+            PackageStrategy::Fifo => self.take_in_order(txs),
+            PackageStrategy::Fair => self.take_fair(txs),
+        });
+        packaged
+    }
+
+    /// Take the transactions which fit in the order given.
+    pub fn take_in_order(&mut self, txs: Vec<SignedTransaction>) -> Vec<SignedTransaction> {
+        txs.into_iter().filter(|tx| self.take(tx)).collect()
+    }
+
+    /// Take the transactions of the senders in turn, until the block quota is used up.
+    ///
+    /// The transactions of a sender keep their order, so a sender is skipped
+    /// for the rest of the block once its next transaction doesn't fit.
+    pub fn take_fair(&mut self, txs: Vec<SignedTransaction>) -> Vec<SignedTransaction> {
+        let mut queues: Vec<VecDeque<SignedTransaction>> = Vec::new();
+        let mut positions: HashMap<Address, usize> = HashMap::new();
+        for tx in txs {
+            let sender = pubkey_to_address(&PubKey::from_slice(tx.get_signer()));
+            let position = *positions.entry(sender).or_insert_with(|| {
+                queues.push(VecDeque::new());
+                queues.len() - 1
+            });
+            queues[position].push_back(tx);
+        }
+
+        let mut packaged = Vec::new();
+        while !queues.is_empty() {
+            for queue in queues.iter_mut() {
+                match queue.pop_front() {
+                    Some(tx) => {
+                        if self.take(&tx) {
+                            packaged.push(tx);
+                        } else {
+                            queue.clear();
+                        }
+                    }
+                    None => continue,
+                }
+            }
+            queues.retain(|queue| !queue.is_empty());
+        }
+        packaged
+    }
+
+    fn take(&mut self, tx: &SignedTransaction) -> bool {
+        let quota = tx.get_transaction_with_sig().get_transaction().get_quota();
+        if quota > self.block_quota_left {
+            return false;
+        }
+        let sender = pubkey_to_address(&PubKey::from_slice(tx.get_signer()));
+        if self.check_quota && Some(sender) != self.admin_address {
+            let account_quota_limit = self.account_quota_limit;
+            let left = self.account_quota_left.entry(sender).or_insert_with(|| {
+                account_quota_limit
+                    .get_specific_quota_limit()
+                    .get(&sender.lower_hex())
+                    .cloned()
+                    .unwrap_or_else(|| account_quota_limit.get_common_quota_limit())
+            });
+            if quota > *left {
+                return false;
+            }
+            *left -= quota;
+        }
+        self.block_quota_left -= quota;
+        true
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStatus {
+    pub pending: usize,
+    /// Pending transactions of each sender.
+    pub senders: BTreeMap<String, usize>,
+    /// Seconds since the oldest pending transaction was received.
+    pub oldest_tx_age: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolTx {
+    pub hash: String,
+    pub from: String,
+    pub nonce: String,
+    pub quota: u64,
+    pub valid_until_block: u64,
+    /// Seconds since the transaction was received.
+    pub age: u64,
+}
+
+/// Where a transaction is, as the result of `getPoolTransaction`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PoolTxState {
+    Pending { transaction: PoolTx },
+    Rejected { reason: String },
+    Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoolQuery {
+    pub request_id: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolReply {
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PackageStrategy, Packer, PoolIndex, PriorityConfig, PriorityLanes};
+    use cita_types::traits::LowerHex;
+    use cita_types::{Address, H256};
+    use crypto::{pubkey_to_address, CreateKey, KeyPair, PubKey};
+    use libproto::blockchain::{AccountGasLimit, SignedTransaction};
+    use libproto::Transaction;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_pool_index() {
+        let alice = Address::from(1);
+        let bob = Address::from(2);
+        let mut index = PoolIndex::new();
+        index.insert(H256::from(1), alice, "1");
+        index.insert(H256::from(2), alice, "2");
+        index.insert(H256::from(2), alice, "2");
+        index.insert(H256::from(3), bob, "1");
+        index.insert(H256::from(4), bob, "");
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.sender_count(&alice), 2);
+        assert_eq!(index.find(&alice, "2"), Some(H256::from(2)));
+        assert_eq!(index.find(&bob, "1"), Some(H256::from(3)));
+        assert_eq!(index.find(&bob, ""), None);
+        index.remove(&H256::from(4));
+
+        let status = index.status();
+        assert_eq!(status.pending, 3);
+        assert_eq!(status.senders.len(), 2);
+
+        let content = index.content(Some(&bob));
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[&bob], vec![H256::from(3)]);
+
+        index.remove(&H256::from(3));
+        assert_eq!(index.sender_count(&bob), 0);
+        assert_eq!(index.find(&bob, "1"), None);
+        assert_eq!(index.status().senders.len(), 1);
+
+        index.retain(|hash| *hash == H256::from(1));
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.sender_count(&alice), 1);
+
+        index.clear();
+        assert!(index.is_empty());
+    }
+
+    fn sign(keypair: &KeyPair, nonce: &str, quota: u64) -> SignedTransaction {
+        let mut raw_tx = Transaction::new();
+        raw_tx.set_nonce(nonce.to_owned());
+        raw_tx.set_quota(quota);
+        raw_tx.sign(*keypair.privkey())
+    }
+
+    fn sender(tx: &SignedTransaction) -> Address {
+        pubkey_to_address(&PubKey::from_slice(tx.get_signer()))
+    }
+
+    #[test]
+    fn test_fair_share() {
+        let noisy = KeyPair::gen_keypair();
+        let quiet = KeyPair::gen_keypair();
+        let mut txs: Vec<SignedTransaction> =
+            (0..4).map(|i| sign(&noisy, &i.to_string(), 100)).collect();
+        txs.push(sign(&quiet, "0", 100));
+        txs.push(sign(&quiet, "1", 100));
+
+        let mut account_quota_limit = AccountGasLimit::new();
+        account_quota_limit.set_common_quota_limit(1000);
+
+        // The quiet sender gets its share though it comes last.
+        let packaged = Packer::new(300, &account_quota_limit, true, None).take_fair(txs.clone());
+        assert_eq!(packaged.len(), 3);
+        assert_eq!(sender(&packaged[0]), sender(&txs[0]));
+        assert_eq!(sender(&packaged[1]), sender(&txs[4]));
+        assert_eq!(packaged[2].get_tx_hash(), txs[1].get_tx_hash());
+
+        // The noisy sender is stopped by its account quota.
+        account_quota_limit.set_common_quota_limit(200);
+        let packaged = Packer::new(10_000, &account_quota_limit, true, None).take_fair(txs.clone());
+        assert_eq!(packaged.len(), 4);
+        let admin = Some(sender(&txs[0]));
+        let packaged = Packer::new(10_000, &account_quota_limit, true, admin).take_fair(txs);
+        assert_eq!(packaged.len(), 6);
+    }
+
+    #[test]
+    fn test_priority_lanes() {
+        let admin = KeyPair::gen_keypair();
+        let user = KeyPair::gen_keypair();
+        let admin_tx = sign(&admin, "0", 100);
+        let user_tx = sign(&user, "0", 100);
+        let lanes = PriorityLanes::new(&PriorityConfig {
+            senders: vec![sender(&admin_tx).lower_hex()],
+            contracts: vec![
+                "0xffffffffffffffffffffffffffffffffff020000".to_owned(),
+                "bad".to_owned(),
+            ],
+        });
+        assert!(!lanes.is_empty());
+        assert!(lanes.contains(&admin_tx));
+        assert!(!lanes.contains(&user_tx));
+
+        let contract = Address::from_str("ffffffffffffffffffffffffffffffffff020000").unwrap();
+        let mut raw_tx = Transaction::new();
+        raw_tx.set_to_v1(contract.to_vec());
+        raw_tx.set_quota(100);
+        assert!(lanes.contains(&raw_tx.sign(*user.privkey())));
+    }
+
+    fn hashes(txs: &[SignedTransaction]) -> Vec<Vec<u8>> {
+        txs.iter().map(|tx| tx.get_tx_hash().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_take_with_lanes() {
+        let lane = KeyPair::gen_keypair();
+        let first = KeyPair::gen_keypair();
+        let second = KeyPair::gen_keypair();
+        let l0 = sign(&lane, "0", 100);
+        let l1 = sign(&lane, "1", 100);
+        let f0 = sign(&first, "0", 100);
+        let f1 = sign(&first, "1", 100);
+        let s0 = sign(&second, "0", 100);
+        let s1 = sign(&second, "1", 100);
+        let txs = vec![
+            f0.clone(),
+            f1.clone(),
+            s0.clone(),
+            l0.clone(),
+            l1.clone(),
+            s1.clone(),
+        ];
+        let lanes = PriorityLanes::new(&PriorityConfig {
+            senders: vec![sender(&l0).lower_hex()],
+            contracts: Vec::new(),
+        });
+        let mut account_quota_limit = AccountGasLimit::new();
+        account_quota_limit.set_common_quota_limit(1000);
+
+        // The lane goes first, then the others in the order of the pool.
+        let packaged = Packer::new(400, &account_quota_limit, false, None).take_with_lanes(
+            txs.clone(),
+            &lanes,
+            PackageStrategy::Fifo,
+        );
+        assert_eq!(
+            hashes(&packaged),
+            hashes(&[l0.clone(), l1.clone(), f0.clone(), f1.clone()])
+        );
+
+        // The lane goes first, then the others in turn.
+        let packaged = Packer::new(400, &account_quota_limit, false, None).take_with_lanes(
+            txs.clone(),
+            &lanes,
+            PackageStrategy::Fair,
+        );
+        assert_eq!(
+            hashes(&packaged),
+            hashes(&[l0.clone(), l1.clone(), f0.clone(), s0.clone()])
+        );
+
+        // The lane may use up the block quota.
+        let packaged = Packer::new(150, &account_quota_limit, false, None).take_with_lanes(
+            txs.clone(),
+            &lanes,
+            PackageStrategy::Fair,
+        );
+        assert_eq!(hashes(&packaged), hashes(&[l0.clone()]));
+
+        // The lane is still limited by the account quota.
+        account_quota_limit.set_common_quota_limit(100);
+        let packaged = Packer::new(10_000, &account_quota_limit, true, None).take_with_lanes(
+            txs,
+            &lanes,
+            PackageStrategy::Fair,
+        );
+        assert_eq!(hashes(&packaged), hashes(&[l0, f0, s0]));
+    }
+}
@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cita_db::{DataCategory, Database, RocksDB};
+use crate::db_indexes::{DBIndex, InstalledFilter, InstalledFilters};
+use crate::filters::logs::LogCursor;
 use jsonrpc_types::rpc_types::Filter;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
 use std::time::SystemTime;
 
 // TODO Refactor:
@@ -73,6 +77,23 @@ impl LogsFilter {
     }
 }
 
+/// The ids of the filters saved in the database. Every filter is saved by
+/// its own key, without its update timestamp, so a poll only writes the
+/// filter polled.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedFilters {
+    next_available_id: usize,
+    ids: Vec<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedFilter {
+    block: Option<u64>,
+    logs: Option<Filter>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
 #[derive(Default)]
 pub struct FilterDB {
     /// Self-increase ID.
@@ -82,8 +103,13 @@ pub struct FilterDB {
     /// Refactor: Note: logs filter includes block filter.
     block_filter: BlockFilter,
     logs_filter: LogsFilter,
+    /// The cursor of the next changes of a logs filter, when the last
+    /// changes are a page of them
+    logs_cursors: HashMap<usize, LogCursor>,
     /// lifetime of fileter id
     lifetime: u32,
+    /// To save the filters, None for keeping them in memory only
+    db: Option<Arc<RocksDB>>,
 }
 
 impl FilterDB {
@@ -95,6 +121,106 @@ impl FilterDB {
         }
     }
 
+    /// Load the filters saved in the database, and save them again once
+    /// changed. The loaded filters are taken as updated now, so the downtime
+    /// of the node is not counted in their lifetime.
+    pub fn load(db: Arc<RocksDB>) -> Self {
+        let mut filterdb = FilterDB::new();
+        let saved = db
+            .get(Some(DataCategory::Extra), &InstalledFilters.get_index())
+            .unwrap_or(None)
+            .and_then(|bytes| {
+                serde_json::from_slice::<SavedFilters>(&bytes)
+                    .map_err(|e| warn!("Load filters error: {:?}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        let now = now();
+        filterdb.next_available_id = saved.next_available_id;
+        for id in saved.ids {
+            let filter = match db
+                .get(
+                    Some(DataCategory::Extra),
+                    &InstalledFilter(id as u64).get_index(),
+                )
+                .unwrap_or(None)
+                .and_then(|bytes| {
+                    serde_json::from_slice::<SavedFilter>(&bytes)
+                        .map_err(|e| warn!("Load filter {} error: {:?}", id, e))
+                        .ok()
+                }) {
+                Some(filter) => filter,
+                None => continue,
+            };
+            filterdb.last_update.insert(id, now);
+            if let Some(block) = filter.block {
+                filterdb.block_filter.insert(id, block);
+            }
+            if let Some(logs) = filter.logs {
+                filterdb.logs_filter.insert(id, logs);
+            }
+            if let Some(cursor) = filter.cursor.and_then(|cursor| cursor.parse().ok()) {
+                filterdb.logs_cursors.insert(id, cursor);
+            }
+        }
+        info!("Load {} filters", filterdb.last_update.len());
+        filterdb.db = Some(db);
+        filterdb
+    }
+
+    /// Save the ids of the filters, once a filter is installed or removed.
+    fn save_ids(&self) {
+        let db = match self.db {
+            Some(ref db) => db,
+            None => return,
+        };
+        let ids = self
+            .block_filter
+            .data
+            .keys()
+            .chain(self.logs_filter.data.keys())
+            .cloned()
+            .collect::<BTreeSet<usize>>();
+        let saved = SavedFilters {
+            next_available_id: self.next_available_id,
+            ids: ids.into_iter().collect(),
+        };
+        if let Err(e) = db.insert(
+            Some(DataCategory::Extra),
+            InstalledFilters.get_index(),
+            serde_json::to_vec(&saved).unwrap(),
+        ) {
+            warn!("Save filters error: {:?}", e);
+        }
+    }
+
+    /// Save the filter of the id, or remove it if it is not installed.
+    fn save_filter(&self, id: usize) {
+        let db = match self.db {
+            Some(ref db) => db,
+            None => return,
+        };
+        let key = InstalledFilter(id as u64).get_index();
+        let result = if self.is_filter(id) {
+            let saved = SavedFilter {
+                block: self.block_filter.get(id).cloned(),
+                logs: self.logs_filter.get(id).cloned(),
+                cursor: self.logs_cursors.get(&id).map(ToString::to_string),
+            };
+            db.insert(
+                Some(DataCategory::Extra),
+                key,
+                serde_json::to_vec(&saved).unwrap(),
+            )
+        } else {
+            db.remove(Some(DataCategory::Extra), &key)
+        };
+        if let Err(e) = result {
+            warn!("Save filter {} error: {:?}", id, e);
+        }
+    }
+
     #[cfg(test)]
     pub fn set_lifetime(&mut self, lifetime: u32) {
         self.lifetime = lifetime;
@@ -112,15 +238,41 @@ impl FilterDB {
     /// Generate a new normal filter
     pub fn gen_logs_filter(&mut self, id: usize, filter: Filter) {
         let now = now();
+        let is_new = !self.is_filter(id);
         self.last_update.insert(id, now);
         self.logs_filter.insert(id, filter);
+        self.save_filter(id);
+        if is_new {
+            self.save_ids();
+        }
     }
 
     /// Generate a new filter for block
     pub fn gen_block_filter(&mut self, id: usize, filter: u64) {
         let now = now();
+        let is_new = !self.is_filter(id);
         self.last_update.insert(id, now);
         self.block_filter.insert(id, filter);
+        self.save_filter(id);
+        if is_new {
+            self.save_ids();
+        }
+    }
+
+    /// Set the cursor of the next changes of a logs filter, None once all
+    /// the changes are returned.
+    pub fn set_logs_cursor(&mut self, id: usize, cursor: Option<LogCursor>) {
+        let changed = match cursor {
+            Some(cursor) => self.logs_cursors.insert(id, cursor) != Some(cursor),
+            None => self.logs_cursors.remove(&id).is_some(),
+        };
+        if changed {
+            self.save_filter(id);
+        }
+    }
+
+    pub fn get_logs_cursor(&self, id: usize) -> Option<LogCursor> {
+        self.logs_cursors.get(&id).cloned()
     }
 
     /// Uninstall the filter id
@@ -133,6 +285,9 @@ impl FilterDB {
             if self.is_logs_filter(id) {
                 self.logs_filter.remove(id);
             }
+            self.logs_cursors.remove(&id);
+            self.save_filter(id);
+            self.save_ids();
 
             true
         } else {
@@ -144,13 +299,24 @@ impl FilterDB {
     /// Remove all the ids that: (now-lastupdate) > self.lifetime
     pub fn prune(&mut self) {
         let now = now();
+        let mut pruned = false;
         for (id, time) in self.last_update.clone().iter() {
             if (now - *time) >= self.lifetime.into() {
                 trace!("Prune filter, time: {:?}", (now - *time));
+                let is_filter = self.is_filter(*id);
                 self.block_filter.remove(*id);
                 self.logs_filter.remove(*id);
+                self.logs_cursors.remove(id);
+                self.last_update.remove(id);
+                if is_filter {
+                    self.save_filter(*id);
+                    pruned = true;
+                }
             }
         }
+        if pruned {
+            self.save_ids();
+        }
     }
 
     /// Get the filter for logs.
@@ -201,8 +367,13 @@ fn now() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::FilterDB;
+    use crate::cita_db::{Config, DataCategory, Database, RocksDB, NUM_COLUMNS};
+    use crate::db_indexes::{DBIndex, InstalledFilter, InstalledFilters};
+    use crate::filters::logs::LogCursor;
     use jsonrpc_types::rpc_types::BlockNumber;
     use jsonrpc_types::rpc_types::Filter;
+    use std::sync::Arc;
+    use tempdir::TempDir;
 
     #[test]
     fn test_gen_id() {
@@ -260,4 +431,49 @@ mod tests {
         assert_eq!(filterdb.get_block_filter(id), None);
         assert_eq!(filterdb.is_filter(id), false);
     }
+
+    #[test]
+    fn test_load_saved_filters() {
+        let dir = TempDir::new("filterdb").unwrap();
+        let config = Config::with_category_num(NUM_COLUMNS);
+        let db = Arc::new(RocksDB::open(dir.path().to_str().unwrap(), &config).unwrap());
+        let filter = Filter::new(BlockNumber::earliest(), BlockNumber::earliest(), None, None);
+
+        let mut filterdb = FilterDB::load(Arc::clone(&db));
+        let logs_id = filterdb.gen_id();
+        filterdb.gen_logs_filter(logs_id, filter.clone());
+        filterdb.gen_block_filter(logs_id, 5);
+        let cursor = LogCursor {
+            block_number: 5,
+            log_index: 3,
+        };
+        filterdb.set_logs_cursor(logs_id, Some(cursor));
+        let block_id = filterdb.gen_id();
+        filterdb.gen_block_filter(block_id, 6);
+        let uninstalled_id = filterdb.gen_id();
+        filterdb.gen_block_filter(uninstalled_id, 7);
+        filterdb.uninstall(uninstalled_id);
+        let read = |key: Vec<u8>| db.get(Some(DataCategory::Extra), &key).unwrap();
+        assert!(read(InstalledFilter(uninstalled_id as u64).get_index()).is_none());
+        // The last polled height is saved, only the polled filter is written.
+        let ids = read(InstalledFilters.get_index());
+        let logs_filter = read(InstalledFilter(logs_id as u64).get_index());
+        filterdb.gen_block_filter(block_id, 8);
+        assert_eq!(read(InstalledFilters.get_index()), ids);
+        assert_eq!(
+            read(InstalledFilter(logs_id as u64).get_index()),
+            logs_filter
+        );
+
+        let mut filterdb = FilterDB::load(db);
+        assert_eq!(filterdb.get_logs_filter(logs_id), Some(&filter));
+        assert_eq!(filterdb.get_block_filter(logs_id), Some(&5));
+        assert_eq!(filterdb.get_logs_cursor(logs_id), Some(cursor));
+        filterdb.set_logs_cursor(logs_id, None);
+        assert_eq!(filterdb.get_logs_cursor(logs_id), None);
+        assert!(filterdb.is_block_filter(block_id));
+        assert_eq!(filterdb.get_block_filter(block_id), Some(&8));
+        assert!(!filterdb.is_filter(uninstalled_id));
+        assert_eq!(filterdb.gen_id(), uninstalled_id + 1);
+    }
 }
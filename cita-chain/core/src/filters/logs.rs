@@ -0,0 +1,136 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limits and pagination of the log queries.
+//!
+//! The limits are not set by default. Once set, `getLogs` and the filter
+//! logs fail if the block range or the number of the logs is beyond them,
+//! and `getFilterChanges` returns a page of the changes, the rest by the next
+//! calls. `getLogsPage` returns the logs page by page, with a cursor for the
+//! next page.
+
+use crate::header::BlockNumber;
+use crate::log::LocalizedLog;
+use std::fmt;
+use std::str::FromStr;
+
+/// Logs of a page if neither the page size nor `max_results` is set.
+pub const DEFAULT_LOGS_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LogsConfig {
+    /// Blocks a query can cover, 0 for no limit.
+    pub max_block_range: u64,
+    /// Logs a query can return, 0 for no limit.
+    pub max_results: usize,
+}
+
+impl Default for LogsConfig {
+    fn default() -> Self {
+        LogsConfig {
+            max_block_range: 0,
+            max_results: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogsError {
+    BlockRangeTooLarge(u64),
+    TooManyResults(usize),
+    InvalidPageSize,
+    InvalidCursor(String),
+}
+
+impl fmt::Display for LogsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogsError::BlockRangeTooLarge(max) => write!(
+                f,
+                "block range is larger than {}, narrow it or use getLogsPage",
+                max
+            ),
+            LogsError::TooManyResults(max) => write!(
+                f,
+                "more than {} logs are matched, narrow the filter or use getLogsPage",
+                max
+            ),
+            LogsError::InvalidPageSize => write!(f, "page size should be positive"),
+            LogsError::InvalidCursor(cursor) => write!(f, "invalid cursor {}", cursor),
+        }
+    }
+}
+
+/// The position of the first log of a page: the block number and the log
+/// index in the block.
+///
+/// It is given to the clients as a hex string of 24 digits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogCursor {
+    pub block_number: BlockNumber,
+    pub log_index: usize,
+}
+
+impl fmt::Display for LogCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:016x}{:08x}", self.block_number, self.log_index)
+    }
+}
+
+impl FromStr for LogCursor {
+    type Err = LogsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LogsError::InvalidCursor(s.to_owned());
+        let hex = s.trim_start_matches("0x");
+        if hex.len() != 24 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let block_number = u64::from_str_radix(&hex[..16], 16).map_err(|_| invalid())?;
+        let log_index = u32::from_str_radix(&hex[16..], 16).map_err(|_| invalid())?;
+        Ok(LogCursor {
+            block_number,
+            log_index: log_index as usize,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LogsPage {
+    pub logs: Vec<LocalizedLog>,
+    /// The cursor of the next page, `None` for the last page.
+    pub next: Option<LogCursor>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogCursor, LogsError};
+
+    #[test]
+    fn test_cursor() {
+        let cursor = LogCursor {
+            block_number: 0x1234,
+            log_index: 7,
+        };
+        let s = cursor.to_string();
+        assert_eq!(s, "0x000000000000123400000007");
+        assert_eq!(s.parse::<LogCursor>(), Ok(cursor));
+        assert_eq!(
+            "0x1234".parse::<LogCursor>(),
+            Err(LogsError::InvalidCursor("0x1234".to_owned()))
+        );
+        assert!("0x00000000000012340000000g".parse::<LogCursor>().is_err());
+    }
+}
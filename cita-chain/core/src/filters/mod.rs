@@ -13,4 +13,5 @@
 // limitations under the License.
 
 pub mod filterdb;
+pub mod logs;
 pub mod rpc_filter;
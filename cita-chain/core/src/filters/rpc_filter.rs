@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::filters::logs::{LogCursor, LogsError};
 use crate::libchain::chain::Chain;
 use crate::types::block_number::{BlockNumber, BlockTag, Tag};
 use crate::types::filter::Filter as FilterType;
@@ -37,7 +38,7 @@ pub trait RpcFilter {
     fn get_filter_changes(&self, id: usize) -> Option<FilterChanges>;
     // Get the logs for the filter with the given id.
     // https://docs.citahub.com/zh-CN/cita/rpc-guide/rpc#getfilterlogs
-    fn get_filter_logs(&self, id: usize) -> Option<Vec<Log>>;
+    fn get_filter_logs(&self, id: usize) -> Result<Option<Vec<Log>>, LogsError>;
     // Remove the filter with the given id.
     // https://docs.citahub.com/zh-CN/cita/rpc-guide/rpc#uninstallfilter
     fn uninstall_filter(&self, id: usize) -> bool;
@@ -48,7 +49,19 @@ trait FilterHelper {
     // Get the block filter with the given id
     fn get_block_filter(&self, id: usize) -> BlockNumber;
     // Get logs with given filter
-    fn get_logs_with_filter(&self, filter: Filter, block_filter: BlockNumber) -> Vec<Log>;
+    fn get_logs_with_filter(
+        &self,
+        filter: Filter,
+        block_filter: BlockNumber,
+    ) -> Result<Vec<Log>, LogsError>;
+    // Get a page of the logs with given filter from the cursor, and the
+    // cursor of the next page
+    fn get_logs_changes_with_filter(
+        &self,
+        filter: Filter,
+        block_filter: BlockNumber,
+        cursor: Option<LogCursor>,
+    ) -> (Vec<Log>, Option<LogCursor>);
 }
 
 impl FilterHelper for Chain {
@@ -64,14 +77,38 @@ impl FilterHelper for Chain {
         block_filter
     }
 
-    fn get_logs_with_filter(&self, filter: Filter, block_filter: BlockNumber) -> Vec<Log> {
+    fn get_logs_with_filter(
+        &self,
+        filter: Filter,
+        block_filter: BlockNumber,
+    ) -> Result<Vec<Log>, LogsError> {
         let mut filter: FilterType = filter.into();
         filter.from_block = BlockTag::Height(block_filter);
         filter.to_block = BlockTag::Tag(Tag::Latest);
         let limit = filter.limit;
-        split_logs(
-            self.get_logs(&filter).into_iter().map(Into::into).collect(),
+        Ok(split_logs(
+            self.get_logs(&filter)?
+                .into_iter()
+                .map(Into::into)
+                .collect(),
             limit,
+        ))
+    }
+
+    fn get_logs_changes_with_filter(
+        &self,
+        filter: Filter,
+        block_filter: BlockNumber,
+        cursor: Option<LogCursor>,
+    ) -> (Vec<Log>, Option<LogCursor>) {
+        let mut filter: FilterType = filter.into();
+        filter.from_block = BlockTag::Height(block_filter);
+        filter.to_block = BlockTag::Tag(Tag::Latest);
+        let limit = filter.limit;
+        let page = self.get_logs_changes(&filter, cursor);
+        (
+            split_logs(page.logs.into_iter().map(Into::into).collect(), limit),
+            page.next,
         )
     }
 }
@@ -107,6 +144,7 @@ impl RpcFilter for Chain {
         let mut changes = Some(FilterChanges::Empty);
         let current_number = self.get_current_height();
         let block_filter = self.get_block_filter(id);
+        let mut next_block_filter = current_number;
 
         if !filterdb.try_lock().unwrap().is_filter(id) {
             drop(filterdb);
@@ -114,11 +152,17 @@ impl RpcFilter for Chain {
         }
 
         // Check the logs
-        if let Some(filter) = filterdb.try_lock().unwrap().get_logs_filter(id) {
+        let filter = filterdb.try_lock().unwrap().get_logs_filter(id).cloned();
+        if let Some(filter) = filter {
             trace!("Into filter changes: logs");
-            changes = Some(FilterChanges::Logs(
-                self.get_logs_with_filter(filter.clone(), block_filter),
-            ));
+            let cursor = filterdb.try_lock().unwrap().get_logs_cursor(id);
+            let (logs, next) = self.get_logs_changes_with_filter(filter, block_filter, cursor);
+            // The rest of the changes are returned by the next calls.
+            if let Some(next) = next {
+                next_block_filter = next.block_number;
+            }
+            filterdb.try_lock().unwrap().set_logs_cursor(id, next);
+            changes = Some(FilterChanges::Logs(logs));
         };
 
         // Check the block
@@ -134,24 +178,24 @@ impl RpcFilter for Chain {
             ));
         };
 
-        // Update the block filter: use the current number
+        // Update the block filter: use the current number, or the block of
+        // the rest of the logs changes
         filterdb
             .try_lock()
             .unwrap()
-            .gen_block_filter(id, current_number);
+            .gen_block_filter(id, next_block_filter);
         drop(filterdb);
         changes
     }
 
-    fn get_filter_logs(&self, id: usize) -> Option<Vec<Log>> {
+    fn get_filter_logs(&self, id: usize) -> Result<Option<Vec<Log>>, LogsError> {
         let filterdb = self.filter_db();
         let block_filter = self.get_block_filter(id);
-        let logs = match filterdb.try_lock().unwrap().get_logs_filter(id) {
-            Some(filter) => Some(self.get_logs_with_filter(filter.clone(), block_filter)),
-            _ => None,
-        };
+        let filter = filterdb.try_lock().unwrap().get_logs_filter(id).cloned();
         drop(filterdb);
-        logs
+        filter
+            .map(|filter| self.get_logs_with_filter(filter, block_filter))
+            .transpose()
     }
 
     fn uninstall_filter(&self, id: usize) -> bool {
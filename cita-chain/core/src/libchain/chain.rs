@@ -17,10 +17,13 @@ use crate::bloomchain::group::{
 };
 use crate::bloomchain::{Bloom, Config as BloomChainConfig, Number as BloomChainNumber};
 use crate::header::{BlockNumber, Header};
+use crate::libchain::history::{HistoryConfig, HistoryPruner};
+use crate::libchain::log_index::{self, LogIndexConfig};
 use crate::libchain::status::Status;
 use crate::log_blooms::LogBloomGroup;
 use crate::receipt::{Receipt, RichReceipt};
 use cita_merklehash;
+use cita_metrics::MetricsConfig;
 use hashable::Hashable;
 
 use libproto::blockchain::{
@@ -34,7 +37,8 @@ use libproto::{
 };
 use proof::BftProof;
 use pubsub::channel::Sender;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::Into;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -46,6 +50,7 @@ use crate::db_indexes::{
 };
 
 use crate::types::block::{Block, BlockBody, OpenBlock};
+pub use crate::types::tx_proof::{RelayInfo, TxProof};
 use crate::types::{
     block_number::BlockTag, block_number::Tag, block_number::TransactionHash,
     block_receipts::BlockReceipts, filter::Filter, log::LocalizedLog, log::Log,
@@ -57,191 +62,13 @@ use cita_types::{Address, Bloom as LogBloom, H256, U256};
 use crate::cita_db::RocksDB;
 use crate::db_indexes::DBIndex;
 use crate::filters::filterdb::FilterDB;
+use crate::filters::logs::{LogCursor, LogsConfig, LogsError, LogsPage, DEFAULT_LOGS_PAGE_SIZE};
 use cita_db::Database;
-use rlp::{self, decode, Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
+use rlp::{self, decode, Encodable, RlpStream};
 
 pub const VERSION: u32 = 0;
-const LOG_BLOOMS_LEVELS: usize = 3;
-const LOG_BLOOMS_ELEMENTS_PER_INDEX: usize = 16;
-
-#[derive(Debug, Clone)]
-pub struct RelayInfo {
-    pub from_chain_id: U256,
-    pub to_chain_id: U256,
-    pub dest_contract: Address,
-    pub dest_hasher: [u8; 4],
-    pub cross_chain_nonce: u64,
-}
-
-#[derive(Debug, Clone)]
-pub struct TxProof {
-    tx: SignedTransaction,
-    receipt: Receipt,
-    receipt_proof: cita_merklehash::Proof,
-    block_header: Header,
-    next_proposal_header: Header,
-    proposal_proof: ProtoProof,
-}
-
-impl Encodable for TxProof {
-    fn rlp_append(&self, s: &mut RlpStream) {
-        s.begin_list(6);
-        s.append(&self.tx);
-        s.append(&self.receipt);
-        s.append(&self.receipt_proof);
-        s.append(&self.block_header);
-        s.append(&self.next_proposal_header);
-        s.append(&self.proposal_proof);
-    }
-}
-
-impl Decodable for TxProof {
-    fn decode(r: &UntrustedRlp) -> Result<Self, DecoderError> {
-        if r.item_count()? != 6 {
-            return Err(DecoderError::RlpIncorrectListLen);
-        }
-        let tx_proof = TxProof {
-            tx: r.val_at(0)?,
-            receipt: r.val_at(1)?,
-            receipt_proof: r.val_at(2)?,
-            block_header: r.val_at(3)?,
-            next_proposal_header: r.val_at(4)?,
-            proposal_proof: r.val_at(5)?,
-        };
-        Ok(tx_proof)
-    }
-}
-
-impl TxProof {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        rlp::decode(bytes)
-    }
-
-    pub fn verify(&self, authorities: &[Address]) -> bool {
-        // Calculate transaction hash, and it should be same as the transaction hash in receipt.
-        let tx_hash = self.tx.calc_transaction_hash();
-        if self.receipt.transaction_hash == tx_hash {
-        } else {
-            warn!("txproof verify transaction_hash failed");
-            return false;
-        };
-        // Use receipt_proof and receipt_root to prove the receipt in the block.
-        let receipt_merkle_proof: cita_merklehash::MerkleProof<H256> =
-            self.receipt_proof.clone().into();
-        if receipt_merkle_proof.verify(
-            self.block_header.receipts_root(),
-            self.receipt.clone().rlp_bytes().into_vec().crypt_hash(),
-            cita_merklehash::merge,
-        ) {
-        } else {
-            warn!("txproof verify receipt root merklehash failed");
-            return false;
-        };
-        // Calculate block header hash, and is should be same as the parent_hash in next header
-        if self.block_header.hash().unwrap() == *self.next_proposal_header.parent_hash() {
-        } else {
-            warn!("txproof verify block header hash failed");
-            return false;
-        };
-        let third_proof = BftProof::from(self.proposal_proof.clone());
-        // Verify next block header, use proof.proposal
-        if self.next_proposal_header.proposal_protobuf().crypt_hash() == third_proof.proposal {
-        } else {
-            warn!("txproof verify next block header failed");
-            return false;
-        };
-        // Verify signatures in proposal proof.
-        if third_proof.check(self.block_header.number() as usize + 1, authorities) {
-        } else {
-            warn!("txproof verify signatures for next block header failed");
-            return false;
-        };
-        true
-    }
-
-    // extract info which relayer needed
-    pub fn extract_relay_info(&self) -> Option<RelayInfo> {
-        if self.receipt.logs.is_empty() {
-            return None;
-        }
-        let data = &self.receipt.logs[0].data;
-        // data must be:
-        // uint256 from_chain_id,
-        // uint256 to_chain_id,
-        // address dest_contract,
-        // bytes4 dest_hasher,
-        // uint256 cross_chain_nonce
-        if data.len() != 160 {
-            None
-        } else {
-            let mut iter = data.chunks(32);
-            let from_chain_id = U256::from(iter.next().unwrap());
-            let to_chain_id = U256::from(iter.next().unwrap());
-            let dest_contract = Address::from(H256::from(iter.next().unwrap()));
-            let dest_hasher = iter.next().unwrap()[..4].iter().take(4).enumerate().fold(
-                [0u8; 4],
-                |mut acc, (idx, val)| {
-                    acc[idx] = *val;
-                    acc
-                },
-            );
-            let cross_chain_nonce = U256::from(iter.next().unwrap()).low_u64();
-            Some(RelayInfo {
-                from_chain_id,
-                to_chain_id,
-                dest_contract,
-                dest_hasher,
-                cross_chain_nonce,
-            })
-        }
-    }
-
-    // verify proof
-    // check as crosschain protocol
-    // extract sender and tx data
-    pub fn extract_crosschain_data(
-        &self,
-        my_contrac_addr: Address,
-        my_hasher: [u8; 4],
-        my_cross_chain_nonce: u64,
-        my_chain_id: U256,
-        authorities: &[Address],
-    ) -> Option<(Address, Vec<u8>)> {
-        if self.verify(authorities) {
-            self.extract_relay_info().and_then(
-                |RelayInfo {
-                     to_chain_id,
-                     dest_contract,
-                     dest_hasher,
-                     cross_chain_nonce,
-                     ..
-                 }| {
-                    // from_chain_id: if we can got authorities, the from_chain_id must be right
-                    // cross chain only between main chain and one sidechain
-                    // check to_chain_id == my chain_id
-                    // check dest_contract == this
-                    // check hasher == RECV_FUNC_HASHER
-                    // check cross_chain_nonce == cross_chain_nonce
-                    // extract origin tx sender and origin tx data
-                    if to_chain_id == my_chain_id
-                        && dest_contract == my_contrac_addr
-                        && dest_hasher == my_hasher
-                        && cross_chain_nonce == my_cross_chain_nonce
-                    {
-                        // sendToSideChain(uint256 toChainId, address destContract, bytes txData)
-                        // skip func hasher, uint32, address, bytes position and length
-                        let (_, origin_tx_data) = self.tx.data.split_at(4 + 32 * 4);
-                        Some((*self.tx.sender(), origin_tx_data.to_owned()))
-                    } else {
-                        None
-                    }
-                },
-            )
-        } else {
-            None
-        }
-    }
-}
+pub const LOG_BLOOMS_LEVELS: usize = 3;
+pub const LOG_BLOOMS_ELEMENTS_PER_INDEX: usize = 16;
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum BlockSource {
@@ -249,14 +76,28 @@ pub enum BlockSource {
     NET = 1,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
     pub prooftype: u8,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub log_index: LogIndexConfig,
+    #[serde(default)]
+    pub logs: LogsConfig,
 }
 
 impl Config {
     pub fn default() -> Self {
-        Config { prooftype: 2 }
+        Config {
+            prooftype: 2,
+            metrics: MetricsConfig::default(),
+            history: HistoryConfig::default(),
+            log_index: LogIndexConfig::default(),
+            logs: LogsConfig::default(),
+        }
     }
 
     pub fn new(path: &str) -> Self {
@@ -283,6 +124,8 @@ pub enum BlockInQueue {
     Proposal(OpenBlock),
     ConsensusBlock(OpenBlock, ProtoProof),
     SyncBlock((OpenBlock, Option<ProtoProof>)),
+    // The block whose state is fast synced by executor
+    SnapshotBlock(OpenBlock),
 }
 
 pub struct Chain {
@@ -312,6 +155,11 @@ pub struct Chain {
     pub is_snapshot: RwLock<bool>,
     admin_address: RwLock<Option<Address>>,
     pub version: RwLock<Option<u32>>,
+    // Prune the old bodies and receipts, None for keeping all
+    history_pruner: Option<HistoryPruner>,
+    // The first block indexed by address and topic, None if not indexed
+    address_topic_from: RwLock<Option<BlockNumber>>,
+    logs_config: LogsConfig,
 }
 
 /// Get latest status
@@ -370,12 +218,17 @@ impl Chain {
     pub fn init_chain(db: Arc<RocksDB>, chain_config: Config) -> Chain {
         info!("chain config: {:?}", chain_config);
 
-        let blooms_config = BloomChainConfig {
-            levels: LOG_BLOOMS_LEVELS,
-            elements_per_index: LOG_BLOOMS_ELEMENTS_PER_INDEX,
-        };
-
-        let header = get_chain(&*db).unwrap_or_default();
+        let header = get_chain(&*db);
+        let blooms_params =
+            log_index::init_blooms_params(&*db, &chain_config.log_index, header.is_none())
+                .expect("Load log blooms params failed.");
+        let blooms_config = log_index::blooms_config(blooms_params);
+        let next_height = header.as_ref().map(|h| h.number() + 1).unwrap_or(0);
+        let address_topic_from =
+            log_index::init_address_topic_index(&*db, &chain_config.log_index, next_height)
+                .expect("Load address topic index failed.");
+
+        let header = header.unwrap_or_default();
         debug!("get chain head is : {:?}", header);
         let current_height = AtomicUsize::new(header.number() as usize);
         let max_store_height = AtomicUsize::new(0);
@@ -388,6 +241,17 @@ impl Chain {
             max_store_height, current_height
         );
 
+        let history_pruner = if chain_config.history.retention > 0 {
+            let pruner = HistoryPruner::new(Arc::clone(&db), &chain_config.history)
+                .expect("Start history pruner failed.");
+            pruner.notify(header.number());
+            Some(pruner)
+        } else {
+            None
+        };
+
+        let filterdb = Arc::new(Mutex::new(FilterDB::load(Arc::clone(&db))));
+
         let chain = Chain {
             blooms_config,
             current_header: RwLock::new(header.clone()),
@@ -395,7 +259,7 @@ impl Chain {
             max_store_height,
             block_map: RwLock::new(BTreeMap::new()),
             db,
-            filterdb: Arc::new(Mutex::new(FilterDB::new())),
+            filterdb,
             nodes: RwLock::new(Vec::new()),
             validators: RwLock::new(Vec::new()),
             // need to be cautious here
@@ -409,6 +273,9 @@ impl Chain {
             is_snapshot: RwLock::new(false),
             admin_address: RwLock::new(None),
             version: RwLock::new(None),
+            history_pruner,
+            address_topic_from: RwLock::new(address_topic_from),
+            logs_config: chain_config.logs,
         };
 
         if let Some(proto_proof) = chain.current_block_poof() {
@@ -513,20 +380,28 @@ impl Chain {
                 .collect()
         };
 
+        let receipts: Vec<Receipt> = info
+            .get_receipts()
+            .iter()
+            .map(|r| Receipt::from(r.get_receipt().clone()))
+            .collect();
+        if self.address_topic_from.read().is_some() && !log_bloom.is_zero() {
+            if let Err(e) = log_index::index_address_topics(&self.db, number, &receipts) {
+                // The blocks before are not all indexed, find them by the blooms.
+                warn!(
+                    "index block {} by address and topic error: {:?}, restart the index",
+                    number, e
+                );
+                *self.address_topic_from.write() = Some(number + 1);
+                if let Err(e) = log_index::restart_address_topic_index(&self.db, number + 1) {
+                    error!("save the address and topic index height error: {:?}", e);
+                }
+            }
+        }
+
         // Save hash -> receipts
-        if !info.get_receipts().is_empty() {
-            let receipts: Vec<Receipt> = info
-                .get_receipts()
-                .iter()
-                .map(|r| Receipt::from(r.get_receipt().clone()))
-                .collect();
-            let block_receipts = BlockReceipts::new(receipts);
-            let hash_key = Hash2BlockReceipts(header_hash).get_index();
-            let _ = self.db.insert(
-                Some(cita_db::DataCategory::Extra),
-                hash_key,
-                rlp::encode(&block_receipts).into_vec(),
-            );
+        if !receipts.is_empty() {
+            self.set_block_receipts(header_hash, &BlockReceipts::new(receipts));
         }
 
         // Save block transaction indexes
@@ -588,6 +463,9 @@ impl Chain {
         *self.current_header.write() = header;
         self.current_height.store(number as usize, Ordering::SeqCst);
         self.clean_proof_with_height(number);
+        if let Some(ref pruner) = self.history_pruner {
+            pruner.notify(number);
+        }
     }
 
     pub fn broadcast_current_status(&self, ctx_pub: &Sender<(String, Vec<u8>)>) {
@@ -653,6 +531,17 @@ impl Chain {
                     debug!("executed set consensus block-{}", number);
                 }
             }
+            // Fast sync jumps from the genesis to the block
+            Some(BlockInQueue::SnapshotBlock(block)) => {
+                if self.get_current_height() == 0 {
+                    self.set_db_result(&ret, &block);
+                    self.set_max_store_height(number);
+                    let tx_hashes = block.body().transaction_hashes();
+                    self.delivery_block_tx_hashes(number, &tx_hashes, &ctx_pub);
+                    self.broadcast_current_status(&ctx_pub);
+                    info!("jump to the fast synced block {}", number);
+                }
+            }
             Some(BlockInQueue::SyncBlock((block, op))) => {
                 if op.is_some() {
                     debug!("SyncBlock has proof in  {} ", block.number());
@@ -801,6 +690,13 @@ impl Chain {
         })
     }
 
+    /// Get the block which contains the transaction, `None` if the body of
+    /// the block is pruned.
+    pub fn transaction_block(&self, hash: TransactionHash) -> Option<Block> {
+        self.transaction_index(hash)
+            .and_then(|addr| self.block_by_hash(addr.block_hash))
+    }
+
     /// Get address of transaction by hash.
     fn transaction_index(&self, hash: TransactionHash) -> Option<TransactionIndex> {
         let hash_key = Hash2TransactionIndex(hash).get_index();
@@ -922,14 +818,14 @@ impl Chain {
                     next_proposal_header,
                     proposal_proof,
                 )| {
-                    TxProof {
+                    TxProof::new(
                         tx,
                         receipt,
                         receipt_proof,
                         block_header,
                         next_proposal_header,
                         proposal_proof,
-                    }
+                    )
                     .rlp_bytes()
                     .into_vec()
                 },
@@ -1177,20 +1073,170 @@ impl Chain {
         }
     }
 
-    pub fn get_logs(&self, filter: &Filter) -> Vec<LocalizedLog> {
-        let blocks = filter
+    /// Returns numbers of blocks having logs of any of the address and topic
+    /// pairs, None if the blocks are not all indexed.
+    fn blocks_with_address_topics(
+        &self,
+        pairs: &[(Address, H256)],
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Option<Vec<BlockNumber>> {
+        if from < (*self.address_topic_from.read())? {
+            return None;
+        }
+        let mut blocks = BTreeSet::new();
+        for (address, topic) in pairs {
+            blocks.extend(
+                log_index::blocks_with_address_topic(&self.db, *address, *topic, from, to).ok()?,
+            );
+        }
+        Some(blocks.into_iter().collect())
+    }
+
+    /// The range of blocks of the filter, `to` is not beyond the pending block.
+    fn filter_range(&self, filter: &Filter) -> Option<(BlockNumber, BlockNumber)> {
+        match (
+            self.block_number(filter.from_block),
+            self.block_number(filter.to_block),
+            self.block_number(BlockTag::Tag(Tag::Pending)),
+        ) {
+            (Some(from), Some(to), Some(pending)) => {
+                let end = if to > pending { pending } else { to };
+                Some((from, end))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns numbers of blocks from `from` to `to` which may have logs
+    /// matching the filter, in ascending order.
+    fn blocks_with_filter(
+        &self,
+        filter: &Filter,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Vec<BlockNumber> {
+        let indexed = filter
+            .address_topic_pairs()
+            .and_then(|pairs| self.blocks_with_address_topics(&pairs, from, to));
+        if let Some(blocks) = indexed {
+            return blocks;
+        }
+
+        filter
             .zip_blooms()
             .iter()
-            .filter_map(|bloom| {
-                self.blocks_with_bloom_by_id(bloom, filter.from_block, filter.to_block)
-            })
-            .flat_map(|m| m)
+            .flat_map(|bloom| self.blocks_with_bloom(bloom, from, to))
             // remove duplicate elements
-            .collect::<HashSet<u64>>()
+            .collect::<BTreeSet<u64>>()
             .into_iter()
-            .collect::<Vec<u64>>();
+            .collect::<Vec<u64>>()
+    }
+
+    pub fn get_logs(&self, filter: &Filter) -> Result<Vec<LocalizedLog>, LogsError> {
+        let (from, to) = match self.filter_range(filter) {
+            Some(range) => range,
+            None => return Ok(Vec::new()),
+        };
+        let max_block_range = self.logs_config.max_block_range;
+        if max_block_range > 0 && to >= from && to - from >= max_block_range {
+            return Err(LogsError::BlockRangeTooLarge(max_block_range));
+        }
+        let blocks = self.blocks_with_filter(filter, from, to);
+
+        // Take one more log than allowed to find out the results are too many.
+        let max_results = self.logs_config.max_results;
+        let limit = match (filter.limit, max_results) {
+            (limit, 0) => limit,
+            (Some(limit), max) if limit <= max => Some(limit),
+            (_, max) => Some(max + 1),
+        };
+        let logs = self.logs(blocks, |entry| filter.matches(entry), limit);
+        if max_results > 0 && logs.len() > max_results {
+            return Err(LogsError::TooManyResults(max_results));
+        }
+        Ok(logs)
+    }
+
+    /// Returns a page of the logs matching the filter from the cursor, or
+    /// from the first block of the filter. A page covers at most
+    /// `max_block_range` blocks, the cursor of the next page is returned if
+    /// there are blocks left.
+    pub fn get_logs_page(
+        &self,
+        filter: &Filter,
+        cursor: Option<LogCursor>,
+        page_size: Option<usize>,
+    ) -> Result<LogsPage, LogsError> {
+        let max_results = self.logs_config.max_results;
+        let page_size = match page_size {
+            Some(0) => return Err(LogsError::InvalidPageSize),
+            Some(size) if max_results > 0 && size > max_results => {
+                return Err(LogsError::TooManyResults(max_results));
+            }
+            Some(size) => size,
+            None if max_results > 0 => max_results,
+            None => DEFAULT_LOGS_PAGE_SIZE,
+        };
+        Ok(self.logs_page(filter, cursor, page_size))
+    }
 
-        self.logs(blocks, |entry| filter.matches(entry), filter.limit)
+    /// Returns a page of the changes of a logs filter from the cursor, of at
+    /// most `max_results` logs.
+    pub fn get_logs_changes(&self, filter: &Filter, cursor: Option<LogCursor>) -> LogsPage {
+        let page_size = match self.logs_config.max_results {
+            0 => ::std::usize::MAX,
+            max => max,
+        };
+        self.logs_page(filter, cursor, page_size)
+    }
+
+    fn logs_page(&self, filter: &Filter, cursor: Option<LogCursor>, page_size: usize) -> LogsPage {
+        let (from, to) = match self.filter_range(filter) {
+            Some(range) => range,
+            None => return LogsPage::default(),
+        };
+        let from = cursor.map_or(from, |cursor| cmp::max(from, cursor.block_number));
+        if from > to {
+            return LogsPage::default();
+        }
+        let max_block_range = self.logs_config.max_block_range;
+        let end = if max_block_range > 0 && to - from >= max_block_range {
+            from + max_block_range - 1
+        } else {
+            to
+        };
+
+        let mut logs = Vec::new();
+        for number in self.blocks_with_filter(filter, from, end) {
+            for log in self.logs(vec![number], |entry| filter.matches(entry), None) {
+                if let Some(cursor) = cursor {
+                    if number == cursor.block_number && log.log_index < cursor.log_index {
+                        continue;
+                    }
+                }
+                if logs.len() == page_size {
+                    let next = LogCursor {
+                        block_number: number,
+                        log_index: log.log_index,
+                    };
+                    return LogsPage {
+                        logs,
+                        next: Some(next),
+                    };
+                }
+                logs.push(log);
+            }
+        }
+        let next = if end < to {
+            Some(LogCursor {
+                block_number: end + 1,
+                log_index: 0,
+            })
+        } else {
+            None
+        };
+        LogsPage { logs, next }
     }
 
     /// Delivery block tx hashes to auth
@@ -1273,6 +1319,16 @@ impl Chain {
             .unwrap();
     }
 
+    /// Save receipts of block with given hash.
+    pub fn set_block_receipts(&self, hash: H256, block_receipts: &BlockReceipts) {
+        let hash_key = Hash2BlockReceipts(hash).get_index();
+        let _ = self.db.insert(
+            Some(cita_db::DataCategory::Extra),
+            hash_key,
+            rlp::encode(block_receipts).into_vec(),
+        );
+    }
+
     /// Get receipts of block with given hash.
     pub fn block_receipts(&self, hash: H256) -> Option<BlockReceipts> {
         let hash_key = Hash2BlockReceipts(hash).get_index();
@@ -0,0 +1,447 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! History retention of the chain.
+//!
+//! The bodies, receipts and transaction indexes of the blocks older than
+//! `retention` blocks are pruned in a background thread, while the headers,
+//! which carry the proofs of their parents, are kept. The pruned blocks can be
+//! exported to append-only archive files first, e.g.
+//!
+//! ```toml
+//! [history]
+//! retention = 100000
+//! archive_dir = "/data/cita/archive"
+//! ```
+//!
+//! The retention is at least `MIN_RETENTION` blocks, for auth checks the new
+//! transactions against the ones of the latest blocks.
+//!
+//! An archive file holds `ARCHIVE_FILE_BLOCKS` blocks, and is named by its
+//! first block. Every record is a 4 bytes big-endian length followed by the
+//! RLP of `[number, hash, body, receipts]`, where `receipts` is empty for a
+//! block without any.
+
+use crate::header::{BlockNumber, Header};
+use crate::types::block::BlockBody;
+use cita_db::error::DatabaseError;
+use cita_db::{DataCategory, Database, RocksDB};
+use cita_types::H256;
+use pubsub::channel::{self, Receiver, Sender};
+use rlp::{self, RlpStream, UntrustedRlp};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use util::BLOCKLIMIT;
+
+use crate::db_indexes::{
+    BlockNumber2Body, BlockNumber2Header, BodyPrunedHeight, DBIndex, Hash2BlockReceipts,
+    Hash2TransactionIndex,
+};
+
+pub const ARCHIVE_FILE_BLOCKS: u64 = 100_000;
+/// Blocks always kept, auth reads their transactions to reject the duplicated
+/// ones.
+pub const MIN_RETENTION: u64 = BLOCKLIMIT;
+// Blocks pruned in one round, so a node enabling the retention catches up
+// without stopping the others for long.
+const MAX_PRUNED_PER_ROUND: u64 = 1000;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Blocks whose bodies and receipts are kept, 0 for keeping all.
+    pub retention: u64,
+    /// Export the blocks to archive files in the directory before pruning.
+    pub archive_dir: Option<String>,
+}
+
+impl HistoryConfig {
+    /// The retention raised to `MIN_RETENTION`, 0 for keeping all.
+    pub fn retention(&self) -> u64 {
+        if self.retention > 0 && self.retention < MIN_RETENTION {
+            warn!(
+                "history retention {} is too small, use {}",
+                self.retention, MIN_RETENTION
+            );
+            MIN_RETENTION
+        } else {
+            self.retention
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Database(DatabaseError),
+    Io(io::Error),
+    Decoder(String),
+}
+
+impl From<DatabaseError> for HistoryError {
+    fn from(e: DatabaseError) -> Self {
+        HistoryError::Database(e)
+    }
+}
+
+impl From<io::Error> for HistoryError {
+    fn from(e: io::Error) -> Self {
+        HistoryError::Io(e)
+    }
+}
+
+/// A pruned block in an archive file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedBlock {
+    pub number: BlockNumber,
+    pub hash: H256,
+    pub body: Vec<u8>,
+    pub receipts: Vec<u8>,
+}
+
+impl ArchivedBlock {
+    fn rlp_bytes(&self) -> Vec<u8> {
+        let mut s = RlpStream::new_list(4);
+        s.append(&self.number);
+        s.append(&self.hash);
+        s.append(&self.body);
+        s.append(&self.receipts);
+        s.out()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, HistoryError> {
+        let r = UntrustedRlp::new(bytes);
+        let decode = || {
+            Ok(ArchivedBlock {
+                number: r.val_at(0)?,
+                hash: r.val_at(1)?,
+                body: r.val_at(2)?,
+                receipts: r.val_at(3)?,
+            })
+        };
+        decode().map_err(|e: rlp::DecoderError| HistoryError::Decoder(format!("{:?}", e)))
+    }
+}
+
+/// Read the complete records of an archive file, a record cut by a crash is
+/// ignored. Returns the records and the length they take.
+fn read_records<R: Read>(reader: &mut R) -> Result<(Vec<ArchivedBlock>, u64), HistoryError> {
+    let mut blocks = Vec::new();
+    let mut valid_len = 0;
+    let mut len_buf = [0u8; 4];
+    loop {
+        if let Err(e) = reader.read_exact(&mut len_buf) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(e.into());
+        }
+        let mut record = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+        if let Err(e) = reader.read_exact(&mut record) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(e.into());
+        }
+        blocks.push(ArchivedBlock::decode(&record)?);
+        valid_len += 4 + record.len() as u64;
+    }
+    Ok((blocks, valid_len))
+}
+
+/// Read the blocks of an archive file.
+pub fn read_archive(path: &Path) -> Result<Vec<ArchivedBlock>, HistoryError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_records(&mut reader).map(|(blocks, _)| blocks)
+}
+
+pub struct ArchiveWriter {
+    dir: PathBuf,
+    // The first block and the file being appended
+    file: Option<(BlockNumber, File)>,
+}
+
+impl ArchiveWriter {
+    pub fn new(dir: &str) -> Result<Self, HistoryError> {
+        fs::create_dir_all(dir)?;
+        Ok(ArchiveWriter {
+            dir: PathBuf::from(dir),
+            file: None,
+        })
+    }
+
+    pub fn file_path(&self, number: BlockNumber) -> PathBuf {
+        let first = number - number % ARCHIVE_FILE_BLOCKS;
+        self.dir.join(format!("blocks-{:012}.archive", first))
+    }
+
+    fn open(&mut self, number: BlockNumber) -> Result<&mut File, HistoryError> {
+        let first = number - number % ARCHIVE_FILE_BLOCKS;
+        let is_opened = self
+            .file
+            .as_ref()
+            .map(|(f, _)| *f == first)
+            .unwrap_or(false);
+        if !is_opened {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(self.file_path(number))?;
+            // Drop the record cut by a crash, or the next ones are unreadable.
+            let (_, valid_len) = read_records(&mut BufReader::new(&file))?;
+            file.set_len(valid_len)?;
+            file.seek(SeekFrom::End(0))?;
+            self.file = Some((first, file));
+        }
+        Ok(&mut self.file.as_mut().expect("opened above; qed").1)
+    }
+
+    /// Append the block, and sync it to disk before it is pruned.
+    pub fn append(&mut self, block: &ArchivedBlock) -> Result<(), HistoryError> {
+        let record = block.rlp_bytes();
+        let mut buf = (record.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(&record);
+        let file = self.open(block.number)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+pub struct HistoryPruner {
+    sender: Sender<BlockNumber>,
+}
+
+impl HistoryPruner {
+    /// Start the pruning thread, it exits when the pruner is dropped.
+    pub fn new(db: Arc<RocksDB>, config: &HistoryConfig) -> Result<Self, HistoryError> {
+        let archive = match config.archive_dir {
+            Some(ref dir) => Some(ArchiveWriter::new(dir)?),
+            None => None,
+        };
+        let mut collector = Collector {
+            db,
+            retention: config.retention(),
+            archive,
+        };
+        let (sender, receiver) = channel::unbounded();
+        thread::Builder::new()
+            .name("history_pruner".to_string())
+            .spawn(move || collector.run(&receiver))
+            .expect("spawn history pruner thread");
+        Ok(HistoryPruner { sender })
+    }
+
+    /// Let the background thread prune the blocks out of the retention.
+    pub fn notify(&self, height: BlockNumber) {
+        let _ = self.sender.send(height);
+    }
+}
+
+struct Collector {
+    db: Arc<RocksDB>,
+    retention: u64,
+    archive: Option<ArchiveWriter>,
+}
+
+impl Collector {
+    fn run(&mut self, receiver: &Receiver<BlockNumber>) {
+        while let Ok(mut height) = receiver.recv() {
+            // Only the latest height matters when falling behind.
+            while let Ok(latest) = receiver.try_recv() {
+                height = latest;
+            }
+            if let Err(e) = self.prune(height) {
+                error!("prune history at height {} error: {:?}", height, e);
+            }
+        }
+        info!("history pruner exit");
+    }
+
+    fn prune(&mut self, height: BlockNumber) -> Result<(), HistoryError> {
+        if height <= self.retention {
+            return Ok(());
+        }
+        let target = height - self.retention;
+        // The genesis block is always kept.
+        let mut next = pruned_height(&self.db)?.max(1);
+        let last = target.min(next + MAX_PRUNED_PER_ROUND - 1);
+        while next <= last {
+            self.prune_block(next)?;
+            next += 1;
+            self.db.insert(
+                Some(DataCategory::Extra),
+                BodyPrunedHeight.get_index(),
+                rlp::encode(&next).into_vec(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn prune_block(&mut self, number: BlockNumber) -> Result<(), HistoryError> {
+        let header: Header = match self.db.get(
+            Some(DataCategory::Headers),
+            &BlockNumber2Header(number).get_index(),
+        )? {
+            Some(header) => rlp::decode(&header),
+            None => return Ok(()),
+        };
+        let hash = header.hash().expect("hash of a stored header; qed");
+        let body_key = BlockNumber2Body(number).get_index();
+        let body = match self.db.get(Some(DataCategory::Bodies), &body_key)? {
+            Some(body) => body,
+            None => return Ok(()),
+        };
+        let receipts_key = Hash2BlockReceipts(hash).get_index();
+        let receipts = self
+            .db
+            .get(Some(DataCategory::Extra), &receipts_key)?
+            .unwrap_or_default();
+
+        let tx_index_keys: Vec<Vec<u8>> = rlp::decode::<BlockBody>(&body)
+            .transaction_hashes()
+            .into_iter()
+            .map(|tx_hash| Hash2TransactionIndex(tx_hash).get_index())
+            .collect();
+        if let Some(ref mut archive) = self.archive {
+            archive.append(&ArchivedBlock {
+                number,
+                hash,
+                body,
+                receipts,
+            })?;
+        }
+
+        self.db
+            .remove_batch(Some(DataCategory::Extra), &tx_index_keys)?;
+        self.db.remove(Some(DataCategory::Extra), &receipts_key)?;
+        self.db.remove(Some(DataCategory::Bodies), &body_key)?;
+        trace!("prune the body and receipts of block {}", number);
+        Ok(())
+    }
+}
+
+/// The next block to prune, 0 if none is pruned.
+pub fn pruned_height(db: &RocksDB) -> Result<BlockNumber, HistoryError> {
+    Ok(db
+        .get(Some(DataCategory::Extra), &BodyPrunedHeight.get_index())?
+        .map(|h| rlp::decode(&h))
+        .unwrap_or(0))
+}
+
+/// Write the blocks of an archive file back to the database, returns the
+/// number of blocks restored. The blocks are below the pruned height, so they
+/// are kept until they are removed by hand.
+pub fn restore_archive(db: &RocksDB, path: &Path) -> Result<usize, HistoryError> {
+    let blocks = read_archive(path)?;
+    for block in &blocks {
+        let body: BlockBody = UntrustedRlp::new(&block.body)
+            .as_val()
+            .map_err(|e| HistoryError::Decoder(format!("{:?}", e)))?;
+        let (keys, values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = body
+            .transaction_indexes(block.hash)
+            .into_iter()
+            .map(|(tx_hash, index)| {
+                (
+                    Hash2TransactionIndex(tx_hash).get_index(),
+                    rlp::encode(&index).into_vec(),
+                )
+            })
+            .unzip();
+        db.insert_batch(Some(DataCategory::Extra), keys, values)?;
+        if !block.receipts.is_empty() {
+            db.insert(
+                Some(DataCategory::Extra),
+                Hash2BlockReceipts(block.hash).get_index(),
+                block.receipts.clone(),
+            )?;
+        }
+        db.insert(
+            Some(DataCategory::Bodies),
+            BlockNumber2Body(block.number).get_index(),
+            block.body.clone(),
+        )?;
+    }
+    Ok(blocks.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_archive, ArchiveWriter, ArchivedBlock, HistoryConfig, ARCHIVE_FILE_BLOCKS,
+        MIN_RETENTION,
+    };
+    use cita_types::H256;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    fn block(number: u64) -> ArchivedBlock {
+        ArchivedBlock {
+            number,
+            hash: H256::from(number),
+            body: vec![0xc0],
+            receipts: if number % 2 == 0 {
+                vec![]
+            } else {
+                vec![1, 2, 3]
+            },
+        }
+    }
+
+    #[test]
+    fn test_retention() {
+        let config = |retention| HistoryConfig {
+            retention,
+            archive_dir: None,
+        };
+        assert_eq!(config(0).retention(), 0);
+        assert_eq!(config(1).retention(), MIN_RETENTION);
+        assert_eq!(config(MIN_RETENTION + 1).retention(), MIN_RETENTION + 1);
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = TempDir::new("archive").unwrap();
+        let mut writer = ArchiveWriter::new(dir.path().to_str().unwrap()).unwrap();
+        for number in 1..=3 {
+            writer.append(&block(number)).unwrap();
+        }
+        writer.append(&block(ARCHIVE_FILE_BLOCKS)).unwrap();
+
+        let path = writer.file_path(1);
+        assert_eq!(
+            read_archive(&path).unwrap(),
+            vec![block(1), block(2), block(3)]
+        );
+        assert_eq!(
+            read_archive(&writer.file_path(ARCHIVE_FILE_BLOCKS)).unwrap(),
+            vec![block(ARCHIVE_FILE_BLOCKS)]
+        );
+
+        // A record cut by a crash is dropped when appending again.
+        drop(writer);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 9, 1]).unwrap();
+        let mut writer = ArchiveWriter::new(dir.path().to_str().unwrap()).unwrap();
+        writer.append(&block(4)).unwrap();
+        assert_eq!(
+            read_archive(&path).unwrap(),
+            vec![block(1), block(2), block(3), block(4)]
+        );
+    }
+}
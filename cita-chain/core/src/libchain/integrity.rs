@@ -0,0 +1,858 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integrity check and repair of the chain database.
+//!
+//! The headers must form a hash chain up to the current block, and the
+//! indexes of a block must agree with its header and its body. The indexes
+//! derived from the bodies, the transaction indexes and the log blooms, can
+//! be rebuilt after an unclean shutdown.
+//!
+//! A fast synced chain starts from the snapshot block, the blocks between
+//! the genesis and it are not saved.
+//!
+//! The executor keeps its own headers, indexed by height, and the states of
+//! the blocks not pruned. The chain can not be ahead of the executor, and the
+//! blocks of both must be the same. A fast synced executor has the genesis
+//! and the headers from the snapshot height, but none between, and the
+//! states from the target of the fast sync.
+
+use crate::bloomchain::group::{BloomGroup, BloomGroupChain, GroupPosition};
+use crate::header::{BlockNumber, Header};
+use crate::libchain::chain::{LOG_BLOOMS_ELEMENTS_PER_INDEX, LOG_BLOOMS_LEVELS};
+use crate::libchain::history::{self, HistoryError};
+use crate::libchain::log_index::{blooms_config, saved_blooms_params, to_bloom, BloomDB};
+use crate::log_blooms::LogBloomGroup;
+use crate::types::block::BlockBody;
+use crate::types::block_receipts::BlockReceipts;
+use crate::types::state_sync::SNAPSHOT_HEADERS;
+use crate::types::transaction_index::TransactionIndex;
+use cita_db::error::DatabaseError;
+use cita_db::{DataCategory, Database, RocksDB};
+use cita_types::{Bloom as LogBloom, H256};
+use hashable::{Hashable, HASH_NULL_RLP};
+use rlp::{self, Decodable, Encodable, UntrustedRlp};
+use std::fmt;
+
+use crate::db_indexes::{
+    BlockNumber2Body, BlockNumber2Hash, BlockNumber2Header, CurrentHash, DBIndex, Hash2BlockNumber,
+    Hash2BlockReceipts, Hash2Header, Hash2TransactionIndex, LogGroupPosition, StatePrunedHeight,
+};
+
+// Blocks whose blooms are rebuilt at once.
+const REBUILD_BLOCKS_PER_ROUND: u64 = 1024;
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    Database(DatabaseError),
+    History(HistoryError),
+    MissingHeader(BlockNumber),
+}
+
+impl From<DatabaseError> for IntegrityError {
+    fn from(e: DatabaseError) -> Self {
+        IntegrityError::Database(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub height: BlockNumber,
+    pub reason: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block {}: {}", self.height, self.reason)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChainReport {
+    /// The height of the current hash, or of the last header if the current
+    /// hash is broken.
+    pub current_height: BlockNumber,
+    pub problems: Vec<Problem>,
+}
+
+// The blooms saved before their params are saved have the default ones.
+fn blooms_params(db: &RocksDB) -> Result<(usize, usize), DatabaseError> {
+    Ok(saved_blooms_params(db)?.unwrap_or((LOG_BLOOMS_LEVELS, LOG_BLOOMS_ELEMENTS_PER_INDEX)))
+}
+
+// The states of the blocks before it are pruned by the executor.
+fn state_pruned_height(db: &RocksDB) -> Result<BlockNumber, DatabaseError> {
+    let height = db
+        .get(Some(DataCategory::Extra), &StatePrunedHeight.get_index())?
+        .filter(|h| h.len() == 8)
+        .map(|h| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&h[..8]);
+            u64::from_be_bytes(bytes)
+        })
+        .unwrap_or(0);
+    Ok(height)
+}
+
+/// The height of the snapshot block, or 0 if the chain is not fast synced.
+fn snapshot_height<F>(
+    current_height: BlockNumber,
+    has_block: F,
+) -> Result<BlockNumber, DatabaseError>
+where
+    F: Fn(BlockNumber) -> Result<bool, DatabaseError>,
+{
+    if current_height <= 1 || has_block(1)? {
+        return Ok(0);
+    }
+    let mut height = 2;
+    while height < current_height && !has_block(height)? {
+        height += 1;
+    }
+    Ok(height)
+}
+
+struct Checker<'a> {
+    db: &'a RocksDB,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(db: &'a RocksDB) -> Self {
+        Checker {
+            db,
+            problems: Vec::new(),
+        }
+    }
+
+    fn report(&mut self, height: BlockNumber, reason: String) {
+        self.problems.push(Problem { height, reason });
+    }
+
+    fn read<T: Decodable>(
+        &mut self,
+        height: BlockNumber,
+        category: DataCategory,
+        key: &[u8],
+        name: &str,
+    ) -> Result<Option<T>, DatabaseError> {
+        let db = self.db;
+        self.read_in(db, height, category, key, name)
+    }
+
+    // A broken record is reported and taken as missing.
+    fn read_in<T: Decodable>(
+        &mut self,
+        db: &RocksDB,
+        height: BlockNumber,
+        category: DataCategory,
+        key: &[u8],
+        name: &str,
+    ) -> Result<Option<T>, DatabaseError> {
+        match db.get(Some(category), key)? {
+            Some(bytes) => match UntrustedRlp::new(&bytes).as_val() {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => {
+                    self.report(height, format!("{} is broken: {:?}", name, e));
+                    Ok(None)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn current_height(&mut self) -> Result<Option<BlockNumber>, DatabaseError> {
+        let hash: H256 = match self.read(
+            0,
+            DataCategory::Extra,
+            &CurrentHash.get_index(),
+            "current hash",
+        )? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let height = self.read(
+            0,
+            DataCategory::Extra,
+            &Hash2BlockNumber(hash).get_index(),
+            "height of the current hash",
+        )?;
+        if height.is_none() {
+            self.report(0, format!("current hash {:?} is unknown", hash));
+        }
+        Ok(height)
+    }
+
+    fn executor_current_height(&mut self) -> Result<Option<BlockNumber>, DatabaseError> {
+        let hash: H256 = match self.read(
+            0,
+            DataCategory::Extra,
+            &CurrentHash.get_index(),
+            "executor current hash",
+        )? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let header: Option<Header> = self.read(
+            0,
+            DataCategory::Headers,
+            &Hash2Header(hash).get_index(),
+            "executor current header",
+        )?;
+        if header.is_none() {
+            self.report(0, format!("executor current hash {:?} is unknown", hash));
+        }
+        Ok(header.map(|header| header.number()))
+    }
+
+    fn check_header(
+        &mut self,
+        height: BlockNumber,
+        parent: Option<&Header>,
+    ) -> Result<Option<Header>, DatabaseError> {
+        let header: Header = match self.read(
+            height,
+            DataCategory::Headers,
+            &BlockNumber2Header(height).get_index(),
+            "header",
+        )? {
+            Some(header) => header,
+            None => {
+                self.report(height, "header is missing".to_owned());
+                return Ok(None);
+            }
+        };
+        if header.number() != height {
+            self.report(height, format!("header is of block {}", header.number()));
+        }
+        let hash = header.hash().expect("hash of a decoded header; qed");
+        let number: Option<BlockNumber> = self.read(
+            height,
+            DataCategory::Extra,
+            &Hash2BlockNumber(hash).get_index(),
+            "height of the hash",
+        )?;
+        if number != Some(height) {
+            self.report(height, format!("hash {:?} is mapped to {:?}", hash, number));
+        }
+        if let Some(parent) = parent {
+            if parent.hash() != Some(*header.parent_hash()) {
+                self.report(
+                    height,
+                    "parent hash is not the hash of its parent".to_owned(),
+                );
+            }
+        }
+        Ok(Some(header))
+    }
+
+    fn check_body(
+        &mut self,
+        header: &Header,
+        pruned_height: BlockNumber,
+    ) -> Result<(), DatabaseError> {
+        let height = header.number();
+        let hash = header.hash().expect("hash of a decoded header; qed");
+        let body: BlockBody = match self.read(
+            height,
+            DataCategory::Bodies,
+            &BlockNumber2Body(height).get_index(),
+            "body",
+        )? {
+            Some(body) => body,
+            None => {
+                if height == 0 || height >= pruned_height {
+                    self.report(height, "body is missing".to_owned());
+                }
+                return Ok(());
+            }
+        };
+        // The transactions root of the genesis block is set by the genesis file.
+        if height > 0 && body.protobuf().transactions_root() != *header.transactions_root() {
+            self.report(
+                height,
+                "transactions root does not match the body".to_owned(),
+            );
+        }
+
+        for (tx_hash, index) in body.transaction_indexes(hash) {
+            let saved: Option<TransactionIndex> = self.read(
+                height,
+                DataCategory::Extra,
+                &Hash2TransactionIndex(tx_hash).get_index(),
+                "transaction index",
+            )?;
+            match saved {
+                Some(ref saved)
+                    if saved.block_hash == index.block_hash && saved.index == index.index => {}
+                _ => self.report(
+                    height,
+                    format!("index of transaction {:?} is wrong", tx_hash),
+                ),
+            }
+        }
+
+        let tx_count = body.transactions().len();
+        if tx_count == 0 {
+            return Ok(());
+        }
+        let receipts: BlockReceipts = match self.read(
+            height,
+            DataCategory::Extra,
+            &Hash2BlockReceipts(hash).get_index(),
+            "receipts",
+        )? {
+            Some(receipts) => receipts,
+            None => {
+                self.report(height, "receipts are missing".to_owned());
+                return Ok(());
+            }
+        };
+        if receipts.receipts.len() != tx_count {
+            self.report(
+                height,
+                format!(
+                    "{} receipts for {} transactions",
+                    receipts.receipts.len(),
+                    tx_count
+                ),
+            );
+        }
+        let receipts_root = cita_merklehash::Tree::from_hashes(
+            receipts
+                .receipts
+                .iter()
+                .map(|r| r.rlp_bytes().into_vec().crypt_hash())
+                .collect::<Vec<_>>(),
+            cita_merklehash::merge,
+        )
+        .get_root_hash()
+        .cloned()
+        .unwrap_or(cita_merklehash::HASH_NULL);
+        if receipts_root != *header.receipts_root() {
+            self.report(
+                height,
+                "receipts root does not match the receipts".to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    // Only the lowest level is checked, the others are made of it.
+    fn check_bloom(
+        &mut self,
+        header: &Header,
+        bloom_elements_per_index: usize,
+    ) -> Result<(), DatabaseError> {
+        let height = header.number();
+        let elements = bloom_elements_per_index as u64;
+        let position = GroupPosition {
+            level: 0,
+            index: (height / elements) as usize,
+        };
+        let group: Option<LogBloomGroup> = self.read(
+            height,
+            DataCategory::Extra,
+            &LogGroupPosition::from(position).get_index(),
+            "bloom group",
+        )?;
+        let saved = group
+            .map(Into::<BloomGroup>::into)
+            .and_then(|group| group.blooms.into_iter().nth((height % elements) as usize))
+            .map(|bloom| LogBloom::from(Into::<[u8; 256]>::into(bloom)))
+            .unwrap_or_else(LogBloom::zero);
+        if saved != *header.log_bloom() {
+            self.report(
+                height,
+                "log bloom index does not match the header".to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Check the blocks from the genesis to the current one.
+pub fn check_chain(db: &RocksDB) -> Result<ChainReport, IntegrityError> {
+    let pruned_height = history::pruned_height(db).map_err(IntegrityError::History)?;
+    let (_, bloom_elements_per_index) = blooms_params(db)?;
+    let mut checker = Checker::new(db);
+    let current_height = checker.current_height()?;
+    let snapshot_height = match current_height {
+        Some(current_height) => snapshot_height(current_height, |height| {
+            db.contains(
+                Some(DataCategory::Headers),
+                &BlockNumber2Header(height).get_index(),
+            )
+        })?,
+        None => 0,
+    };
+
+    let mut parent: Option<Header> = None;
+    let mut height = 0;
+    loop {
+        if height > 0 && height < snapshot_height {
+            height = snapshot_height;
+            parent = None;
+        }
+        match current_height {
+            Some(current_height) if height > current_height => break,
+            // Without a current hash, go on until a header is missing.
+            None if db
+                .get(
+                    Some(DataCategory::Headers),
+                    &BlockNumber2Header(height).get_index(),
+                )?
+                .is_none() =>
+            {
+                break
+            }
+            _ => {}
+        }
+        parent = match checker.check_header(height, parent.as_ref())? {
+            Some(header) => {
+                checker.check_body(&header, pruned_height)?;
+                checker.check_bloom(&header, bloom_elements_per_index)?;
+                Some(header)
+            }
+            None => None,
+        };
+        height += 1;
+    }
+    if current_height.is_none() && height > 0 {
+        checker.report(height - 1, "current hash is missing".to_owned());
+    }
+
+    Ok(ChainReport {
+        current_height: current_height.unwrap_or_else(|| height.saturating_sub(1)),
+        problems: checker.problems,
+    })
+}
+
+/// Check the executor headers and states, and compare the chain headers with
+/// them up to `chain_height`.
+pub fn check_executor(
+    db: &RocksDB,
+    chain_db: &RocksDB,
+    chain_height: BlockNumber,
+) -> Result<Vec<Problem>, IntegrityError> {
+    let mut checker = Checker::new(db);
+    let current_height = match checker.executor_current_height()? {
+        Some(height) => height,
+        None => {
+            checker.report(0, "executor current hash is missing".to_owned());
+            return Ok(checker.problems);
+        }
+    };
+    if chain_height > current_height {
+        checker.report(
+            chain_height,
+            format!("chain is ahead of executor at height {}", current_height),
+        );
+    }
+    // The state of a block is broken once the journal of the next block is pruned.
+    let mut state_height = state_pruned_height(db)?.saturating_sub(1);
+    let snapshot_height = snapshot_height(current_height, |height| {
+        db.contains(
+            Some(DataCategory::Extra),
+            &BlockNumber2Hash(height).get_index(),
+        )
+    })?;
+    if snapshot_height > 0 {
+        // Only the state of the target is downloaded.
+        state_height = state_height.max(snapshot_height + SNAPSHOT_HEADERS as u64 - 1);
+    }
+
+    let mut parent_hash: Option<H256> = None;
+    for height in 0..=current_height {
+        if height > 0 && height < snapshot_height {
+            parent_hash = None;
+            continue;
+        }
+        let hash: H256 = match checker.read(
+            height,
+            DataCategory::Extra,
+            &BlockNumber2Hash(height).get_index(),
+            "executor block hash",
+        )? {
+            Some(hash) => hash,
+            None => {
+                checker.report(height, "executor block hash is missing".to_owned());
+                parent_hash = None;
+                continue;
+            }
+        };
+        let header: Header = match checker.read(
+            height,
+            DataCategory::Headers,
+            &Hash2Header(hash).get_index(),
+            "executor header",
+        )? {
+            Some(header) => header,
+            None => {
+                checker.report(height, "executor header is missing".to_owned());
+                parent_hash = None;
+                continue;
+            }
+        };
+        if header.number() != height || header.hash() != Some(hash) {
+            checker.report(height, "executor header is of another block".to_owned());
+        }
+        if let Some(parent_hash) = parent_hash {
+            if *header.parent_hash() != parent_hash {
+                checker.report(height, "executor parent hash is wrong".to_owned());
+            }
+        }
+        parent_hash = Some(hash);
+
+        let state_root = *header.state_root();
+        if height >= state_height
+            && state_root != HASH_NULL_RLP
+            && !db.contains(Some(DataCategory::State), &state_root.to_vec())?
+        {
+            checker.report(height, format!("state root {:?} is missing", state_root));
+        }
+
+        if height <= chain_height {
+            let chain_header: Option<Header> = checker.read_in(
+                chain_db,
+                height,
+                DataCategory::Headers,
+                &BlockNumber2Header(height).get_index(),
+                "chain header",
+            )?;
+            if let Some(chain_header) = chain_header {
+                if chain_header.hash() != Some(hash) {
+                    checker.report(
+                        height,
+                        "chain and executor have different blocks".to_owned(),
+                    );
+                }
+            }
+        }
+    }
+    Ok(checker.problems)
+}
+
+/// Rebuild the transaction indexes and the log blooms of the blocks from
+/// `from` to `to` by their headers and bodies, returns the number of
+/// transactions indexed.
+pub fn rebuild_indexes(
+    db: &RocksDB,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<usize, IntegrityError> {
+    let params = blooms_params(db)?;
+    let bloom_db = BloomDB(db);
+    let mut tx_count = 0;
+    let mut start = from;
+    while start <= to {
+        let end = to.min(start + REBUILD_BLOCKS_PER_ROUND - 1);
+        let mut blooms = Vec::new();
+        for height in start..=end {
+            let header: Header = match db.get(
+                Some(DataCategory::Headers),
+                &BlockNumber2Header(height).get_index(),
+            )? {
+                Some(header) => rlp::decode(&header),
+                None => return Err(IntegrityError::MissingHeader(height)),
+            };
+            blooms.push(to_bloom(header.log_bloom()));
+
+            let body: BlockBody = match db.get(
+                Some(DataCategory::Bodies),
+                &BlockNumber2Body(height).get_index(),
+            )? {
+                Some(body) => rlp::decode(&body),
+                None => continue,
+            };
+            let hash = header.hash().expect("hash of a decoded header; qed");
+            let (keys, values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = body
+                .transaction_indexes(hash)
+                .iter()
+                .map(|(tx_hash, index)| {
+                    (
+                        Hash2TransactionIndex(*tx_hash).get_index(),
+                        rlp::encode(index).into_vec(),
+                    )
+                })
+                .unzip();
+            tx_count += keys.len();
+            db.insert_batch(Some(DataCategory::Extra), keys, values)?;
+        }
+
+        // The upper levels are made of the lower ones saved in the last round.
+        let groups = BloomGroupChain::new(blooms_config(params), &bloom_db)
+            .replace(&(start as usize..end as usize + 1), blooms);
+        let (keys, values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = groups
+            .into_iter()
+            .map(|(position, group)| {
+                (
+                    LogGroupPosition::from(position).get_index(),
+                    rlp::encode(&LogBloomGroup::from(group)).into_vec(),
+                )
+            })
+            .unzip();
+        db.insert_batch(Some(DataCategory::Extra), keys, values)?;
+        info!("rebuild the indexes of blocks {}-{}", start, end);
+        start = end + 1;
+    }
+    Ok(tx_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_chain, check_executor, rebuild_indexes};
+    use crate::db_indexes::{
+        BlockNumber2Body, BlockNumber2Hash, BlockNumber2Header, CurrentHash, DBIndex,
+        Hash2BlockNumber, Hash2Header,
+    };
+    use crate::header::{BlockNumber, Header};
+    use crate::types::block::BlockBody;
+    use cita_db::{Config, DataCategory, Database, RocksDB, NUM_COLUMNS};
+    use cita_types::{Bloom as LogBloom, H256};
+    use rlp;
+    use tempdir::TempDir;
+
+    fn save_blocks(db: &RocksDB, count: u64) -> Vec<Header> {
+        let mut headers: Vec<Header> = Vec::new();
+        for number in 0..count {
+            let mut header = Header::default();
+            header.set_number(number);
+            if let Some(parent) = headers.last() {
+                header.set_parent_hash(parent.hash().unwrap());
+            }
+            if number % 2 == 1 {
+                header.set_log_bloom(LogBloom::from([number as u8; 256]));
+            }
+            header.rehash();
+            let hash = header.hash().unwrap();
+            db.insert(
+                Some(DataCategory::Headers),
+                BlockNumber2Header(number).get_index(),
+                rlp::encode(&header).into_vec(),
+            )
+            .unwrap();
+            db.insert(
+                Some(DataCategory::Bodies),
+                BlockNumber2Body(number).get_index(),
+                rlp::encode(&BlockBody::default()).into_vec(),
+            )
+            .unwrap();
+            db.insert(
+                Some(DataCategory::Extra),
+                Hash2BlockNumber(hash).get_index(),
+                rlp::encode(&number).into_vec(),
+            )
+            .unwrap();
+            db.insert(
+                Some(DataCategory::Extra),
+                CurrentHash.get_index(),
+                rlp::encode(&hash).into_vec(),
+            )
+            .unwrap();
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_check_and_rebuild() {
+        let dir = TempDir::new("integrity").unwrap();
+        let config = Config::with_category_num(NUM_COLUMNS);
+        let db = RocksDB::open(dir.path().to_str().unwrap(), &config).unwrap();
+        let headers = save_blocks(&db, 40);
+
+        // The blooms are never saved.
+        let report = check_chain(&db).unwrap();
+        assert_eq!(report.current_height, 39);
+        assert_eq!(report.problems.len(), 20);
+        assert!(report.problems.iter().all(|p| p.height % 2 == 1));
+
+        rebuild_indexes(&db, 0, 39).unwrap();
+        assert!(check_chain(&db).unwrap().problems.is_empty());
+
+        // Break the hash chain.
+        let mut header = headers[20].clone();
+        header.set_parent_hash(H256::from(1));
+        header.rehash();
+        db.insert(
+            Some(DataCategory::Headers),
+            BlockNumber2Header(20).get_index(),
+            rlp::encode(&header).into_vec(),
+        )
+        .unwrap();
+        let problems = check_chain(&db).unwrap().problems;
+        let heights: Vec<u64> = problems.iter().map(|p| p.height).collect();
+        // Block 20 has an unknown hash and a wrong parent, and block 21 is
+        // not its child.
+        assert_eq!(heights, vec![20, 20, 21]);
+    }
+
+    #[test]
+    fn test_check_fast_synced() {
+        let dir = TempDir::new("integrity").unwrap();
+        let config = Config::with_category_num(NUM_COLUMNS);
+        let db = RocksDB::open(dir.path().to_str().unwrap(), &config).unwrap();
+        save_blocks(&db, 40);
+        rebuild_indexes(&db, 0, 39).unwrap();
+        // A chain fast synced from block 30.
+        for number in 1..30 {
+            db.remove(
+                Some(DataCategory::Headers),
+                &BlockNumber2Header(number).get_index(),
+            )
+            .unwrap();
+        }
+        assert!(check_chain(&db).unwrap().problems.is_empty());
+
+        // A header after the snapshot block is missing.
+        db.remove(
+            Some(DataCategory::Headers),
+            &BlockNumber2Header(35).get_index(),
+        )
+        .unwrap();
+        let problems = check_chain(&db).unwrap().problems;
+        let heights: Vec<u64> = problems.iter().map(|p| p.height).collect();
+        assert_eq!(heights, vec![35]);
+    }
+
+    fn open_db(dir: &TempDir, name: &str) -> RocksDB {
+        let db_config = Config::with_category_num(NUM_COLUMNS);
+        RocksDB::open(dir.path().join(name).to_str().unwrap(), &db_config).unwrap()
+    }
+
+    fn generate_headers(count: u64) -> Vec<Header> {
+        let mut headers: Vec<Header> = Vec::new();
+        for number in 0..count {
+            let mut header = Header::default();
+            header.set_number(number);
+            if let Some(parent) = headers.last() {
+                header.set_parent_hash(parent.hash().unwrap());
+            }
+            header.rehash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn save_executor_header(db: &RocksDB, header: &Header) {
+        let hash = header.hash().unwrap();
+        db.insert(
+            Some(DataCategory::Headers),
+            Hash2Header(hash).get_index(),
+            rlp::encode(header).into_vec(),
+        )
+        .unwrap();
+        db.insert(
+            Some(DataCategory::Extra),
+            BlockNumber2Hash(header.number()).get_index(),
+            rlp::encode(&hash).into_vec(),
+        )
+        .unwrap();
+        db.insert(
+            Some(DataCategory::Extra),
+            CurrentHash.get_index(),
+            rlp::encode(&hash).into_vec(),
+        )
+        .unwrap();
+    }
+
+    fn save_chain_header(db: &RocksDB, header: &Header) {
+        db.insert(
+            Some(DataCategory::Headers),
+            BlockNumber2Header(header.number()).get_index(),
+            rlp::encode(header).into_vec(),
+        )
+        .unwrap();
+    }
+
+    fn problem_heights(db: &RocksDB, chain_db: &RocksDB, chain_height: BlockNumber) -> Vec<u64> {
+        check_executor(db, chain_db, chain_height)
+            .unwrap()
+            .iter()
+            .map(|p| p.height)
+            .collect()
+    }
+
+    #[test]
+    fn test_check_executor() {
+        let dir = TempDir::new("integrity_executor").unwrap();
+        let db = open_db(&dir, "executor");
+        let chain_db = open_db(&dir, "chain");
+        let headers = generate_headers(40);
+        for header in &headers {
+            save_executor_header(&db, header);
+            save_chain_header(&chain_db, header);
+        }
+        assert!(problem_heights(&db, &chain_db, 39).is_empty());
+        assert_eq!(problem_heights(&db, &chain_db, 40), vec![40]);
+
+        // The chain has another block 10.
+        let mut header = headers[10].clone();
+        header.set_timestamp(1);
+        header.rehash();
+        save_chain_header(&chain_db, &header);
+        assert_eq!(problem_heights(&db, &chain_db, 39), vec![10]);
+        save_chain_header(&chain_db, &headers[10]);
+
+        // The header of block 20 is missing.
+        db.remove(
+            Some(DataCategory::Headers),
+            &Hash2Header(headers[20].hash().unwrap()).get_index(),
+        )
+        .unwrap();
+        assert_eq!(problem_heights(&db, &chain_db, 39), vec![20]);
+
+        // The state of the current block is missing.
+        let mut header = headers[39].clone();
+        header.set_state_root(H256::from(1));
+        header.rehash();
+        save_executor_header(&db, &header);
+        assert_eq!(problem_heights(&db, &chain_db, 38), vec![20, 39]);
+    }
+
+    #[test]
+    fn test_check_fast_synced_executor() {
+        let dir = TempDir::new("integrity_executor_fast_sync").unwrap();
+        let db = open_db(&dir, "executor");
+        let chain_db = open_db(&dir, "chain");
+        let mut headers = generate_headers(1100);
+        save_executor_header(&db, &headers[0]);
+        save_chain_header(&chain_db, &headers[0]);
+        // Fast synced to the target 1000, with the 255 headers before it.
+        for number in 745..1100 {
+            let mut header = headers[number].clone();
+            header.set_parent_hash(headers[number - 1].hash().unwrap());
+            // Only the state of the target is downloaded.
+            if number == 900 {
+                header.set_state_root(H256::from(1));
+            }
+            header.rehash();
+            headers[number] = header;
+            save_executor_header(&db, &headers[number]);
+            if number >= 1000 {
+                save_chain_header(&chain_db, &headers[number]);
+            }
+        }
+        assert!(problem_heights(&db, &chain_db, 1099).is_empty());
+
+        // A header after the snapshot is missing.
+        db.remove(
+            Some(DataCategory::Extra),
+            &BlockNumber2Hash(1050).get_index(),
+        )
+        .unwrap();
+        assert_eq!(problem_heights(&db, &chain_db, 1099), vec![1050]);
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Indexes of the logs.
+//!
+//! The log blooms are saved in bloom groups, whose levels and elements per
+//! index are saved with them. A change of them in the config takes effect
+//! after the blooms are rebuilt.
+//!
+//! The blocks can also be indexed by the address and the first topic of
+//! their logs, so the logs of a contract event over a long range are found
+//! without checking the blooms. The blocks of a bucket are saved in chunks,
+//! so indexing a block writes the last chunk only.
+
+use crate::bloomchain::group::{BloomGroup, BloomGroupChain, BloomGroupDatabase, GroupPosition};
+use crate::bloomchain::{Bloom, Config as BloomChainConfig};
+use crate::header::{BlockNumber, Header};
+use crate::libchain::chain::{LOG_BLOOMS_ELEMENTS_PER_INDEX, LOG_BLOOMS_LEVELS};
+use crate::libchain::integrity::IntegrityError;
+use crate::log_blooms::LogBloomGroup;
+use crate::receipt::Receipt;
+use crate::types::block_receipts::BlockReceipts;
+use cita_db::error::DatabaseError;
+use cita_db::{DataCategory, Database, RocksDB};
+use cita_types::{Address, Bloom as LogBloom, H256};
+use rlp::{self, RlpStream, UntrustedRlp};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::db_indexes::{
+    AddressTopic2Blocks, AddressTopicChunks, AddressTopicIndexedHeight, BlockNumber2Header,
+    DBIndex, Hash2BlockReceipts, LogBloomsParams, LogGroupPosition,
+};
+
+/// Block numbers in a bucket of the address and topic index.
+pub const ADDRESS_TOPIC_BUCKET_BLOCKS: u64 = 65536;
+/// Block numbers in a chunk of a bucket.
+pub const ADDRESS_TOPIC_CHUNK_BLOCKS: usize = 256;
+// Blocks whose blooms are rebuilt at once.
+const REBUILD_BLOCKS_PER_ROUND: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LogIndexConfig {
+    pub bloom_levels: usize,
+    pub bloom_elements_per_index: usize,
+    /// Index the blocks by the address and the first topic of their logs.
+    pub address_topic: bool,
+}
+
+impl Default for LogIndexConfig {
+    fn default() -> Self {
+        LogIndexConfig {
+            bloom_levels: LOG_BLOOMS_LEVELS,
+            bloom_elements_per_index: LOG_BLOOMS_ELEMENTS_PER_INDEX,
+            address_topic: false,
+        }
+    }
+}
+
+impl LogIndexConfig {
+    fn blooms_params(&self) -> (usize, usize) {
+        (self.bloom_levels, self.bloom_elements_per_index)
+    }
+}
+
+pub fn blooms_config((levels, elements_per_index): (usize, usize)) -> BloomChainConfig {
+    BloomChainConfig {
+        levels,
+        elements_per_index,
+    }
+}
+
+pub fn to_bloom(log_bloom: &LogBloom) -> Bloom {
+    Bloom::from(Into::<[u8; 256]>::into(*log_bloom))
+}
+
+pub struct BloomDB<'a>(pub &'a RocksDB);
+
+impl<'a> BloomGroupDatabase for BloomDB<'a> {
+    fn blooms_at(&self, position: &GroupPosition) -> Option<BloomGroup> {
+        let p = LogGroupPosition::from(position.clone());
+        self.0
+            .get(Some(DataCategory::Extra), &p.get_index())
+            .unwrap_or(None)
+            .map(|blooms| {
+                let g: LogBloomGroup = rlp::decode(&blooms);
+                g.into()
+            })
+    }
+}
+
+/// The levels and the elements per index of the saved blooms.
+pub fn saved_blooms_params(db: &RocksDB) -> Result<Option<(usize, usize)>, DatabaseError> {
+    Ok(db
+        .get(Some(DataCategory::Extra), &LogBloomsParams.get_index())?
+        .map(|params| {
+            let r = UntrustedRlp::new(&params);
+            (
+                r.val_at::<u64>(0).unwrap_or(0) as usize,
+                r.val_at::<u64>(1).unwrap_or(0) as usize,
+            )
+        }))
+}
+
+fn save_blooms_params(
+    db: &RocksDB,
+    (levels, elements): (usize, usize),
+) -> Result<(), DatabaseError> {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&(levels as u64));
+    stream.append(&(elements as u64));
+    db.insert(
+        Some(DataCategory::Extra),
+        LogBloomsParams.get_index(),
+        stream.out(),
+    )
+}
+
+/// The blooms params to use with the database. A new database takes the
+/// config, and an old one without saved params has the default ones.
+pub fn init_blooms_params(
+    db: &RocksDB,
+    config: &LogIndexConfig,
+    is_new: bool,
+) -> Result<(usize, usize), DatabaseError> {
+    let params = match saved_blooms_params(db)? {
+        Some(params) => params,
+        None => {
+            let params = if is_new {
+                config.blooms_params()
+            } else {
+                LogIndexConfig::default().blooms_params()
+            };
+            save_blooms_params(db, params)?;
+            params
+        }
+    };
+    if params != config.blooms_params() {
+        warn!(
+            "log blooms are saved with (levels, elements per index) {:?} rather than {:?} \
+             in the config, rebuild the log index to change them",
+            params,
+            config.blooms_params()
+        );
+    }
+    Ok(params)
+}
+
+/// The first block indexed by address and topic, `None` if the index is
+/// disabled. A new index starts from `next_height`.
+pub fn init_address_topic_index(
+    db: &RocksDB,
+    config: &LogIndexConfig,
+    next_height: BlockNumber,
+) -> Result<Option<BlockNumber>, DatabaseError> {
+    let key = AddressTopicIndexedHeight.get_index();
+    // The index has a gap once disabled, so it has to be rebuilt.
+    if !config.address_topic {
+        db.remove(Some(DataCategory::Extra), &key)?;
+        return Ok(None);
+    }
+    match db.get(Some(DataCategory::Extra), &key)? {
+        Some(height) => Ok(Some(rlp::decode(&height))),
+        None => {
+            db.insert(
+                Some(DataCategory::Extra),
+                key,
+                rlp::encode(&next_height).into_vec(),
+            )?;
+            Ok(Some(next_height))
+        }
+    }
+}
+
+fn address_topics(receipts: &[Receipt]) -> BTreeSet<(Address, H256)> {
+    receipts
+        .iter()
+        .flat_map(|receipt| receipt.logs.iter())
+        .filter_map(|log| log.topics.get(0).map(|topic| (log.address, *topic)))
+        .collect()
+}
+
+fn read_blocks(db: &RocksDB, key: &[u8]) -> Result<Vec<BlockNumber>, DatabaseError> {
+    Ok(db
+        .get(Some(DataCategory::Extra), key)?
+        .map(|blocks| UntrustedRlp::new(&blocks).as_list().unwrap_or_default())
+        .unwrap_or_default())
+}
+
+fn encode_blocks(blocks: &[BlockNumber]) -> Vec<u8> {
+    rlp::encode_list::<BlockNumber, BlockNumber>(blocks).into_vec()
+}
+
+/// Restart the address and topic index from `next_height`, after a block
+/// failed to be indexed.
+pub fn restart_address_topic_index(
+    db: &RocksDB,
+    next_height: BlockNumber,
+) -> Result<(), DatabaseError> {
+    db.insert(
+        Some(DataCategory::Extra),
+        AddressTopicIndexedHeight.get_index(),
+        rlp::encode(&next_height).into_vec(),
+    )
+}
+
+fn read_chunks(
+    db: &RocksDB,
+    address: Address,
+    topic: H256,
+    bucket: u64,
+) -> Result<u64, DatabaseError> {
+    Ok(db
+        .get(
+            Some(DataCategory::Extra),
+            &AddressTopicChunks(address, topic, bucket).get_index(),
+        )?
+        .map(|chunks| rlp::decode(&chunks))
+        .unwrap_or(0))
+}
+
+/// Keys and values to save the blocks of a bucket from the chunk `first`.
+fn bucket_chunks(
+    address: Address,
+    topic: H256,
+    bucket: u64,
+    first: u64,
+    blocks: &[BlockNumber],
+) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    let mut chunks = first;
+    for chunk in blocks.chunks(ADDRESS_TOPIC_CHUNK_BLOCKS) {
+        keys.push(AddressTopic2Blocks(address, topic, bucket, chunks).get_index());
+        values.push(encode_blocks(chunk));
+        chunks += 1;
+    }
+    keys.push(AddressTopicChunks(address, topic, bucket).get_index());
+    values.push(rlp::encode(&chunks).into_vec());
+    (keys, values)
+}
+
+/// Index the block by the addresses and first topics of its logs.
+pub fn index_address_topics(
+    db: &RocksDB,
+    number: BlockNumber,
+    receipts: &[Receipt],
+) -> Result<(), DatabaseError> {
+    let bucket = number / ADDRESS_TOPIC_BUCKET_BLOCKS;
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for (address, topic) in address_topics(receipts) {
+        let chunks = read_chunks(db, address, topic, bucket)?;
+        let (mut last_chunk, mut blocks) = if chunks > 0 {
+            let key = AddressTopic2Blocks(address, topic, bucket, chunks - 1).get_index();
+            (chunks - 1, read_blocks(db, &key)?)
+        } else {
+            (0, Vec::new())
+        };
+        if blocks.last().map(|last| *last >= number).unwrap_or(false) {
+            continue;
+        }
+        // Append to the last chunk, or start a new one when it is full.
+        if blocks.len() >= ADDRESS_TOPIC_CHUNK_BLOCKS {
+            last_chunk += 1;
+            blocks.clear();
+        }
+        blocks.push(number);
+        let (chunk_keys, chunk_values) = bucket_chunks(address, topic, bucket, last_chunk, &blocks);
+        keys.extend(chunk_keys);
+        values.extend(chunk_values);
+    }
+    db.insert_batch(Some(DataCategory::Extra), keys, values)
+}
+
+/// The blocks from `from` to `to` having logs of the address and topic.
+pub fn blocks_with_address_topic(
+    db: &RocksDB,
+    address: Address,
+    topic: H256,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<Vec<BlockNumber>, DatabaseError> {
+    let mut result = Vec::new();
+    if from > to {
+        return Ok(result);
+    }
+    for bucket in from / ADDRESS_TOPIC_BUCKET_BLOCKS..=to / ADDRESS_TOPIC_BUCKET_BLOCKS {
+        for chunk in 0..read_chunks(db, address, topic, bucket)? {
+            let key = AddressTopic2Blocks(address, topic, bucket, chunk).get_index();
+            result.extend(
+                read_blocks(db, &key)?
+                    .into_iter()
+                    .filter(|number| *number >= from && *number <= to),
+            );
+        }
+    }
+    Ok(result)
+}
+
+/// Rebuild the log blooms with the params of the config from the receipts,
+/// or from the headers for the blocks whose receipts are pruned, and the
+/// address and topic index if enabled. Returns the number of blocks.
+pub fn rebuild(
+    db: &RocksDB,
+    config: &LogIndexConfig,
+    to: BlockNumber,
+) -> Result<u64, IntegrityError> {
+    let params = config.blooms_params();
+    let bloom_db = BloomDB(db);
+    let mut address_topics_blocks: BTreeMap<(Address, H256), Vec<BlockNumber>> = BTreeMap::new();
+    let mut start = 0;
+    while start <= to {
+        let end = to.min(start + REBUILD_BLOCKS_PER_ROUND - 1);
+        let mut blooms = Vec::new();
+        for number in start..=end {
+            let header: Header = match db.get(
+                Some(DataCategory::Headers),
+                &BlockNumber2Header(number).get_index(),
+            )? {
+                Some(header) => rlp::decode(&header),
+                None => return Err(IntegrityError::MissingHeader(number)),
+            };
+            let hash = header.hash().expect("hash of a decoded header; qed");
+            let receipts: Option<BlockReceipts> = db
+                .get(
+                    Some(DataCategory::Extra),
+                    &Hash2BlockReceipts(hash).get_index(),
+                )?
+                .map(|receipts| rlp::decode(&receipts));
+            let log_bloom = match receipts {
+                Some(receipts) => {
+                    if config.address_topic {
+                        for pair in address_topics(&receipts.receipts) {
+                            address_topics_blocks
+                                .entry(pair)
+                                .or_insert_with(Vec::new)
+                                .push(number);
+                        }
+                    }
+                    receipts
+                        .receipts
+                        .iter()
+                        .fold(LogBloom::zero(), |b, r| b | r.log_bloom)
+                }
+                None => *header.log_bloom(),
+            };
+            blooms.push(to_bloom(&log_bloom));
+        }
+
+        // The upper levels are made of the lower ones saved in the last round.
+        let groups = BloomGroupChain::new(blooms_config(params), &bloom_db)
+            .replace(&(start as usize..end as usize + 1), blooms);
+        let (keys, values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = groups
+            .into_iter()
+            .map(|(position, group)| {
+                (
+                    LogGroupPosition::from(position).get_index(),
+                    rlp::encode(&LogBloomGroup::from(group)).into_vec(),
+                )
+            })
+            .unzip();
+        db.insert_batch(Some(DataCategory::Extra), keys, values)?;
+
+        // Save a bucket once all of its blocks are done.
+        if end == to || (end + 1) % ADDRESS_TOPIC_BUCKET_BLOCKS == 0 {
+            let bucket = end / ADDRESS_TOPIC_BUCKET_BLOCKS;
+            let mut keys = Vec::new();
+            let mut values = Vec::new();
+            for ((address, topic), blocks) in &address_topics_blocks {
+                let (chunk_keys, chunk_values) = bucket_chunks(*address, *topic, bucket, 0, blocks);
+                keys.extend(chunk_keys);
+                values.extend(chunk_values);
+            }
+            db.insert_batch(Some(DataCategory::Extra), keys, values)?;
+            address_topics_blocks.clear();
+        }
+        info!("rebuild the log index of blocks {}-{}", start, end);
+        start = end + 1;
+    }
+
+    save_blooms_params(db, params)?;
+    let key = AddressTopicIndexedHeight.get_index();
+    if config.address_topic {
+        db.insert(
+            Some(DataCategory::Extra),
+            key,
+            rlp::encode(&0u64).into_vec(),
+        )?;
+    } else {
+        db.remove(Some(DataCategory::Extra), &key)?;
+    }
+    Ok(to + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        blocks_with_address_topic, index_address_topics, init_address_topic_index, read_chunks,
+        restart_address_topic_index, LogIndexConfig, ADDRESS_TOPIC_BUCKET_BLOCKS,
+        ADDRESS_TOPIC_CHUNK_BLOCKS,
+    };
+    use crate::log::Log;
+    use crate::receipt::Receipt;
+    use cita_db::{Config, RocksDB, NUM_COLUMNS};
+    use cita_types::{Address, H256};
+    use tempdir::TempDir;
+
+    fn receipt(logs: &[(u64, u64)]) -> Receipt {
+        let mut receipt = Receipt::default();
+        receipt.logs = logs
+            .iter()
+            .map(|(address, topic)| Log {
+                address: Address::from(*address),
+                topics: vec![H256::from(*topic)],
+                data: Vec::new(),
+            })
+            .collect();
+        receipt
+    }
+
+    #[test]
+    fn test_address_topic_index() {
+        let dir = TempDir::new("log_index").unwrap();
+        let config = Config::with_category_num(NUM_COLUMNS);
+        let db = RocksDB::open(dir.path().to_str().unwrap(), &config).unwrap();
+
+        let mut config = LogIndexConfig::default();
+        assert_eq!(init_address_topic_index(&db, &config, 5).unwrap(), None);
+        config.address_topic = true;
+        assert_eq!(init_address_topic_index(&db, &config, 5).unwrap(), Some(5));
+        assert_eq!(init_address_topic_index(&db, &config, 9).unwrap(), Some(5));
+
+        let last = ADDRESS_TOPIC_BUCKET_BLOCKS + 1;
+        for number in &[5, 6, last] {
+            index_address_topics(&db, *number, &[receipt(&[(1, 2), (1, 2)])]).unwrap();
+        }
+        index_address_topics(&db, 7, &[receipt(&[(1, 3)]), receipt(&[(4, 2)])]).unwrap();
+
+        let blocks = |address: u64, topic: u64, from, to| {
+            blocks_with_address_topic(&db, Address::from(address), H256::from(topic), from, to)
+                .unwrap()
+        };
+        assert_eq!(blocks(1, 2, 0, last), vec![5, 6, last]);
+        assert_eq!(blocks(1, 2, 6, last - 1), vec![6]);
+        assert_eq!(blocks(1, 3, 0, last), vec![7]);
+        assert_eq!(blocks(4, 2, 0, last), vec![7]);
+        assert!(blocks(4, 3, 0, last).is_empty());
+
+        restart_address_topic_index(&db, 8).unwrap();
+        assert_eq!(init_address_topic_index(&db, &config, 9).unwrap(), Some(8));
+    }
+
+    #[test]
+    fn test_address_topic_chunks() {
+        let dir = TempDir::new("log_index_chunks").unwrap();
+        let config = Config::with_category_num(NUM_COLUMNS);
+        let db = RocksDB::open(dir.path().to_str().unwrap(), &config).unwrap();
+
+        let count = ADDRESS_TOPIC_CHUNK_BLOCKS as u64 * 2 + 1;
+        for number in 0..count {
+            index_address_topics(&db, number, &[receipt(&[(1, 2)])]).unwrap();
+        }
+        // Indexed again.
+        index_address_topics(&db, count - 1, &[receipt(&[(1, 2)])]).unwrap();
+        assert_eq!(
+            read_chunks(&db, Address::from(1), H256::from(2), 0).unwrap(),
+            3
+        );
+        let blocks =
+            blocks_with_address_topic(&db, Address::from(1), H256::from(2), 1, count).unwrap();
+        assert_eq!(blocks, (1..count).collect::<Vec<_>>());
+    }
+}
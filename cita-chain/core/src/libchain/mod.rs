@@ -13,5 +13,8 @@
 // limitations under the License.
 
 pub mod chain;
+pub mod history;
+pub mod integrity;
+pub mod log_index;
 pub mod rich_status;
 pub mod status;
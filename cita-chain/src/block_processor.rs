@@ -12,10 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::metrics;
+use cita_metrics::elapsed_secs;
 use core::libchain::chain::Chain;
 use libproto::executor::ExecutedResult;
 use pubsub::channel::Sender;
 use std::sync::Arc;
+use std::time::Instant;
 
 /// Processing blocks and transaction storage
 #[derive(Clone)]
@@ -34,7 +37,10 @@ impl BlockProcessor {
     }
 
     pub fn set_executed_result(&self, ret: &ExecutedResult) {
+        let start = Instant::now();
         self.chain.set_executed_result(ret, &self.ctx_pub);
+        metrics::STORE_RESULT_SECONDS.observe(elapsed_secs(start));
+        metrics::CHAIN_HEIGHT.set(self.chain.get_current_height() as i64);
     }
 
     pub fn reset_max_store_height(&self) {
@@ -14,30 +14,53 @@
 
 use std::convert::Into;
 use std::mem;
+use std::str::FromStr;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use cita_types::H256;
+use cita_types::{clean_0x, H256};
+use core::filters::logs::LogCursor;
 use core::filters::rpc_filter::RpcFilter as FilterMethod;
 use core::libchain::chain::{BlockInQueue, Chain};
 use error::ErrorCode;
 use jsonrpc_types::rpc_types::{
-    BlockNumber as RpcBlockNumber, BlockParamsByHash, BlockParamsByNumber, Filter as RpcFilter,
-    Log as RpcLog, Receipt as RpcReceipt, RpcBlock,
+    BlockNumber as RpcBlockNumber, BlockParamsByHash, BlockParamsByNumber, Data,
+    Filter as RpcFilter, Log as RpcLog, Receipt as RpcReceipt, RpcBlock,
 };
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::{
     request, response, Block as ProtobufBlock, BlockTxHashes, BlockTxHashesReq, BlockWithProof,
-    ExecutedResult, Message, OperateType, ProofType, Request_oneof_req as Request, SyncRequest,
-    SyncResponse, TryFrom, TryInto,
+    ExecutedResult, Message, MsgClass, OperateType, ProofType, Request_oneof_req as Request,
+    SyncRequest, SyncResponse, TryFrom, TryInto,
 };
 use proof::BftProof;
 use pubsub::channel::Sender;
-use serde_json;
+use serde_json::{self, json, Value};
 
 use crate::types::block::OpenBlock;
 use crate::types::block_number::BlockTag;
+use crate::types::block_receipts::BlockReceipts;
 use crate::types::filter::Filter;
+use crate::types::state_sync::{ServeLimit, StateSyncMessage};
+
+const TRACE_TRANSACTION: &str = "traceTransaction";
+
+/// The trace calls of JSON-RPC, libproto has no message type for them.
+pub const TRACE_REQUEST: &str = "jsonrpc.trace_request";
+/// The trace calls forwarded to executor.
+const EXECUTOR_TRACE_REQUEST: &str = "chain.trace_request";
+/// The logs calls of JSON-RPC.
+pub const LOGS_REQUEST: &str = "jsonrpc.logs_request";
+/// The replies to the logs calls, and to the trace calls not forwarded.
+const RAW_RESPONSE: &str = "chain.raw_response";
+
+/// The receipts requests of the fast sync of peers.
+pub const RECEIPTS_REQUEST: &str = "network.receipts_request";
+/// The receipts replied to peers.
+const RECEIPTS_RESPONSE: &str = "chain.receipts_response";
+/// The receipts downloaded by the fast sync of executor.
+pub const SNAPSHOT_RECEIPTS: &str = "executor.snapshot_receipts";
 
 /// Message forwarding and query data
 #[derive(Clone)]
@@ -45,6 +68,8 @@ pub struct Forward {
     write_sender: Sender<ExecutedResult>,
     chain: Arc<Chain>,
     ctx_pub: Sender<(String, Vec<u8>)>,
+    // Bytes served to the fast sync of peers
+    serve_limit: Arc<Mutex<ServeLimit>>,
 }
 
 // TODO: Add future client to support forward
@@ -58,6 +83,7 @@ impl Forward {
             chain,
             ctx_pub,
             write_sender,
+            serve_limit: Arc::new(Mutex::new(ServeLimit::default())),
         }
     }
 
@@ -65,6 +91,32 @@ impl Forward {
     pub fn dispatch_msg(&self, key: &str, msg_bytes: &[u8]) {
         let mut msg = Message::try_from(msg_bytes).unwrap();
         let origin = msg.get_origin();
+        if key == TRACE_REQUEST {
+            if let Some(query) = msg.take_raw_bytes() {
+                self.forward_trace(&query);
+            }
+            return;
+        }
+        if key == LOGS_REQUEST {
+            if let Some(query) = msg.take_raw_bytes() {
+                self.reply_logs_query(&query);
+            }
+            return;
+        }
+        if key == RECEIPTS_REQUEST {
+            if let Some(request) = Self::take_state_sync_message(&mut msg) {
+                self.reply_receipts(request, origin);
+            }
+            return;
+        }
+        if key == SNAPSHOT_RECEIPTS {
+            if let Some(StateSyncMessage::Receipts(receipts)) =
+                Self::take_state_sync_message(&mut msg)
+            {
+                self.save_snapshot_receipts(receipts);
+            }
+            return;
+        }
         match RoutingKey::from(key) {
             routing_key!(Jsonrpc >> Request) => {
                 let req = msg.take_request().unwrap();
@@ -101,6 +153,11 @@ impl Forward {
                 self.deal_sync_blocks(sync_res);
             }
 
+            routing_key!(Executor >> SyncResponse) => {
+                let mut sync_res = msg.take_sync_response().unwrap();
+                self.snapshot_block_enqueue(sync_res.take_blocks().into_iter().next());
+            }
+
             routing_key!(Auth >> BlockTxHashesReq) => {
                 let block_tx_hashes_req = msg.take_block_tx_hashes_req().unwrap();
                 self.deal_block_tx_req(&block_tx_hashes_req);
@@ -112,6 +169,166 @@ impl Forward {
         }
     }
 
+    fn reply_logs_query(&self, query: &[u8]) {
+        let query: Value = match serde_json::from_slice(query) {
+            Ok(query) => query,
+            Err(e) => {
+                warn!("receive invalid logs query: {:?}", e);
+                return;
+            }
+        };
+        let params = query["params"].as_array().cloned().unwrap_or_default();
+        let reply = match self.logs_page(&params) {
+            Ok(result) => json!({"request_id": query["request_id"], "result": result}),
+            Err(error) => json!({"request_id": query["request_id"], "error": error}),
+        };
+        self.send_raw_reply(&reply);
+    }
+
+    fn send_raw_reply(&self, reply: &Value) {
+        let msg = Message::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(reply.to_string().into_bytes()),
+        );
+        self.ctx_pub
+            .send((RAW_RESPONSE.to_owned(), msg.try_into().unwrap()))
+            .unwrap();
+    }
+
+    fn take_state_sync_message(msg: &mut Message) -> Option<StateSyncMessage> {
+        let bytes = msg.take_raw_bytes()?;
+        StateSyncMessage::from_bytes(&bytes)
+            .map_err(|e| warn!("invalid state sync message: {}", e))
+            .ok()
+    }
+
+    // Serve the receipts to the fast sync of peers, in the limit of bytes.
+    fn reply_receipts(&self, request: StateSyncMessage, origin: u32) {
+        let hashes = match request {
+            StateSyncMessage::GetReceipts(hashes) => hashes,
+            _ => return,
+        };
+        let mut serve_limit = self.serve_limit.lock().unwrap();
+        if !serve_limit.allow(origin, Instant::now()) {
+            debug!(
+                "drop the receipts request of origin {} over the limit",
+                origin
+            );
+            return;
+        }
+        let bytes = StateSyncMessage::receipts_of(hashes, |hash| self.chain.block_receipts(hash))
+            .to_bytes();
+        serve_limit.add(origin, bytes.len());
+        let msg = Message::init(OperateType::Single, origin, MsgClass::RawBytes(bytes));
+        self.ctx_pub
+            .send((RECEIPTS_RESPONSE.to_owned(), msg.try_into().unwrap()))
+            .unwrap();
+    }
+
+    // The receipts of the latest blocks before the fast synced one, which
+    // executor sends before the block.
+    fn save_snapshot_receipts(&self, receipts: Vec<(H256, BlockReceipts)>) {
+        if self.chain.get_current_height() != 0 {
+            warn!("drop the fast synced receipts of a chain not new");
+            return;
+        }
+        for (hash, block_receipts) in receipts {
+            self.chain.set_block_receipts(hash, &block_receipts);
+        }
+    }
+
+    // Executor only keeps the headers, so `traceTransaction` is sent with
+    // the block of the transaction, and its receipt to check the trace
+    // against, `traceCall` as it is.
+    fn forward_trace(&self, query: &[u8]) {
+        let mut query: Value = match serde_json::from_slice(query) {
+            Ok(query) => query,
+            Err(e) => {
+                warn!("receive invalid trace query: {:?}", e);
+                return;
+            }
+        };
+        if query["method"] == TRACE_TRANSACTION {
+            match self.trace_block(&query["params"][0]) {
+                Ok((block, receipt)) => {
+                    query["block"] = block;
+                    query["receipt"] = receipt;
+                }
+                Err(error) => {
+                    self.send_raw_reply(
+                        &json!({"request_id": query["request_id"], "error": error}),
+                    );
+                    return;
+                }
+            }
+        }
+        let msg = Message::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(query.to_string().into_bytes()),
+        );
+        self.ctx_pub
+            .send((EXECUTOR_TRACE_REQUEST.to_owned(), msg.try_into().unwrap()))
+            .unwrap();
+    }
+
+    fn trace_block(&self, hash: &Value) -> Result<(Value, Value), String> {
+        let hash = hash
+            .as_str()
+            .ok_or_else(|| "missing transaction hash".to_owned())?;
+        let hash = H256::from_str(clean_0x(hash)).map_err(|_| format!("invalid hash {}", hash))?;
+        let block = self
+            .chain
+            .transaction_block(hash)
+            .ok_or_else(|| format!("transaction {:?} not found or its block is pruned", hash))?;
+        let receipt = self
+            .chain
+            .get_rich_receipt(hash)
+            .ok_or_else(|| format!("receipt of transaction {:?} not found", hash))?;
+        let bytes: Vec<u8> = block
+            .protobuf()
+            .try_into()
+            .map_err(|e| format!("encode block: {:?}", e))?;
+        let block = serde_json::to_value(Data::from(bytes)).map_err(|e| e.to_string())?;
+        let receipt = json!({
+            "quotaUsed": format!("{:#x}", receipt.quota_used),
+            "failed": receipt.error.is_some(),
+        });
+        Ok((block, receipt))
+    }
+
+    // The params are the filter, the cursor and the page size, the last two
+    // are optional.
+    fn logs_page(&self, params: &[Value]) -> Result<Value, String> {
+        let filter: RpcFilter =
+            serde_json::from_value(params.get(0).cloned().unwrap_or(Value::Null))
+                .map_err(|e| format!("invalid filter: {}", e))?;
+        let cursor = match params.get(1) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(s)) => Some(s.parse::<LogCursor>().map_err(|e| e.to_string())?),
+            Some(v) => return Err(format!("invalid cursor {}", v)),
+        };
+        let page_size = match params.get(2) {
+            None | Some(Value::Null) => None,
+            Some(v) => Some(
+                v.as_u64()
+                    .ok_or_else(|| format!("invalid page size {}", v))? as usize,
+            ),
+        };
+
+        let filter: Filter = filter.into();
+        let page = self
+            .chain
+            .get_logs_page(&filter, cursor, page_size)
+            .map_err(|e| e.to_string())?;
+        let logs: Vec<RpcLog> = page.logs.into_iter().map(Into::into).collect();
+        Ok(json!({
+            "logs": logs,
+            "next": page.next.map(|cursor| cursor.to_string()),
+        }))
+    }
+
     fn reply_request(&self, mut req: request::Request, imsg: Vec<u8>) {
         let mut response = response::Response::new();
         response.set_request_id(req.take_request_id());
@@ -216,9 +433,16 @@ impl Forward {
                     response.set_error_msg(format!("{:?}", err));
                 }) {
                     let filter: Filter = rpc_filter.into();
-                    let logs = self.chain.get_logs(&filter);
-                    let rpc_logs: Vec<RpcLog> = logs.into_iter().map(Into::into).collect();
-                    response.set_logs(serde_json::to_string(&rpc_logs).unwrap());
+                    match self.chain.get_logs(&filter) {
+                        Ok(logs) => {
+                            let rpc_logs: Vec<RpcLog> = logs.into_iter().map(Into::into).collect();
+                            response.set_logs(serde_json::to_string(&rpc_logs).unwrap());
+                        }
+                        Err(e) => {
+                            response.set_code(ErrorCode::query_error());
+                            response.set_error_msg(e.to_string());
+                        }
+                    }
                 };
             }
 
@@ -306,12 +530,17 @@ impl Forward {
 
             Request::filter_logs(filter_id) => {
                 trace!("filter_log's id is {:?}", filter_id);
-                let log = self
-                    .chain
-                    .get_filter_logs(filter_id as usize)
-                    .unwrap_or_default();
-                trace!("Log is: {:?}", log);
-                response.set_filter_logs(serde_json::to_string(&log).unwrap());
+                match self.chain.get_filter_logs(filter_id as usize) {
+                    Ok(log) => {
+                        let log = log.unwrap_or_default();
+                        trace!("Log is: {:?}", log);
+                        response.set_filter_logs(serde_json::to_string(&log).unwrap());
+                    }
+                    Err(e) => {
+                        response.set_code(ErrorCode::query_error());
+                        response.set_error_msg(e.to_string());
+                    }
+                }
             }
 
             Request::state_proof(state_info) => {
@@ -396,6 +625,25 @@ impl Forward {
         }
     }
 
+    // The block fast synced by executor, whose executed result follows.
+    fn snapshot_block_enqueue(&self, proto_block: Option<ProtobufBlock>) {
+        let block = match proto_block {
+            Some(block) => OpenBlock::from(block),
+            None => return,
+        };
+        if self.chain.get_current_height() != 0 {
+            warn!(
+                "chain is not empty, drop the snapshot block {}",
+                block.number()
+            );
+            return;
+        }
+        self.chain
+            .block_map
+            .write()
+            .insert(block.number(), BlockInQueue::SnapshotBlock(block));
+    }
+
     fn reply_syn_req(&self, sync_req: SyncRequest, origin: u32) {
         let mut sync_req = sync_req;
         let heights = sync_req.take_heights();
@@ -30,6 +30,7 @@
 //!     | chain   | Executor    | ExecutedResult   |
 //!     | chain   | Snapshot    | SnapshotReq      |
 //!     | chain   | Executor    | StateSignal      |
+//!     | chain   | Executor    | SyncResponse     |
 //!
 //! 2. Publish channel
 //!
@@ -46,6 +47,19 @@
 //!     | chain | Chain     | Consensus     | RichStatus    |
 //!     | chain | Chain     | Executor      | RichStatus    |
 //!
+//! 3. Trace and logs calls
+//!
+//!     libproto has no message type for them, they use plain keys: chain
+//!     subscribes `jsonrpc.trace_request` and forwards the calls to executor
+//!     on `chain.trace_request`, and subscribes `jsonrpc.logs_request` and
+//!     replies on `chain.raw_response`.
+//!
+//! 4. Fast sync
+//!
+//!     Plain keys too: chain serves the receipts requests of peers on
+//!     `network.receipts_request`, replying on `chain.receipts_response`, and
+//!     saves the receipts from `executor.snapshot_receipts`.
+//!
 //! ### Key behavior
 //!
 //! the key struct:
@@ -65,6 +79,8 @@
 
 extern crate common_types as types;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate libproto;
 #[macro_use]
 extern crate cita_logger as logger;
@@ -73,7 +89,9 @@ extern crate util;
 
 mod block_processor;
 mod forward;
+mod metrics;
 
+use std::path::Path;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -99,6 +117,8 @@ fn main() {
         .about("CITA Block Chain Node powered by Rust")
         .args_from_usage(
             "-c, --config=[FILE] 'Sets a chain config file'
+                          --import-archive=[FILE] 'Restore the pruned blocks of an archive file, then exit'
+                          --rebuild-log-index 'Rebuild the log index with the log_index config, then exit'
                           -s, --stdout 'Log to console'",
         )
         .get_matches();
@@ -109,30 +129,52 @@ fn main() {
 
     let config_path = matches.value_of("config").unwrap_or("chain.toml");
 
-    let (tx, rx) = channel::unbounded();
-    let (ctx_pub, crx_pub) = channel::unbounded();
-    start_pubsub(
-        "chain",
-        routing_key!([
-            Net >> SyncResponse,
-            Net >> SyncRequest,
-            Consensus >> BlockWithProof,
-            Jsonrpc >> Request,
-            Auth >> BlockTxHashesReq,
-            Executor >> ExecutedResult,
-            Executor >> StateSignal,
-            Snapshot >> SnapshotReq,
-        ]),
-        tx,
-        crx_pub,
-    );
-
     let nosql_path = DataPath::nosql_path();
     trace!("nosql_path is {:?}", nosql_path);
     let db_config = DatabaseConfig::with_category_num(NUM_COLUMNS);
     let db = RocksDB::open(&nosql_path, &db_config).expect("Open DB failed unexpected.");
 
+    if let Some(path) = matches.value_of("import-archive") {
+        match libchain::history::restore_archive(&db, Path::new(path)) {
+            Ok(count) => info!("restore {} blocks from archive {}", count, path),
+            Err(e) => error!("restore archive {} error: {:?}", path, e),
+        }
+        return;
+    }
+
+    if matches.is_present("rebuild-log-index") {
+        let chain_config = libchain::chain::Config::new(config_path);
+        let height = libchain::chain::get_chain(&db)
+            .map(|header| header.number())
+            .unwrap_or(0);
+        match libchain::log_index::rebuild(&db, &chain_config.log_index, height) {
+            Ok(count) => info!("rebuild the log index of {} blocks", count),
+            Err(e) => error!("rebuild the log index error: {:?}", e),
+        }
+        return;
+    }
+
+    let (tx, rx) = channel::unbounded();
+    let (ctx_pub, crx_pub) = channel::unbounded();
+    let mut keys = routing_key!([
+        Net >> SyncResponse,
+        Net >> SyncRequest,
+        Consensus >> BlockWithProof,
+        Jsonrpc >> Request,
+        Auth >> BlockTxHashesReq,
+        Executor >> ExecutedResult,
+        Executor >> StateSignal,
+        Executor >> SyncResponse,
+        Snapshot >> SnapshotReq,
+    ]);
+    keys.push(forward::TRACE_REQUEST.to_owned());
+    keys.push(forward::LOGS_REQUEST.to_owned());
+    keys.push(forward::RECEIPTS_REQUEST.to_owned());
+    keys.push(forward::SNAPSHOT_RECEIPTS.to_owned());
+    start_pubsub("chain", keys, tx, crx_pub);
+
     let chain_config = libchain::chain::Config::new(config_path);
+    cita_metrics::start_server(chain_config.metrics);
     let chain = Arc::new(libchain::chain::Chain::init_chain(
         Arc::new(db),
         chain_config,
@@ -0,0 +1,24 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cita_metrics::{histogram, int_gauge, Histogram, IntGauge};
+
+lazy_static! {
+    pub static ref CHAIN_HEIGHT: IntGauge =
+        int_gauge("chain_height", "Height of the latest stored block");
+    pub static ref STORE_RESULT_SECONDS: Histogram = histogram(
+        "chain_store_result_seconds",
+        "Time of storing an executed result"
+    );
+}
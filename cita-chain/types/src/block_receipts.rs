@@ -14,9 +14,11 @@
 
 // FixMe: Rewrite
 use crate::receipt::Receipt;
+use cita_types::H256;
+use hashable::Hashable;
 use rlp::{Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockReceipts {
     pub receipts: Vec<Receipt>,
 }
@@ -25,6 +27,20 @@ impl BlockReceipts {
     pub fn new(receipts: Vec<Receipt>) -> Self {
         BlockReceipts { receipts }
     }
+
+    /// The receipts root in the header of the block.
+    pub fn receipts_root(&self) -> H256 {
+        cita_merklehash::Tree::from_hashes(
+            self.receipts
+                .iter()
+                .map(|r| r.rlp_bytes().into_vec().crypt_hash())
+                .collect::<Vec<_>>(),
+            cita_merklehash::merge,
+        )
+        .get_root_hash()
+        .cloned()
+        .unwrap_or(cita_merklehash::HASH_NULL)
+    }
 }
 
 impl Decodable for BlockReceipts {
@@ -44,6 +60,7 @@ impl Encodable for BlockReceipts {
 #[cfg(test)]
 mod tests {
     use super::BlockReceipts;
+    use crate::receipt::Receipt;
     use rlp::*;
 
     #[test]
@@ -57,4 +74,12 @@ mod tests {
         assert!(s.is_finished(), "List should be finished now");
         s.out();
     }
+
+    #[test]
+    fn test_receipts_root() {
+        let empty = BlockReceipts::new(Vec::new());
+        assert_eq!(empty.receipts_root(), cita_merklehash::HASH_NULL);
+        let receipts = BlockReceipts::new(vec![Receipt::default()]);
+        assert_ne!(receipts.receipts_root(), cita_merklehash::HASH_NULL);
+    }
 }
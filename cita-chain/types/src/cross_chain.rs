@@ -0,0 +1,184 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Versioned cross-chain messages.
+//!
+//! A contract sends a message by the log of the event, as
+//! `CrossChainMessenger.sol` does,
+//!
+//! ```solidity
+//! event CrossChainMessage(
+//!     uint8 version,
+//!     uint256 fromChainId,
+//!     uint256 toChainId,
+//!     address destContract,
+//!     uint64 nonce,
+//!     uint64 timeoutHeight,
+//!     uint8 kind,
+//!     bytes payload
+//! );
+//! ```
+//!
+//! A transaction may send several messages, to any chains. The nonce counts
+//! the messages from the sending contract to the destination contract, so
+//! each pair of contracts is an ordered channel.
+
+use super::Bytes;
+use crate::block_number::BlockNumber;
+use crate::log::Log;
+use cita_types::{Address, H256, U256};
+use util::sha3;
+
+pub const CROSS_CHAIN_MESSAGE_VERSION: u8 = 1;
+pub const CROSS_CHAIN_MESSAGE_EVENT: &str =
+    "CrossChainMessage(uint8,uint256,uint256,address,uint64,uint64,uint8,bytes)";
+/// The words before the length of the payload.
+const HEAD_WORDS: usize = 8;
+
+lazy_static! {
+    pub static ref CROSS_CHAIN_MESSAGE_TOPIC: H256 =
+        H256::from_slice(&sha3::keccak256(CROSS_CHAIN_MESSAGE_EVENT.as_bytes()));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    Data = 0,
+    /// The answer to a message, or to its timeout.
+    Ack = 1,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossChainMessage {
+    pub version: u8,
+    /// The contract sending the message.
+    pub sender: Address,
+    pub from_chain_id: U256,
+    pub to_chain_id: U256,
+    pub dest_contract: Address,
+    pub nonce: u64,
+    /// The message is timed out from this height of the destination chain
+    /// on, 0 for never.
+    pub timeout_height: u64,
+    pub kind: MessageKind,
+    pub payload: Bytes,
+}
+
+fn word(data: &[u8], index: usize) -> U256 {
+    U256::from(&data[index * 32..(index + 1) * 32])
+}
+
+impl CrossChainMessage {
+    /// Decode the message from the log, `None` if it is not a message of a
+    /// supported version.
+    pub fn from_log(log: &Log) -> Option<Self> {
+        if log.topics.first() != Some(&*CROSS_CHAIN_MESSAGE_TOPIC) {
+            return None;
+        }
+        let data = &log.data;
+        if data.len() < (HEAD_WORDS + 1) * 32 {
+            return None;
+        }
+        if word(data, 0) != U256::from(CROSS_CHAIN_MESSAGE_VERSION) {
+            return None;
+        }
+        let kind = match word(data, 6).low_u64() {
+            0 => MessageKind::Data,
+            1 => MessageKind::Ack,
+            _ => return None,
+        };
+        if word(data, 7) != U256::from(HEAD_WORDS * 32) {
+            return None;
+        }
+        let start = (HEAD_WORDS + 1) * 32;
+        let len = word(data, HEAD_WORDS);
+        if len > U256::from(data.len() - start) {
+            return None;
+        }
+        Some(CrossChainMessage {
+            version: CROSS_CHAIN_MESSAGE_VERSION,
+            sender: log.address,
+            from_chain_id: word(data, 1),
+            to_chain_id: word(data, 2),
+            dest_contract: Address::from(H256::from(word(data, 3))),
+            nonce: word(data, 4).low_u64(),
+            timeout_height: word(data, 5).low_u64(),
+            kind,
+            payload: data[start..start + len.low_u64() as usize].to_vec(),
+        })
+    }
+
+    /// Encode the message to the log the sender emits.
+    pub fn to_log(&self) -> Log {
+        let mut data = Vec::with_capacity((HEAD_WORDS + 2) * 32 + self.payload.len());
+        let words = [
+            U256::from(self.version),
+            self.from_chain_id,
+            self.to_chain_id,
+            U256::from(H256::from(self.dest_contract)),
+            U256::from(self.nonce),
+            U256::from(self.timeout_height),
+            U256::from(self.kind as u8),
+            U256::from(HEAD_WORDS * 32),
+            U256::from(self.payload.len()),
+        ];
+        for w in words.iter() {
+            data.extend_from_slice(&H256::from(*w));
+        }
+        data.extend_from_slice(&self.payload);
+        let padding = (32 - self.payload.len() % 32) % 32;
+        data.extend(vec![0u8; padding]);
+        Log {
+            address: self.sender,
+            topics: vec![*CROSS_CHAIN_MESSAGE_TOPIC],
+            data,
+        }
+    }
+
+    /// Whether the message is timed out at the height of the destination
+    /// chain.
+    pub fn is_timed_out(&self, height: BlockNumber) -> bool {
+        self.timeout_height != 0 && height >= self.timeout_height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CrossChainMessage, MessageKind, CROSS_CHAIN_MESSAGE_VERSION};
+    use cita_types::{Address, H256, U256};
+
+    #[test]
+    fn test_message_log() {
+        let message = CrossChainMessage {
+            version: CROSS_CHAIN_MESSAGE_VERSION,
+            sender: Address::from(0x11),
+            from_chain_id: U256::from(3),
+            to_chain_id: U256::from(5),
+            dest_contract: Address::from(0x22),
+            nonce: 7,
+            timeout_height: 100,
+            kind: MessageKind::Data,
+            payload: vec![1, 2, 3],
+        };
+        let mut log = message.to_log();
+        assert_eq!(log.data.len(), 10 * 32);
+        assert_eq!(CrossChainMessage::from_log(&log), Some(message.clone()));
+        assert!(!message.is_timed_out(99));
+        assert!(message.is_timed_out(100));
+
+        log.data.truncate(9 * 32 + 2);
+        assert_eq!(CrossChainMessage::from_log(&log), None);
+        log.topics[0] = H256::from(1);
+        assert_eq!(CrossChainMessage::from_log(&log), None);
+    }
+}
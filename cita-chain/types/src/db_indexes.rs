@@ -14,7 +14,7 @@
 
 use crate::block_number::BlockNumber;
 use bloomchain::group::GroupPosition;
-use cita_types::{H256, H264};
+use cita_types::{Address, H256, H264};
 
 const TRANSACTION_INDEX: u8 = 0;
 const BLOCKRECEIPTS_INDEX: u8 = 1;
@@ -22,6 +22,11 @@ const BLOCKSBLOOMS_INDEX: u8 = 2;
 const BLOCKHASH_INDEX: u8 = 3;
 const BLOCKHEADHASH_INDEX: u8 = 4;
 const BLOCKBODYHASH_INDEX: u8 = 5;
+const STATEJOURNAL_INDEX: u8 = 6;
+const STATEREFCOUNT_INDEX: u8 = 7;
+const ADDRESSTOPIC_INDEX: u8 = 8;
+const ADDRESSTOPICCHUNKS_INDEX: u8 = 9;
+const INSTALLEDFILTER_INDEX: u8 = 10;
 
 pub trait DBIndex {
     fn get_index(&self) -> Vec<u8>;
@@ -51,6 +56,71 @@ impl DBIndex for CurrentHeight {
     }
 }
 
+pub struct StateJournalType;
+
+impl DBIndex for StateJournalType {
+    fn get_index(&self) -> Vec<u8> {
+        H256::from("7cabfb7709b29c16d9e876e876c9988d03f9c3414e1d3ff77ec1de2d0ee59f69").to_vec()
+    }
+}
+
+/// The next block number whose state journal should be pruned.
+pub struct StatePrunedHeight;
+
+impl DBIndex for StatePrunedHeight {
+    fn get_index(&self) -> Vec<u8> {
+        H256::from("7cabfb7709b29c16d9e876e876c9988d03f9c3414e1d3ff77ec1de2d0ee59f6a").to_vec()
+    }
+}
+
+/// The next block number whose body and receipts should be pruned.
+pub struct BodyPrunedHeight;
+
+impl DBIndex for BodyPrunedHeight {
+    fn get_index(&self) -> Vec<u8> {
+        H256::from("7cabfb7709b29c16d9e876e876c9988d03f9c3414e1d3ff77ec1de2d0ee59f6b").to_vec()
+    }
+}
+
+/// The levels and the elements per index of the saved log blooms.
+pub struct LogBloomsParams;
+
+impl DBIndex for LogBloomsParams {
+    fn get_index(&self) -> Vec<u8> {
+        H256::from("7cabfb7709b29c16d9e876e876c9988d03f9c3414e1d3ff77ec1de2d0ee59f6c").to_vec()
+    }
+}
+
+/// The first block number indexed by the address and topic of its logs.
+pub struct AddressTopicIndexedHeight;
+
+impl DBIndex for AddressTopicIndexedHeight {
+    fn get_index(&self) -> Vec<u8> {
+        H256::from("7cabfb7709b29c16d9e876e876c9988d03f9c3414e1d3ff77ec1de2d0ee59f6d").to_vec()
+    }
+}
+
+/// The ids of the filters installed by `newFilter` and `newBlockFilter`.
+pub struct InstalledFilters;
+
+impl DBIndex for InstalledFilters {
+    fn get_index(&self) -> Vec<u8> {
+        H256::from("7cabfb7709b29c16d9e876e876c9988d03f9c3414e1d3ff77ec1de2d0ee59f6e").to_vec()
+    }
+}
+
+/// A filter installed by `newFilter` or `newBlockFilter`, by its id.
+pub struct InstalledFilter(pub u64);
+
+impl DBIndex for InstalledFilter {
+    fn get_index(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(9);
+        result.push(INSTALLEDFILTER_INDEX);
+        result.extend_from_slice(&self.0.to_be_bytes());
+        result
+    }
+}
+
 pub struct Hash2Header(pub H256);
 
 impl DBIndex for Hash2Header {
@@ -125,6 +195,24 @@ impl DBIndex for BlockNumber2Hash {
     }
 }
 
+pub struct BlockNumber2StateJournal(pub BlockNumber);
+
+impl DBIndex for BlockNumber2StateJournal {
+    fn get_index(&self) -> Vec<u8> {
+        let mut result = [0u8; 9];
+        result[0] = STATEJOURNAL_INDEX as u8;
+        result[1] = (self.0 >> 56) as u8;
+        result[2] = (self.0 >> 48) as u8;
+        result[3] = (self.0 >> 40) as u8;
+        result[4] = (self.0 >> 32) as u8;
+        result[5] = (self.0 >> 24) as u8;
+        result[6] = (self.0 >> 16) as u8;
+        result[7] = (self.0 >> 8) as u8;
+        result[8] = self.0 as u8;
+        result.to_vec()
+    }
+}
+
 pub struct Hash2TransactionIndex(pub H256);
 
 impl DBIndex for Hash2TransactionIndex {
@@ -147,6 +235,47 @@ impl DBIndex for Hash2BlockReceipts {
     }
 }
 
+pub struct Hash2StateRefCount(pub H256);
+
+impl DBIndex for Hash2StateRefCount {
+    fn get_index(&self) -> Vec<u8> {
+        let mut result = H264::default();
+        result[0] = STATEREFCOUNT_INDEX as u8;
+        (*result)[1..].clone_from_slice(&self.0);
+        result.to_vec()
+    }
+}
+
+/// The block numbers having logs of an address and a first topic, in a
+/// chunk of a bucket of block numbers.
+pub struct AddressTopic2Blocks(pub Address, pub H256, pub u64, pub u64);
+
+impl DBIndex for AddressTopic2Blocks {
+    fn get_index(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(69);
+        result.push(ADDRESSTOPIC_INDEX);
+        result.extend_from_slice(&self.0);
+        result.extend_from_slice(&self.1);
+        result.extend_from_slice(&self.2.to_be_bytes());
+        result.extend_from_slice(&self.3.to_be_bytes());
+        result
+    }
+}
+
+/// The number of chunks of `AddressTopic2Blocks` in a bucket.
+pub struct AddressTopicChunks(pub Address, pub H256, pub u64);
+
+impl DBIndex for AddressTopicChunks {
+    fn get_index(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(61);
+        result.push(ADDRESSTOPICCHUNKS_INDEX);
+        result.extend_from_slice(&self.0);
+        result.extend_from_slice(&self.1);
+        result.extend_from_slice(&self.2.to_be_bytes());
+        result
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct LogGroupPosition(GroupPosition);
 
@@ -179,6 +179,24 @@ impl Filter {
         self.addresses.matches(log) && self.topics.matches(log)
     }
 
+    /// The pairs of address and first topic, one of which a matched log must
+    /// have, or `None` if the filter does not limit both of them.
+    pub fn address_topic_pairs(&self) -> Option<Vec<(Address, H256)>> {
+        match (&self.addresses.addresses, self.topics.topics.get(0)) {
+            (Some(addresses), Some(Some(topics)))
+                if !addresses.is_empty() && !topics.is_empty() =>
+            {
+                Some(
+                    addresses
+                        .iter()
+                        .flat_map(|address| topics.iter().map(move |topic| (*address, *topic)))
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+
     // For test
     #[cfg(test)]
     pub fn new_with_address_and_topic(addresses: AddressFilter, topics: TopicFilter) -> Self {
@@ -378,4 +396,34 @@ mod tests {
         assert_eq!(filter.addresses.matches(&entry1), true);
         assert_eq!(filter.addresses.matches(&entry2), false);
     }
+
+    #[test]
+    fn test_address_topic_pairs() {
+        let addresses = Some(vec![Address::from(1), Address::from(2)]);
+        let topics = vec![
+            Some(vec![H256::from(3)]),
+            Some(vec![H256::from(4)]),
+            None,
+            None,
+        ];
+        let filter = Filter::new_with_address_and_topic(
+            AddressFilter::new(addresses.clone()),
+            TopicFilter::new(topics),
+        );
+        assert_eq!(
+            filter.address_topic_pairs(),
+            Some(vec![
+                (Address::from(1), H256::from(3)),
+                (Address::from(2), H256::from(3)),
+            ])
+        );
+
+        // Any first topic
+        let topics = vec![None, Some(vec![H256::from(4)]), None, None];
+        let filter = Filter::new_with_address_and_topic(
+            AddressFilter::new(addresses),
+            TopicFilter::new(topics),
+        );
+        assert_eq!(filter.address_topic_pairs(), None);
+    }
 }
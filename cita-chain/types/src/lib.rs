@@ -28,14 +28,18 @@ pub mod block;
 pub mod block_number;
 pub mod block_receipts;
 pub mod context;
+pub mod cross_chain;
 pub mod db_indexes;
 pub mod errors;
 pub mod filter;
 pub mod header;
 pub mod log;
 pub mod log_blooms;
+pub mod node_manager;
 pub mod receipt;
 pub mod reserved_addresses;
 pub mod state_proof;
+pub mod state_sync;
 pub mod transaction;
 pub mod transaction_index;
+pub mod tx_proof;
@@ -0,0 +1,138 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validators proved by the storage of the `NodeManager` contract.
+//!
+//! The validators are the consensus nodes returned by `listNode`, which is
+//! the array `nodes` at slot 39 of the storage. The slots before it are the
+//! 13 addresses and the 24 built-in permissions of `ReservedAddrPublic`,
+//! then `status` and `block_op`.
+
+use crate::reserved_addresses;
+use crate::state_proof::{StateProof, StateProofError};
+use cita_types::{Address, H256, U256};
+use hasher::{Hasher, HasherKeccak};
+use std::str::FromStr;
+
+const NODES_SLOT: u64 = 39;
+
+pub fn contract_address() -> Address {
+    Address::from_str(reserved_addresses::NODE_MANAGER).unwrap()
+}
+
+/// The key of the number of the nodes.
+pub fn nodes_key() -> H256 {
+    H256::from(NODES_SLOT)
+}
+
+/// The key of the node at `index`, hashed by keccak as the EVM does.
+pub fn node_key(index: u64) -> H256 {
+    let start = H256::from_slice(&HasherKeccak::new().digest(&nodes_key()));
+    H256::from(U256::from(start).overflowing_add(U256::from(index)).0)
+}
+
+/// The keys to prove `count` validators, in the order of `verify_validators`.
+pub fn validators_keys(count: u64) -> Vec<H256> {
+    let mut keys = vec![nodes_key()];
+    keys.extend((0..count).map(node_key));
+    keys
+}
+
+fn proved_value(
+    proof: Option<&StateProof>,
+    state_root: H256,
+    key: H256,
+) -> Result<H256, StateProofError> {
+    match proof {
+        Some(proof) if *proof.address() == contract_address() && *proof.key() == key => {
+            proof.verify_value(state_root)
+        }
+        _ => Err(StateProofError::MissingProof(key)),
+    }
+}
+
+/// Verify the proofs of the keys from `validators_keys` against a state
+/// root, and return the validators as `listNode` does.
+pub fn verify_validators(
+    state_root: H256,
+    proofs: &[StateProof],
+) -> Result<Vec<Address>, StateProofError> {
+    let count = U256::from(proved_value(proofs.first(), state_root, nodes_key())?);
+    (0..count.low_u64())
+        .map(|index| {
+            proved_value(proofs.get(index as usize + 1), state_root, node_key(index))
+                .map(Address::from)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contract_address, node_key, validators_keys, verify_validators};
+    use crate::state_proof::{StateProof, StateProofError};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::state::{MemoryDB, State, StateObjectInfo};
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_node_key() {
+        // keccak256(uint256(39))
+        let start =
+            H256::from_str("98a476f1687bc3d60a2da2adbcba2c46958e61fa2fb4042cd7bc5816a710195b")
+                .unwrap();
+        assert_eq!(node_key(0), start);
+        assert_eq!(
+            U256::from(node_key(1)),
+            U256::from(start).overflowing_add(U256::from(1)).0
+        );
+        assert_eq!(
+            validators_keys(2),
+            vec![H256::from(39), node_key(0), node_key(1)]
+        );
+    }
+
+    #[test]
+    fn test_verify_validators() {
+        let validators = vec![Address::from(1), Address::from(2), Address::from(3)];
+        let address = contract_address();
+        let mut state = State::new(Arc::new(MemoryDB::new(false))).unwrap();
+        state.new_contract(&address, U256::from(0), U256::from(1), vec![1]);
+        let keys = validators_keys(validators.len() as u64);
+        state
+            .set_storage(&address, keys[0], H256::from(validators.len() as u64))
+            .unwrap();
+        for (key, validator) in keys[1..].iter().zip(validators.iter()) {
+            state
+                .set_storage(&address, *key, H256::from(*validator))
+                .unwrap();
+        }
+        state.commit().unwrap();
+        let root = state.root;
+
+        let proofs: Vec<StateProof> = keys
+            .iter()
+            .map(|key| StateProof::prove(&state, &address, key).unwrap())
+            .collect();
+        assert_eq!(verify_validators(root, &proofs), Ok(validators));
+
+        // A validator is not proved.
+        match verify_validators(root, &proofs[..3]) {
+            Err(StateProofError::MissingProof(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // Another state.
+        assert!(verify_validators(H256::from(1), &proofs).is_err());
+    }
+}
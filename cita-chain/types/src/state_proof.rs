@@ -1,15 +1,78 @@
+//! Proofs of the accounts and the storage values in a state.
+//!
+//! A state proof has the trie nodes from the state root to an account, and
+//! from the storage root of the account to a storage key. It is verified
+//! against a state root alone, so a light client can check the response of
+//! `getStateProof` with the state root of a trusted header.
+
 use super::Bytes;
-use cita_types::{Address, H256};
+use cita_trie::{MemoryDB, PatriciaTrie, Trie, DB};
+use cita_types::{Address, H256, U256};
+use cita_vm::state::{Error as StateError, State};
+use hashable::HASH_NULL_RLP;
+use hasher::HasherKeccak;
 use rlp::{self, Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateProofError {
+    /// The nodes do not lead from the root to the key.
+    InvalidProof(String),
+    InvalidAccount(DecoderError),
+    InvalidValue(DecoderError),
+    /// No proof of the key is given.
+    MissingProof(H256),
+}
+
+/// The account proved by a state proof.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvedAccount {
+    pub nonce: U256,
+    pub balance: U256,
+    pub storage_root: H256,
+    pub code_hash: H256,
+    pub abi_hash: H256,
+}
+
+impl Decodable for ProvedAccount {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 5 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        Ok(ProvedAccount {
+            nonce: rlp.val_at(0)?,
+            balance: rlp.val_at(1)?,
+            storage_root: rlp.val_at(2)?,
+            code_hash: rlp.val_at(3)?,
+            abi_hash: rlp.val_at(4)?,
+        })
+    }
+}
 
-#[derive(Default, Debug, Clone)]
+// The value of the key in the trie of the root, `None` if it is not in the trie.
+fn verify_trie_proof(
+    root: &H256,
+    key: &[u8],
+    proof: &[Bytes],
+) -> Result<Option<Bytes>, StateProofError> {
+    if *root == HASH_NULL_RLP {
+        return Ok(None);
+    }
+    let trie = PatriciaTrie::new(
+        Arc::new(MemoryDB::new(false)),
+        Arc::new(HasherKeccak::new()),
+    );
+    trie.verify_proof(&root.0, key, proof.to_vec())
+        .map_err(|e| StateProofError::InvalidProof(format!("{:?}", e)))
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct StateProof {
     address: Address,
     account_proof: Vec<Bytes>,
     key: H256,
     value_proof: Vec<Bytes>,
 }
-
 impl Encodable for StateProof {
     fn rlp_append(&self, s: &mut RlpStream) {
         s.begin_list(4);
@@ -32,27 +95,84 @@ impl Decodable for StateProof {
 }
 
 impl StateProof {
+    pub fn new(
+        address: Address,
+        account_proof: Vec<Bytes>,
+        key: H256,
+        value_proof: Vec<Bytes>,
+    ) -> Self {
+        StateProof {
+            address,
+            account_proof,
+            key,
+            value_proof,
+        }
+    }
+
+    /// Prove the account and the storage value of the key in the state.
+    pub fn prove<B: DB>(
+        state: &State<B>,
+        address: &Address,
+        key: &H256,
+    ) -> Result<Self, StateError> {
+        Ok(StateProof {
+            address: *address,
+            account_proof: state.get_account_proof(address)?,
+            key: *key,
+            value_proof: state.get_storage_proof(address, key)?,
+        })
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Self {
         rlp::decode(bytes)
     }
 
-    // FixMe: The implementation should be finished while fixing cross chain
-    pub fn verify(&self, _state_root: H256) -> Option<H256> {
-        // let state = State();
-        // state.verify_proof();
+    pub fn to_bytes(&self) -> Bytes {
+        rlp::encode(self).into_vec()
+    }
 
-        // let trie = PatriciaTrie::new(db, codec);
-        // trie.verify_proof();
-        // trie.verify_proof();
+    /// Decode the bytes of a `getStateProof` response.
+    pub fn decode_bytes(bytes: &[u8]) -> Result<Self, DecoderError> {
+        UntrustedRlp::new(bytes).as_val()
+    }
 
-        // trie::triedb::verify_value_proof(
-        //     &self.address,
-        //     state_root,
-        //     &self.account_proof,
-        //     Account::from_rlp,
-        // )
-        // .and_then(|a| a.verify_value_proof(&self.key, &self.value_proof))
-        None
+    /// Verify the account proof against the state root, returns the account,
+    /// or `None` if the account is not in the state.
+    pub fn verify_account(
+        &self,
+        state_root: H256,
+    ) -> Result<Option<ProvedAccount>, StateProofError> {
+        verify_trie_proof(&state_root, &self.address, &self.account_proof)?
+            .map(|account| {
+                UntrustedRlp::new(&account)
+                    .as_val()
+                    .map_err(StateProofError::InvalidAccount)
+            })
+            .transpose()
+    }
+
+    /// Verify both proofs against the state root, returns the storage value
+    /// of the key, which is zero if the account or the key is not in the
+    /// state.
+    pub fn verify_value(&self, state_root: H256) -> Result<H256, StateProofError> {
+        let storage_root = match self.verify_account(state_root)? {
+            Some(account) => account.storage_root,
+            None => return Ok(H256::zero()),
+        };
+        match verify_trie_proof(&storage_root, &self.key, &self.value_proof)? {
+            Some(value) => {
+                let value: U256 = UntrustedRlp::new(&value)
+                    .as_val()
+                    .map_err(StateProofError::InvalidValue)?;
+                Ok(H256::from(value))
+            }
+            None => Ok(H256::zero()),
+        }
+    }
+
+    /// The storage value of the key, or `None` if the proof is invalid.
+    pub fn verify(&self, state_root: H256) -> Option<H256> {
+        self.verify_value(state_root).ok()
     }
 
     pub fn address(&self) -> &Address {
@@ -67,6 +187,10 @@ impl StateProof {
         &self.key
     }
 
+    pub fn value_proof(&self) -> &Vec<Bytes> {
+        &self.value_proof
+    }
+
     #[cfg(test)]
     pub fn set_address(&mut self, new_address: Address) {
         self.address = new_address;
@@ -75,8 +199,11 @@ impl StateProof {
 
 #[cfg(test)]
 mod test {
-    use super::StateProof;
+    use super::{StateProof, StateProofError};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::state::{MemoryDB, State, StateObjectInfo};
     use rlp;
+    use std::sync::Arc;
 
     #[test]
     fn test_encode_and_decode_state_proof() {
@@ -87,4 +214,48 @@ mod test {
         let encoded_rlp = rlp::encode(&decoded_res).into_vec();
         assert_eq!(proof_rlp, encoded_rlp);
     }
+
+    #[test]
+    fn test_verify_state_proof() {
+        let mut state = State::new(Arc::new(MemoryDB::new(false))).unwrap();
+        for i in 1..50u64 {
+            let address = Address::from(i);
+            state.new_contract(&address, U256::from(i), U256::from(1), vec![i as u8; 10]);
+            state
+                .set_storage(&address, H256::from(1), H256::from(i))
+                .unwrap();
+        }
+        state.commit().unwrap();
+        let root = state.root;
+
+        let address = Address::from(42);
+        let proof = StateProof::prove(&state, &address, &H256::from(1)).unwrap();
+        let account = proof.verify_account(root).unwrap().unwrap();
+        assert_eq!(account.balance, U256::from(42));
+        assert_eq!(proof.verify(root), Some(H256::from(42)));
+
+        // The proof is sent as RLP.
+        let bytes = proof.to_bytes();
+        let decoded = StateProof::decode_bytes(&bytes).unwrap();
+        assert_eq!(decoded.verify(root), Some(H256::from(42)));
+
+        // A key not in the storage.
+        let proof = StateProof::prove(&state, &address, &H256::from(2)).unwrap();
+        assert_eq!(proof.verify(root), Some(H256::zero()));
+
+        // Another root or another address.
+        let proof = StateProof::prove(&state, &address, &H256::from(1)).unwrap();
+        assert!(proof.verify(H256::from(1)).is_none());
+        let mut other = proof.clone();
+        other.set_address(Address::from(43));
+        assert_ne!(other.verify(root), Some(H256::from(42)));
+
+        // A broken node.
+        let mut broken = proof;
+        broken.value_proof[0][1] ^= 1;
+        match broken.verify_value(root) {
+            Err(StateProofError::InvalidProof(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
 }
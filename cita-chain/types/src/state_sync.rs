@@ -0,0 +1,318 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Messages of the fast sync between peers.
+//!
+//! The executor of a new node asks the headers, the validators and the
+//! state nodes to the executors of its peers, and the receipts to their
+//! chains.
+
+use crate::block_receipts::BlockReceipts;
+use crate::header::Header;
+use crate::state_proof::StateProof;
+use crate::Bytes;
+use cita_types::H256;
+use rlp::{Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Headers asked by one request.
+pub const MAX_HEADERS_PER_REQUEST: u64 = 256;
+/// Blocks whose receipts are asked by one request.
+pub const MAX_RECEIPTS_PER_REQUEST: usize = 64;
+/// Bytes of an answer.
+pub const MAX_RESPONSE_BYTES: usize = 2 * 1024 * 1024;
+/// Headers saved with the target: the 255 hashes before it for executing
+/// the next block, and the target.
+pub const SNAPSHOT_HEADERS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateSyncMessage {
+    /// Ask the height of the latest block.
+    GetHeight,
+    Height(u64),
+    /// Ask the headers from a height, in ascending order.
+    GetHeaders(u64, u64),
+    Headers(Vec<Header>),
+    /// Ask the proofs of the validators in the state of a height.
+    GetValidators(u64),
+    Validators(u64, Vec<StateProof>),
+    GetNodes(Vec<H256>),
+    Nodes(Vec<Bytes>),
+    /// Ask the receipts of the blocks of the hashes.
+    GetReceipts(Vec<H256>),
+    /// The receipts found, with the hashes of their blocks.
+    Receipts(Vec<(H256, BlockReceipts)>),
+}
+
+impl StateSyncMessage {
+    pub fn to_bytes(&self) -> Bytes {
+        rlp::encode(self).into_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        UntrustedRlp::new(bytes)
+            .as_val()
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    /// Answer `GetReceipts` with the receipts found, in the limits of one
+    /// answer.
+    pub fn receipts_of<F>(hashes: Vec<H256>, find: F) -> Self
+    where
+        F: Fn(H256) -> Option<BlockReceipts>,
+    {
+        let mut receipts = Vec::new();
+        let mut size = 0;
+        for hash in hashes.into_iter().take(MAX_RECEIPTS_PER_REQUEST) {
+            if let Some(block_receipts) = find(hash) {
+                size += rlp::encode(&block_receipts).len();
+                if size > MAX_RESPONSE_BYTES && !receipts.is_empty() {
+                    break;
+                }
+                receipts.push((hash, block_receipts));
+            }
+        }
+        StateSyncMessage::Receipts(receipts)
+    }
+
+    fn item_count(&self) -> usize {
+        match self {
+            StateSyncMessage::GetHeight => 1,
+            StateSyncMessage::GetHeaders(_, _) | StateSyncMessage::Validators(_, _) => 3,
+            _ => 2,
+        }
+    }
+}
+
+impl Encodable for StateSyncMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match self {
+            StateSyncMessage::GetHeight => {
+                s.begin_list(1);
+                s.append(&0u8);
+            }
+            StateSyncMessage::Height(height) => {
+                s.begin_list(2);
+                s.append(&1u8);
+                s.append(height);
+            }
+            StateSyncMessage::GetHeaders(start, count) => {
+                s.begin_list(3);
+                s.append(&2u8);
+                s.append(start);
+                s.append(count);
+            }
+            StateSyncMessage::Headers(headers) => {
+                s.begin_list(2);
+                s.append(&3u8);
+                s.append_list(headers);
+            }
+            StateSyncMessage::GetValidators(height) => {
+                s.begin_list(2);
+                s.append(&4u8);
+                s.append(height);
+            }
+            StateSyncMessage::Validators(height, proofs) => {
+                s.begin_list(3);
+                s.append(&5u8);
+                s.append(height);
+                s.append_list(proofs);
+            }
+            StateSyncMessage::GetNodes(hashes) => {
+                s.begin_list(2);
+                s.append(&6u8);
+                s.append_list(hashes);
+            }
+            StateSyncMessage::Nodes(nodes) => {
+                s.begin_list(2);
+                s.append(&7u8);
+                s.begin_list(nodes.len());
+                for node in nodes {
+                    s.append(node);
+                }
+            }
+            StateSyncMessage::GetReceipts(hashes) => {
+                s.begin_list(2);
+                s.append(&8u8);
+                s.append_list(hashes);
+            }
+            StateSyncMessage::Receipts(receipts) => {
+                s.begin_list(2);
+                s.append(&9u8);
+                s.begin_list(receipts.len());
+                for (hash, block_receipts) in receipts {
+                    s.begin_list(2);
+                    s.append(hash);
+                    s.append(block_receipts);
+                }
+            }
+        }
+    }
+}
+
+impl Decodable for StateSyncMessage {
+    fn decode(r: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let message = match r.val_at::<u8>(0)? {
+            0 => StateSyncMessage::GetHeight,
+            1 => StateSyncMessage::Height(r.val_at(1)?),
+            2 => StateSyncMessage::GetHeaders(r.val_at(1)?, r.val_at(2)?),
+            3 => StateSyncMessage::Headers(r.list_at(1)?),
+            4 => StateSyncMessage::GetValidators(r.val_at(1)?),
+            5 => StateSyncMessage::Validators(r.val_at(1)?, r.list_at(2)?),
+            6 => StateSyncMessage::GetNodes(r.list_at(1)?),
+            7 => StateSyncMessage::Nodes(r.list_at(1)?),
+            8 => StateSyncMessage::GetReceipts(r.list_at(1)?),
+            9 => StateSyncMessage::Receipts(
+                r.at(1)?
+                    .iter()
+                    .map(|item| Ok((item.val_at(0)?, item.val_at(1)?)))
+                    .collect::<Result<_, DecoderError>>()?,
+            ),
+            _ => return Err(DecoderError::Custom("unknown state sync message")),
+        };
+        if r.item_count()? != message.item_count() {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        Ok(message)
+    }
+}
+
+/// Bytes served to one peer in a second.
+pub const MAX_SERVED_BYTES_PER_PEER: usize = 4 * 1024 * 1024;
+/// Bytes served to all the peers in a second.
+pub const MAX_SERVED_BYTES: usize = 16 * 1024 * 1024;
+
+const SERVE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Limit the bytes served to the fast sync of peers.
+///
+/// A request is dropped when its peer, or all the peers together, have been
+/// served their bytes in the current second, and the peer asks again after
+/// its request is timed out. So the peers can not keep the node busy.
+pub struct ServeLimit {
+    per_peer: usize,
+    total: usize,
+    window_start: Instant,
+    served: HashMap<u32, usize>,
+    served_total: usize,
+}
+
+impl ServeLimit {
+    pub fn new(per_peer: usize, total: usize, now: Instant) -> Self {
+        ServeLimit {
+            per_peer,
+            total,
+            window_start: now,
+            served: HashMap::new(),
+            served_total: 0,
+        }
+    }
+
+    /// Whether a request of the peer can be served now.
+    pub fn allow(&mut self, origin: u32, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= SERVE_WINDOW {
+            self.window_start = now;
+            self.served.clear();
+            self.served_total = 0;
+        }
+        let served = self.served.get(&origin).cloned().unwrap_or(0);
+        served < self.per_peer && self.served_total < self.total
+    }
+
+    /// Count the bytes of an answer to the peer.
+    pub fn add(&mut self, origin: u32, bytes: usize) {
+        *self.served.entry(origin).or_insert(0) += bytes;
+        self.served_total += bytes;
+    }
+}
+
+impl Default for ServeLimit {
+    fn default() -> Self {
+        ServeLimit::new(MAX_SERVED_BYTES_PER_PEER, MAX_SERVED_BYTES, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ServeLimit, StateSyncMessage, MAX_RECEIPTS_PER_REQUEST};
+    use crate::block_receipts::BlockReceipts;
+    use crate::header::Header;
+    use crate::receipt::Receipt;
+    use crate::state_proof::StateProof;
+    use cita_types::H256;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_message_rlp() {
+        let messages = vec![
+            StateSyncMessage::GetHeight,
+            StateSyncMessage::Height(1000),
+            StateSyncMessage::GetHeaders(1, 256),
+            StateSyncMessage::Headers(vec![Header::default()]),
+            StateSyncMessage::GetValidators(999),
+            StateSyncMessage::Validators(999, vec![StateProof::default()]),
+            StateSyncMessage::GetNodes(vec![H256::from(1), H256::from(2)]),
+            StateSyncMessage::Nodes(vec![vec![1, 2, 3], vec![]]),
+            StateSyncMessage::GetReceipts(vec![H256::from(3)]),
+            StateSyncMessage::Receipts(vec![(
+                H256::from(3),
+                BlockReceipts::new(vec![Receipt::default()]),
+            )]),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(StateSyncMessage::from_bytes(&bytes), Ok(message));
+        }
+        assert!(StateSyncMessage::from_bytes(&[0xc0]).is_err());
+        // GetHeight with a payload
+        assert!(StateSyncMessage::from_bytes(&[0xc2, 0x80, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_receipts_of() {
+        let hashes: Vec<H256> = (0..100).map(H256::from).collect();
+        let find = |hash: H256| {
+            if hash[31] % 2 == 0 {
+                Some(BlockReceipts::new(vec![Receipt::default()]))
+            } else {
+                None
+            }
+        };
+        match StateSyncMessage::receipts_of(hashes, find) {
+            StateSyncMessage::Receipts(receipts) => {
+                assert_eq!(receipts.len(), MAX_RECEIPTS_PER_REQUEST / 2);
+                assert_eq!(receipts[1].0, H256::from(2));
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_serve_limit() {
+        let now = Instant::now();
+        let mut limit = ServeLimit::new(100, 150, now);
+        assert!(limit.allow(1, now));
+        limit.add(1, 100);
+        assert!(!limit.allow(1, now));
+        assert!(limit.allow(2, now));
+        limit.add(2, 50);
+        // All the peers together
+        assert!(!limit.allow(3, now));
+
+        let later = now + Duration::from_secs(1);
+        assert!(limit.allow(1, later));
+        assert!(limit.allow(3, later));
+    }
+}
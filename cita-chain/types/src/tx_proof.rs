@@ -0,0 +1,498 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proof of a transaction and its receipt in a block, signed by the
+//! authorities through the proof of the next block.
+
+use crate::cross_chain::CrossChainMessage;
+use crate::header::Header;
+use crate::receipt::Receipt;
+use crate::transaction::SignedTransaction;
+use cita_types::{Address, H256, U256};
+use hashable::Hashable;
+use libproto::blockchain::Proof as ProtoProof;
+use proof::BftProof;
+use rlp::{self, Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
+
+#[derive(Debug, Clone)]
+pub struct RelayInfo {
+    pub from_chain_id: U256,
+    pub to_chain_id: U256,
+    pub dest_contract: Address,
+    pub dest_hasher: [u8; 4],
+    pub cross_chain_nonce: u64,
+}
+
+impl RelayInfo {
+    /// Decode the data of a `SendCrossChain` log.
+    pub fn from_log_data(data: &[u8]) -> Option<RelayInfo> {
+        // data must be:
+        // uint256 from_chain_id,
+        // uint256 to_chain_id,
+        // address dest_contract,
+        // bytes4 dest_hasher,
+        // uint256 cross_chain_nonce
+        if data.len() != 160 {
+            None
+        } else {
+            let mut iter = data.chunks(32);
+            let from_chain_id = U256::from(iter.next().unwrap());
+            let to_chain_id = U256::from(iter.next().unwrap());
+            let dest_contract = Address::from(H256::from(iter.next().unwrap()));
+            let dest_hasher = iter.next().unwrap()[..4].iter().take(4).enumerate().fold(
+                [0u8; 4],
+                |mut acc, (idx, val)| {
+                    acc[idx] = *val;
+                    acc
+                },
+            );
+            let cross_chain_nonce = U256::from(iter.next().unwrap()).low_u64();
+            Some(RelayInfo {
+                from_chain_id,
+                to_chain_id,
+                dest_contract,
+                dest_hasher,
+                cross_chain_nonce,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxProof {
+    tx: SignedTransaction,
+    receipt: Receipt,
+    receipt_proof: cita_merklehash::Proof,
+    block_header: Header,
+    next_proposal_header: Header,
+    proposal_proof: ProtoProof,
+}
+
+impl Encodable for TxProof {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(6);
+        s.append(&self.tx);
+        s.append(&self.receipt);
+        s.append(&self.receipt_proof);
+        s.append(&self.block_header);
+        s.append(&self.next_proposal_header);
+        s.append(&self.proposal_proof);
+    }
+}
+
+impl Decodable for TxProof {
+    fn decode(r: &UntrustedRlp) -> Result<Self, DecoderError> {
+        if r.item_count()? != 6 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let tx_proof = TxProof {
+            tx: r.val_at(0)?,
+            receipt: r.val_at(1)?,
+            receipt_proof: r.val_at(2)?,
+            block_header: r.val_at(3)?,
+            next_proposal_header: r.val_at(4)?,
+            proposal_proof: r.val_at(5)?,
+        };
+        Ok(tx_proof)
+    }
+}
+
+impl TxProof {
+    pub fn new(
+        tx: SignedTransaction,
+        receipt: Receipt,
+        receipt_proof: cita_merklehash::Proof,
+        block_header: Header,
+        next_proposal_header: Header,
+        proposal_proof: ProtoProof,
+    ) -> Self {
+        TxProof {
+            tx,
+            receipt,
+            receipt_proof,
+            block_header,
+            next_proposal_header,
+            proposal_proof,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        rlp::decode(bytes)
+    }
+
+    /// Decode the proof from untrusted bytes, without panic.
+    pub fn decode_bytes(bytes: &[u8]) -> Result<Self, DecoderError> {
+        UntrustedRlp::new(bytes).as_val()
+    }
+
+    pub fn tx(&self) -> &SignedTransaction {
+        &self.tx
+    }
+
+    pub fn receipt(&self) -> &Receipt {
+        &self.receipt
+    }
+
+    pub fn block_header(&self) -> &Header {
+        &self.block_header
+    }
+
+    pub fn next_proposal_header(&self) -> &Header {
+        &self.next_proposal_header
+    }
+
+    pub fn proposal_proof(&self) -> &ProtoProof {
+        &self.proposal_proof
+    }
+
+    pub fn verify(&self, authorities: &[Address]) -> bool {
+        // Calculate transaction hash, and it should be same as the transaction hash in receipt.
+        let tx_hash = self.tx.calc_transaction_hash();
+        if self.receipt.transaction_hash == tx_hash {
+        } else {
+            warn!("txproof verify transaction_hash failed");
+            return false;
+        };
+        // Use receipt_proof and receipt_root to prove the receipt in the block.
+        let receipt_merkle_proof: cita_merklehash::MerkleProof<H256> =
+            self.receipt_proof.clone().into();
+        if receipt_merkle_proof.verify(
+            self.block_header.receipts_root(),
+            self.receipt.clone().rlp_bytes().into_vec().crypt_hash(),
+            cita_merklehash::merge,
+        ) {
+        } else {
+            warn!("txproof verify receipt root merklehash failed");
+            return false;
+        };
+        // Calculate block header hash, and is should be same as the parent_hash in next header
+        if self.block_header.hash().unwrap() == *self.next_proposal_header.parent_hash() {
+        } else {
+            warn!("txproof verify block header hash failed");
+            return false;
+        };
+        let third_proof = BftProof::from(self.proposal_proof.clone());
+        // Verify next block header, use proof.proposal
+        if self.next_proposal_header.proposal_protobuf().crypt_hash() == third_proof.proposal {
+        } else {
+            warn!("txproof verify next block header failed");
+            return false;
+        };
+        // Verify signatures in proposal proof.
+        if third_proof.check(self.block_header.number() as usize + 1, authorities) {
+        } else {
+            warn!("txproof verify signatures for next block header failed");
+            return false;
+        };
+        true
+    }
+
+    // extract info which relayer needed
+    pub fn extract_relay_info(&self) -> Option<RelayInfo> {
+        self.receipt
+            .logs
+            .first()
+            .and_then(|log| RelayInfo::from_log_data(&log.data))
+    }
+
+    // verify proof
+    // check as crosschain protocol
+    // extract sender and tx data
+    pub fn extract_crosschain_data(
+        &self,
+        my_contrac_addr: Address,
+        my_hasher: [u8; 4],
+        my_cross_chain_nonce: u64,
+        my_chain_id: U256,
+        authorities: &[Address],
+    ) -> Option<(Address, Vec<u8>)> {
+        if self.verify(authorities) {
+            self.extract_relay_info().and_then(
+                |RelayInfo {
+                     to_chain_id,
+                     dest_contract,
+                     dest_hasher,
+                     cross_chain_nonce,
+                     ..
+                 }| {
+                    // from_chain_id: if we can got authorities, the from_chain_id must be right
+                    // cross chain only between main chain and one sidechain
+                    // check to_chain_id == my chain_id
+                    // check dest_contract == this
+                    // check hasher == RECV_FUNC_HASHER
+                    // check cross_chain_nonce == cross_chain_nonce
+                    // extract origin tx sender and origin tx data
+                    if to_chain_id == my_chain_id
+                        && dest_contract == my_contrac_addr
+                        && dest_hasher == my_hasher
+                        && cross_chain_nonce == my_cross_chain_nonce
+                    {
+                        // sendToSideChain(uint256 toChainId, address destContract, bytes txData)
+                        // skip func hasher, uint32, address, bytes position and length
+                        let (_, origin_tx_data) = self.tx.data.split_at(4 + 32 * 4);
+                        Some((*self.tx.sender(), origin_tx_data.to_owned()))
+                    } else {
+                        None
+                    }
+                },
+            )
+        } else {
+            None
+        }
+    }
+
+    /// The cross-chain messages sent by the transaction.
+    pub fn extract_messages(&self) -> Vec<CrossChainMessage> {
+        self.receipt
+            .logs
+            .iter()
+            .filter_map(CrossChainMessage::from_log)
+            .collect()
+    }
+
+    /// Verify the proof, and find the message to this chain of the channel
+    /// with the nonce.
+    pub fn extract_message(
+        &self,
+        authorities: &[Address],
+        from_chain_id: U256,
+        sender: Address,
+        my_chain_id: U256,
+        dest_contract: Address,
+        nonce: u64,
+    ) -> Option<CrossChainMessage> {
+        if !self.verify(authorities) {
+            return None;
+        }
+        self.extract_messages().into_iter().find(|message| {
+            message.from_chain_id == from_chain_id
+                && message.sender == sender
+                && message.to_chain_id == my_chain_id
+                && message.dest_contract == dest_contract
+                && message.nonce == nonce
+        })
+    }
+}
+
+#[cfg(all(test, feature = "secp256k1"))]
+mod tests {
+    use super::TxProof;
+    use crate::block_receipts::BlockReceipts;
+    use crate::cross_chain::{CrossChainMessage, MessageKind, CROSS_CHAIN_MESSAGE_VERSION};
+    use crate::crypto::{PrivKey, Sign, Signature, Signer};
+    use crate::header::Header;
+    use crate::log::Log;
+    use crate::receipt::Receipt;
+    use crate::transaction::SignedTransaction;
+    use cita_types::{Address, H256, U256};
+    use hashable::Hashable;
+    use libproto::blockchain::Proof as ProtoProof;
+    use proof::BftProof;
+    use rlp::{self, Encodable};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    const KEY: &str = "ef98e68db428906d626cd37782cdfb052ac282132beee53a99948738ea553b4a";
+
+    fn generate_proof(signer: &Signer, height: u64, proposal: H256) -> ProtoProof {
+        let serialized = bincode::serialize(
+            &(height as usize, 0usize, 0usize, signer.address, proposal),
+            bincode::Infinite,
+        )
+        .unwrap();
+        let signature =
+            Signature::sign(signer.keypair.privkey(), &serialized.crypt_hash()).unwrap();
+        let mut commits = HashMap::new();
+        commits.insert(signer.address, signature);
+        BftProof::new(height as usize, 0, proposal, commits).into()
+    }
+
+    // The proof of the first transaction in block 1, signed by the signer
+    // in the proof of block 3.
+    fn generate_tx_proof(signer: &Signer, receipt: Receipt) -> TxProof {
+        let tx = SignedTransaction::default();
+        let other = Receipt::new(
+            None,
+            U256::from(1),
+            vec![],
+            None,
+            U256::from(1),
+            H256::from(1),
+        );
+        let hashes = vec![
+            receipt.rlp_bytes().into_vec().crypt_hash(),
+            other.rlp_bytes().into_vec().crypt_hash(),
+        ];
+        let receipt_proof = cita_merklehash::Tree::from_hashes(hashes, cita_merklehash::merge)
+            .get_proof_by_input_index(0)
+            .unwrap()
+            .into();
+
+        let mut block_header = Header::default();
+        block_header.set_number(1);
+        block_header
+            .set_receipts_root(BlockReceipts::new(vec![receipt.clone(), other]).receipts_root());
+        block_header.rehash();
+        let mut next_header = Header::default();
+        next_header.set_number(2);
+        next_header.set_parent_hash(block_header.hash().unwrap());
+        let next_proposal_header = next_header.proposal();
+        let proposal_proof = generate_proof(
+            signer,
+            2,
+            next_proposal_header.proposal_protobuf().crypt_hash(),
+        );
+        TxProof::new(
+            tx,
+            receipt,
+            receipt_proof,
+            block_header,
+            next_proposal_header,
+            proposal_proof,
+        )
+    }
+
+    fn generate_receipt() -> Receipt {
+        generate_receipt_with_logs(vec![])
+    }
+
+    fn generate_receipt_with_logs(logs: Vec<Log>) -> Receipt {
+        let tx = SignedTransaction::default();
+        Receipt::new(
+            None,
+            U256::from(21000),
+            logs,
+            None,
+            U256::zero(),
+            tx.calc_transaction_hash(),
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let signer = Signer::from(PrivKey::from_str(KEY).unwrap());
+        let proof = generate_tx_proof(&signer, generate_receipt());
+        assert!(proof.verify(&[signer.address]));
+        // Not signed by the authorities.
+        assert!(!proof.verify(&[Address::from(1)]));
+
+        let bytes = proof.rlp_bytes().into_vec();
+        let decoded = TxProof::decode_bytes(&bytes).unwrap();
+        assert!(decoded.verify(&[signer.address]));
+        assert_eq!(decoded.receipt(), proof.receipt());
+        assert!(TxProof::decode_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let signer = Signer::from(PrivKey::from_str(KEY).unwrap());
+
+        // The receipt is of another transaction.
+        let mut receipt = generate_receipt();
+        receipt.transaction_hash = H256::from(2);
+        let proof = generate_tx_proof(&signer, receipt);
+        assert!(!proof.verify(&[signer.address]));
+
+        // The receipt is not in the block.
+        let proof = generate_tx_proof(&signer, generate_receipt());
+        let mut receipt = generate_receipt();
+        receipt.quota_used = U256::from(1);
+        let tampered = TxProof::new(
+            proof.tx().clone(),
+            receipt,
+            proof.receipt_proof.clone(),
+            proof.block_header().clone(),
+            proof.next_proposal_header().clone(),
+            proof.proposal_proof().clone(),
+        );
+        assert!(!tampered.verify(&[signer.address]));
+
+        // The next header is not of the block.
+        let mut next_header = proof.next_proposal_header().clone();
+        next_header.set_parent_hash(H256::from(3));
+        next_header.rehash();
+        let tampered = TxProof::new(
+            proof.tx().clone(),
+            proof.receipt().clone(),
+            proof.receipt_proof.clone(),
+            proof.block_header().clone(),
+            next_header,
+            proof.proposal_proof().clone(),
+        );
+        assert!(!tampered.verify(&[signer.address]));
+
+        // The proof signs another proposal.
+        let tampered = TxProof::new(
+            proof.tx().clone(),
+            proof.receipt().clone(),
+            proof.receipt_proof.clone(),
+            proof.block_header().clone(),
+            proof.next_proposal_header().clone(),
+            generate_proof(&signer, 2, H256::from(4)),
+        );
+        assert!(!tampered.verify(&[signer.address]));
+    }
+
+    fn generate_message(to_chain_id: u64, nonce: u64) -> CrossChainMessage {
+        CrossChainMessage {
+            version: CROSS_CHAIN_MESSAGE_VERSION,
+            sender: Address::from(0x11),
+            from_chain_id: U256::from(3),
+            to_chain_id: U256::from(to_chain_id),
+            dest_contract: Address::from(0x22),
+            nonce,
+            timeout_height: 0,
+            kind: MessageKind::Data,
+            payload: vec![nonce as u8; 40],
+        }
+    }
+
+    #[test]
+    fn test_extract_message() {
+        let signer = Signer::from(PrivKey::from_str(KEY).unwrap());
+        let messages = vec![
+            generate_message(5, 0),
+            generate_message(5, 1),
+            generate_message(6, 0),
+        ];
+        let mut logs: Vec<Log> = messages.iter().map(CrossChainMessage::to_log).collect();
+        // Not a message.
+        logs.push(Log {
+            address: Address::from(0x11),
+            topics: vec![H256::from(1)],
+            data: vec![],
+        });
+        let proof = generate_tx_proof(&signer, generate_receipt_with_logs(logs));
+        assert_eq!(proof.extract_messages(), messages);
+
+        let extract = |authorities: &[Address], to_chain_id: u64, nonce: u64| {
+            proof.extract_message(
+                authorities,
+                U256::from(3),
+                Address::from(0x11),
+                U256::from(to_chain_id),
+                Address::from(0x22),
+                nonce,
+            )
+        };
+        assert_eq!(extract(&[signer.address], 5, 1), Some(messages[1].clone()));
+        assert_eq!(extract(&[signer.address], 6, 0), Some(messages[2].clone()));
+        // Another nonce.
+        assert_eq!(extract(&[signer.address], 6, 1), None);
+        // Another chain.
+        assert_eq!(extract(&[signer.address], 7, 0), None);
+        // Not signed by the authorities.
+        assert_eq!(extract(&[Address::from(1)], 5, 0), None);
+    }
+}
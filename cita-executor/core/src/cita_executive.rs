@@ -34,6 +34,7 @@ use crate::contracts::native::factory::Factory as NativeFactory;
 use crate::exception::ExecutedException;
 use crate::libexecutor::economical_model::EconomicalModel;
 use crate::libexecutor::sys_config::BlockSysConfig;
+use crate::tracer::{CallType, Tracer};
 use crate::tx_gas_schedule::TxGasSchedule;
 use crate::types::context::Context;
 use crate::types::errors::AuthenticationError;
@@ -62,6 +63,7 @@ pub struct CitaExecutive<'a, B> {
     state_provider: Arc<RefCell<State<B>>>,
     context: &'a Context,
     economical_model: EconomicalModel,
+    tracer: Option<Arc<RefCell<Tracer>>>,
 }
 
 impl<'a, B: DB + 'static> CitaExecutive<'a, B> {
@@ -76,9 +78,16 @@ impl<'a, B: DB + 'static> CitaExecutive<'a, B> {
             state_provider: state,
             context,
             economical_model,
+            tracer: None,
         }
     }
 
+    /// Record the call frames and storage writes of the executed transaction.
+    pub fn with_tracer(mut self, tracer: Arc<RefCell<Tracer>>) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
     pub fn exec(
         &mut self,
         t: &SignedTransaction,
@@ -132,6 +141,10 @@ impl<'a, B: DB + 'static> CitaExecutive<'a, B> {
         let mut store = VMSubState::default();
         store.evm_context = build_evm_context(&self.context.clone());
         store.evm_cfg = get_interpreter_conf();
+        store.tracer = self.tracer.clone();
+        if let Some(ref tracer) = self.tracer {
+            tracer.borrow_mut().set_context(store.evm_context.clone());
+        }
         let store = Arc::new(RefCell::new(store));
 
         let result = match t.action {
@@ -164,13 +177,17 @@ impl<'a, B: DB + 'static> CitaExecutive<'a, B> {
                 if !self.payment_required() {
                     vm_exec_params.disable_transfer_value = true;
                 }
-                create(
+                let request: InterpreterParams = vm_exec_params.into();
+                self.trace_enter(CallType::Create, &request);
+                let r = create(
                     self.block_provider.clone(),
                     self.state_provider.clone(),
                     store.clone(),
-                    &vm_exec_params.into(),
+                    &request,
                     CreateKind::FromAddressAndNonce,
-                )
+                );
+                self.trace_exit(&r);
+                r
             }
 
             Action::AmendData => {
@@ -227,12 +244,16 @@ impl<'a, B: DB + 'static> CitaExecutive<'a, B> {
                 if !self.payment_required() {
                     vm_exec_params.disable_transfer_value = true;
                 }
-                call(
+                let request: InterpreterParams = vm_exec_params.into();
+                self.trace_enter(CallType::Call, &request);
+                let r = call(
                     self.block_provider.clone(),
                     self.state_provider.clone(),
                     store.clone(),
-                    &vm_exec_params.into(),
-                )
+                    &request,
+                );
+                self.trace_exit(&r);
+                r
             }
         };
 
@@ -375,6 +396,18 @@ impl<'a, B: DB + 'static> CitaExecutive<'a, B> {
         finalize_result
     }
 
+    fn trace_enter(&self, call_type: CallType, request: &InterpreterParams) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.borrow_mut().enter(call_type, request);
+        }
+    }
+
+    fn trace_exit(&self, result: &Result<InterpreterResult, VMError>) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.borrow_mut().exit(result);
+        }
+    }
+
     fn payment_required(&self) -> bool {
         self.economical_model == EconomicalModel::Charge
     }
@@ -576,6 +609,9 @@ pub fn create<B: DB + 'static>(
         }
     };
     debug!("create address={:?}", address);
+    if let Some(ref tracer) = store.borrow().tracer {
+        tracer.borrow_mut().created(address);
+    }
     // Ensure there's no existing contract already at the designated address
     if !can_create(state_provider.clone(), &address)? {
         return Err(VMError::ContractAlreadyExist);
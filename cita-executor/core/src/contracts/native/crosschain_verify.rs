@@ -16,7 +16,6 @@ use crate::cita_executive::VmExecParams;
 use crate::contracts::{
     native::factory::Contract, solc::ChainManagement, tools::method as method_tools,
 };
-// use crate::state::StateProof;
 use cita_types::{Address, H256, U256};
 use core::header::Header;
 use core::libchain::chain::TxProof;
@@ -25,6 +24,7 @@ use ethabi;
 use crate::storage::Map;
 use crate::types::context::Context;
 use crate::types::errors::NativeError;
+use crate::types::state_proof::StateProof;
 use cita_vm::evm::DataProvider;
 use cita_vm::evm::InterpreterResult;
 
@@ -37,6 +37,8 @@ lazy_static! {
         method_tools::encode_to_u32(b"verifyBlockHeader(uint256,bytes)");
     static ref GET_EXPECTED_BLOCK_NUMBER_FUNC: u32 =
         method_tools::encode_to_u32(b"getExpectedBlockNumber(uint256)");
+    static ref VERIFY_MESSAGE_FUNC: u32 =
+        method_tools::encode_to_u32(b"verifyMessage(uint256,address,uint64,bytes)");
 }
 
 #[derive(Clone)]
@@ -50,7 +52,7 @@ impl Contract for CrossChainVerify {
     fn exec(
         &mut self,
         params: &VmExecParams,
-        _context: &Context,
+        context: &Context,
         data_provider: &mut dyn DataProvider,
     ) -> Result<InterpreterResult, NativeError> {
         method_tools::extract_to_u32(&params.data[..]).and_then(|signature| match signature {
@@ -64,6 +66,9 @@ impl Contract for CrossChainVerify {
             sig if sig == *GET_EXPECTED_BLOCK_NUMBER_FUNC => {
                 self.get_expected_block_number(params, data_provider)
             }
+            sig if sig == *VERIFY_MESSAGE_FUNC => {
+                self.verify_message(params, context, data_provider)
+            }
             _ => Err(NativeError::Internal("out of gas".to_string())),
         })
     }
@@ -193,110 +198,199 @@ impl CrossChainVerify {
         ))
     }
 
+    // Verify the message to the calling contract in the proof.
+    // Returns whether the message is timed out, its kind and payload.
+    //
+    // The proof is signed by the authorities the ChainManager knows for the
+    // source chain: the parent chain, an enabled side chain, or a peer chain
+    // registered by `setPeerChain`, as a sibling side chain is. The messages
+    // are not routed through the parent chain.
+    fn verify_message(
+        &mut self,
+        params: &VmExecParams,
+        context: &Context,
+        data_provider: &mut dyn DataProvider,
+    ) -> Result<InterpreterResult, NativeError> {
+        let gas_cost = 10000;
+        if params.gas < gas_cost {
+            return Err(NativeError::Internal("out of gas".to_string()));
+        }
+        let gas_left = params.gas - gas_cost;
+
+        let data = params.data.clone();
+        trace!("data = {:?}", data);
+        let tokens = vec![
+            ethabi::ParamType::Uint(256),
+            ethabi::ParamType::Address,
+            ethabi::ParamType::Uint(64),
+            ethabi::ParamType::Bytes,
+        ];
+
+        let result = ethabi::decode(&tokens, &data[4..]);
+        if result.is_err() {
+            return Err(NativeError::Internal("decode failed".to_string()));
+        }
+        let mut decoded = result.unwrap();
+        trace!("decoded = {:?}", decoded);
+
+        let result = decoded.remove(0).to_uint();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 1st param failed".to_string()));
+        }
+        let from_chain_id = U256::from_big_endian(&result.unwrap());
+        trace!("from_chain_id = {}", from_chain_id);
+        let result = decoded.remove(0).to_address();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 2nd param failed".to_string()));
+        }
+        let sender = Address::from(result.unwrap());
+        trace!("sender = {}", sender);
+        let result = decoded.remove(0).to_uint();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 3rd param failed".to_string()));
+        }
+        let nonce = U256::from_big_endian(&result.unwrap()).low_u64();
+        trace!("nonce = {}", nonce);
+        let result = decoded.remove(0).to_bytes();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 4th param failed".to_string()));
+        }
+        let proof_data = result.unwrap();
+        trace!("data = {:?}", proof_data);
+
+        let proof = TxProof::decode_bytes(&proof_data)
+            .map_err(|_| NativeError::Internal("decode tx proof failed".to_string()))?;
+
+        let ret =
+            ChainManagement::ext_chain_id(data_provider, &U256::from(gas_left), &params.sender);
+        if ret.is_none() {
+            return Err(NativeError::Internal("get chain id failed".to_owned()));
+        }
+        let (gas_left, chain_id) = ret.unwrap();
+
+        let ret = ChainManagement::ext_authorities(
+            data_provider,
+            &gas_left,
+            &params.sender,
+            from_chain_id,
+        );
+        if ret.is_none() {
+            return Err(NativeError::Internal("get authorities failed".to_owned()));
+        }
+        let (gas_left, authorities) = ret.unwrap();
+
+        let message = proof
+            .extract_message(
+                &authorities[..],
+                from_chain_id,
+                sender,
+                chain_id,
+                params.sender,
+                nonce,
+            )
+            .ok_or_else(|| NativeError::Internal("extract message failed".to_string()))?;
+        let timed_out = message.is_timed_out(context.block_number);
+        trace!("message = {:?}, timed out: {}", message, timed_out);
+
+        let tokens = vec![
+            ethabi::Token::Bool(timed_out),
+            ethabi::Token::Uint(U256::from(message.kind as u8).into()),
+            ethabi::Token::Bytes(message.payload),
+        ];
+        let result = ethabi::encode(&tokens);
+        trace!("encoded {:?}", result);
+
+        self.output = result;
+        Ok(InterpreterResult::Normal(
+            self.output.clone(),
+            gas_left.low_u64(),
+            vec![],
+        ))
+    }
+
     fn verify_state(
         &mut self,
-        _params: &VmExecParams,
-        _data_provider: &mut dyn DataProvider,
+        params: &VmExecParams,
+        data_provider: &mut dyn DataProvider,
     ) -> Result<InterpreterResult, NativeError> {
-        unimplemented!()
-        // let gas_cost = U256::from(10000);
-        // if params.gas < gas_cost {
-        //     return Err(NativeError::Internal("out of gas".to_string()));
-        // }
-        // let gas_left = params.gas - gas_cost;
-
-        // if params.data.is_none() {
-        //     return Err(NativeError::Internal("no data".to_string()));
-        // }
-
-        // let data = params.data.to_owned().unwrap();
-        // trace!("data = {:?}", data);
-        // let tokens = vec![
-        //     ethabi::ParamType::Uint(32),
-        //     ethabi::ParamType::Uint(64),
-        //     ethabi::ParamType::Bytes,
-        // ];
-
-        // let result = ethabi::decode(&tokens, &data[4..]);
-        // if result.is_err() {
-        //     return Err(NativeError::Internal("decode failed".to_string()));
-        // }
-        // let mut decoded = result.unwrap();
-        // trace!("decoded = {:?}", decoded);
-
-        // let result = decoded.remove(0).to_uint();
-        // if result.is_none() {
-        //     return Err(NativeError::Internal("decode 1th param failed".to_string()));
-        // }
-        // let chain_id = U256::from_big_endian(&result.unwrap());
-        // trace!("chain_id = {}", chain_id);
-
-        // let result = decoded.remove(0).to_uint();
-        // if result.is_none() {
-        //     return Err(NativeError::Internal("decode 2nd param failed".to_string()));
-        // }
-        // let block_number = U256::from_big_endian(&result.unwrap()).low_u64();
-        // trace!("block_number = {}", block_number);
-
-        // let result = self.state_roots.get_array(&chain_id).unwrap().get(
-        //     data_provider,
-        //     &params.code_address.unwrap(),
-        //     block_number,
-        // );
-        // if result.is_err() {
-        //     return Err(NativeError::Internal("get state root failed".to_string()));
-        // }
-        // let result1 = self.state_roots.get_array(&chain_id).unwrap().get(
-        //     data_provider,
-        //     &params.code_address.unwrap(),
-        //     block_number + 1,
-        // );
-        // if result1.is_err() {
-        //     return Err(NativeError::Internal(
-        //         "get next state root failed".to_string(),
-        //     ));
-        // }
-        // let state_root: H256 = result.unwrap().into();
-        // trace!("state_root = {:?}", state_root);
-        // let next_state_root: H256 = result1.unwrap().into();
-        // trace!("next_state_root = {:?}", next_state_root);
-        // if state_root == H256::zero() || next_state_root == H256::zero() {
-        //     return Err(NativeError::Internal(
-        //         "state root have not confirmed".to_string(),
-        //     ));
-        // }
-
-        // let result = decoded.remove(0).to_bytes();
-        // if result.is_none() {
-        //     return Err(NativeError::Internal("decode 3rd param failed".to_string()));
-        // }
-        // let state_proof_bytes = result.unwrap();
-        // trace!("state_proof_bytes = {:?}", state_proof_bytes);
-
-        // let state_proof = StateProof::from_bytes(&state_proof_bytes);
-        // let maybe_val = state_proof.verify(state_root);
-        // if maybe_val.is_none() {
-        //     return Err(NativeError::Internal(
-        //         "state proof verify failed".to_string(),
-        //     ));
-        // }
-        // let val = maybe_val.unwrap();
-        // trace!("val = {:?}", val);
-
-        // let tokens = vec![
-        //     ethabi::Token::Address((*state_proof.address()).into()),
-        //     ethabi::Token::Uint((*state_proof.key()).into()),
-        //     ethabi::Token::Uint(val.into()),
-        // ];
-        // let result = ethabi::encode(&tokens);
-        // trace!("encoded {:?}", result);
-
-        // self.output = result;
-        // Ok(InterpreterResult::Normal(
-        //     self.output.clone(),
-        //     gas_left.low_u64(),
-        //     vec![],
-        // ))
+        let gas_cost = 10000;
+        if params.gas < gas_cost {
+            return Err(NativeError::Internal("out of gas".to_string()));
+        }
+        let gas_left = params.gas - gas_cost;
+
+        let data = params.data.clone();
+        trace!("data = {:?}", data);
+        let tokens = vec![
+            ethabi::ParamType::Uint(32),
+            ethabi::ParamType::Uint(64),
+            ethabi::ParamType::Bytes,
+        ];
+
+        let result = ethabi::decode(&tokens, &data[4..]);
+        if result.is_err() {
+            return Err(NativeError::Internal("decode failed".to_string()));
+        }
+        let mut decoded = result.unwrap();
+        trace!("decoded = {:?}", decoded);
+
+        let result = decoded.remove(0).to_uint();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 1th param failed".to_string()));
+        }
+        let chain_id = U256::from_big_endian(&result.unwrap());
+        trace!("chain_id = {}", chain_id);
+
+        let result = decoded.remove(0).to_uint();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 2nd param failed".to_string()));
+        }
+        let block_number = U256::from_big_endian(&result.unwrap()).low_u64();
+        trace!("block_number = {}", block_number);
+
+        let state_roots = self.state_roots.get_array(&chain_id)?;
+        let state_root: H256 = state_roots
+            .get(data_provider, &params.code_address, block_number)?
+            .into();
+        trace!("state_root = {:?}", state_root);
+        let next_state_root: H256 = state_roots
+            .get(data_provider, &params.code_address, block_number + 1)?
+            .into();
+        trace!("next_state_root = {:?}", next_state_root);
+        if state_root == H256::zero() || next_state_root == H256::zero() {
+            return Err(NativeError::Internal(
+                "state root have not confirmed".to_string(),
+            ));
+        }
+
+        let result = decoded.remove(0).to_bytes();
+        if result.is_none() {
+            return Err(NativeError::Internal("decode 3rd param failed".to_string()));
+        }
+        let state_proof_bytes = result.unwrap();
+        trace!("state_proof_bytes = {:?}", state_proof_bytes);
+
+        let state_proof = StateProof::decode_bytes(&state_proof_bytes)
+            .map_err(|_| NativeError::Internal("decode state proof failed".to_string()))?;
+        let val = state_proof
+            .verify(state_root)
+            .ok_or_else(|| NativeError::Internal("state proof verify failed".to_string()))?;
+        trace!("val = {:?}", val);
+
+        let tokens = vec![
+            ethabi::Token::Address((*state_proof.address()).into()),
+            ethabi::Token::Uint(U256::from(*state_proof.key()).into()),
+            ethabi::Token::Uint(U256::from(val).into()),
+        ];
+        let result = ethabi::encode(&tokens);
+        trace!("encoded {:?}", result);
+
+        self.output = result;
+        Ok(InterpreterResult::Normal(
+            self.output.clone(),
+            gas_left,
+            vec![],
+        ))
     }
 
     fn verify_block_header(
@@ -446,3 +540,407 @@ impl CrossChainVerify {
         ))
     }
 }
+
+#[cfg(all(test, feature = "secp256k1"))]
+mod tests {
+    use super::CrossChainVerify;
+    use crate::cita_executive::{CitaExecutive, ExecutedResult, VmExecParams};
+    use crate::contracts::native::factory::Contract;
+    use crate::contracts::tools::method as method_tools;
+    use crate::libexecutor::economical_model::EconomicalModel;
+    use crate::libexecutor::{block::EVMBlockDataProvider, sys_config::BlockSysConfig};
+    use crate::tests::exemock::DataProviderMock;
+    use crate::tests::helpers::{get_temp_state, solc};
+    use crate::types::block_receipts::BlockReceipts;
+    use crate::types::context::Context;
+    use crate::types::cross_chain::{CrossChainMessage, MessageKind, CROSS_CHAIN_MESSAGE_VERSION};
+    use crate::types::header::Header;
+    use crate::types::log::Log;
+    use crate::types::receipt::Receipt;
+    use crate::types::reserved_addresses;
+    use crate::types::transaction::{Action, SignedTransaction, Transaction};
+    use cita_crypto::{PrivKey, Sign, Signature, Signer};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::evm::InterpreterResult;
+    use cita_vm::state::{MemoryDB, State};
+    use core::libchain::chain::TxProof;
+    use hashable::Hashable;
+    use libproto::blockchain::Proof as ProtoProof;
+    use proof::BftProof;
+    use rlp::Encodable;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    const KEY: &str = "ef98e68db428906d626cd37782cdfb052ac282132beee53a99948738ea553b4a";
+    const FROM_CHAIN_ID: u64 = 3;
+    const MY_CHAIN_ID: u64 = 5;
+    const CROSS_CHAIN: &str =
+        include_str!("../../../../../scripts/contracts/src/native/CrossChain.sol");
+    const MESSENGER: &str =
+        include_str!("../../../../../scripts/contracts/src/native/CrossChainMessenger.sol");
+
+    fn generate_proof(signer: &Signer, height: u64, proposal: H256) -> ProtoProof {
+        let serialized = bincode::serialize(
+            &(height as usize, 0usize, 0usize, signer.address, proposal),
+            bincode::Infinite,
+        )
+        .unwrap();
+        let signature =
+            Signature::sign(signer.keypair.privkey(), &serialized.crypt_hash()).unwrap();
+        let mut commits = HashMap::new();
+        commits.insert(signer.address, signature);
+        BftProof::new(height as usize, 0, proposal, commits).into()
+    }
+
+    // The proof of the transaction sending the messages in block 1, signed
+    // by the signer in the proof of block 2.
+    fn generate_tx_proof(signer: &Signer, messages: &[CrossChainMessage]) -> TxProof {
+        let tx = SignedTransaction::default();
+        let logs: Vec<Log> = messages.iter().map(CrossChainMessage::to_log).collect();
+        let receipt = Receipt::new(
+            None,
+            U256::from(21000),
+            logs,
+            None,
+            U256::zero(),
+            tx.calc_transaction_hash(),
+        );
+        let other = Receipt::new(
+            None,
+            U256::from(1),
+            vec![],
+            None,
+            U256::from(1),
+            H256::from(1),
+        );
+        let hashes = vec![
+            receipt.rlp_bytes().into_vec().crypt_hash(),
+            other.rlp_bytes().into_vec().crypt_hash(),
+        ];
+        let receipt_proof = cita_merklehash::Tree::from_hashes(hashes, cita_merklehash::merge)
+            .get_proof_by_input_index(0)
+            .unwrap()
+            .into();
+
+        let mut block_header = Header::default();
+        block_header.set_number(1);
+        block_header
+            .set_receipts_root(BlockReceipts::new(vec![receipt.clone(), other]).receipts_root());
+        block_header.rehash();
+        let mut next_header = Header::default();
+        next_header.set_number(2);
+        next_header.set_parent_hash(block_header.hash().unwrap());
+        let next_proposal_header = next_header.proposal();
+        let proposal_proof = generate_proof(
+            signer,
+            2,
+            next_proposal_header.proposal_protobuf().crypt_hash(),
+        );
+        TxProof::new(
+            tx,
+            receipt,
+            receipt_proof,
+            block_header,
+            next_proposal_header,
+            proposal_proof,
+        )
+    }
+
+    fn generate_message(
+        dest_contract: Address,
+        nonce: u64,
+        timeout_height: u64,
+    ) -> CrossChainMessage {
+        CrossChainMessage {
+            version: CROSS_CHAIN_MESSAGE_VERSION,
+            sender: Address::from(0x11),
+            from_chain_id: U256::from(FROM_CHAIN_ID),
+            to_chain_id: U256::from(MY_CHAIN_ID),
+            dest_contract,
+            nonce,
+            timeout_height,
+            kind: MessageKind::Data,
+            payload: vec![nonce as u8 + 1; 40],
+        }
+    }
+
+    // The output of `verifyMessage`.
+    fn message_output(timed_out: bool, kind: MessageKind, payload: &[u8]) -> Vec<u8> {
+        ethabi::encode(&[
+            ethabi::Token::Bool(timed_out),
+            ethabi::Token::Uint(U256::from(kind as u8).into()),
+            ethabi::Token::Bytes(payload.to_vec()),
+        ])
+    }
+
+    // Answer `getChainId()` and `getAuthorities(FROM_CHAIN_ID)` of the
+    // ChainManager.
+    fn chain_manager_mock(authorities: &[Address]) -> DataProviderMock {
+        let chain_manager = Address::from_str(reserved_addresses::CHAIN_MANAGER).unwrap();
+        let mut data_provider = DataProviderMock::default();
+        data_provider.call_outputs.insert(
+            (chain_manager, method_tools::encode_to_vec(b"getChainId()")),
+            H256::from(MY_CHAIN_ID).to_vec(),
+        );
+        let mut input = method_tools::encode_to_vec(b"getAuthorities(uint256)");
+        input.extend(H256::from(FROM_CHAIN_ID).to_vec());
+        let nodes = authorities
+            .iter()
+            .map(|node| ethabi::Token::Address((*node).into()))
+            .collect();
+        data_provider.call_outputs.insert(
+            (chain_manager, input),
+            ethabi::encode(&[ethabi::Token::Array(nodes)]),
+        );
+        data_provider
+    }
+
+    fn verify_message(
+        data_provider: &mut DataProviderMock,
+        context: &Context,
+        dest_contract: Address,
+        nonce: u64,
+        proof: &TxProof,
+    ) -> Option<Vec<u8>> {
+        let mut params = VmExecParams::default();
+        params.sender = dest_contract;
+        params.gas = 100_000;
+        params.data = method_tools::encode_to_vec(b"verifyMessage(uint256,address,uint64,bytes)");
+        params.data.extend(ethabi::encode(&[
+            ethabi::Token::Uint(U256::from(FROM_CHAIN_ID).into()),
+            ethabi::Token::Address(Address::from(0x11).into()),
+            ethabi::Token::Uint(U256::from(nonce).into()),
+            ethabi::Token::Bytes(proof.rlp_bytes().into_vec()),
+        ]));
+        match CrossChainVerify::default().exec(&params, context, data_provider) {
+            Ok(InterpreterResult::Normal(output, _, _)) => Some(output),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_verify_message() {
+        let signer = Signer::from(PrivKey::from_str(KEY).unwrap());
+        let dest = Address::from(0x22);
+        let messages = vec![generate_message(dest, 0, 0), generate_message(dest, 1, 10)];
+        let proof = generate_tx_proof(&signer, &messages);
+        let mut data_provider = chain_manager_mock(&[signer.address]);
+        let mut context = Context::default();
+        context.block_number = 9;
+
+        assert_eq!(
+            verify_message(&mut data_provider, &context, dest, 0, &proof),
+            Some(message_output(
+                false,
+                MessageKind::Data,
+                &messages[0].payload
+            ))
+        );
+        assert_eq!(
+            verify_message(&mut data_provider, &context, dest, 1, &proof),
+            Some(message_output(
+                false,
+                MessageKind::Data,
+                &messages[1].payload
+            ))
+        );
+        context.block_number = 10;
+        assert_eq!(
+            verify_message(&mut data_provider, &context, dest, 1, &proof),
+            Some(message_output(
+                true,
+                MessageKind::Data,
+                &messages[1].payload
+            ))
+        );
+
+        // Not in the proof.
+        assert_eq!(
+            verify_message(&mut data_provider, &context, dest, 2, &proof),
+            None
+        );
+        // To another contract.
+        assert_eq!(
+            verify_message(&mut data_provider, &context, Address::from(0x33), 0, &proof),
+            None
+        );
+        // Not signed by the authorities of the source chain.
+        let mut data_provider = chain_manager_mock(&[Address::from(1)]);
+        assert_eq!(
+            verify_message(&mut data_provider, &context, dest, 0, &proof),
+            None
+        );
+    }
+
+    // A receiver of `CrossChainMessenger`, and a ChainManager of this chain
+    // which knows the signer as the authority of the source chain.
+    fn messenger_source(authority: Address) -> String {
+        let strip = |source: &str| {
+            source
+                .lines()
+                .filter(|line| !line.starts_with("pragma") && !line.starts_with("import"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        format!(
+            r#"
+pragma solidity ^0.4.24;
+{}
+{}
+contract Receiver is CrossChainMessenger {{
+    event Received(bool timedOut, uint8 kind, bytes payload);
+
+    function recvCrossChainMessage(
+        uint fromChainId,
+        address sender,
+        bytes txProof
+    ) public {{
+        bool timedOut;
+        uint8 kind;
+        bytes memory payload;
+        (timedOut, kind, payload) = verifyMessage(fromChainId, sender, txProof, 64);
+        emit Received(timedOut, kind, payload);
+    }}
+}}
+
+contract FakeChainManager {{
+    function getChainId() public pure returns (uint) {{
+        return {};
+    }}
+
+    function getAuthorities(uint id) public pure returns (address[] nodes) {{
+        if (id == {}) {{
+            nodes = new address[](1);
+            nodes[0] = address({});
+        }}
+    }}
+}}
+"#,
+            strip(CROSS_CHAIN),
+            strip(MESSENGER),
+            MY_CHAIN_ID,
+            FROM_CHAIN_ID,
+            U256::from(H256::from(authority)),
+        )
+    }
+
+    fn transaction(action: Action, data: Vec<u8>) -> SignedTransaction {
+        Transaction {
+            action,
+            value: U256::from(0),
+            data,
+            gas: U256::from(5_000_000),
+            gas_price: U256::one(),
+            nonce: U256::zero().to_string(),
+            block_limit: 100u64,
+            chain_id: 1.into(),
+            version: 2,
+        }
+        .fake_sign(Address::from(0x44))
+    }
+
+    fn exec(
+        state: &Arc<RefCell<State<MemoryDB>>>,
+        context: &Context,
+        t: &SignedTransaction,
+    ) -> ExecutedResult {
+        CitaExecutive::new(
+            Arc::new(EVMBlockDataProvider::new(context.clone())),
+            state.clone(),
+            context,
+            EconomicalModel::Quota,
+        )
+        .exec(t, &BlockSysConfig::default())
+        .unwrap()
+    }
+
+    #[test]
+    fn test_messenger() {
+        let signer = Signer::from(PrivKey::from_str(KEY).unwrap());
+        let source = messenger_source(signer.address);
+        let (_, chain_manager_code) = solc("FakeChainManager", &source);
+        let (receiver_code, _) = solc("Receiver", &source);
+
+        let mut state = get_temp_state();
+        state
+            .set_code(
+                &Address::from_str(reserved_addresses::CHAIN_MANAGER).unwrap(),
+                chain_manager_code,
+            )
+            .unwrap();
+        let state = Arc::new(RefCell::new(state));
+        let mut context = Context::default();
+        let receiver = exec(
+            &state,
+            &context,
+            &transaction(Action::Create, receiver_code),
+        )
+        .contract_address
+        .unwrap();
+
+        let messages = vec![
+            generate_message(receiver, 0, 0),
+            generate_message(receiver, 1, 10),
+        ];
+        let proof = generate_tx_proof(&signer, &messages);
+        let mut data = method_tools::encode_to_vec(b"recvCrossChainMessage(uint256,address,bytes)");
+        data.extend(ethabi::encode(&[
+            ethabi::Token::Uint(U256::from(FROM_CHAIN_ID).into()),
+            ethabi::Token::Address(Address::from(0x11).into()),
+            ethabi::Token::Bytes(proof.rlp_bytes().into_vec()),
+        ]));
+        let t = transaction(Action::Call(receiver), data);
+        let received = |result: &ExecutedResult, timed_out: bool, payload: &[u8]| {
+            let output = message_output(timed_out, MessageKind::Data, payload);
+            result
+                .logs
+                .iter()
+                .any(|log| log.address == receiver && log.data == output)
+        };
+
+        context.block_number = 9;
+        let result = exec(&state, &context, &t);
+        assert!(result.exception.is_none());
+        assert!(received(&result, false, &messages[0].payload));
+        assert!(result
+            .logs
+            .iter()
+            .all(|log| CrossChainMessage::from_log(log).is_none()));
+
+        // The next message of the proof is timed out, and the timeout is
+        // acked to the source.
+        context.block_number = 10;
+        let result = exec(&state, &context, &t);
+        assert!(result.exception.is_none());
+        assert!(received(&result, true, &messages[1].payload));
+        let acks: Vec<CrossChainMessage> = result
+            .logs
+            .iter()
+            .filter_map(CrossChainMessage::from_log)
+            .collect();
+        assert_eq!(
+            acks,
+            vec![CrossChainMessage {
+                version: CROSS_CHAIN_MESSAGE_VERSION,
+                sender: receiver,
+                from_chain_id: U256::from(MY_CHAIN_ID),
+                to_chain_id: U256::from(FROM_CHAIN_ID),
+                dest_contract: Address::from(0x11),
+                nonce: 0,
+                timeout_height: 0,
+                kind: MessageKind::Ack,
+                payload: ethabi::encode(&[
+                    ethabi::Token::Uint(U256::from(1).into()),
+                    ethabi::Token::Bool(false),
+                    ethabi::Token::Bytes(vec![]),
+                ]),
+            }]
+        );
+
+        // No message with the next nonce.
+        let result = exec(&state, &context, &t);
+        assert!(result.exception.is_some());
+    }
+}
@@ -67,6 +67,7 @@ impl ChainManagement {
         params.gas_limit = gas.low_u64();
         let mut tx_data = AUTHORITIES_ENCODED.to_vec();
         tx_data.extend(H256::from(chain_id).to_vec());
+        params.input = tx_data;
 
         match data_provider.call(OpCode::CALL, params) {
             Ok(InterpreterResult::Normal(output, gas_left, _logs)) => {
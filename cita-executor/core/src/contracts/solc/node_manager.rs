@@ -134,7 +134,46 @@ impl<'a> NodeManager<'a> {
 #[cfg(test)]
 mod tests {
     extern crate cita_logger as logger;
-    use super::{party_seats, shuffle};
+    use super::{party_seats, shuffle, NodeManager};
+    use crate::libexecutor::command::Commander;
+    use crate::tests::helpers::init_executor;
+    use crate::types::block_number::{BlockTag, Tag};
+    use crate::types::node_manager;
+    use crate::types::state_proof::StateProof;
+    use cita_types::{Address, U256};
+    use cita_vm::state::StateObjectInfo;
+
+    #[test]
+    fn test_nodes_storage() {
+        // The storage keys computed by the EVM, which the fast sync of
+        // validators proves.
+        let executor = init_executor();
+        let nodes = NodeManager::new(&executor, 0)
+            .nodes(BlockTag::Tag(Tag::Pending))
+            .unwrap();
+        assert!(!nodes.is_empty());
+
+        let mut state = executor.state_at(BlockTag::Tag(Tag::Pending)).unwrap();
+        let address = node_manager::contract_address();
+        let keys = node_manager::validators_keys(nodes.len() as u64);
+        let count = state.get_storage(&address, &keys[0]).unwrap();
+        assert_eq!(U256::from(count), U256::from(nodes.len()));
+        let stored: Vec<Address> = keys[1..]
+            .iter()
+            .map(|key| Address::from(state.get_storage(&address, key).unwrap()))
+            .collect();
+        assert_eq!(stored, nodes);
+
+        // The light client verifies the validators with the proofs of the keys.
+        let proofs: Vec<StateProof> = keys
+            .iter()
+            .map(|key| StateProof::prove(&state, &address, key).unwrap())
+            .collect();
+        assert_eq!(
+            node_manager::verify_validators(state.root, &proofs),
+            Ok(nodes)
+        );
+    }
 
     #[test]
     fn test_party_seats() {
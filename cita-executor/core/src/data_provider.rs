@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::cita_executive::{call as ext_call, create as ext_create, CreateKind};
+use crate::tracer::{CallType, ReadValue, StateRead, Tracer};
 use cita_trie::DB;
 use cita_types::{Address, H256, U256};
 use cita_vm::evm;
@@ -66,6 +67,8 @@ pub struct Store {
     pub inused: HashSet<Address>,
     pub evm_context: evm::Context,
     pub evm_cfg: evm::InterpreterConf,
+    // Shared by all sub stores of a transaction, only set when tracing.
+    pub tracer: Option<Arc<RefCell<Tracer>>>,
 }
 
 impl Store {
@@ -107,14 +110,24 @@ impl<B: DB> DataProvider<B> {
             store,
         }
     }
+
+    /// Record a state read for the opcode replay of the tracer.
+    fn traced<T: Clone, F: FnOnce(T) -> ReadValue>(&self, read: StateRead, value: T, f: F) -> T {
+        if let Some(tracer) = self.store.borrow().tracer.as_ref() {
+            tracer.borrow_mut().read(read, f(value.clone()));
+        }
+        value
+    }
 }
 
 impl<B: DB + 'static> evm::DataProvider for DataProvider<B> {
     fn get_balance(&self, address: &Address) -> U256 {
-        self.state_provider
+        let balance = self
+            .state_provider
             .borrow_mut()
             .balance(address)
-            .unwrap_or_else(|_| U256::zero())
+            .unwrap_or_else(|_| U256::zero());
+        self.traced(StateRead::Balance(*address), balance, ReadValue::U256)
     }
 
     fn add_refund(&mut self, address: &Address, n: u64) {
@@ -145,35 +158,46 @@ impl<B: DB + 'static> evm::DataProvider for DataProvider<B> {
     }
 
     fn get_code_size(&self, address: &Address) -> u64 {
-        self.state_provider
+        let size = self
+            .state_provider
             .borrow_mut()
             .code_size(address)
-            .unwrap_or(0) as u64
+            .unwrap_or(0) as u64;
+        self.traced(StateRead::CodeSize(*address), size, |size| {
+            ReadValue::U256(size.into())
+        })
     }
 
     fn get_code(&self, address: &Address) -> Vec<u8> {
-        self.state_provider
+        let code = self
+            .state_provider
             .borrow_mut()
             .code(address)
-            .unwrap_or_else(|_| vec![])
+            .unwrap_or_else(|_| vec![]);
+        self.traced(StateRead::Code(*address), code, ReadValue::Code)
     }
 
     fn get_code_hash(&self, address: &Address) -> H256 {
-        self.state_provider
+        let hash = self
+            .state_provider
             .borrow_mut()
             .code_hash(address)
-            .unwrap_or_else(|_| H256::zero())
+            .unwrap_or_else(|_| H256::zero());
+        self.traced(StateRead::CodeHash(*address), hash, ReadValue::Hash)
     }
 
     fn get_block_hash(&self, number: &U256) -> H256 {
-        self.block_provider.get_block_hash(number)
+        let hash = self.block_provider.get_block_hash(number);
+        self.traced(StateRead::BlockHash(*number), hash, ReadValue::Hash)
     }
 
     fn get_storage(&self, address: &Address, key: &H256) -> H256 {
-        self.state_provider
+        let value = self
+            .state_provider
             .borrow_mut()
             .get_storage(address, key)
-            .unwrap_or_else(|_| H256::zero())
+            .unwrap_or_else(|_| H256::zero());
+        self.traced(StateRead::Storage(*address, *key), value, ReadValue::Hash)
     }
 
     fn set_storage(&mut self, address: &Address, key: H256, value: H256) {
@@ -185,6 +209,9 @@ impl<B: DB + 'static> evm::DataProvider for DataProvider<B> {
             .or_insert_with(HashMap::new)
             .entry(key)
             .or_insert(a);
+        if let Some(tracer) = self.store.borrow().tracer.as_ref() {
+            tracer.borrow_mut().storage_write(*address, key, a, value);
+        }
         if let Err(e) = self
             .state_provider
             .borrow_mut()
@@ -196,11 +223,15 @@ impl<B: DB + 'static> evm::DataProvider for DataProvider<B> {
 
     fn get_storage_origin(&self, address: &Address, key: &H256) -> H256 {
         //self.store.borrow_mut().used(address.clone());
-        match self.store.borrow_mut().origin.get(address) {
-            Some(account) => match account.get(key) {
-                Some(val) => *val,
-                None => self.get_storage(address, key),
-            },
+        // Release the store before `get_storage`, which may record a read.
+        let origin = self
+            .store
+            .borrow()
+            .origin
+            .get(address)
+            .and_then(|account| account.get(key).cloned());
+        match origin {
+            Some(val) => val,
             None => self.get_storage(address, key),
         }
     }
@@ -237,23 +268,49 @@ impl<B: DB + 'static> evm::DataProvider for DataProvider<B> {
     }
 
     fn is_empty(&self, address: &Address) -> bool {
-        self.state_provider
+        let empty = self
+            .state_provider
             .borrow_mut()
             .is_empty(address)
-            .unwrap_or(false)
+            .unwrap_or(false);
+        self.traced(StateRead::Empty(*address), empty, ReadValue::Bool)
     }
 
     fn exist(&self, address: &Address) -> bool {
-        self.state_provider
+        let exist = self
+            .state_provider
             .borrow_mut()
             .exist(address)
-            .unwrap_or(false)
+            .unwrap_or(false);
+        self.traced(StateRead::Exist(*address), exist, ReadValue::Bool)
     }
 
     fn call(
         &self,
         opcode: evm::OpCode,
         params: evm::InterpreterParams,
+    ) -> Result<evm::InterpreterResult, evm::Error> {
+        let tracer = self.store.borrow().tracer.clone();
+        if let Some(ref tracer) = tracer {
+            if let Some(call_type) = CallType::from_opcode(opcode) {
+                tracer.borrow_mut().enter(call_type, &params);
+            }
+        }
+        let r = self.call_inner(opcode, params);
+        if let Some(ref tracer) = tracer {
+            if CallType::from_opcode(opcode).is_some() {
+                tracer.borrow_mut().exit(&r);
+            }
+        }
+        r
+    }
+}
+
+impl<B: DB + 'static> DataProvider<B> {
+    fn call_inner(
+        &self,
+        opcode: evm::OpCode,
+        params: evm::InterpreterParams,
     ) -> Result<evm::InterpreterResult, evm::Error> {
         match opcode {
             evm::OpCode::CALL
@@ -0,0 +1,513 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! State pruning.
+//!
+//! In pruning mode every trie node carries a reference count, which is
+//! increased when a committed block inserts the node. The nodes a block
+//! replaces are kept in a per-block journal, and the journal is applied
+//! (decreasing the reference counts) only when the block falls out of the
+//! history window. Nodes whose reference count drops to zero are removed
+//! from the database by a background thread.
+//!
+//! The nodes inserted by a journal which is dropped without being committed,
+//! such as the one of a proposal replaced by another block of the same height,
+//! are removed at once unless they are referenced by a committed block.
+
+use crate::types::db_indexes::{
+    BlockNumber2StateJournal, DBIndex, Hash2StateRefCount, StateJournalType, StatePrunedHeight,
+};
+use byteorder::{BigEndian, ByteOrder};
+use cita_database::error::DatabaseError;
+use cita_database::{DataCategory, Database};
+use cita_types::H256;
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread;
+
+pub const DEFAULT_PRUNING_HISTORY: u64 = 4096;
+
+const NODE_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalDBType {
+    /// Keep all the trie nodes forever.
+    Archive,
+    /// Keep the states of the latest N blocks only.
+    Pruning(u64),
+}
+
+impl JournalDBType {
+    pub fn new(name: &str, history: u64) -> Option<Self> {
+        match name {
+            "archive" => Some(JournalDBType::Archive),
+            "pruning" => Some(JournalDBType::Pruning(history)),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JournalDBType::Archive => "archive",
+            JournalDBType::Pruning(_) => "pruning",
+        }
+    }
+
+    /// Save the type into a new database, or make sure an existing database
+    /// is opened with the type it was created with.
+    pub fn check<DB: Database + ?Sized>(self, db: &DB, is_new: bool) -> Result<(), String> {
+        let key = StateJournalType.get_index();
+        let saved = db
+            .get(Some(DataCategory::Extra), &key)
+            .map_err(|e| format!("get journaldb type error: {:?}", e))?;
+        match saved {
+            Some(name) => {
+                if name.as_slice() != self.name().as_bytes() {
+                    return Err(format!(
+                        "statedb was created with journaldb_type {}, can not open it as {}",
+                        String::from_utf8_lossy(&name),
+                        self.name()
+                    ));
+                }
+                Ok(())
+            }
+            None => {
+                // Databases created before the journaldb type is saved are archive ones.
+                if !is_new && self != JournalDBType::Archive {
+                    return Err(format!(
+                        "statedb was created with journaldb_type archive, can not open it as {}",
+                        self.name()
+                    ));
+                }
+                db.insert(
+                    Some(DataCategory::Extra),
+                    key,
+                    self.name().as_bytes().to_vec(),
+                )
+                .map_err(|e| format!("save journaldb type error: {:?}", e))
+            }
+        }
+    }
+}
+
+/// Trie nodes inserted and removed by the execution of a block.
+#[derive(Debug)]
+pub struct Journal<DB>
+where
+    DB: Database,
+{
+    collector: Arc<Collector<DB>>,
+    inserted: Vec<Vec<u8>>,
+    removed: Vec<Vec<u8>>,
+    committed: bool,
+}
+
+impl<DB> Journal<DB>
+where
+    DB: Database,
+{
+    fn new(collector: Arc<Collector<DB>>) -> Self {
+        Journal {
+            collector,
+            inserted: Vec::new(),
+            removed: Vec::new(),
+            committed: false,
+        }
+    }
+
+    pub fn insert(&mut self, keys: &[Vec<u8>]) {
+        self.collector.protect(keys);
+        self.inserted.extend_from_slice(keys);
+    }
+
+    pub fn remove(&mut self, keys: &[Vec<u8>]) {
+        self.removed.extend_from_slice(keys);
+    }
+}
+
+impl<DB> Drop for Journal<DB>
+where
+    DB: Database,
+{
+    fn drop(&mut self) {
+        if let Err(e) = self.collector.release(&self.inserted, !self.committed) {
+            error!("release state journal error: {:?}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StatePruner<DB>
+where
+    DB: Database,
+{
+    collector: Arc<Collector<DB>>,
+    sender: Sender<u64>,
+}
+
+impl<DB> StatePruner<DB>
+where
+    DB: Database + 'static,
+{
+    /// Start the garbage collecting thread, it exits when the pruner is dropped.
+    pub fn new(db: Arc<DB>, history: u64) -> Self {
+        let collector = Arc::new(Collector::new(db, history));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let gc = Arc::clone(&collector);
+        thread::Builder::new()
+            .name("state_pruner".to_string())
+            .spawn(move || gc.run(&receiver))
+            .expect("spawn state pruner thread");
+        StatePruner { collector, sender }
+    }
+
+    pub fn journal(&self) -> Journal<DB> {
+        Journal::new(Arc::clone(&self.collector))
+    }
+
+    /// Save the journal of a committed block, and let the background thread
+    /// prune the blocks out of the history window.
+    pub fn commit(&self, height: u64, journal: &mut Journal<DB>) -> Result<(), DatabaseError> {
+        self.collector.commit(height, journal)?;
+        let _ = self.sender.send(height);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Collector<DB>
+where
+    DB: Database,
+{
+    db: Arc<DB>,
+    history: u64,
+    // Nodes inserted by the uncommitted journals, they may be referenced by
+    // a block being executed even if their reference counts are zero.
+    // The lock also serializes the updating of reference counts.
+    protected: Mutex<HashMap<Vec<u8>, usize>>,
+}
+
+impl<DB> Collector<DB>
+where
+    DB: Database,
+{
+    fn new(db: Arc<DB>, history: u64) -> Self {
+        Collector {
+            db,
+            history,
+            protected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn protect(&self, keys: &[Vec<u8>]) {
+        let mut protected = self.protected.lock();
+        for key in keys {
+            *protected.entry(key.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Unprotect the nodes inserted by a journal, and remove the ones not
+    /// referenced by any block if the journal is discarded.
+    fn release(&self, keys: &[Vec<u8>], discard: bool) -> Result<(), DatabaseError> {
+        let mut protected = self.protected.lock();
+        for key in keys {
+            let unused = match protected.get_mut(key) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if unused {
+                protected.remove(key);
+            }
+        }
+        if !discard {
+            return Ok(());
+        }
+
+        let keys: HashSet<&Vec<u8>> = keys.iter().filter(|k| k.len() == NODE_KEY_LEN).collect();
+        let mut nodes = Vec::new();
+        for key in keys {
+            if !protected.contains_key(key) && self.ref_count(key)?.is_none() {
+                nodes.push(key.clone());
+            }
+        }
+        if !nodes.is_empty() {
+            trace!("discard {} uncommitted state nodes", nodes.len());
+            self.db.remove_batch(Some(DataCategory::State), &nodes)?;
+        }
+        Ok(())
+    }
+
+    fn ref_count(&self, node: &[u8]) -> Result<Option<u64>, DatabaseError> {
+        let key = Hash2StateRefCount(H256::from_slice(node)).get_index();
+        self.db
+            .get(Some(DataCategory::Extra), &key)
+            .map(|count| count.map(|count| BigEndian::read_u64(&count)))
+    }
+
+    fn commit(&self, height: u64, journal: &mut Journal<DB>) -> Result<(), DatabaseError> {
+        let _lock = self.protected.lock();
+
+        let mut inserted: HashMap<&[u8], u64> = HashMap::new();
+        for node in journal.inserted.iter().filter(|k| k.len() == NODE_KEY_LEN) {
+            *inserted.entry(node.as_slice()).or_insert(0) += 1;
+        }
+        let mut keys = Vec::with_capacity(inserted.len());
+        let mut values = Vec::with_capacity(inserted.len());
+        for (node, n) in inserted {
+            let count = self.ref_count(node)?.unwrap_or(0) + n;
+            keys.push(Hash2StateRefCount(H256::from_slice(node)).get_index());
+            values.push(encode_u64(count));
+        }
+        self.db
+            .insert_batch(Some(DataCategory::Extra), keys, values)?;
+
+        let removed: Vec<u8> = journal
+            .removed
+            .iter()
+            .filter(|k| k.len() == NODE_KEY_LEN)
+            .flat_map(|k| k.iter().cloned())
+            .collect();
+        self.db.insert(
+            Some(DataCategory::Extra),
+            BlockNumber2StateJournal(height).get_index(),
+            removed,
+        )?;
+        journal.committed = true;
+        Ok(())
+    }
+
+    fn run(&self, receiver: &Receiver<u64>) {
+        while let Ok(mut height) = receiver.recv() {
+            // Only the latest height matters when falling behind.
+            while let Ok(latest) = receiver.try_recv() {
+                height = latest;
+            }
+            if let Err(e) = self.prune(height) {
+                error!("prune state at height {} error: {:?}", height, e);
+            }
+        }
+        info!("state pruner exit");
+    }
+
+    /// Prune the journals out of the history window of the height.
+    fn prune(&self, height: u64) -> Result<(), DatabaseError> {
+        if height < self.history {
+            return Ok(());
+        }
+        let target = height - self.history;
+        let pruned_key = StatePrunedHeight.get_index();
+        let mut next = self
+            .db
+            .get(Some(DataCategory::Extra), &pruned_key)?
+            .map(|h| BigEndian::read_u64(&h))
+            .unwrap_or(0);
+        while next <= target {
+            self.prune_journal(next)?;
+            next += 1;
+            self.db.insert(
+                Some(DataCategory::Extra),
+                pruned_key.clone(),
+                encode_u64(next),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn prune_journal(&self, height: u64) -> Result<(), DatabaseError> {
+        let journal_key = BlockNumber2StateJournal(height).get_index();
+        let removed = match self.db.get(Some(DataCategory::Extra), &journal_key)? {
+            Some(removed) => removed,
+            None => return Ok(()),
+        };
+        let mut counts: HashMap<&[u8], u64> = HashMap::new();
+        for node in removed.chunks(NODE_KEY_LEN) {
+            *counts.entry(node).or_insert(0) += 1;
+        }
+
+        let protected = self.protected.lock();
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        let mut zero_keys = Vec::new();
+        let mut nodes = Vec::new();
+        for (node, n) in counts {
+            // Nodes without a reference count are not managed by the pruner.
+            let count = match self.ref_count(node)? {
+                Some(count) => count,
+                None => continue,
+            };
+            let key = Hash2StateRefCount(H256::from_slice(node)).get_index();
+            if count > n {
+                keys.push(key);
+                values.push(encode_u64(count - n));
+            } else {
+                zero_keys.push(key);
+                if !protected.contains_key(node) {
+                    nodes.push(node.to_vec());
+                }
+            }
+        }
+        self.db
+            .insert_batch(Some(DataCategory::Extra), keys, values)?;
+        self.db
+            .remove_batch(Some(DataCategory::Extra), &zero_keys)?;
+        self.db.remove_batch(Some(DataCategory::State), &nodes)?;
+        trace!(
+            "prune state at height {}, remove {} nodes",
+            height,
+            nodes.len()
+        );
+        self.db.remove(Some(DataCategory::Extra), &journal_key)
+    }
+}
+
+fn encode_u64(value: u64) -> Vec<u8> {
+    let mut bytes = [0u8; 8];
+    BigEndian::write_u64(&mut bytes, value);
+    bytes.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+    use self::tempdir::TempDir;
+    use super::{Collector, Journal, JournalDBType};
+    use cita_database::{Config, DataCategory, Database, RocksDB, NUM_COLUMNS};
+    use cita_types::H256;
+    use std::sync::Arc;
+
+    fn open_db() -> Arc<RocksDB> {
+        let tempdir = TempDir::new("journaldb").unwrap().into_path();
+        let config = Config::with_category_num(NUM_COLUMNS);
+        Arc::new(RocksDB::open(tempdir.to_str().unwrap(), &config).unwrap())
+    }
+
+    fn insert_nodes(db: &RocksDB, nodes: &[Vec<u8>]) {
+        for node in nodes {
+            db.insert(Some(DataCategory::State), node.clone(), vec![1])
+                .unwrap();
+        }
+    }
+
+    fn contains(db: &RocksDB, node: &[u8]) -> bool {
+        db.contains(Some(DataCategory::State), node).unwrap()
+    }
+
+    #[test]
+    fn test_check_journaldb_type() {
+        let db = open_db();
+        assert!(JournalDBType::Pruning(10).check(&*db, true).is_ok());
+        assert!(JournalDBType::Pruning(20).check(&*db, false).is_ok());
+        assert!(JournalDBType::Archive.check(&*db, false).is_err());
+
+        let db = open_db();
+        assert!(JournalDBType::Pruning(10).check(&*db, false).is_err());
+    }
+
+    #[test]
+    fn test_prune_unreferenced_nodes() {
+        let db = open_db();
+        let collector = Arc::new(Collector::new(Arc::clone(&db), 1));
+        let a = H256::from(1).to_vec();
+        let b = H256::from(2).to_vec();
+        let c = H256::from(3).to_vec();
+        insert_nodes(&db, &[a.clone(), b.clone()]);
+
+        // Block 1 inserts a and b.
+        let mut journal = Journal::new(Arc::clone(&collector));
+        journal.insert(&[a.clone(), b.clone()]);
+        collector.commit(1, &mut journal).unwrap();
+        drop(journal);
+
+        // Block 2 replaces a with c, and inserts b again.
+        insert_nodes(&db, &[c.clone()]);
+        let mut journal = Journal::new(Arc::clone(&collector));
+        journal.insert(&[c.clone(), b.clone()]);
+        journal.remove(&[a.clone(), b.clone()]);
+        collector.commit(2, &mut journal).unwrap();
+        drop(journal);
+
+        collector.prune(2).unwrap();
+        assert!(contains(&db, &a));
+
+        collector.prune(3).unwrap();
+        assert!(!contains(&db, &a));
+        assert!(contains(&db, &b));
+        assert!(contains(&db, &c));
+    }
+
+    #[test]
+    fn test_keep_protected_nodes() {
+        let db = open_db();
+        let collector = Arc::new(Collector::new(Arc::clone(&db), 0));
+        let a = H256::from(1).to_vec();
+        insert_nodes(&db, &[a.clone()]);
+
+        let mut journal = Journal::new(Arc::clone(&collector));
+        journal.insert(&[a.clone()]);
+        collector.commit(1, &mut journal).unwrap();
+        drop(journal);
+
+        // A block being executed inserts a again.
+        let mut executing = Journal::new(Arc::clone(&collector));
+        executing.insert(&[a.clone()]);
+
+        let mut journal = Journal::new(Arc::clone(&collector));
+        journal.remove(&[a.clone()]);
+        collector.commit(2, &mut journal).unwrap();
+        drop(journal);
+
+        collector.prune(2).unwrap();
+        assert!(contains(&db, &a));
+
+        collector.commit(3, &mut executing).unwrap();
+        assert_eq!(collector.ref_count(&a).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_discard_uncommitted_nodes() {
+        let db = open_db();
+        let collector = Arc::new(Collector::new(Arc::clone(&db), 10));
+        let a = H256::from(1).to_vec();
+        let b = H256::from(2).to_vec();
+        let c = H256::from(3).to_vec();
+        insert_nodes(&db, &[a.clone()]);
+
+        let mut journal = Journal::new(Arc::clone(&collector));
+        journal.insert(&[a.clone()]);
+        collector.commit(1, &mut journal).unwrap();
+        drop(journal);
+
+        // Two proposals of height 2, only the second one is committed.
+        insert_nodes(&db, &[b.clone(), c.clone()]);
+        let mut proposal = Journal::new(Arc::clone(&collector));
+        proposal.insert(&[a.clone(), b.clone(), c.clone()]);
+        let mut block = Journal::new(Arc::clone(&collector));
+        block.insert(&[c.clone()]);
+
+        drop(proposal);
+        assert!(contains(&db, &a));
+        assert!(!contains(&db, &b));
+        assert!(contains(&db, &c));
+
+        collector.commit(2, &mut block).unwrap();
+        drop(block);
+        assert!(contains(&db, &c));
+        assert_eq!(collector.ref_count(&c).unwrap(), Some(1));
+    }
+}
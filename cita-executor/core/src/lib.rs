@@ -40,8 +40,10 @@ pub mod cita_executive;
 pub mod cita_vm_helper;
 pub mod contracts;
 pub mod data_provider;
+pub mod journaldb;
 pub mod libexecutor;
 pub mod storage;
+pub mod tracer;
 pub mod tx_gas_schedule;
 
 mod authentication;
@@ -50,4 +52,4 @@ mod trie_db;
 
 pub use crate::types::*;
 pub use cita_database as cita_db;
-pub use trie_db::TrieDB;
+pub use trie_db::{CacheStats, TrieDB};
@@ -28,6 +28,7 @@ use crate::libexecutor::executor::CitaTrieDB;
 use crate::libexecutor::sys_config::BlockSysConfig;
 use crate::libexecutor::sys_config::GlobalSysConfig;
 use crate::receipt::Receipt;
+use crate::tracer::{TraceOptions, Tracer, TransactionTrace};
 use crate::tx_gas_schedule::TxGasSchedule;
 pub use crate::types::block::{Block, BlockBody, OpenBlock};
 use crate::types::errors::Error;
@@ -133,7 +134,12 @@ impl ExecutedBlock {
         }
     }
 
-    pub fn apply_transaction(&mut self, t: &SignedTransaction, sys_config: &GlobalSysConfig) {
+    /// Execution env info and block config of a transaction.
+    fn transaction_context(
+        &mut self,
+        t: &SignedTransaction,
+        sys_config: &GlobalSysConfig,
+    ) -> (Context, BlockSysConfig) {
         let mut context = self.get_context();
         context.block_quota_limit = U256::from(sys_config.block_quota_limit);
         trace!("block quota limit is {:?}", context.block_quota_limit);
@@ -156,6 +162,11 @@ impl ExecutedBlock {
                 context.coin_base = conf.chain_owner;
             }
         }
+        (context, conf)
+    }
+
+    pub fn apply_transaction(&mut self, t: &SignedTransaction, sys_config: &GlobalSysConfig) {
+        let (context, conf) = self.transaction_context(t, sys_config);
         let block_data_provider = EVMBlockDataProvider::new(context.clone());
 
         let tx_quota_used = match CitaExecutive::new(
@@ -298,6 +309,44 @@ impl ExecutedBlock {
         }
     }
 
+    /// Execute a transaction with a tracer, the block state is changed
+    /// but no receipt is generated.
+    pub fn trace_transaction(
+        &mut self,
+        t: &SignedTransaction,
+        sys_config: &GlobalSysConfig,
+        options: &TraceOptions,
+    ) -> TransactionTrace {
+        let (context, conf) = self.transaction_context(t, sys_config);
+        let block_data_provider = EVMBlockDataProvider::new(context.clone());
+        let tracer = Arc::new(RefCell::new(Tracer::new()));
+
+        let mut trace = TransactionTrace {
+            transaction_hash: t.get_transaction_hash(),
+            ..Default::default()
+        };
+        match CitaExecutive::new(
+            Arc::new(block_data_provider),
+            self.state.clone(),
+            &context,
+            conf.economical_model,
+        )
+        .with_tracer(tracer.clone())
+        .exec(t, &conf)
+        {
+            Ok(ret) => {
+                trace.quota_used = ret.quota_used;
+                trace.output = ret.output;
+                trace.error = ret.exception.map(|e| format!("{}", e));
+            }
+            Err(err) => {
+                trace.error = Some(format!("{}", err));
+            }
+        }
+        trace.call = tracer.borrow().trace_root(options);
+        trace
+    }
+
     fn deal_err_quota_cost(
         &self,
         sender: &Address,
@@ -20,17 +20,19 @@ use crate::cita_executive::{CitaExecutive, ExecutedResult as CitaExecuted};
 use crate::contracts::solc::{
     sys_config::ChainId, PermissionManagement, SysConfig, VersionManager,
 };
+use crate::header::Header;
 use crate::libexecutor::block::EVMBlockDataProvider;
 pub use crate::libexecutor::block::*;
 use crate::libexecutor::call_request::CallRequest;
-use crate::trie_db::TrieDB;
+use crate::libexecutor::state_sync;
+use crate::tracer::{TraceOptions, Tracer, TransactionTrace};
 use crate::types::block_number::{BlockTag, Tag};
 use crate::types::context::Context;
 use crate::types::errors::CallError;
 use crate::types::errors::ExecutionError;
+use crate::types::state_sync::MAX_HEADERS_PER_REQUEST;
 use crate::types::transaction::{Action, SignedTransaction, Transaction};
 pub use byteorder::{BigEndian, ByteOrder};
-use cita_database::RocksDB;
 use cita_types::traits::LowerHex;
 use cita_types::{Address, H256, U256};
 use cita_vm::state::{State as CitaState, StateObjectInfo};
@@ -60,6 +62,8 @@ pub enum Command {
     EstimateQuota(CallRequest, BlockTag),
     SignCall(CallRequest),
     Call(SignedTransaction, BlockTag),
+    TraceTransaction(OpenBlock, H256, TraceOptions),
+    TraceCall(CallRequest, BlockTag, TraceOptions),
     ChainID,
     Metadata(String),
     EconomicalModel,
@@ -67,6 +71,10 @@ pub enum Command {
     Grow(ClosedBlock),
     Exit(BlockTag),
     CloneExecutorReader,
+    Headers(u64, u64),
+    StateNodes(Vec<H256>),
+    StateDB,
+    ImportHeaders(Vec<Header>),
 }
 
 #[cfg_attr(feature = "cargo-clippy", allow(clippy::large_enum_variant))]
@@ -81,6 +89,8 @@ pub enum CommandResp {
     EstimateQuota(Result<Bytes, String>),
     SignCall(SignedTransaction),
     Call(Result<CitaExecuted, CallError>),
+    TraceTransaction(Result<TransactionTrace, String>),
+    TraceCall(Result<TransactionTrace, String>),
     ChainID(Option<ChainId>),
     Metadata(Result<MetaData, String>),
     EconomicalModel(EconomicalModel),
@@ -88,6 +98,10 @@ pub enum CommandResp {
     Grow(ExecutedResult),
     Exit,
     CloneExecutorReader(Executor),
+    Headers(Vec<Header>),
+    StateNodes(Vec<Bytes>),
+    StateDB(CitaTrieDB),
+    ImportHeaders,
 }
 
 impl fmt::Display for Command {
@@ -103,6 +117,8 @@ impl fmt::Display for Command {
             Command::EstimateQuota(_, _) => write!(f, "Command::EstimateQuota"),
             Command::SignCall(_) => write!(f, "Command::SignCall"),
             Command::Call(_, _) => write!(f, "Command::Call"),
+            Command::TraceTransaction(_, _, _) => write!(f, "Command::TraceTransaction"),
+            Command::TraceCall(_, _, _) => write!(f, "Command::TraceCall"),
             Command::ChainID => write!(f, "Command::ChainID "),
             Command::Metadata(_) => write!(f, "Command::Metadata"),
             Command::EconomicalModel => write!(f, "Command::EconomicalModel"),
@@ -110,6 +126,10 @@ impl fmt::Display for Command {
             Command::Grow(_) => write!(f, "Command::Grow"),
             Command::Exit(_) => write!(f, "Command::Exit"),
             Command::CloneExecutorReader => write!(f, "Command::CloneExecutorReader"),
+            Command::Headers(_, _) => write!(f, "Command::Headers"),
+            Command::StateNodes(_) => write!(f, "Command::StateNodes"),
+            Command::StateDB => write!(f, "Command::StateDB"),
+            Command::ImportHeaders(_) => write!(f, "Command::ImportHeaders"),
         }
     }
 }
@@ -127,6 +147,8 @@ impl fmt::Display for CommandResp {
             CommandResp::EstimateQuota(_) => write!(f, "CommandResp::EstimateQuota"),
             CommandResp::SignCall(_) => write!(f, "CommandResp::SignCall"),
             CommandResp::Call(_) => write!(f, "CommandResp::Call"),
+            CommandResp::TraceTransaction(_) => write!(f, "CommandResp::TraceTransaction"),
+            CommandResp::TraceCall(_) => write!(f, "CommandResp::TraceCall"),
             CommandResp::ChainID(_) => write!(f, "CommandResp::ChainID "),
             CommandResp::Metadata(_) => write!(f, "CommandResp::Metadata"),
             CommandResp::EconomicalModel(_) => write!(f, "CommandResp::EconomicalModel"),
@@ -134,6 +156,10 @@ impl fmt::Display for CommandResp {
             CommandResp::Grow(_) => write!(f, "CommandResp::Grow"),
             CommandResp::Exit => write!(f, "CommandResp::Exit"),
             CommandResp::CloneExecutorReader(_) => write!(f, "CommandResp::CloneExecurorReader"),
+            CommandResp::Headers(_) => write!(f, "CommandResp::Headers"),
+            CommandResp::StateNodes(_) => write!(f, "CommandResp::StateNodes"),
+            CommandResp::StateDB(_) => write!(f, "CommandResp::StateDB"),
+            CommandResp::ImportHeaders => write!(f, "CommandResp::ImportHeaders"),
         }
     }
 }
@@ -150,6 +176,18 @@ pub trait Commander {
     fn estimate_quota(&self, request: CallRequest, block_tag: BlockTag) -> Result<Bytes, String>;
     fn sign_call(&self, request: CallRequest) -> SignedTransaction;
     fn call(&self, t: &SignedTransaction, block_tag: BlockTag) -> Result<CitaExecuted, CallError>;
+    fn trace_transaction(
+        &self,
+        block: OpenBlock,
+        tx_hash: H256,
+        options: TraceOptions,
+    ) -> Result<TransactionTrace, String>;
+    fn trace_call(
+        &self,
+        request: CallRequest,
+        block_tag: BlockTag,
+        options: TraceOptions,
+    ) -> Result<TransactionTrace, String>;
     fn chain_id(&self) -> Option<ChainId>;
     fn metadata(&self, data: String) -> Result<MetaData, String>;
     fn economical_model(&self) -> EconomicalModel;
@@ -157,6 +195,9 @@ pub trait Commander {
     fn grow(&mut self, closed_block: &ClosedBlock) -> ExecutedResult;
     fn exit(&mut self, rollback_id: BlockTag);
     fn clone_executor_reader(&mut self) -> Self;
+    fn headers(&self, start: u64, count: u64) -> Vec<Header>;
+    fn state_nodes(&self, hashes: &[H256]) -> Vec<Bytes>;
+    fn import_headers(&self, headers: &[Header]);
 }
 
 impl Commander for Executor {
@@ -188,6 +229,12 @@ impl Commander for Executor {
             Command::Call(signed_transaction, block_tag) => {
                 CommandResp::Call(self.call(&signed_transaction, block_tag))
             }
+            Command::TraceTransaction(block, tx_hash, options) => {
+                CommandResp::TraceTransaction(self.trace_transaction(block, tx_hash, options))
+            }
+            Command::TraceCall(call_request, block_tag, options) => {
+                CommandResp::TraceCall(self.trace_call(call_request, block_tag, options))
+            }
             Command::ChainID => CommandResp::ChainID(self.chain_id()),
             Command::Metadata(data) => CommandResp::Metadata(self.metadata(data)),
             Command::EconomicalModel => CommandResp::EconomicalModel(self.economical_model()),
@@ -206,6 +253,13 @@ impl Commander for Executor {
             Command::CloneExecutorReader => {
                 CommandResp::CloneExecutorReader(self.clone_executor_reader())
             }
+            Command::Headers(start, count) => CommandResp::Headers(self.headers(start, count)),
+            Command::StateNodes(hashes) => CommandResp::StateNodes(self.state_nodes(&hashes)),
+            Command::StateDB => CommandResp::StateDB(self.state_db.journaled()),
+            Command::ImportHeaders(headers) => {
+                self.import_headers(&headers);
+                CommandResp::ImportHeaders
+            }
         }
     }
 
@@ -215,10 +269,9 @@ impl Commander for Executor {
             .and_then(|h| self.gen_state(*h.state_root(), *h.parent_hash()))
     }
 
-    /// Generate block's final state.
+    /// Generate block's final state, changes to it are never saved.
     fn gen_state(&self, root: H256, _parent_hash: H256) -> Option<CitaState<CitaTrieDB>> {
-        // FIXME: There is a RWLock for clone a db, is it ok for using Arc::clone?
-        CitaState::from_existing(Arc::<CitaTrieDB>::clone(&self.state_db), root).ok()
+        CitaState::from_existing(Arc::new(self.state_db.scratch()), root).ok()
     }
 
     /// Get code by address
@@ -386,61 +439,90 @@ impl Commander for Executor {
     }
 
     fn call(&self, t: &SignedTransaction, block_tag: BlockTag) -> Result<CitaExecuted, CallError> {
-        let header = self.block_header(block_tag).ok_or(CallError::StatePruned)?;
-        let last_hashes = self.build_last_hashes(Some(header.hash().unwrap()), header.number());
-        let mut context = Context {
-            block_number: header.number(),
-            coin_base: *header.proposer(),
-            timestamp: if self.eth_compatibility {
-                header.timestamp() / 1000
-            } else {
-                header.timestamp()
-            },
-            difficulty: U256::default(),
-            last_hashes: ::std::sync::Arc::new(last_hashes),
-            quota_used: *header.quota_used(),
-            block_quota_limit: *header.quota_limit(),
-            account_quota_limit: u64::max_value().into(),
-        };
-        context.block_quota_limit = U256::from(self.sys_config.block_quota_limit);
-
-        // FIXME: Need to implement state_at
-        // that's just a copy of the state.
-        //        let mut state = self.state_at(block_tag).ok_or(CallError::StatePruned)?;
-
-        // Never check permission and quota
-        let mut conf = self.sys_config.block_sys_config.clone();
-        conf.exempt_checking();
-
-        let block_data_provider = EVMBlockDataProvider::new(context.clone());
+        exec_call(self, t, block_tag, None)
+    }
 
-        let state_root = if let Some(h) = self.block_header(block_tag) {
-            *h.state_root()
-        } else {
-            error!("Can not get state root from trie db!");
-            return Err(CallError::StatePruned);
-        };
+    /// Re-execute the block up to the transaction against the parent state,
+    /// then execute the transaction with a tracer.
+    fn trace_transaction(
+        &self,
+        block: OpenBlock,
+        tx_hash: H256,
+        options: TraceOptions,
+    ) -> Result<TransactionTrace, String> {
+        let index = block
+            .body()
+            .transactions()
+            .iter()
+            .position(|t| t.get_transaction_hash() == tx_hash)
+            .ok_or_else(|| {
+                format!(
+                    "Trace Error transaction {:?} not in block {}",
+                    tx_hash,
+                    block.number()
+                )
+            })?;
+        if block.number() == 0 {
+            return Err("Trace Error genesis block can not be traced".to_owned());
+        }
+        let parent = self
+            .block_header_by_height(block.number() - 1)
+            .ok_or_else(|| "Trace Error CallError::StatePruned".to_owned())?;
+        let parent_state_root = *parent.state_root();
+        if CitaState::from_existing(Arc::<CitaTrieDB>::clone(&self.state_db), parent_state_root)
+            .is_err()
+        {
+            return Err("Trace Error CallError::StatePruned".to_owned());
+        }
+        let last_hashes = self.build_last_hashes(Some(parent.hash().unwrap()), parent.number());
+
+        let transactions = block.body().transactions().to_vec();
+        let mut executed_block = ExecutedBlock::create(
+            &self.sys_config.block_sys_config,
+            block,
+            Arc::new(self.state_db.scratch()),
+            parent_state_root,
+            last_hashes.into(),
+            self.eth_compatibility,
+        )
+        .map_err(|e| format!("Trace Error {:?}", e))?;
 
-        let state = match CitaState::from_existing(
-            Arc::<TrieDB<RocksDB>>::clone(&self.state_db),
-            state_root,
-        ) {
-            Ok(state_db) => state_db,
-            Err(e) => {
-                error!("Can not get state from trie db! error: {:?}", e);
-                return Err(CallError::StatePruned);
+        let conf = &self.sys_config.block_sys_config;
+        let prepare = |t: &SignedTransaction| {
+            let mut t = t.clone();
+            if conf.economical_model == EconomicalModel::Charge {
+                t.gas_price = conf.quota_price;
             }
+            t
         };
+        for t in &transactions[..index] {
+            executed_block.apply_transaction(&prepare(t), &self.sys_config);
+        }
+        Ok(executed_block.trace_transaction(
+            &prepare(&transactions[index]),
+            &self.sys_config,
+            &options,
+        ))
+    }
 
-        let state = Arc::new(RefCell::new(state));
-        CitaExecutive::new(
-            Arc::new(block_data_provider),
-            state,
-            &context,
-            conf.economical_model,
-        )
-        .exec(t, &conf)
-        .map_err(Into::into)
+    fn trace_call(
+        &self,
+        request: CallRequest,
+        block_tag: BlockTag,
+        options: TraceOptions,
+    ) -> Result<TransactionTrace, String> {
+        let signed = self.sign_call(request);
+        let tracer = Arc::new(RefCell::new(Tracer::new()));
+        let ret = exec_call(self, &signed, block_tag, Some(tracer.clone()))
+            .map_err(|e| format!("Trace Error {}", e))?;
+        let call = tracer.borrow().trace_root(&options);
+        Ok(TransactionTrace {
+            transaction_hash: signed.get_transaction_hash(),
+            quota_used: ret.quota_used,
+            output: ret.output,
+            error: ret.exception.map(|e| format!("{}", e)),
+            call,
+        })
     }
 
     fn chain_id(&self) -> Option<ChainId> {
@@ -584,6 +666,7 @@ impl Commander for Executor {
 
         // Must make sure write into database before load_sys_config
         self.write_batch(closed_block);
+        debug!("statedb cache {:?}", self.state_db.cache_stats());
 
         if are_permissions_changed {
             trace!("Permissions changed, reload global sys config.");
@@ -624,6 +707,101 @@ impl Commander for Executor {
             eth_compatibility,
         }
     }
+
+    /// The headers from `start` in ascending order, at most `count`.
+    ///
+    /// The headers before a fast synced block are not saved, so the headers
+    /// stop at the first one not found.
+    fn headers(&self, start: u64, count: u64) -> Vec<Header> {
+        let end = start
+            .saturating_add(count.min(MAX_HEADERS_PER_REQUEST))
+            .min(self.get_current_height() + 1);
+        let mut headers = Vec::new();
+        for height in start..end {
+            match self.block_header_by_height(height) {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+        }
+        headers
+    }
+
+    fn state_nodes(&self, hashes: &[H256]) -> Vec<Bytes> {
+        state_sync::read_nodes(&*self.state_db, hashes)
+    }
+
+    /// Save the headers downloaded with a state, which is not executed locally.
+    fn import_headers(&self, headers: &[Header]) {
+        for header in headers {
+            self.write_header(header);
+        }
+    }
+}
+
+/// Execute a transaction on the state of `block_tag` without changing it.
+fn exec_call(
+    executor: &Executor,
+    t: &SignedTransaction,
+    block_tag: BlockTag,
+    tracer: Option<Arc<RefCell<Tracer>>>,
+) -> Result<CitaExecuted, CallError> {
+    let header = executor
+        .block_header(block_tag)
+        .ok_or(CallError::StatePruned)?;
+    let last_hashes = executor.build_last_hashes(Some(header.hash().unwrap()), header.number());
+    let mut context = Context {
+        block_number: header.number(),
+        coin_base: *header.proposer(),
+        timestamp: if executor.eth_compatibility {
+            header.timestamp() / 1000
+        } else {
+            header.timestamp()
+        },
+        difficulty: U256::default(),
+        last_hashes: ::std::sync::Arc::new(last_hashes),
+        quota_used: *header.quota_used(),
+        block_quota_limit: *header.quota_limit(),
+        account_quota_limit: u64::max_value().into(),
+    };
+    context.block_quota_limit = U256::from(executor.sys_config.block_quota_limit);
+
+    // FIXME: Need to implement state_at
+    // that's just a copy of the state.
+    //        let mut state = executor.state_at(block_tag).ok_or(CallError::StatePruned)?;
+
+    // Never check permission and quota
+    let mut conf = executor.sys_config.block_sys_config.clone();
+    conf.exempt_checking();
+
+    let block_data_provider = EVMBlockDataProvider::new(context.clone());
+
+    let state_root = if let Some(h) = executor.block_header(block_tag) {
+        *h.state_root()
+    } else {
+        error!("Can not get state root from trie db!");
+        return Err(CallError::StatePruned);
+    };
+
+    // The writes of the call are dropped with the scratch TrieDB.
+    let state = match CitaState::from_existing(Arc::new(executor.state_db.scratch()), state_root) {
+        Ok(state_db) => state_db,
+        Err(e) => {
+            error!("Can not get state from trie db! error: {:?}", e);
+            return Err(CallError::StatePruned);
+        }
+    };
+
+    let state = Arc::new(RefCell::new(state));
+    let mut executive = CitaExecutive::new(
+        Arc::new(block_data_provider),
+        state,
+        &context,
+        conf.economical_model,
+    );
+    if let Some(tracer) = tracer {
+        executive = executive.with_tracer(tracer);
+    }
+    executive.exec(t, &conf).map_err(Into::into)
 }
 
 // TODO hope someone refactor these public function via macro
@@ -757,6 +935,34 @@ pub fn call(
     }
 }
 
+pub fn trace_transaction(
+    command_req_sender: &Sender<Command>,
+    command_resp_receiver: &Receiver<CommandResp>,
+    block: OpenBlock,
+    tx_hash: H256,
+    options: TraceOptions,
+) -> Result<TransactionTrace, String> {
+    let _ = command_req_sender.send(Command::TraceTransaction(block, tx_hash, options));
+    match command_resp_receiver.recv().unwrap() {
+        CommandResp::TraceTransaction(r) => r,
+        _ => unimplemented!(),
+    }
+}
+
+pub fn trace_call(
+    command_req_sender: &Sender<Command>,
+    command_resp_receiver: &Receiver<CommandResp>,
+    call_request: CallRequest,
+    block_tag: BlockTag,
+    options: TraceOptions,
+) -> Result<TransactionTrace, String> {
+    let _ = command_req_sender.send(Command::TraceCall(call_request, block_tag, options));
+    match command_resp_receiver.recv().unwrap() {
+        CommandResp::TraceCall(r) => r,
+        _ => unimplemented!(),
+    }
+}
+
 pub fn chain_id(
     command_req_sender: &Sender<Command>,
     command_resp_receiver: &Receiver<CommandResp>,
@@ -827,6 +1033,54 @@ pub fn exit(
     }
 }
 
+pub fn headers(
+    command_req_sender: &Sender<Command>,
+    command_resp_receiver: &Receiver<CommandResp>,
+    start: u64,
+    count: u64,
+) -> Vec<Header> {
+    let _ = command_req_sender.send(Command::Headers(start, count));
+    match command_resp_receiver.recv().unwrap() {
+        CommandResp::Headers(r) => r,
+        _ => unimplemented!(),
+    }
+}
+
+pub fn state_nodes(
+    command_req_sender: &Sender<Command>,
+    command_resp_receiver: &Receiver<CommandResp>,
+    hashes: Vec<H256>,
+) -> Vec<Bytes> {
+    let _ = command_req_sender.send(Command::StateNodes(hashes));
+    match command_resp_receiver.recv().unwrap() {
+        CommandResp::StateNodes(r) => r,
+        _ => unimplemented!(),
+    }
+}
+
+pub fn state_db(
+    command_req_sender: &Sender<Command>,
+    command_resp_receiver: &Receiver<CommandResp>,
+) -> CitaTrieDB {
+    let _ = command_req_sender.send(Command::StateDB);
+    match command_resp_receiver.recv().unwrap() {
+        CommandResp::StateDB(r) => r,
+        _ => unimplemented!(),
+    }
+}
+
+pub fn import_headers(
+    command_req_sender: &Sender<Command>,
+    command_resp_receiver: &Receiver<CommandResp>,
+    headers: Vec<Header>,
+) {
+    let _ = command_req_sender.send(Command::ImportHeaders(headers));
+    match command_resp_receiver.recv().unwrap() {
+        CommandResp::ImportHeaders => {}
+        _ => unimplemented!(),
+    }
+}
+
 pub fn clone_executor_reader(
     command_req_sender: &Sender<Command>,
     command_resp_receiver: &Receiver<CommandResp>,
@@ -19,6 +19,7 @@ use super::sys_config::GlobalSysConfig;
 use crate::contracts::solc::NodeManager;
 use crate::core::context::LastHashes;
 use crate::header::*;
+use crate::journaldb::JournalDBType;
 pub use crate::libexecutor::block::*;
 use crate::libexecutor::genesis::Genesis;
 use crate::trie_db::TrieDB;
@@ -62,6 +63,8 @@ impl Executor {
         command_req_receiver: Receiver<Command>,
         command_resp_sender: Sender<CommandResp>,
         eth_compatibility: bool,
+        journaldb_type: JournalDBType,
+        statedb_cache_size: usize,
     ) -> Executor {
         let mut genesis = Genesis::init(&genesis_path);
 
@@ -70,16 +73,27 @@ impl Executor {
         let nosql_path = data_path + "/statedb";
         let rocks_db = RocksDB::open(&nosql_path, &config).unwrap();
         let db = Arc::new(rocks_db);
-        let state_db = Arc::new(TrieDB::new(db.clone()));
+        let current_header = get_current_header(db.clone());
+        if let Err(e) = journaldb_type.check(&*db, current_header.is_none()) {
+            panic!("{}", e);
+        }
+        let state_db = Arc::new(
+            TrieDB::with_journal_type(db.clone(), journaldb_type)
+                .with_cache_size(statedb_cache_size),
+        );
 
-        let current_header = match get_current_header(db.clone()) {
+        let current_header = match current_header {
             Some(header) => header,
             None => {
                 warn!("Not found exist block within database. Loading genesis block...");
+                let genesis_state_db = Arc::new(state_db.journaled());
                 genesis
                     // FIXME
-                    .lazy_execute(state_db.clone())
+                    .lazy_execute(genesis_state_db.clone())
                     .expect("failed to load genesis");
+                genesis_state_db
+                    .commit_journal(0)
+                    .expect("Commit genesis state journal error.");
                 genesis.block.header().clone()
             }
         };
@@ -192,9 +206,10 @@ impl Executor {
     }
 
     /// Write data to db
-    /// 1. Header
-    /// 2. CurrentHash
-    /// 3. State
+    /// 1. State journal
+    /// 2. Header
+    /// 3. Height to hash
+    /// 4. CurrentHash
     pub fn write_batch(&self, block: &ClosedBlock) {
         let height = block.number();
         let hash = block.hash().unwrap();
@@ -206,15 +221,15 @@ impl Executor {
             version
         );
 
-        // Insert [hash : block_header].
-        let hash_key = db_indexes::Hash2Header(hash).get_index();
-        self.db
-            .insert(
-                Some(DataCategory::Headers),
-                hash_key.to_vec(),
-                block.header().rlp(),
-            )
-            .expect("Insert block header error.");
+        // The reference counts of state must be saved before the block,
+        // otherwise the nodes may be pruned after a crash.
+        block
+            .state
+            .db
+            .commit_journal(height)
+            .expect("Commit state journal error.");
+
+        self.write_header(block.header());
 
         // Insert [CurrentHash : hash].
         let current_hash_key = db_indexes::CurrentHash.get_index();
@@ -223,14 +238,29 @@ impl Executor {
             .insert(
                 Some(DataCategory::Extra),
                 current_hash_key.to_vec(),
-                hash_value.clone(),
+                hash_value,
             )
             .expect("Insert block hash error.");
+    }
+
+    /// Write the header, and the hash of its height
+    pub fn write_header(&self, header: &Header) {
+        let hash = header.hash().unwrap();
+
+        // Insert [hash : block_header].
+        let hash_key = db_indexes::Hash2Header(hash).get_index();
+        self.db
+            .insert(Some(DataCategory::Headers), hash_key.to_vec(), header.rlp())
+            .expect("Insert block header error.");
 
         // Insert [height : hash]
-        let height_key = db_indexes::BlockNumber2Hash(height).get_index();
+        let height_key = db_indexes::BlockNumber2Hash(header.number()).get_index();
         self.db
-            .insert(Some(DataCategory::Extra), height_key.to_vec(), hash_value)
+            .insert(
+                Some(DataCategory::Extra),
+                height_key.to_vec(),
+                encode(&hash).to_vec(),
+            )
             .expect("Insert block hash error.");
     }
 
@@ -376,7 +406,7 @@ impl Executor {
         ExecutedBlock::create(
             &self.sys_config.block_sys_config,
             open_block,
-            self.state_db.clone(),
+            Arc::new(self.state_db.journaled()),
             current_state_root,
             last_hashes.into(),
             self.eth_compatibility,
@@ -444,6 +474,7 @@ mod tests {
     use crate::libexecutor::command::{Command, CommandResp};
     use crate::libexecutor::fsm::FSM;
     use crate::tests::helpers;
+    use crate::tracer::{op_name, CallType};
     use crate::types::block_number::{BlockTag, Tag};
     use cita_crypto::{CreateKey, KeyPair};
     use cita_types::Address;
@@ -537,6 +568,39 @@ mod tests {
         assert_eq!(closed_block_hash, current_hash);
     }
 
+    #[test]
+    fn test_trace_transaction() {
+        let keypair = KeyPair::gen_keypair();
+        let privkey = keypair.privkey();
+        let mut executor = helpers::init_executor();
+
+        let data = helpers::generate_contract();
+        let block = helpers::create_block(&executor, Address::from(0), &data, (0, 2), &privkey);
+        let mut closed_block = executor.into_fsm(block.clone());
+        executor.grow(&closed_block);
+        closed_block.clear_cache();
+
+        let tx_hash = block.body().transactions()[1].get_transaction_hash();
+        let trace = executor
+            .trace_transaction(block, tx_hash, Default::default())
+            .unwrap();
+        assert_eq!(trace.transaction_hash, tx_hash);
+        assert_eq!(trace.error, None);
+        assert!(trace.verify(None).is_ok());
+        let call = trace.call.unwrap();
+        assert_eq!(call.call_type, CallType::Create);
+        assert!(call.is_success());
+        assert_ne!(call.to, Address::from(0));
+        // The init code is replayed into opcode steps.
+        assert_eq!(call.replay_error, None);
+        assert_eq!(op_name(call.steps[0].op), "PUSH1");
+
+        let unknown = helpers::create_block(&executor, Address::from(0), &data, (2, 3), &privkey);
+        assert!(executor
+            .trace_transaction(unknown, Default::default(), Default::default())
+            .is_err());
+    }
+
     #[test]
     fn test_executor_exit() {
         let (_fsm_req_sender, fsm_req_receiver) = crossbeam_channel::unbounded();
@@ -0,0 +1,345 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Download the state of a block from peers.
+//!
+//! Every trie node, contract code and ABI is saved under the hash of its
+//! content, so the state is downloaded node by node from the state root:
+//! a node is verified by its hash, and decoded to find the nodes it refers.
+//! The nodes already in the database are walked locally, so an interrupted
+//! download goes on without fetching them again.
+
+use cita_trie::DB;
+use cita_types::H256;
+use hashable::HASH_NULL_RLP;
+use hasher::{Hasher, HasherKeccak};
+use rlp::{DecoderError, UntrustedRlp};
+use std::collections::{HashMap, HashSet};
+pub use types::state_sync::StateSyncMessage;
+use types::state_sync::MAX_RESPONSE_BYTES;
+use types::Bytes;
+
+/// Nodes asked by one request.
+pub const MAX_NODES_PER_REQUEST: usize = 384;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeKind {
+    /// A node of the account trie.
+    Account,
+    /// A node of a storage trie.
+    Storage,
+    /// Code or ABI of a contract.
+    Code,
+}
+
+pub struct StateSync {
+    root: H256,
+    // Nodes to walk or download, the last one first
+    pending: Vec<(H256, NodeKind)>,
+    requested: HashMap<H256, NodeKind>,
+    imported: usize,
+    empty_code_hash: H256,
+}
+
+impl StateSync {
+    pub fn new(root: H256) -> Self {
+        let mut sync = StateSync {
+            root,
+            pending: Vec::new(),
+            requested: HashMap::new(),
+            imported: 0,
+            empty_code_hash: keccak(&[]),
+        };
+        sync.push(root, NodeKind::Account);
+        sync
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root
+    }
+
+    /// Nodes downloaded so far.
+    pub fn imported(&self) -> usize {
+        self.imported
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty() && self.requested.is_empty()
+    }
+
+    fn push(&mut self, hash: H256, kind: NodeKind) {
+        // Empty tries and codes are not saved.
+        if hash != HASH_NULL_RLP && hash != self.empty_code_hash {
+            self.pending.push((hash, kind));
+        }
+    }
+
+    fn expand(&mut self, kind: NodeKind, node: &[u8]) -> Result<(), DecoderError> {
+        for (hash, kind) in node_children(kind, node)? {
+            self.push(hash, kind);
+        }
+        Ok(())
+    }
+
+    /// Take at most `max` nodes to download, walking the ones found in the database.
+    pub fn next_request<D: DB>(&mut self, db: &D, max: usize) -> Result<Vec<H256>, String> {
+        let mut hashes = Vec::new();
+        while hashes.len() < max {
+            let (hash, kind) = match self.pending.pop() {
+                Some(node) => node,
+                None => break,
+            };
+            if self.requested.contains_key(&hash) {
+                continue;
+            }
+            match db.get(&hash).map_err(|e| format!("{:?}", e))? {
+                Some(node) => self
+                    .expand(kind, &node)
+                    .map_err(|e| format!("invalid node {:?} in database: {:?}", hash, e))?,
+                None => {
+                    self.requested.insert(hash, kind);
+                    hashes.push(hash);
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Import the nodes answered to a request, returns the number of them.
+    ///
+    /// The nodes not answered are requested again later, and the whole answer
+    /// is dropped if any node is not requested or can not be decoded.
+    pub fn import<D: DB>(
+        &mut self,
+        db: &D,
+        request: &[H256],
+        nodes: Vec<Bytes>,
+    ) -> Result<usize, String> {
+        let asked: HashSet<&H256> = request.iter().collect();
+        let mut keys = Vec::with_capacity(nodes.len());
+        let mut children = Vec::new();
+        for node in &nodes {
+            let hash = keccak(node);
+            let kind = match self.requested.get(&hash) {
+                Some(kind) if asked.contains(&hash) => *kind,
+                _ => {
+                    self.retry(request);
+                    return Err(format!("node {:?} is not requested", hash));
+                }
+            };
+            match node_children(kind, node) {
+                Ok(node_children) => children.extend(node_children),
+                Err(e) => {
+                    self.retry(request);
+                    return Err(format!("invalid node {:?}: {:?}", hash, e));
+                }
+            }
+            keys.push(hash.to_vec());
+        }
+
+        let count = nodes.len();
+        db.insert_batch(keys.clone(), nodes)
+            .and_then(|_| db.flush())
+            .map_err(|e| format!("{:?}", e))?;
+        for key in &keys {
+            self.requested.remove(&H256::from_slice(key));
+        }
+        for (hash, kind) in children {
+            self.push(hash, kind);
+        }
+        // The nodes not answered
+        let missing: Vec<H256> = request
+            .iter()
+            .filter(|hash| self.requested.contains_key(hash))
+            .cloned()
+            .collect();
+        self.retry(&missing);
+        self.imported += count;
+        Ok(count)
+    }
+
+    /// Download the nodes of a request again.
+    pub fn retry(&mut self, request: &[H256]) {
+        for hash in request {
+            if let Some(kind) = self.requested.remove(hash) {
+                self.pending.push((*hash, kind));
+            }
+        }
+    }
+}
+
+/// Read the nodes asked by a peer, the unknown ones are skipped.
+pub fn read_nodes<D: DB>(db: &D, hashes: &[H256]) -> Vec<Bytes> {
+    let mut nodes = Vec::new();
+    let mut size = 0;
+    for hash in hashes.iter().take(MAX_NODES_PER_REQUEST) {
+        if let Ok(Some(node)) = db.get(hash) {
+            size += node.len();
+            nodes.push(node);
+            if size >= MAX_RESPONSE_BYTES {
+                break;
+            }
+        }
+    }
+    nodes
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from_slice(&HasherKeccak::new().digest(data))
+}
+
+fn node_children(kind: NodeKind, node: &[u8]) -> Result<Vec<(H256, NodeKind)>, DecoderError> {
+    let mut children = Vec::new();
+    if kind != NodeKind::Code {
+        trie_node_children(kind, &UntrustedRlp::new(node), &mut children)?;
+    }
+    Ok(children)
+}
+
+fn trie_node_children(
+    kind: NodeKind,
+    node: &UntrustedRlp,
+    children: &mut Vec<(H256, NodeKind)>,
+) -> Result<(), DecoderError> {
+    // The empty node
+    if !node.is_list() {
+        return Ok(());
+    }
+    match node.item_count()? {
+        // Leaf or extension, the high nibble of the path tells which one.
+        2 => {
+            let path = node.at(0)?.data()?;
+            let is_leaf = path.first().map(|b| b >> 4 >= 2).unwrap_or(false);
+            if is_leaf {
+                value_children(kind, node.at(1)?.data()?, children)
+            } else {
+                child_children(kind, &node.at(1)?, children)
+            }
+        }
+        // Branch
+        17 => {
+            for i in 0..16 {
+                child_children(kind, &node.at(i)?, children)?;
+            }
+            let value = node.at(16)?.data()?;
+            if value.is_empty() {
+                Ok(())
+            } else {
+                value_children(kind, value, children)
+            }
+        }
+        _ => Err(DecoderError::RlpIncorrectListLen),
+    }
+}
+
+// A child is the hash of a node, or the node itself if its encoding is
+// shorter than a hash.
+fn child_children(
+    kind: NodeKind,
+    child: &UntrustedRlp,
+    children: &mut Vec<(H256, NodeKind)>,
+) -> Result<(), DecoderError> {
+    if child.is_list() {
+        return trie_node_children(kind, child, children);
+    }
+    let data = child.data()?;
+    match data.len() {
+        0 => Ok(()),
+        32 => {
+            children.push((H256::from_slice(data), kind));
+            Ok(())
+        }
+        _ => Err(DecoderError::RlpInvalidLength),
+    }
+}
+
+fn value_children(
+    kind: NodeKind,
+    value: &[u8],
+    children: &mut Vec<(H256, NodeKind)>,
+) -> Result<(), DecoderError> {
+    if kind == NodeKind::Account {
+        // nonce, balance, storage root, code hash and abi hash
+        let account = UntrustedRlp::new(value);
+        if account.item_count()? != 5 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        children.push((account.val_at(2)?, NodeKind::Storage));
+        children.push((account.val_at(3)?, NodeKind::Code));
+        children.push((account.val_at(4)?, NodeKind::Code));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_nodes, StateSync, MAX_NODES_PER_REQUEST};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::state::{MemoryDB, State, StateObjectInfo};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sync_state() {
+        let source = Arc::new(MemoryDB::new(false));
+        let mut state = State::new(Arc::clone(&source)).unwrap();
+        for i in 1..100u64 {
+            let address = Address::from(i);
+            state.new_contract(&address, U256::from(i), U256::from(1), vec![i as u8; 40]);
+            state
+                .set_storage(&address, H256::from(1), H256::from(i))
+                .unwrap();
+        }
+        state.commit().unwrap();
+        let root = state.root;
+
+        let target = Arc::new(MemoryDB::new(false));
+        let mut sync = StateSync::new(root);
+        while !sync.is_complete() {
+            let request = sync.next_request(&*target, MAX_NODES_PER_REQUEST).unwrap();
+            // Answer a part of the request only.
+            let answered = &request[..(request.len() + 1) / 2];
+            let nodes = read_nodes(&*source, answered);
+            sync.import(&*target, &request, nodes).unwrap();
+        }
+        assert!(sync.imported() > 0);
+
+        let mut state = State::from_existing(Arc::clone(&target), root).unwrap();
+        let address = Address::from(42);
+        assert_eq!(state.balance(&address).unwrap(), U256::from(42));
+        assert_eq!(
+            state.get_storage(&address, &H256::from(1)).unwrap(),
+            H256::from(42)
+        );
+        assert_eq!(state.code(&address).unwrap(), vec![42u8; 40]);
+
+        // Everything is in the database now.
+        let mut sync = StateSync::new(root);
+        assert!(sync.next_request(&*target, 1).unwrap().is_empty());
+        assert!(sync.is_complete());
+    }
+
+    #[test]
+    fn test_reject_unknown_node() {
+        let db = Arc::new(MemoryDB::new(false));
+        let mut sync = StateSync::new(H256::from(1));
+        let request = sync.next_request(&*db, MAX_NODES_PER_REQUEST).unwrap();
+        assert_eq!(request, vec![H256::from(1)]);
+        assert!(sync.import(&*db, &request, vec![vec![0x80]]).is_err());
+        // Requested again
+        assert_eq!(
+            sync.next_request(&*db, MAX_NODES_PER_REQUEST).unwrap(),
+            request
+        );
+    }
+}
@@ -33,6 +33,9 @@ pub struct DataProviderMock {
     pub db: BTreeMap<Address, Account>,
     pub db_origin: BTreeMap<Address, Account>,
     pub refund: BTreeMap<Address, u64>,
+    /// Outputs of the calls to an address with an input, returned instead
+    /// of running its code.
+    pub call_outputs: BTreeMap<(Address, Vec<u8>), Vec<u8>>,
 }
 
 impl DataProvider for DataProviderMock {
@@ -135,6 +138,16 @@ impl DataProvider for DataProviderMock {
     ) -> (Result<InterpreterResult, Error>) {
         match opcode {
             OpCode::CALL => {
+                if let Some(output) = self
+                    .call_outputs
+                    .get(&(params.receiver, params.input.clone()))
+                {
+                    return Ok(InterpreterResult::Normal(
+                        output.clone(),
+                        params.gas_limit,
+                        vec![],
+                    ));
+                }
                 let mut it = Interpreter::new(
                     Context::default(),
                     InterpreterConf::default(),
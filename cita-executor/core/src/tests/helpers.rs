@@ -27,9 +27,11 @@ use std::time::UNIX_EPOCH;
 use self::rustc_serialize::hex::FromHex;
 use self::tempdir::TempDir;
 
+use crate::journaldb::JournalDBType;
 use crate::libexecutor::block::{BlockBody, ClosedBlock, OpenBlock};
 use crate::libexecutor::command;
 use crate::libexecutor::executor::Executor;
+use crate::trie_db::DEFAULT_CACHE_SIZE;
 use crate::types::header::OpenHeader;
 use crate::types::transaction::SignedTransaction;
 
@@ -122,6 +124,8 @@ pub fn init_executor2(
         command_req_receiver,
         command_resp_sender,
         false,
+        JournalDBType::Archive,
+        DEFAULT_CACHE_SIZE,
     );
     executor
 }
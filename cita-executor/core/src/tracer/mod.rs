@@ -0,0 +1,628 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transaction tracing.
+//!
+//! The tracer is shared through `data_provider::Store`, so every nested
+//! CALL/CREATE frame, storage write and state read performed by the VM is
+//! recorded.
+//!
+//! cita-vm's interpreter doesn't expose a per-instruction hook, so opcode
+//! steps are rebuilt afterwards: each frame is replayed by `replay` against
+//! the state reads recorded for it. The replay is checked against the output
+//! and gas of the real execution, a frame which doesn't match gets a
+//! `replay_error`, and `TransactionTrace::verify` refuses the whole trace
+//! instead of returning it silently wrong. A traced transaction is also
+//! checked against its stored receipt.
+
+mod replay;
+
+pub use self::replay::op_name;
+
+use cita_types::{Address, H256, U256};
+use cita_vm::evm::{self, InterpreterParams, InterpreterResult, OpCode};
+use rustc_hex::ToHex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use types::Bytes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallType {
+    Call,
+    CallCode,
+    DelegateCall,
+    StaticCall,
+    Create,
+    Create2,
+}
+
+impl CallType {
+    pub fn from_opcode(opcode: OpCode) -> Option<Self> {
+        match opcode {
+            OpCode::CALL => Some(CallType::Call),
+            OpCode::CALLCODE => Some(CallType::CallCode),
+            OpCode::DELEGATECALL => Some(CallType::DelegateCall),
+            OpCode::STATICCALL => Some(CallType::StaticCall),
+            OpCode::CREATE => Some(CallType::Create),
+            OpCode::CREATE2 => Some(CallType::Create2),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CallType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallType::Call => write!(f, "CALL"),
+            CallType::CallCode => write!(f, "CALLCODE"),
+            CallType::DelegateCall => write!(f, "DELEGATECALL"),
+            CallType::StaticCall => write!(f, "STATICCALL"),
+            CallType::Create => write!(f, "CREATE"),
+            CallType::Create2 => write!(f, "CREATE2"),
+        }
+    }
+}
+
+/// A storage slot written inside a frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageWrite {
+    /// Global write order inside the transaction.
+    pub sequence: u64,
+    pub address: Address,
+    pub key: H256,
+    pub original: H256,
+    pub value: H256,
+}
+
+/// A state read made by the VM through the data provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateRead {
+    Balance(Address),
+    CodeSize(Address),
+    Code(Address),
+    CodeHash(Address),
+    BlockHash(U256),
+    Storage(Address, H256),
+    Exist(Address),
+    Empty(Address),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadValue {
+    U256(U256),
+    Hash(H256),
+    Code(Bytes),
+    Bool(bool),
+}
+
+/// Options of a trace request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceOptions {
+    /// Replay the frames into opcode steps.
+    pub steps: bool,
+    /// Keep the stack of every step.
+    pub stack: bool,
+    /// Keep the memory written by every step.
+    pub memory: bool,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            steps: true,
+            stack: true,
+            memory: true,
+        }
+    }
+}
+
+impl TraceOptions {
+    /// Parse `{"disableSteps": bool, "disableStack": bool, "disableMemory": bool}`,
+    /// a missing field keeps the default.
+    pub fn from_json(value: &Value) -> Self {
+        let disabled = |name: &str| value[name].as_bool().unwrap_or(false);
+        TraceOptions {
+            steps: !disabled("disableSteps"),
+            stack: !disabled("disableStack"),
+            memory: !disabled("disableMemory"),
+        }
+    }
+}
+
+/// Memory written by a step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryWrite {
+    pub offset: u64,
+    pub data: Bytes,
+}
+
+/// The slot loaded by SLOAD or stored by SSTORE.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageAccess {
+    pub key: H256,
+    pub value: H256,
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub pc: u64,
+    pub op: u8,
+    /// Gas left before the instruction.
+    pub gas: u64,
+    /// Gas charged by the instruction, including the gas given to a child
+    /// frame.
+    pub gas_cost: u64,
+    pub depth: u64,
+    /// The stack before the instruction, top last. Empty if disabled.
+    pub stack: Vec<U256>,
+    pub memory: Option<MemoryWrite>,
+    /// Memory size in bytes after the instruction.
+    pub memory_size: u64,
+    pub storage: Option<StorageAccess>,
+    /// Set on the instruction which halted the frame with an error.
+    pub error: Option<String>,
+}
+
+/// What the replay needs to run a frame again.
+#[derive(Debug, Clone, Default)]
+struct FrameEnv {
+    code: Bytes,
+    address: Address,
+    origin: Address,
+    gas_price: U256,
+    read_only: bool,
+    /// First value of every state read, keyed by the number of child frames
+    /// finished before the read.
+    reads: HashMap<(usize, StateRead), ReadValue>,
+}
+
+/// The net effect of a transaction on a storage slot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageChange {
+    pub address: Address,
+    pub key: H256,
+    pub original: H256,
+    pub value: H256,
+}
+
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    pub call_type: CallType,
+    pub depth: u64,
+    pub from: Address,
+    /// Zero until the created address is known for CREATE frames.
+    pub to: Address,
+    pub value: U256,
+    pub gas: u64,
+    pub gas_used: u64,
+    pub input: Bytes,
+    pub output: Bytes,
+    /// `None` if the frame succeeded.
+    pub error: Option<String>,
+    pub storage_writes: Vec<StorageWrite>,
+    pub calls: Vec<CallFrame>,
+    /// Opcode steps, empty until replayed and for frames without code
+    /// (native contracts, precompiles and plain transfers).
+    pub steps: Vec<Step>,
+    /// Set when the replay doesn't match the real execution.
+    pub replay_error: Option<String>,
+    env: FrameEnv,
+}
+
+impl CallFrame {
+    fn new(call_type: CallType, params: &InterpreterParams) -> Self {
+        let is_create = call_type == CallType::Create || call_type == CallType::Create2;
+        let (to, code) = if is_create {
+            // The init code is passed as input, the address is set by
+            // `Tracer::created`.
+            (Address::zero(), params.input.clone())
+        } else {
+            (params.receiver, params.contract.code_data.clone())
+        };
+        CallFrame {
+            call_type,
+            depth: params.depth,
+            from: params.sender,
+            to,
+            value: params.value,
+            gas: params.gas_limit,
+            gas_used: 0,
+            input: params.input.clone(),
+            output: Vec::new(),
+            error: None,
+            storage_writes: Vec::new(),
+            calls: Vec::new(),
+            steps: Vec::new(),
+            replay_error: None,
+            env: FrameEnv {
+                code,
+                address: if is_create { to } else { params.address },
+                origin: params.origin,
+                gas_price: params.gas_price,
+                read_only: params.read_only,
+                reads: HashMap::new(),
+            },
+        }
+    }
+
+    pub fn is_create(&self) -> bool {
+        self.call_type == CallType::Create || self.call_type == CallType::Create2
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The first replay mismatch of the frame or its children.
+    pub fn find_replay_error(&self) -> Option<&str> {
+        self.replay_error
+            .as_ref()
+            .map(String::as_str)
+            .or_else(|| self.calls.iter().find_map(CallFrame::find_replay_error))
+    }
+
+    /// Storage writes kept in the final state, a write is dropped when the
+    /// frame or one of its ancestors failed.
+    fn committed_writes(&self, writes: &mut Vec<StorageWrite>) {
+        if !self.is_success() {
+            return;
+        }
+        writes.extend(self.storage_writes.iter().cloned());
+        for call in &self.calls {
+            call.committed_writes(writes);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tracer {
+    stack: Vec<CallFrame>,
+    root: Option<CallFrame>,
+    sequence: u64,
+    context: evm::Context,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Tracer::default()
+    }
+
+    /// The block context the transaction runs in, used by the replay.
+    pub fn set_context(&mut self, context: evm::Context) {
+        self.context = context;
+    }
+
+    pub fn enter(&mut self, call_type: CallType, params: &InterpreterParams) {
+        self.stack.push(CallFrame::new(call_type, params));
+    }
+
+    pub fn exit<E: fmt::Debug>(&mut self, result: &Result<InterpreterResult, E>) {
+        let mut frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => {
+                warn!("tracer exit without frame");
+                return;
+            }
+        };
+        match result {
+            Ok(InterpreterResult::Normal(output, gas_left, _)) => {
+                frame.output = output.clone();
+                frame.gas_used = frame.gas.saturating_sub(*gas_left);
+            }
+            Ok(InterpreterResult::Revert(output, gas_left)) => {
+                frame.output = output.clone();
+                frame.gas_used = frame.gas.saturating_sub(*gas_left);
+                frame.error = Some("Reverted".to_owned());
+            }
+            Ok(InterpreterResult::Create(output, gas_left, _, address)) => {
+                frame.output = output.clone();
+                frame.gas_used = frame.gas.saturating_sub(*gas_left);
+                frame.to = *address;
+                frame.env.address = *address;
+            }
+            Err(e) => {
+                frame.gas_used = frame.gas;
+                frame.error = Some(format!("{:?}", e));
+            }
+        }
+        match self.stack.last_mut() {
+            Some(parent) => parent.calls.push(frame),
+            None => self.root = Some(frame),
+        }
+    }
+
+    pub fn storage_write(&mut self, address: Address, key: H256, original: H256, value: H256) {
+        self.sequence += 1;
+        let write = StorageWrite {
+            sequence: self.sequence,
+            address,
+            key,
+            original,
+            value,
+        };
+        if let Some(frame) = self.stack.last_mut() {
+            frame.storage_writes.push(write);
+        }
+    }
+
+    /// The address of the contract being created by the current frame.
+    pub fn created(&mut self, address: Address) {
+        if let Some(frame) = self.stack.last_mut() {
+            frame.to = address;
+            frame.env.address = address;
+        }
+    }
+
+    /// Record a state read of the current frame, only the first value read
+    /// between two child frames is kept.
+    pub fn read(&mut self, read: StateRead, value: ReadValue) {
+        if let Some(frame) = self.stack.last_mut() {
+            let segment = frame.calls.len();
+            frame.env.reads.entry((segment, read)).or_insert(value);
+        }
+    }
+
+    /// The finished call tree, with the opcode steps if asked for.
+    pub fn trace_root(&self, options: &TraceOptions) -> Option<CallFrame> {
+        let mut root = self.root.clone()?;
+        if options.steps {
+            replay::replay(&mut root, &self.context, options);
+        }
+        Some(root)
+    }
+
+    pub fn root(&self) -> Option<&CallFrame> {
+        self.root.as_ref()
+    }
+
+    pub fn into_root(self) -> Option<CallFrame> {
+        self.root
+    }
+}
+
+/// Trace result of a transaction or a call.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionTrace {
+    pub transaction_hash: H256,
+    pub quota_used: U256,
+    pub output: Bytes,
+    /// The executed exception or error of the transaction.
+    pub error: Option<String>,
+    /// The top level frame, `None` for transactions which don't enter the VM
+    /// (store, abi store and amend data).
+    pub call: Option<CallFrame>,
+}
+
+impl TransactionTrace {
+    /// Net storage changes of the transaction, ordered by the first write.
+    pub fn storage_changes(&self) -> Vec<StorageChange> {
+        let mut writes = Vec::new();
+        if let Some(ref call) = self.call {
+            call.committed_writes(&mut writes);
+        }
+        writes.sort_by_key(|write| write.sequence);
+
+        let mut indexes: HashMap<(Address, H256), usize> = HashMap::new();
+        let mut changes: Vec<StorageChange> = Vec::new();
+        for write in writes {
+            match indexes.get(&(write.address, write.key)) {
+                Some(&index) => changes[index].value = write.value,
+                None => {
+                    indexes.insert((write.address, write.key), changes.len());
+                    changes.push(StorageChange {
+                        address: write.address,
+                        key: write.key,
+                        original: write.original,
+                        value: write.value,
+                    });
+                }
+            }
+        }
+        changes.retain(|change| change.original != change.value);
+        changes
+    }
+
+    /// Refuse a trace which diverged from the chain: a replayed frame which
+    /// doesn't match the execution, or for a transaction, an execution whose
+    /// status or quota used differs from `receipt`, given as
+    /// `(quota_used, failed)`.
+    ///
+    /// A failed receipt may be charged the whole quota limit, so only the
+    /// quota used of a successful one is compared.
+    pub fn verify(&self, receipt: Option<(U256, bool)>) -> Result<(), String> {
+        if let Some(error) = self.call.as_ref().and_then(CallFrame::find_replay_error) {
+            return Err(format!("Trace Error replay diverged: {}", error));
+        }
+        if let Some((quota_used, failed)) = receipt {
+            if failed != self.error.is_some() {
+                return Err(format!(
+                    "Trace Error execution ended with {:?}, the receipt {}",
+                    self.error,
+                    if failed { "failed" } else { "succeeded" }
+                ));
+            }
+            if !failed && quota_used != self.quota_used {
+                return Err(format!(
+                    "Trace Error quota used {} differs from the receipt {}",
+                    self.quota_used, quota_used
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let changes: Vec<Value> = self
+            .storage_changes()
+            .iter()
+            .map(|change| {
+                json!({
+                    "address": format!("{:#x}", change.address),
+                    "key": format!("{:#x}", change.key),
+                    "original": format!("{:#x}", change.original),
+                    "value": format!("{:#x}", change.value),
+                })
+            })
+            .collect();
+        json!({
+            "transactionHash": format!("{:#x}", self.transaction_hash),
+            "quotaUsed": format!("{:#x}", self.quota_used),
+            "output": hex(&self.output),
+            "error": self.error,
+            "call": self.call.as_ref().map(frame_json),
+            "storageChanges": changes,
+        })
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    format!("0x{}", data.to_hex())
+}
+
+fn frame_json(frame: &CallFrame) -> Value {
+    json!({
+        "type": frame.call_type.to_string(),
+        "depth": frame.depth,
+        "from": format!("{:#x}", frame.from),
+        "to": format!("{:#x}", frame.to),
+        "value": format!("{:#x}", frame.value),
+        "gas": frame.gas,
+        "gasUsed": frame.gas_used,
+        "input": hex(&frame.input),
+        "output": hex(&frame.output),
+        "error": frame.error,
+        "replayError": frame.replay_error,
+        "steps": frame.steps.iter().map(step_json).collect::<Vec<_>>(),
+        "calls": frame.calls.iter().map(frame_json).collect::<Vec<_>>(),
+    })
+}
+
+fn step_json(step: &Step) -> Value {
+    json!({
+        "pc": step.pc,
+        "op": op_name(step.op),
+        "gas": step.gas,
+        "gasCost": step.gas_cost,
+        "depth": step.depth,
+        "stack": step
+            .stack
+            .iter()
+            .map(|item| format!("{:#x}", item))
+            .collect::<Vec<_>>(),
+        "memory": step.memory.as_ref().map(|write| {
+            json!({"offset": write.offset, "data": hex(&write.data)})
+        }),
+        "memorySize": step.memory_size,
+        "storage": step.storage.as_ref().map(|access| {
+            json!({
+                "key": format!("{:#x}", access.key),
+                "value": format!("{:#x}", access.value),
+            })
+        }),
+        "error": step.error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallType, Tracer, TransactionTrace};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::evm::{InterpreterParams, InterpreterResult};
+
+    fn params(depth: u64) -> InterpreterParams {
+        let mut params = InterpreterParams::default();
+        params.sender = Address::from(1);
+        params.receiver = Address::from(2);
+        params.gas_limit = 100;
+        params.depth = depth;
+        params
+    }
+
+    #[test]
+    fn test_call_tree() {
+        let mut tracer = Tracer::new();
+        tracer.enter(CallType::Call, &params(0));
+        tracer.enter(CallType::StaticCall, &params(1));
+        let r: Result<_, ()> = Ok(InterpreterResult::Revert(vec![1], 40));
+        tracer.exit(&r);
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![2], 10, vec![]));
+        tracer.exit(&r);
+
+        let root = tracer.into_root().unwrap();
+        assert_eq!(root.call_type, CallType::Call);
+        assert_eq!(root.gas_used, 90);
+        assert_eq!(root.output, vec![2]);
+        assert!(root.is_success());
+        assert_eq!(root.calls.len(), 1);
+        assert_eq!(root.calls[0].call_type, CallType::StaticCall);
+        assert_eq!(root.calls[0].gas_used, 60);
+        assert_eq!(root.calls[0].error, Some("Reverted".to_owned()));
+    }
+
+    #[test]
+    fn test_storage_changes_skip_reverted_frames() {
+        let address = Address::from(2);
+        let (k1, k2) = (H256::from(1), H256::from(2));
+
+        let mut tracer = Tracer::new();
+        tracer.enter(CallType::Call, &params(0));
+        tracer.storage_write(address, k1, H256::zero(), H256::from(10));
+        tracer.enter(CallType::Call, &params(1));
+        tracer.storage_write(address, k2, H256::zero(), H256::from(20));
+        let r: Result<InterpreterResult, _> = Err("OutOfGas");
+        tracer.exit(&r);
+        tracer.storage_write(address, k1, H256::from(10), H256::from(11));
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 0, vec![]));
+        tracer.exit(&r);
+
+        let trace = TransactionTrace {
+            call: tracer.into_root(),
+            ..Default::default()
+        };
+        let changes = trace.storage_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, k1);
+        assert_eq!(changes[0].original, H256::zero());
+        assert_eq!(changes[0].value, H256::from(11));
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut tracer = Tracer::new();
+        tracer.enter(CallType::Call, &params(0));
+        tracer.enter(CallType::Call, &params(1));
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 30, vec![]));
+        tracer.exit(&r);
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 10, vec![]));
+        tracer.exit(&r);
+
+        let mut trace = TransactionTrace {
+            quota_used: U256::from(21090),
+            call: tracer.into_root(),
+            ..Default::default()
+        };
+        assert!(trace.verify(None).is_ok());
+        assert!(trace.verify(Some((U256::from(21090), false))).is_ok());
+        assert!(trace.verify(Some((U256::from(21091), false))).is_err());
+        assert!(trace.verify(Some((U256::from(21090), true))).is_err());
+
+        trace.error = Some("Reverted".to_owned());
+        assert!(trace.verify(Some((U256::from(30000), true))).is_ok());
+
+        trace.call.as_mut().unwrap().calls[0].replay_error = Some("differs".to_owned());
+        assert!(trace.verify(None).is_err());
+    }
+}
@@ -0,0 +1,1250 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opcode replay of a traced frame.
+//!
+//! A frame is run again by a small interpreter following the Petersburg
+//! rules `get_interpreter_conf` configures cita-vm with. State comes from the
+//! reads recorded for the frame, overlaid with the frame's own SSTOREs, and a
+//! CALL/CREATE takes its gas and result from the next recorded child frame.
+
+use super::{
+    CallFrame, CallType, MemoryWrite, ReadValue, StateRead, Step, StorageAccess, TraceOptions,
+};
+use cita_types::{Address, H256, U256, U512};
+use cita_vm::evm;
+use hasher::Hasher;
+use std::collections::{HashMap, HashSet};
+use types::Bytes;
+
+const STACK_LIMIT: usize = 1024;
+// Memory beyond this is out of gas long before.
+const MEMORY_LIMIT: u64 = 0xffff_ffff;
+
+const GAS_ZERO: u64 = 0;
+const GAS_BASE: u64 = 2;
+const GAS_VERY_LOW: u64 = 3;
+const GAS_LOW: u64 = 5;
+const GAS_MID: u64 = 8;
+const GAS_HIGH: u64 = 10;
+const GAS_EXP: u64 = 10;
+const GAS_EXP_BYTE: u64 = 50;
+const GAS_SHA3: u64 = 30;
+const GAS_SHA3_WORD: u64 = 6;
+const GAS_COPY_WORD: u64 = 3;
+const GAS_MEMORY: u64 = 3;
+const GAS_QUAD_COEFF_DIV: u64 = 512;
+const GAS_BALANCE: u64 = 400;
+const GAS_EXT_CODE: u64 = 700;
+const GAS_EXT_CODE_HASH: u64 = 400;
+const GAS_BLOCKHASH: u64 = 20;
+const GAS_SLOAD: u64 = 200;
+const GAS_SSTORE_SET: u64 = 20000;
+const GAS_SSTORE_RESET: u64 = 5000;
+const GAS_JUMPDEST: u64 = 1;
+const GAS_LOG: u64 = 375;
+const GAS_LOG_TOPIC: u64 = 375;
+const GAS_LOG_DATA: u64 = 8;
+const GAS_CREATE: u64 = 32000;
+const GAS_CALL: u64 = 700;
+const GAS_CALL_VALUE: u64 = 9000;
+const GAS_CALL_STIPEND: u64 = 2300;
+const GAS_NEW_ACCOUNT: u64 = 25000;
+const GAS_SELF_DESTRUCT: u64 = 5000;
+/// Per byte of code deployed by a successful CREATE, see `cita_executive::create`.
+const GAS_CODE_DEPOSIT: u64 = 200;
+
+pub fn op_name(op: u8) -> &'static str {
+    match op {
+        0x00 => "STOP",
+        0x01 => "ADD",
+        0x02 => "MUL",
+        0x03 => "SUB",
+        0x04 => "DIV",
+        0x05 => "SDIV",
+        0x06 => "MOD",
+        0x07 => "SMOD",
+        0x08 => "ADDMOD",
+        0x09 => "MULMOD",
+        0x0a => "EXP",
+        0x0b => "SIGNEXTEND",
+        0x10 => "LT",
+        0x11 => "GT",
+        0x12 => "SLT",
+        0x13 => "SGT",
+        0x14 => "EQ",
+        0x15 => "ISZERO",
+        0x16 => "AND",
+        0x17 => "OR",
+        0x18 => "XOR",
+        0x19 => "NOT",
+        0x1a => "BYTE",
+        0x1b => "SHL",
+        0x1c => "SHR",
+        0x1d => "SAR",
+        0x20 => "SHA3",
+        0x30 => "ADDRESS",
+        0x31 => "BALANCE",
+        0x32 => "ORIGIN",
+        0x33 => "CALLER",
+        0x34 => "CALLVALUE",
+        0x35 => "CALLDATALOAD",
+        0x36 => "CALLDATASIZE",
+        0x37 => "CALLDATACOPY",
+        0x38 => "CODESIZE",
+        0x39 => "CODECOPY",
+        0x3a => "GASPRICE",
+        0x3b => "EXTCODESIZE",
+        0x3c => "EXTCODECOPY",
+        0x3d => "RETURNDATASIZE",
+        0x3e => "RETURNDATACOPY",
+        0x3f => "EXTCODEHASH",
+        0x40 => "BLOCKHASH",
+        0x41 => "COINBASE",
+        0x42 => "TIMESTAMP",
+        0x43 => "NUMBER",
+        0x44 => "DIFFICULTY",
+        0x45 => "GASLIMIT",
+        0x50 => "POP",
+        0x51 => "MLOAD",
+        0x52 => "MSTORE",
+        0x53 => "MSTORE8",
+        0x54 => "SLOAD",
+        0x55 => "SSTORE",
+        0x56 => "JUMP",
+        0x57 => "JUMPI",
+        0x58 => "PC",
+        0x59 => "MSIZE",
+        0x5a => "GAS",
+        0x5b => "JUMPDEST",
+        0x60 => "PUSH1",
+        0x61 => "PUSH2",
+        0x62 => "PUSH3",
+        0x63 => "PUSH4",
+        0x64 => "PUSH5",
+        0x65 => "PUSH6",
+        0x66 => "PUSH7",
+        0x67 => "PUSH8",
+        0x68 => "PUSH9",
+        0x69 => "PUSH10",
+        0x6a => "PUSH11",
+        0x6b => "PUSH12",
+        0x6c => "PUSH13",
+        0x6d => "PUSH14",
+        0x6e => "PUSH15",
+        0x6f => "PUSH16",
+        0x70 => "PUSH17",
+        0x71 => "PUSH18",
+        0x72 => "PUSH19",
+        0x73 => "PUSH20",
+        0x74 => "PUSH21",
+        0x75 => "PUSH22",
+        0x76 => "PUSH23",
+        0x77 => "PUSH24",
+        0x78 => "PUSH25",
+        0x79 => "PUSH26",
+        0x7a => "PUSH27",
+        0x7b => "PUSH28",
+        0x7c => "PUSH29",
+        0x7d => "PUSH30",
+        0x7e => "PUSH31",
+        0x7f => "PUSH32",
+        0x80 => "DUP1",
+        0x81 => "DUP2",
+        0x82 => "DUP3",
+        0x83 => "DUP4",
+        0x84 => "DUP5",
+        0x85 => "DUP6",
+        0x86 => "DUP7",
+        0x87 => "DUP8",
+        0x88 => "DUP9",
+        0x89 => "DUP10",
+        0x8a => "DUP11",
+        0x8b => "DUP12",
+        0x8c => "DUP13",
+        0x8d => "DUP14",
+        0x8e => "DUP15",
+        0x8f => "DUP16",
+        0x90 => "SWAP1",
+        0x91 => "SWAP2",
+        0x92 => "SWAP3",
+        0x93 => "SWAP4",
+        0x94 => "SWAP5",
+        0x95 => "SWAP6",
+        0x96 => "SWAP7",
+        0x97 => "SWAP8",
+        0x98 => "SWAP9",
+        0x99 => "SWAP10",
+        0x9a => "SWAP11",
+        0x9b => "SWAP12",
+        0x9c => "SWAP13",
+        0x9d => "SWAP14",
+        0x9e => "SWAP15",
+        0x9f => "SWAP16",
+        0xa0 => "LOG0",
+        0xa1 => "LOG1",
+        0xa2 => "LOG2",
+        0xa3 => "LOG3",
+        0xa4 => "LOG4",
+        0xf0 => "CREATE",
+        0xf1 => "CALL",
+        0xf2 => "CALLCODE",
+        0xf3 => "RETURN",
+        0xf4 => "DELEGATECALL",
+        0xf5 => "CREATE2",
+        0xfa => "STATICCALL",
+        0xfd => "REVERT",
+        0xff => "SELFDESTRUCT",
+        _ => "INVALID",
+    }
+}
+
+/// Replay `frame` and all its children into opcode steps.
+pub fn replay(frame: &mut CallFrame, context: &evm::Context, options: &TraceOptions) {
+    if !frame.env.code.is_empty() {
+        let (steps, end, gas_left) = {
+            let mut machine = Machine::new(frame, context, options);
+            let end = machine.run();
+            (machine.steps, end, machine.gas)
+        };
+        frame.steps = steps;
+        frame.replay_error = check(frame, &end, gas_left);
+    }
+    for call in frame.calls.iter_mut() {
+        replay(call, context, options);
+    }
+}
+
+/// How a replayed frame ended.
+#[derive(Debug)]
+enum End {
+    Return(Bytes),
+    Revert(Bytes),
+    Error(String),
+}
+
+/// Compare the replay with the recorded execution.
+fn check(frame: &CallFrame, end: &End, gas_left: u64) -> Option<String> {
+    let recorded_left = frame.gas.saturating_sub(frame.gas_used);
+    let (output, expected_left) = match (end, &frame.error) {
+        (End::Return(output), None) => {
+            // The code deposit is paid after the init code returned.
+            let deposit = if frame.is_create() {
+                GAS_CODE_DEPOSIT * output.len() as u64
+            } else {
+                0
+            };
+            (output, recorded_left + deposit)
+        }
+        (End::Revert(output), Some(_))
+            if frame.error.as_ref().map(String::as_str) == Some("Reverted") =>
+        {
+            (output, recorded_left)
+        }
+        // Gas is all consumed, a CREATE also fails after a successful init
+        // code when the deposit or the code size is too large.
+        (End::Error(_), Some(_)) => return None,
+        (End::Return(_), Some(_)) if frame.is_create() => return None,
+        (end, error) => {
+            return Some(format!(
+                "replay ended with {:?}, execution with {:?}",
+                end, error
+            ))
+        }
+    };
+    if *output != frame.output {
+        return Some("replay output differs from execution".to_owned());
+    }
+    if gas_left != expected_left {
+        return Some(format!(
+            "replay gas left {} differs from execution {}",
+            gas_left, expected_left
+        ));
+    }
+    None
+}
+
+/// The result of a child frame, as seen by its caller.
+struct ChildResult {
+    success: bool,
+    reverted: bool,
+    gas: u64,
+    gas_left: u64,
+    output: Bytes,
+    address: Address,
+}
+
+struct Machine<'a> {
+    frame: &'a CallFrame,
+    context: &'a evm::Context,
+    options: &'a TraceOptions,
+    jump_dests: HashSet<usize>,
+    pc: usize,
+    gas: u64,
+    stack: Vec<U256>,
+    memory: Vec<u8>,
+    return_data: Bytes,
+    /// Storage written by this frame since the last child frame.
+    overlay: HashMap<H256, H256>,
+    /// Child frames consumed so far.
+    children: usize,
+    /// Gas charged by the current step.
+    cost: u64,
+    steps: Vec<Step>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(frame: &'a CallFrame, context: &'a evm::Context, options: &'a TraceOptions) -> Self {
+        Machine {
+            frame,
+            context,
+            options,
+            jump_dests: jump_dests(&frame.env.code),
+            pc: 0,
+            gas: frame.gas,
+            stack: Vec::new(),
+            memory: Vec::new(),
+            return_data: Vec::new(),
+            overlay: HashMap::new(),
+            children: 0,
+            cost: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> End {
+        loop {
+            // Running off the end of the code is a STOP.
+            let op = match self.frame.env.code.get(self.pc) {
+                Some(op) => *op,
+                None => return End::Return(Vec::new()),
+            };
+            let mut step = Step {
+                pc: self.pc as u64,
+                op,
+                gas: self.gas,
+                gas_cost: 0,
+                depth: self.frame.depth,
+                stack: if self.options.stack {
+                    self.stack.clone()
+                } else {
+                    Vec::new()
+                },
+                memory: None,
+                memory_size: 0,
+                storage: None,
+                error: None,
+            };
+            self.cost = 0;
+            let result = self.execute(op, &mut step);
+            step.gas_cost = self.cost;
+            step.memory_size = self.memory.len() as u64;
+            match result {
+                Ok(None) => self.steps.push(step),
+                Ok(Some(end)) => {
+                    self.steps.push(step);
+                    return end;
+                }
+                Err(e) => {
+                    step.error = Some(e.clone());
+                    self.steps.push(step);
+                    self.gas = 0;
+                    return End::Error(e);
+                }
+            }
+        }
+    }
+
+    fn charge(&mut self, cost: u64) -> Result<(), String> {
+        if cost > self.gas {
+            return Err("OutOfGas".to_owned());
+        }
+        self.gas -= cost;
+        self.cost += cost;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<U256, String> {
+        self.stack.pop().ok_or_else(|| "StackUnderflow".to_owned())
+    }
+
+    fn push(&mut self, value: U256) -> Result<(), String> {
+        if self.stack.len() >= STACK_LIMIT {
+            return Err("StackOverflow".to_owned());
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn require(&self, n: usize) -> Result<(), String> {
+        if self.stack.len() < n {
+            return Err("StackUnderflow".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Charge and grow the memory for `[offset, offset + size)`, returns the
+    /// range as usize.
+    fn expand(&mut self, offset: U256, size: U256) -> Result<(usize, usize), String> {
+        if size.is_zero() {
+            return Ok((0, 0));
+        }
+        let end = offset
+            .checked_add(size)
+            .filter(|end| *end <= U256::from(MEMORY_LIMIT))
+            .ok_or_else(|| "OutOfGas".to_owned())?
+            .as_u64();
+        let words = (end + 31) / 32;
+        let current = self.memory.len() as u64 / 32;
+        if words > current {
+            self.charge(memory_cost(words) - memory_cost(current))?;
+            self.memory.resize(words as usize * 32, 0);
+        }
+        Ok((offset.as_u64() as usize, size.as_u64() as usize))
+    }
+
+    fn write_memory(&mut self, offset: usize, data: &[u8], step: &mut Step) {
+        self.memory[offset..offset + data.len()].copy_from_slice(data);
+        if self.options.memory && !data.is_empty() {
+            step.memory = Some(MemoryWrite {
+                offset: offset as u64,
+                data: data.to_vec(),
+            });
+        }
+    }
+
+    fn state(&self, read: StateRead) -> Result<ReadValue, String> {
+        self.frame
+            .env
+            .reads
+            .get(&(self.children, read))
+            .cloned()
+            .ok_or_else(|| format!("missing state read {:?}", read))
+    }
+
+    fn storage(&self, key: H256) -> Result<H256, String> {
+        if let Some(value) = self.overlay.get(&key) {
+            return Ok(*value);
+        }
+        match self.state(StateRead::Storage(self.frame.env.address, key))? {
+            ReadValue::Hash(value) => Ok(value),
+            value => Err(format!("unexpected storage value {:?}", value)),
+        }
+    }
+
+    fn state_u256(&self, read: StateRead) -> Result<U256, String> {
+        match self.state(read)? {
+            ReadValue::U256(value) => Ok(value),
+            value => Err(format!("unexpected value {:?} of {:?}", value, read)),
+        }
+    }
+
+    fn is_empty(&self, address: Address) -> bool {
+        match self.state(StateRead::Empty(address)) {
+            Ok(ReadValue::Bool(empty)) => empty,
+            _ => match self.state(StateRead::Exist(address)) {
+                Ok(ReadValue::Bool(exist)) => !exist,
+                _ => false,
+            },
+        }
+    }
+
+    /// The next recorded child frame if it was made by `op`, a CALL/CREATE
+    /// without one failed before entering the child.
+    fn next_child(&self, call_type: CallType) -> Option<ChildResult> {
+        let child = self.frame.calls.get(self.children)?;
+        if child.call_type != call_type {
+            return None;
+        }
+        Some(ChildResult {
+            success: child.is_success(),
+            reverted: child.error.as_ref().map(String::as_str) == Some("Reverted"),
+            gas: child.gas,
+            gas_left: child.gas.saturating_sub(child.gas_used),
+            output: child.output.clone(),
+            address: child.to,
+        })
+    }
+
+    /// Account a finished child frame, own writes are visible in the state
+    /// read after it.
+    fn finish_child(&mut self, child: &ChildResult) {
+        self.children += 1;
+        self.overlay.clear();
+        if child.success || child.reverted {
+            self.gas += child.gas_left;
+        }
+    }
+
+    fn execute(&mut self, op: u8, step: &mut Step) -> Result<Option<End>, String> {
+        let frame = self.frame;
+        let env = &frame.env;
+        self.pc += 1;
+        match op {
+            0x00 => return Ok(Some(End::Return(Vec::new()))),
+            0x01..=0x0b | 0x10..=0x1d => self.arithmetic(op)?,
+            0x20 => {
+                let (offset, size) = (self.pop()?, self.pop()?);
+                let (offset, size) = self.expand(offset, size)?;
+                self.charge(GAS_SHA3 + GAS_SHA3_WORD * words(size as u64))?;
+                let hash = hasher::HasherKeccak::new().digest(&self.memory[offset..offset + size]);
+                self.push(U256::from_big_endian(&hash))?;
+            }
+            0x30 => {
+                self.charge(GAS_BASE)?;
+                self.push(address_to_u256(&env.address))?;
+            }
+            0x31 => {
+                let address = u256_to_address(&self.pop()?);
+                self.charge(GAS_BALANCE)?;
+                let balance = self.state_u256(StateRead::Balance(address))?;
+                self.push(balance)?;
+            }
+            0x32 => {
+                self.charge(GAS_BASE)?;
+                self.push(address_to_u256(&env.origin))?;
+            }
+            0x33 => {
+                self.charge(GAS_BASE)?;
+                self.push(address_to_u256(&frame.from))?;
+            }
+            0x34 => {
+                self.charge(GAS_BASE)?;
+                self.push(frame.value)?;
+            }
+            0x35 => {
+                let offset = self.pop()?;
+                self.charge(GAS_VERY_LOW)?;
+                let data = padded(self.input(), offset, 32);
+                self.push(U256::from_big_endian(&data))?;
+            }
+            0x36 => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.input().len()))?;
+            }
+            0x37 | 0x39 | 0x3e => {
+                let (dest, offset, size) = (self.pop()?, self.pop()?, self.pop()?);
+                let source = match op {
+                    0x37 => self.input().to_vec(),
+                    0x39 => env.code.clone(),
+                    _ => {
+                        let end = offset.checked_add(size);
+                        if end.map_or(true, |end| end > U256::from(self.return_data.len())) {
+                            return Err("OutOfBounds".to_owned());
+                        }
+                        self.return_data.clone()
+                    }
+                };
+                let (dest, len) = self.expand(dest, size)?;
+                self.charge(GAS_VERY_LOW + GAS_COPY_WORD * words(len as u64))?;
+                let data = padded(&source, offset, len);
+                self.write_memory(dest, &data, step);
+            }
+            0x38 => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(env.code.len()))?;
+            }
+            0x3a => {
+                self.charge(GAS_BASE)?;
+                self.push(env.gas_price)?;
+            }
+            0x3b => {
+                let address = u256_to_address(&self.pop()?);
+                self.charge(GAS_EXT_CODE)?;
+                let size = self.state_u256(StateRead::CodeSize(address))?;
+                self.push(size)?;
+            }
+            0x3c => {
+                let address = u256_to_address(&self.pop()?);
+                let (dest, offset, size) = (self.pop()?, self.pop()?, self.pop()?);
+                let (dest, len) = self.expand(dest, size)?;
+                self.charge(GAS_EXT_CODE + GAS_COPY_WORD * words(len as u64))?;
+                if len > 0 {
+                    let code = match self.state(StateRead::Code(address))? {
+                        ReadValue::Code(code) => code,
+                        value => return Err(format!("unexpected code {:?}", value)),
+                    };
+                    let data = padded(&code, offset, len);
+                    self.write_memory(dest, &data, step);
+                }
+            }
+            0x3d => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.return_data.len()))?;
+            }
+            0x3f => {
+                let address = u256_to_address(&self.pop()?);
+                self.charge(GAS_EXT_CODE_HASH)?;
+                let hash = match self.state(StateRead::CodeHash(address))? {
+                    ReadValue::Hash(hash) => hash,
+                    value => return Err(format!("unexpected code hash {:?}", value)),
+                };
+                self.push(U256::from_big_endian(&hash))?;
+            }
+            0x40 => {
+                let number = self.pop()?;
+                self.charge(GAS_BLOCKHASH)?;
+                let current = self.context.number;
+                let in_range = number < current && current - number <= U256::from(256);
+                let hash = if in_range {
+                    match self.state(StateRead::BlockHash(number))? {
+                        ReadValue::Hash(hash) => hash,
+                        value => return Err(format!("unexpected block hash {:?}", value)),
+                    }
+                } else {
+                    H256::zero()
+                };
+                self.push(U256::from_big_endian(&hash))?;
+            }
+            0x41 => {
+                self.charge(GAS_BASE)?;
+                self.push(address_to_u256(&self.context.coinbase))?;
+            }
+            0x42 => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.context.timestamp))?;
+            }
+            0x43 => {
+                self.charge(GAS_BASE)?;
+                self.push(self.context.number)?;
+            }
+            0x44 => {
+                self.charge(GAS_BASE)?;
+                self.push(self.context.difficulty)?;
+            }
+            0x45 => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.context.gas_limit))?;
+            }
+            0x50 => {
+                self.charge(GAS_BASE)?;
+                self.pop()?;
+            }
+            0x51 => {
+                let offset = self.pop()?;
+                self.charge(GAS_VERY_LOW)?;
+                let (offset, _) = self.expand(offset, U256::from(32))?;
+                let value = U256::from_big_endian(&self.memory[offset..offset + 32]);
+                self.push(value)?;
+            }
+            0x52 | 0x53 => {
+                let (offset, value) = (self.pop()?, self.pop()?);
+                self.charge(GAS_VERY_LOW)?;
+                if op == 0x52 {
+                    let (offset, _) = self.expand(offset, U256::from(32))?;
+                    let mut data = [0u8; 32];
+                    value.to_big_endian(&mut data);
+                    self.write_memory(offset, &data, step);
+                } else {
+                    let (offset, _) = self.expand(offset, U256::one())?;
+                    self.write_memory(offset, &[value.byte(0)], step);
+                }
+            }
+            0x54 => {
+                let key = u256_to_h256(&self.pop()?);
+                self.charge(GAS_SLOAD)?;
+                let value = self.storage(key)?;
+                step.storage = Some(StorageAccess { key, value });
+                self.push(U256::from_big_endian(&value))?;
+            }
+            0x55 => {
+                if env.read_only {
+                    return Err("MutableCallInStaticContext".to_owned());
+                }
+                let (key, value) = (u256_to_h256(&self.pop()?), u256_to_h256(&self.pop()?));
+                let current = self.storage(key)?;
+                if current.is_zero() && !value.is_zero() {
+                    self.charge(GAS_SSTORE_SET)?;
+                } else {
+                    self.charge(GAS_SSTORE_RESET)?;
+                }
+                self.overlay.insert(key, value);
+                step.storage = Some(StorageAccess { key, value });
+            }
+            0x56 => {
+                let dest = self.pop()?;
+                self.charge(GAS_MID)?;
+                self.jump(dest)?;
+            }
+            0x57 => {
+                let (dest, condition) = (self.pop()?, self.pop()?);
+                self.charge(GAS_HIGH)?;
+                if !condition.is_zero() {
+                    self.jump(dest)?;
+                }
+            }
+            0x58 => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.pc - 1))?;
+            }
+            0x59 => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.memory.len()))?;
+            }
+            0x5a => {
+                self.charge(GAS_BASE)?;
+                self.push(U256::from(self.gas))?;
+            }
+            0x5b => self.charge(GAS_JUMPDEST)?,
+            0x60..=0x7f => {
+                let size = usize::from(op - 0x5f);
+                self.charge(GAS_VERY_LOW)?;
+                let data = padded(&env.code, U256::from(self.pc), size);
+                self.pc += size;
+                self.push(U256::from_big_endian(&data))?;
+            }
+            0x80..=0x8f => {
+                let n = usize::from(op - 0x7f);
+                self.require(n)?;
+                self.charge(GAS_VERY_LOW)?;
+                let value = self.stack[self.stack.len() - n];
+                self.push(value)?;
+            }
+            0x90..=0x9f => {
+                let n = usize::from(op - 0x8f);
+                self.require(n + 1)?;
+                self.charge(GAS_VERY_LOW)?;
+                let top = self.stack.len() - 1;
+                self.stack.swap(top, top - n);
+            }
+            0xa0..=0xa4 => {
+                if env.read_only {
+                    return Err("MutableCallInStaticContext".to_owned());
+                }
+                let topics = u64::from(op - 0xa0);
+                let (offset, size) = (self.pop()?, self.pop()?);
+                self.require(topics as usize)?;
+                for _ in 0..topics {
+                    self.pop()?;
+                }
+                let (_, size) = self.expand(offset, size)?;
+                self.charge(GAS_LOG + GAS_LOG_TOPIC * topics + GAS_LOG_DATA * size as u64)?;
+            }
+            0xf0 | 0xf5 => self.create(op)?,
+            0xf1 | 0xf2 | 0xf4 | 0xfa => self.call(op, step)?,
+            0xf3 | 0xfd => {
+                let (offset, size) = (self.pop()?, self.pop()?);
+                let (offset, size) = self.expand(offset, size)?;
+                let output = self.memory[offset..offset + size].to_vec();
+                return Ok(Some(if op == 0xf3 {
+                    End::Return(output)
+                } else {
+                    End::Revert(output)
+                }));
+            }
+            0xff => {
+                if env.read_only {
+                    return Err("MutableCallInStaticContext".to_owned());
+                }
+                let beneficiary = u256_to_address(&self.pop()?);
+                let mut cost = GAS_SELF_DESTRUCT;
+                let balance = self.state_u256(StateRead::Balance(env.address))?;
+                if !balance.is_zero() && self.is_empty(beneficiary) {
+                    cost += GAS_NEW_ACCOUNT;
+                }
+                self.charge(cost)?;
+                return Ok(Some(End::Return(Vec::new())));
+            }
+            _ => return Err("InvalidOpcode".to_owned()),
+        }
+        Ok(None)
+    }
+
+    fn input(&self) -> &[u8] {
+        if self.frame.is_create() {
+            &[]
+        } else {
+            &self.frame.input
+        }
+    }
+
+    fn jump(&mut self, dest: U256) -> Result<(), String> {
+        if dest > U256::from(self.frame.env.code.len())
+            || !self.jump_dests.contains(&dest.as_usize())
+        {
+            return Err("BadJumpDestination".to_owned());
+        }
+        self.pc = dest.as_usize();
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, op: u8) -> Result<(), String> {
+        let cost = match op {
+            0x01 | 0x03 | 0x10..=0x1d => GAS_VERY_LOW,
+            0x02 | 0x04..=0x07 | 0x0b => GAS_LOW,
+            0x08 | 0x09 => GAS_MID,
+            _ => GAS_ZERO,
+        };
+        // Unary operators.
+        if op == 0x15 || op == 0x19 {
+            let a = self.pop()?;
+            self.charge(cost)?;
+            let r = if op == 0x15 {
+                bool_to_u256(a.is_zero())
+            } else {
+                !a
+            };
+            return self.push(r);
+        }
+        let (a, b) = (self.pop()?, self.pop()?);
+        let r = match op {
+            0x01 => a.overflowing_add(b).0,
+            0x02 => a.overflowing_mul(b).0,
+            0x03 => a.overflowing_sub(b).0,
+            0x04 => a.checked_div(b).unwrap_or_else(U256::zero),
+            0x05 => signed_div(a, b),
+            0x06 => a.checked_rem(b).unwrap_or_else(U256::zero),
+            0x07 => signed_rem(a, b),
+            0x08 | 0x09 => {
+                let n = self.pop()?;
+                self.charge(cost)?;
+                let r = if n.is_zero() {
+                    U256::zero()
+                } else if op == 0x08 {
+                    u512_to_u256((U512::from(a) + U512::from(b)) % U512::from(n))
+                } else {
+                    u512_to_u256(a.full_mul(b) % U512::from(n))
+                };
+                return self.push(r);
+            }
+            0x0a => {
+                let bytes = (256 - u64::from(b.leading_zeros()) + 7) / 8;
+                self.charge(GAS_EXP + GAS_EXP_BYTE * bytes)?;
+                return self.push(a.overflowing_pow(b).0);
+            }
+            0x0b => sign_extend(a, b),
+            0x10 => bool_to_u256(a < b),
+            0x11 => bool_to_u256(a > b),
+            0x12 => bool_to_u256(signed_lt(a, b)),
+            0x13 => bool_to_u256(signed_lt(b, a)),
+            0x14 => bool_to_u256(a == b),
+            0x16 => a & b,
+            0x17 => a | b,
+            0x18 => a ^ b,
+            0x1a => {
+                if a < U256::from(32) {
+                    U256::from(b.byte(31 - a.as_usize()))
+                } else {
+                    U256::zero()
+                }
+            }
+            0x1b => {
+                if a < U256::from(256) {
+                    b << a.as_usize()
+                } else {
+                    U256::zero()
+                }
+            }
+            0x1c => {
+                if a < U256::from(256) {
+                    b >> a.as_usize()
+                } else {
+                    U256::zero()
+                }
+            }
+            0x1d => arithmetic_shr(a, b),
+            _ => return Err("InvalidOpcode".to_owned()),
+        };
+        self.charge(cost)?;
+        self.push(r)
+    }
+
+    fn create(&mut self, op: u8) -> Result<(), String> {
+        if self.frame.env.read_only {
+            return Err("MutableCallInStaticContext".to_owned());
+        }
+        let (_value, offset, size) = (self.pop()?, self.pop()?, self.pop()?);
+        let mut cost = GAS_CREATE;
+        if op == 0xf5 {
+            self.pop()?;
+            cost += GAS_SHA3_WORD * words(size.low_u64());
+        }
+        self.expand(offset, size)?;
+        self.charge(cost)?;
+        let call_type = if op == 0xf0 {
+            CallType::Create
+        } else {
+            CallType::Create2
+        };
+        self.return_data = Vec::new();
+        match self.next_child(call_type) {
+            Some(child) => {
+                self.charge(child.gas)?;
+                self.finish_child(&child);
+                if child.success {
+                    self.push(address_to_u256(&child.address))?;
+                } else {
+                    if child.reverted {
+                        self.return_data = child.output;
+                    }
+                    self.push(U256::zero())?;
+                }
+            }
+            None => self.push(U256::zero())?,
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, op: u8, step: &mut Step) -> Result<(), String> {
+        // The gas passed is taken from the recorded child frame.
+        self.pop()?;
+        let to = u256_to_address(&self.pop()?);
+        let value = if op == 0xf1 || op == 0xf2 {
+            self.pop()?
+        } else {
+            U256::zero()
+        };
+        let (in_offset, in_size) = (self.pop()?, self.pop()?);
+        let (out_offset, out_size) = (self.pop()?, self.pop()?);
+        if op == 0xf1 && self.frame.env.read_only && !value.is_zero() {
+            return Err("MutableCallInStaticContext".to_owned());
+        }
+
+        let mut cost = GAS_CALL;
+        if !value.is_zero() {
+            cost += GAS_CALL_VALUE;
+            if op == 0xf1 && self.is_empty(to) {
+                cost += GAS_NEW_ACCOUNT;
+            }
+        }
+        self.expand(in_offset, in_size)?;
+        let (out_offset, out_size) = self.expand(out_offset, out_size)?;
+        self.charge(cost)?;
+
+        let call_type = match op {
+            0xf1 => CallType::Call,
+            0xf2 => CallType::CallCode,
+            0xf4 => CallType::DelegateCall,
+            _ => CallType::StaticCall,
+        };
+        self.return_data = Vec::new();
+        match self.next_child(call_type) {
+            Some(child) => {
+                // The stipend is given for free on top of the gas passed.
+                let stipend = if value.is_zero() { 0 } else { GAS_CALL_STIPEND };
+                self.charge(child.gas.saturating_sub(stipend))?;
+                self.finish_child(&child);
+                if child.success || child.reverted {
+                    self.return_data = child.output;
+                    let len = out_size.min(self.return_data.len());
+                    let data = self.return_data[..len].to_vec();
+                    self.write_memory(out_offset, &data, step);
+                }
+                self.push(bool_to_u256(child.success))?;
+            }
+            None => self.push(U256::zero())?,
+        }
+        Ok(())
+    }
+}
+
+fn jump_dests(code: &[u8]) -> HashSet<usize> {
+    let mut dests = HashSet::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = code[pc];
+        if op == 0x5b {
+            dests.insert(pc);
+        } else if op >= 0x60 && op <= 0x7f {
+            pc += usize::from(op - 0x5f);
+        }
+        pc += 1;
+    }
+    dests
+}
+
+fn words(size: u64) -> u64 {
+    (size + 31) / 32
+}
+
+fn memory_cost(words: u64) -> u64 {
+    GAS_MEMORY * words + words * words / GAS_QUAD_COEFF_DIV
+}
+
+/// `len` bytes of `data` from `offset`, zero padded.
+fn padded(data: &[u8], offset: U256, len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    if offset < U256::from(data.len()) {
+        let offset = offset.as_usize();
+        let end = data.len().min(offset + len);
+        out[..end - offset].copy_from_slice(&data[offset..end]);
+    }
+    out
+}
+
+fn bool_to_u256(value: bool) -> U256 {
+    if value {
+        U256::one()
+    } else {
+        U256::zero()
+    }
+}
+
+fn address_to_u256(address: &Address) -> U256 {
+    U256::from_big_endian(&address[..])
+}
+
+fn u256_to_address(value: &U256) -> Address {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    Address::from_slice(&bytes[12..])
+}
+
+fn u256_to_h256(value: &U256) -> H256 {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    H256::from(bytes)
+}
+
+fn u512_to_u256(value: U512) -> U256 {
+    let mut bytes = [0u8; 64];
+    value.to_big_endian(&mut bytes);
+    U256::from_big_endian(&bytes[32..])
+}
+
+fn is_negative(value: &U256) -> bool {
+    value.bit(255)
+}
+
+fn negate(value: U256) -> U256 {
+    (!value).overflowing_add(U256::one()).0
+}
+
+fn abs(value: U256) -> U256 {
+    if is_negative(&value) {
+        negate(value)
+    } else {
+        value
+    }
+}
+
+fn signed_div(a: U256, b: U256) -> U256 {
+    if b.is_zero() {
+        return U256::zero();
+    }
+    let quotient = abs(a) / abs(b);
+    if is_negative(&a) != is_negative(&b) {
+        negate(quotient)
+    } else {
+        quotient
+    }
+}
+
+fn signed_rem(a: U256, b: U256) -> U256 {
+    if b.is_zero() {
+        return U256::zero();
+    }
+    let remainder = abs(a) % abs(b);
+    if is_negative(&a) {
+        negate(remainder)
+    } else {
+        remainder
+    }
+}
+
+fn signed_lt(a: U256, b: U256) -> bool {
+    match (is_negative(&a), is_negative(&b)) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => a < b,
+    }
+}
+
+fn sign_extend(byte: U256, value: U256) -> U256 {
+    if byte >= U256::from(31) {
+        return value;
+    }
+    let bit = byte.as_usize() * 8 + 7;
+    let mask = (U256::one() << (bit + 1)) - U256::one();
+    if value.bit(bit) {
+        value | !mask
+    } else {
+        value & mask
+    }
+}
+
+fn arithmetic_shr(shift: U256, value: U256) -> U256 {
+    let negative = is_negative(&value);
+    if shift >= U256::from(256) {
+        return if negative {
+            U256::max_value()
+        } else {
+            U256::zero()
+        };
+    }
+    let shift = shift.as_usize();
+    if negative {
+        !((!value) >> shift)
+    } else {
+        value >> shift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::op_name;
+    use crate::tracer::{CallType, ReadValue, StateRead, TraceOptions, Tracer};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::evm::{Context, InterpreterParams, InterpreterResult};
+    use rustc_hex::FromHex;
+
+    fn params(code: &str, gas: u64) -> InterpreterParams {
+        let mut params = InterpreterParams::default();
+        params.sender = Address::from(1);
+        params.receiver = Address::from(2);
+        params.address = Address::from(2);
+        params.gas_limit = gas;
+        params.contract.code_address = Address::from(2);
+        params.contract.code_data = code.from_hex().unwrap();
+        params
+    }
+
+    fn word(value: u64) -> Vec<u8> {
+        let mut data = [0u8; 32];
+        U256::from(value).to_big_endian(&mut data);
+        data.to_vec()
+    }
+
+    #[test]
+    fn test_replay_steps() {
+        // PUSH1 2 PUSH1 3 ADD PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+        let mut tracer = Tracer::new();
+        tracer.enter(CallType::Call, &params("600260030160005260206000f3", 1000));
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(word(5), 1000 - 24, vec![]));
+        tracer.exit(&r);
+
+        let root = tracer.trace_root(&TraceOptions::default()).unwrap();
+        assert_eq!(root.replay_error, None);
+        let ops: Vec<_> = root.steps.iter().map(|step| op_name(step.op)).collect();
+        assert_eq!(
+            ops,
+            vec!["PUSH1", "PUSH1", "ADD", "PUSH1", "MSTORE", "PUSH1", "PUSH1", "RETURN"]
+        );
+        let add = &root.steps[2];
+        assert_eq!(add.pc, 4);
+        assert_eq!(add.gas, 994);
+        assert_eq!(add.gas_cost, 3);
+        assert_eq!(add.stack, vec![U256::from(2), U256::from(3)]);
+        let mstore = &root.steps[4];
+        assert_eq!(mstore.gas_cost, 6);
+        assert_eq!(mstore.memory_size, 32);
+        let write = mstore.memory.as_ref().unwrap();
+        assert_eq!(write.offset, 0);
+        assert_eq!(write.data, word(5));
+
+        let root = tracer
+            .trace_root(&TraceOptions {
+                stack: false,
+                memory: false,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(root.steps[2].stack.is_empty());
+        assert!(root.steps[4].memory.is_none());
+        assert!(tracer
+            .trace_root(&TraceOptions {
+                steps: false,
+                ..Default::default()
+            })
+            .unwrap()
+            .steps
+            .is_empty());
+    }
+
+    #[test]
+    fn test_replay_storage_reads() {
+        // PUSH1 1 SLOAD PUSH1 1 ADD PUSH1 1 SSTORE STOP
+        let key = H256::from(1);
+        let mut tracer = Tracer::new();
+        tracer.set_context(Context::default());
+        tracer.enter(CallType::Call, &params("600154600101600155", 30000));
+        tracer.read(
+            StateRead::Storage(Address::from(2), key),
+            ReadValue::Hash(H256::from(7)),
+        );
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 30000 - 5212, vec![]));
+        tracer.exit(&r);
+
+        let root = tracer.trace_root(&TraceOptions::default()).unwrap();
+        assert_eq!(root.replay_error, None);
+        let sload = root.steps[1].storage.as_ref().unwrap();
+        assert_eq!((sload.key, sload.value), (key, H256::from(7)));
+        let sstore = root.steps[5].storage.as_ref().unwrap();
+        assert_eq!((sstore.key, sstore.value), (key, H256::from(8)));
+        assert_eq!(root.steps[5].gas_cost, 5000);
+    }
+
+    #[test]
+    fn test_replay_mismatch() {
+        let mut tracer = Tracer::new();
+        tracer.enter(CallType::Call, &params("600154", 1000));
+        // No storage read recorded for the SLOAD.
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 795, vec![]));
+        tracer.exit(&r);
+        let root = tracer.trace_root(&TraceOptions::default()).unwrap();
+        assert!(root.replay_error.is_some());
+        assert!(root.steps[1].error.is_some());
+
+        // Gas doesn't match.
+        let mut tracer = Tracer::new();
+        tracer.enter(CallType::Call, &params("6001", 1000));
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 990, vec![]));
+        tracer.exit(&r);
+        let root = tracer.trace_root(&TraceOptions::default()).unwrap();
+        assert!(root.replay_error.is_some());
+    }
+
+    #[test]
+    fn test_replay_child_call() {
+        // PUSH1 0 DUP1 DUP1 DUP1 DUP1 PUSH1 3 PUSH2 0xffff CALL STOP
+        let mut tracer = Tracer::new();
+        tracer.enter(
+            CallType::Call,
+            &params("600080808080600361fffff100", 100_000),
+        );
+        let mut child = params("00", 1000);
+        child.depth = 1;
+        tracer.enter(CallType::Call, &child);
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 1000, vec![]));
+        tracer.exit(&r);
+        // 7 pushes/dups * 3 + 700 for the call, the child returned all gas.
+        let r: Result<_, ()> = Ok(InterpreterResult::Normal(vec![], 100_000 - 721, vec![]));
+        tracer.exit(&r);
+
+        let root = tracer.trace_root(&TraceOptions::default()).unwrap();
+        assert_eq!(root.replay_error, None);
+        let call = &root.steps[7];
+        assert_eq!(op_name(call.op), "CALL");
+        assert_eq!(call.gas_cost, 1700);
+        assert_eq!(root.steps[8].gas, 100_000 - 721);
+        assert_eq!(root.calls[0].steps.len(), 1);
+    }
+
+    #[test]
+    fn test_signed_arithmetic() {
+        let minus_one = U256::max_value();
+        let minus_two = super::negate(U256::from(2));
+        assert_eq!(super::signed_div(minus_two, U256::from(2)), minus_one);
+        assert_eq!(
+            super::signed_rem(super::negate(U256::from(7)), U256::from(3)),
+            minus_one
+        );
+        assert!(super::signed_lt(minus_one, U256::zero()));
+        assert_eq!(
+            super::sign_extend(U256::zero(), U256::from(0xff)),
+            minus_one
+        );
+        assert_eq!(super::arithmetic_shr(U256::from(4), minus_two), minus_one);
+    }
+}
@@ -12,24 +12,110 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
+use crate::journaldb::{Journal, JournalDBType, StatePruner};
 use cita_database::error::DatabaseError;
 use cita_database::{DataCategory, Database};
 use cita_types::H256;
 use hashable::HASH_NULL_RLP;
-use parking_lot::RwLock;
+use lru_cache::LruCache;
+use parking_lot::{Mutex, RwLock};
 use std::collections::HashMap;
 
 static NULL_RLP_STATIC: [u8; 1] = [0x80; 1];
 
+pub const DEFAULT_CACHE_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Bytes of the cached keys and values.
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// Trie nodes read from or flushed into the database, the least recently
+/// used ones are evicted when the total bytes exceed the capacity.
+#[derive(Debug)]
+struct NodeCache {
+    lru: Mutex<LruCache<Vec<u8>, Vec<u8>>>,
+    size: AtomicUsize,
+    capacity: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl NodeCache {
+    fn new(capacity: usize) -> Self {
+        NodeCache {
+            lru: Mutex::new(LruCache::new(usize::max_value())),
+            size: AtomicUsize::new(0),
+            capacity,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.lru.lock().get_mut(key).map(|v| v.clone());
+        if value.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.lru.lock().contains_key(key)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) {
+        let len = key.len() + value.len();
+        if len > self.capacity {
+            return;
+        }
+        let mut lru = self.lru.lock();
+        let mut size = self.size.load(Ordering::Relaxed) + len;
+        if let Some(old) = lru.insert(key.clone(), value) {
+            size -= key.len() + old.len();
+        }
+        while size > self.capacity {
+            match lru.remove_lru() {
+                Some((k, v)) => size -= k.len() + v.len(),
+                None => break,
+            }
+        }
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.size.load(Ordering::Relaxed),
+            capacity: self.capacity,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TrieDB<DB>
 where
     DB: Database,
 {
     db: Arc<DB>,
+    // Nodes not flushed yet, they can't be evicted.
     cache: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    node_cache: Arc<NodeCache>,
+    pruner: Option<Arc<StatePruner<DB>>>,
+    journal: Option<Arc<RwLock<Journal<DB>>>>,
+    // The shared cache of a scratch TrieDB, whose own cache is never flushed.
+    base_cache: Option<Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>>,
 }
 
 impl<DB> TrieDB<DB>
@@ -40,12 +126,87 @@ where
         TrieDB {
             db,
             cache: Arc::new(RwLock::new(HashMap::new())),
+            node_cache: Arc::new(NodeCache::new(DEFAULT_CACHE_SIZE)),
+            pruner: None,
+            journal: None,
+            base_cache: None,
         }
     }
 
+    /// Set the bytes limit of the node cache.
+    pub fn with_cache_size(mut self, cache_size: usize) -> Self {
+        self.node_cache = Arc::new(NodeCache::new(cache_size));
+        self
+    }
+
     pub fn database(&self) -> Arc<DB> {
         self.db.clone()
     }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.node_cache.stats()
+    }
+
+    /// A TrieDB sharing the database and cache, which records the trie nodes
+    /// inserted and removed through it, so that they can be committed with a block.
+    /// It is the same as a clone in archive mode.
+    pub fn journaled(&self) -> Self {
+        TrieDB {
+            db: Arc::clone(&self.db),
+            cache: Arc::clone(&self.cache),
+            node_cache: Arc::clone(&self.node_cache),
+            pruner: self.pruner.clone(),
+            journal: self
+                .pruner
+                .as_ref()
+                .map(|pruner| Arc::new(RwLock::new(pruner.journal()))),
+            base_cache: None,
+        }
+    }
+
+    /// A TrieDB reading through the database and cache, whose writes are kept
+    /// in memory and dropped with it. Used to execute calls and traces, whose
+    /// states are never committed.
+    pub fn scratch(&self) -> Self {
+        TrieDB {
+            db: Arc::clone(&self.db),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+            node_cache: Arc::clone(&self.node_cache),
+            pruner: None,
+            journal: None,
+            base_cache: Some(Arc::clone(&self.cache)),
+        }
+    }
+
+    /// Commit the recorded trie nodes as the journal of the block.
+    /// Must be called before the block is saved.
+    pub fn commit_journal(&self, height: u64) -> Result<(), DatabaseError> {
+        match (&self.pruner, &self.journal) {
+            (Some(pruner), Some(journal)) => {
+                let mut journal = mem::replace(&mut *journal.write(), pruner.journal());
+                pruner.commit(height, &mut journal)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<DB> TrieDB<DB>
+where
+    DB: Database + 'static,
+{
+    pub fn with_journal_type(db: Arc<DB>, journal_type: JournalDBType) -> Self {
+        let pruner = match journal_type {
+            JournalDBType::Archive => None,
+            JournalDBType::Pruning(history) => {
+                Some(Arc::new(StatePruner::new(Arc::clone(&db), history)))
+            }
+        };
+        TrieDB {
+            pruner,
+            ..TrieDB::new(db)
+        }
+    }
 }
 
 /// "TrieDB" provides state read/write capabilities for executor.
@@ -59,16 +220,33 @@ where
         if H256::from(key) == HASH_NULL_RLP {
             return Ok(Some(NULL_RLP_STATIC.to_vec()));
         }
-        match self.cache.read().get(key) {
-            Some(v) => Ok(Some(v.to_vec())),
-            None => self.db.get(Some(DataCategory::State), key),
+        if let Some(v) = self.cache.read().get(key) {
+            return Ok(Some(v.to_vec()));
         }
+        if let Some(v) = self
+            .base_cache
+            .as_ref()
+            .and_then(|c| c.read().get(key).cloned())
+        {
+            return Ok(Some(v));
+        }
+        if let Some(v) = self.node_cache.get(key) {
+            return Ok(Some(v));
+        }
+        let value = self.db.get(Some(DataCategory::State), key)?;
+        if let Some(ref v) = value {
+            self.node_cache.insert(key.to_vec(), v.clone());
+        }
+        Ok(value)
     }
 
     fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
         if H256::from(key.as_slice()) == HASH_NULL_RLP {
             return Ok(());
         }
+        if let Some(ref journal) = self.journal {
+            journal.write().insert(&[key.clone()]);
+        }
         self.cache.write().insert(key, value);
         Ok(())
     }
@@ -77,14 +255,23 @@ where
         if H256::from(key) == HASH_NULL_RLP {
             return Ok(true);
         }
-        if self.cache.read().contains_key(key) {
+        if self.cache.read().contains_key(key)
+            || self
+                .base_cache
+                .as_ref()
+                .map_or(false, |c| c.read().contains_key(key))
+            || self.node_cache.contains(key)
+        {
             Ok(true)
         } else {
             self.db.contains(Some(DataCategory::State), key)
         }
     }
 
-    fn remove(&self, _key: &[u8]) -> Result<(), Self::Error> {
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        if let Some(ref journal) = self.journal {
+            journal.write().remove(&[key.to_vec()]);
+        }
         Ok(())
     }
 
@@ -98,14 +285,23 @@ where
             let value = values[i].clone();
             cache.insert(key, value);
         }
+        if let Some(ref journal) = self.journal {
+            journal.write().insert(&keys);
+        }
         Ok(())
     }
 
-    fn remove_batch(&self, _keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        if let Some(ref journal) = self.journal {
+            journal.write().remove(keys);
+        }
         Ok(())
     }
 
     fn flush(&self) -> Result<(), Self::Error> {
+        if self.base_cache.is_some() {
+            return Ok(());
+        }
         let len = self.cache.read().len();
         let mut keys = Vec::with_capacity(len);
         let mut values = Vec::with_capacity(len);
@@ -116,7 +312,11 @@ where
         }
 
         self.db
-            .insert_batch(Some(DataCategory::State), keys.to_vec(), values.to_vec())
+            .insert_batch(Some(DataCategory::State), keys.to_vec(), values.to_vec())?;
+        for (key, value) in keys.into_iter().zip(values.into_iter()) {
+            self.node_cache.insert(key, value);
+        }
+        Ok(())
     }
 }
 
@@ -128,6 +328,39 @@ where
         TrieDB {
             db: Arc::clone(&self.db),
             cache: Arc::clone(&self.cache),
+            node_cache: Arc::clone(&self.node_cache),
+            pruner: self.pruner.clone(),
+            journal: self.journal.clone(),
+            base_cache: self.base_cache.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::NodeCache;
+
+    #[test]
+    fn test_node_cache_evict_by_bytes() {
+        let cache = NodeCache::new(10);
+        cache.insert(vec![1], vec![0; 4]);
+        cache.insert(vec![2], vec![0; 4]);
+        assert_eq!(cache.stats().size, 10);
+
+        // Touch 1, so 2 is the least recently used.
+        assert!(cache.get(&[1]).is_some());
+        cache.insert(vec![3], vec![0; 2]);
+        assert!(cache.get(&[2]).is_none());
+        assert!(cache.get(&[1]).is_some());
+        assert!(cache.get(&[3]).is_some());
+
+        // Too large to be cached.
+        cache.insert(vec![4], vec![0; 10]);
+        assert!(cache.get(&[4]).is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.size, 8);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 2);
+    }
+}
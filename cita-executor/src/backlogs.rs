@@ -377,6 +377,9 @@ impl Backlogs {
 
         let backlog = self.backlogs.remove(&height).unwrap();
         let closed_block = backlog.complete();
+        // Drop the proposals which can never be committed, their uncommitted
+        // state nodes are removed with them.
+        self.backlogs = self.backlogs.split_off(&(height + 1));
         self.current_height += 1;
         self.current_hash = closed_block
             .hash()
@@ -384,6 +387,11 @@ impl Backlogs {
         Ok(closed_block)
     }
 
+    /// Number of the pending processing blocks
+    pub fn pending_len(&self) -> usize {
+        self.backlogs.len()
+    }
+
     pub fn completed_keys(&self) -> ::std::vec::Vec<&u64> {
         self.completed.keys().sorted()
     }
@@ -0,0 +1,1123 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fast sync of a new node.
+//!
+//! Instead of executing all the blocks from the genesis, a new node asks the
+//! heights of its peers, and takes the parent of the median latest block as
+//! the target, for a peer may claim any height. The headers from the genesis
+//! to the target are downloaded and verified in order, every proof against
+//! the validators of the parent block. The validators start from the genesis,
+//! and are carried forward by the proofs of the `NodeManager` storage in the
+//! state of the latest verified block, asked when a proof does not match the
+//! current ones. The state root of a block is only signed with the next block,
+//! which is signed by the new validators, so the new validators are taken
+//! after several peers prove them, and fast sync is given up if a peer proves
+//! otherwise. The proofs of old states are served by the peers keeping them,
+//! as the archive nodes do.
+//!
+//! With a checkpoint, the hash of a block trusted by the operator, the block
+//! is the target, and the headers before it are checked by their hashes
+//! instead of the proofs.
+//!
+//! Then the state of the target is downloaded from the peers having it, and
+//! the receipts of the latest blocks from the chains of the peers. The node
+//! goes on executing the blocks after the target.
+
+use crate::core::libexecutor::executor::CitaTrieDB;
+use crate::core::libexecutor::state_sync::{StateSync, StateSyncMessage, MAX_NODES_PER_REQUEST};
+use crate::types::block_receipts::BlockReceipts;
+use crate::types::header::Header;
+use crate::types::node_manager;
+use crate::types::state_proof::StateProof;
+use crate::types::state_sync::{
+    MAX_HEADERS_PER_REQUEST, MAX_RECEIPTS_PER_REQUEST, SNAPSHOT_HEADERS,
+};
+use crate::types::Bytes;
+use cita_types::{Address, H256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// A chain lower than this is executed from the genesis.
+pub const MIN_TARGET_HEIGHT: u64 = 1000;
+pub const FAST_SYNC_TICK: Duration = Duration::from_secs(1);
+const REQUEST_TIME_OUT: Duration = Duration::from_secs(10);
+// Wait for the heights of the peers before taking the best one.
+const DISCOVER_TIME: Duration = Duration::from_secs(5);
+// Give up if no peer has answered for so long.
+const WAIT_PEERS_TIME_OUT: Duration = Duration::from_secs(60);
+// The new validators are taken after so many peers prove them.
+const VALIDATORS_QUORUM: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Request {
+    Headers(u64, u64),
+    Validators(u64),
+    Nodes(Vec<H256>),
+    Receipts(Vec<H256>),
+}
+
+impl Request {
+    fn is_headers(&self) -> bool {
+        match self {
+            Request::Headers(..) => true,
+            _ => false,
+        }
+    }
+
+    fn is_receipts(&self) -> bool {
+        match self {
+            Request::Receipts(_) => true,
+            _ => false,
+        }
+    }
+}
+
+pub struct FastSync {
+    state_db: CitaTrieDB,
+    started: Instant,
+    last_query: Option<Instant>,
+    // The latest heights of the peers
+    peers: HashMap<u32, u64>,
+    // Peers without the old headers or states, which are fast synced or
+    // pruned
+    without_history: BTreeSet<u32>,
+    // One request to a peer at a time
+    requests: HashMap<u32, (Request, Instant)>,
+    // The height and the hash of a trusted block, which is the target
+    checkpoint: Option<(u64, H256)>,
+    target_height: Option<u64>,
+    // No peer has the state of the target
+    is_target_pruned: bool,
+    is_short_chain: bool,
+    // The validators of the blocks after `validators_height`
+    validators: Vec<Address>,
+    validators_height: u64,
+    needs_validators: bool,
+    // The validators in the state of the latest verified block, and the peers
+    // proving them
+    proved_validators: Option<Vec<Address>>,
+    validators_provers: BTreeSet<u32>,
+    // A peer proves other validators than the others
+    is_contested: bool,
+    // The latest verified headers, from the genesis
+    verified: VecDeque<Header>,
+    // The headers downloaded after the verified ones
+    unverified: Vec<Header>,
+    sync: Option<StateSync>,
+    // The receipts roots of the blocks whose receipts are not downloaded,
+    // and the peers not having them.
+    missing_receipts: BTreeMap<H256, (H256, BTreeSet<u32>)>,
+    receipts: Vec<(H256, BlockReceipts)>,
+}
+
+impl FastSync {
+    pub fn new(
+        genesis: Header,
+        validators: Vec<Address>,
+        checkpoint: Option<(u64, H256)>,
+        state_db: CitaTrieDB,
+        now: Instant,
+    ) -> Self {
+        let mut verified = VecDeque::new();
+        verified.push_back(genesis);
+        FastSync {
+            state_db,
+            started: now,
+            last_query: None,
+            peers: HashMap::new(),
+            without_history: BTreeSet::new(),
+            requests: HashMap::new(),
+            checkpoint,
+            target_height: checkpoint.map(|(height, _)| height),
+            is_target_pruned: false,
+            is_short_chain: false,
+            validators,
+            validators_height: 0,
+            needs_validators: false,
+            proved_validators: None,
+            validators_provers: BTreeSet::new(),
+            is_contested: false,
+            verified,
+            unverified: Vec::new(),
+            sync: None,
+            missing_receipts: BTreeMap::new(),
+            receipts: Vec::new(),
+        }
+    }
+
+    fn latest_verified(&self) -> &Header {
+        self.verified.back().expect("the genesis at least; qed")
+    }
+
+    fn headers_done(&self) -> bool {
+        self.target_height == Some(self.latest_verified().number())
+    }
+
+    /// The target block, after its header is verified.
+    pub fn target(&self) -> Option<&Header> {
+        if self.headers_done() {
+            Some(self.latest_verified())
+        } else {
+            None
+        }
+    }
+
+    /// The headers to save with the state, the genesis excluded.
+    pub fn target_headers(&self) -> Vec<Header> {
+        self.verified
+            .iter()
+            .filter(|header| header.number() > 0)
+            .cloned()
+            .collect()
+    }
+
+    /// The receipts downloaded, with the hashes of their blocks.
+    pub fn receipts(&self) -> &[(H256, BlockReceipts)] {
+        &self.receipts
+    }
+
+    pub fn state_db(&self) -> &CitaTrieDB {
+        &self.state_db
+    }
+
+    pub fn imported(&self) -> usize {
+        self.sync.as_ref().map(StateSync::imported).unwrap_or(0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.headers_done()
+            && self
+                .sync
+                .as_ref()
+                .map(StateSync::is_complete)
+                .unwrap_or(false)
+            && self.missing_receipts.is_empty()
+    }
+
+    /// Fast sync is useless for a short chain, and impossible without peers
+    /// or with the peers proving different validators.
+    pub fn should_give_up(&self, now: Instant) -> bool {
+        self.is_short_chain
+            || self.is_contested
+            || (self.target_height.is_none()
+                && now.duration_since(self.started) >= WAIT_PEERS_TIME_OUT)
+    }
+
+    // A peer without request, having the block of the height. The lowest one
+    // is taken, for a peer may claim any height.
+    fn idle_peer(&self, height: u64, with_history: bool) -> Option<u32> {
+        self.peers
+            .iter()
+            .filter(|(origin, peer_height)| {
+                **peer_height >= height
+                    && !self.requests.contains_key(origin)
+                    && !(with_history && self.without_history.contains(origin))
+            })
+            .min_by_key(|(_, peer_height)| **peer_height)
+            .map(|(origin, _)| *origin)
+    }
+
+    // Whether a peer, with a request or not, may have the header of the height.
+    fn can_serve(&self, height: u64) -> bool {
+        self.peers.iter().any(|(origin, peer_height)| {
+            *peer_height >= height && !self.without_history.contains(origin)
+        })
+    }
+
+    // The lower median of the heights of the peers.
+    fn median_height(&self) -> Option<u64> {
+        let mut heights: Vec<u64> = self.peers.values().cloned().collect();
+        heights.sort();
+        heights.get(heights.len().saturating_sub(1) / 2).cloned()
+    }
+
+    // The first header downloaded before the checkpoint.
+    fn checkpoint_start(height: u64) -> u64 {
+        height.saturating_sub(SNAPSHOT_HEADERS as u64 - 1).max(1)
+    }
+
+    // The header to download after the downloaded ones.
+    fn next_header(&self) -> u64 {
+        let downloaded = self.unverified.len() as u64;
+        match self.checkpoint {
+            Some((height, _)) => Self::checkpoint_start(height) + downloaded,
+            None => self.latest_verified().number() + downloaded + 1,
+        }
+    }
+
+    fn remove_peer(&mut self, origin: u32) {
+        self.peers.remove(&origin);
+        if let Some((Request::Nodes(hashes), _)) = self.requests.remove(&origin) {
+            if let Some(ref mut sync) = self.sync {
+                sync.retry(&hashes);
+            }
+        }
+    }
+
+    /// The messages to send, to all the peers if the origin is `None`.
+    pub fn requests(
+        &mut self,
+        now: Instant,
+    ) -> Result<Vec<(Option<u32>, StateSyncMessage)>, String> {
+        let mut messages = Vec::new();
+
+        let timed_out: Vec<u32> = self
+            .requests
+            .iter()
+            .filter(|(_, (_, sent))| now.duration_since(*sent) >= REQUEST_TIME_OUT)
+            .map(|(origin, _)| *origin)
+            .collect();
+        for origin in timed_out {
+            warn!("fast sync: request to origin {} is timed out", origin);
+            self.remove_peer(origin);
+        }
+
+        // The heights of the peers are asked again and again, for the peers
+        // connected later.
+        let is_due = self
+            .last_query
+            .map(|last| now.duration_since(last) >= REQUEST_TIME_OUT)
+            .unwrap_or(true);
+        if is_due {
+            self.last_query = Some(now);
+            messages.push((None, StateSyncMessage::GetHeight));
+        }
+
+        let median_height = match self.median_height() {
+            Some(height) => height,
+            None => return Ok(messages),
+        };
+        let target_height = match self.target_height {
+            // Take a new target if the state is pruned by the peers, the
+            // nodes downloaded are kept in the database.
+            Some(height)
+                if self.is_target_pruned
+                    && self.checkpoint.is_none()
+                    && median_height > height.saturating_add(1) =>
+            {
+                let height = median_height - 1;
+                info!(
+                    "fast sync: the state is pruned, new target block {}",
+                    height
+                );
+                self.target_height = Some(height);
+                self.is_target_pruned = false;
+                self.sync = None;
+                self.requests.retain(|_, (request, _)| match request {
+                    Request::Nodes(_) => false,
+                    _ => true,
+                });
+                height
+            }
+            Some(height) => height,
+            None if now.duration_since(self.started) < DISCOVER_TIME => return Ok(messages),
+            None if median_height < MIN_TARGET_HEIGHT => {
+                info!(
+                    "fast sync: the median chain of peers is only {} high",
+                    median_height
+                );
+                self.is_short_chain = true;
+                return Ok(messages);
+            }
+            None => {
+                // The headers verified for a dropped target are kept.
+                let height = (median_height - 1).max(self.latest_verified().number());
+                info!("fast sync: target block {}", height);
+                self.target_height = Some(height);
+                height
+            }
+        };
+
+        if !self.headers_done() {
+            let latest = self.latest_verified().number();
+            if self.needs_validators {
+                self.ask_validators(latest, now, &mut messages)?;
+            } else if !self
+                .requests
+                .values()
+                .any(|(request, _)| request.is_headers())
+            {
+                let start = self.next_header();
+                let end = match self.checkpoint {
+                    Some((height, _)) => height,
+                    // The header after the target proves it.
+                    None => target_height.saturating_add(1),
+                };
+                let count = end
+                    .saturating_add(1)
+                    .saturating_sub(start)
+                    .min(MAX_HEADERS_PER_REQUEST);
+                if count > 0 {
+                    if let Some(origin) = self.idle_peer(start, true) {
+                        self.requests
+                            .insert(origin, (Request::Headers(start, count), now));
+                        messages.push((Some(origin), StateSyncMessage::GetHeaders(start, count)));
+                    } else if !self.can_serve(start) && self.checkpoint.is_none() {
+                        // The peers claiming the target are gone or lied.
+                        warn!(
+                            "fast sync: no peer has the header {}, drop the target block {}",
+                            start, target_height
+                        );
+                        self.target_height = None;
+                        self.unverified.clear();
+                    }
+                }
+            }
+            return Ok(messages);
+        }
+
+        if self.sync.is_none() {
+            let target = self.latest_verified();
+            info!(
+                "fast sync: download the state {:?} of block {}",
+                target.state_root(),
+                target_height
+            );
+            self.sync = Some(StateSync::new(*target.state_root()));
+            let empty_root = BlockReceipts::new(Vec::new()).receipts_root();
+            self.missing_receipts = self
+                .verified
+                .iter()
+                .filter(|header| header.number() > 0 && *header.receipts_root() != empty_root)
+                .map(|header| {
+                    (
+                        header.hash().unwrap(),
+                        (*header.receipts_root(), BTreeSet::new()),
+                    )
+                })
+                .collect();
+        }
+
+        if !self
+            .sync
+            .as_ref()
+            .map(StateSync::is_complete)
+            .unwrap_or(false)
+        {
+            loop {
+                let origin = match self.idle_peer(target_height, false) {
+                    Some(origin) => origin,
+                    None => break,
+                };
+                let hashes = self
+                    .sync
+                    .as_mut()
+                    .expect("created above; qed")
+                    .next_request(&self.state_db, MAX_NODES_PER_REQUEST)?;
+                if hashes.is_empty() {
+                    break;
+                }
+                self.requests
+                    .insert(origin, (Request::Nodes(hashes.clone()), now));
+                messages.push((Some(origin), StateSyncMessage::GetNodes(hashes)));
+            }
+            return Ok(messages);
+        }
+
+        // The receipts which no peer has are given up.
+        let peers: BTreeSet<u32> = self
+            .peers
+            .iter()
+            .filter(|(_, height)| **height >= target_height)
+            .map(|(origin, _)| *origin)
+            .collect();
+        if !peers.is_empty() {
+            let given_up: Vec<H256> = self
+                .missing_receipts
+                .iter()
+                .filter(|(_, (_, without))| peers.is_subset(without))
+                .map(|(hash, _)| *hash)
+                .collect();
+            for hash in given_up {
+                warn!("fast sync: no peer has the receipts of block {:?}", hash);
+                self.missing_receipts.remove(&hash);
+            }
+        }
+        let is_asked = self
+            .requests
+            .values()
+            .any(|(request, _)| request.is_receipts());
+        if !is_asked {
+            for origin in peers {
+                if self.requests.contains_key(&origin) {
+                    continue;
+                }
+                let hashes: Vec<H256> = self
+                    .missing_receipts
+                    .iter()
+                    .filter(|(_, (_, without))| !without.contains(&origin))
+                    .map(|(hash, _)| *hash)
+                    .take(MAX_RECEIPTS_PER_REQUEST)
+                    .collect();
+                if !hashes.is_empty() {
+                    self.requests
+                        .insert(origin, (Request::Receipts(hashes.clone()), now));
+                    messages.push((Some(origin), StateSyncMessage::GetReceipts(hashes)));
+                    break;
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    // Ask the validators in the state of the latest verified block from the
+    // peers, until enough of them are asked.
+    fn ask_validators(
+        &mut self,
+        latest: u64,
+        now: Instant,
+        messages: &mut Vec<(Option<u32>, StateSyncMessage)>,
+    ) -> Result<(), String> {
+        let candidates: Vec<u32> = self
+            .peers
+            .iter()
+            .filter(|(origin, height)| {
+                **height >= latest
+                    && !self.without_history.contains(origin)
+                    && !self.validators_provers.contains(origin)
+            })
+            .map(|(origin, _)| *origin)
+            .collect();
+        if self.validators_provers.len() + candidates.len() < VALIDATORS_QUORUM {
+            return Err(format!(
+                "not enough peers to prove the validators after block {}, a checkpoint is needed",
+                latest
+            ));
+        }
+        let asked = self
+            .requests
+            .values()
+            .filter(|(request, _)| *request == Request::Validators(latest))
+            .count();
+        let mut wanted = VALIDATORS_QUORUM.saturating_sub(self.validators_provers.len() + asked);
+        for origin in candidates {
+            if wanted == 0 {
+                break;
+            }
+            if self.requests.contains_key(&origin) {
+                continue;
+            }
+            self.requests
+                .insert(origin, (Request::Validators(latest), now));
+            messages.push((Some(origin), StateSyncMessage::GetValidators(latest)));
+            wanted -= 1;
+        }
+        Ok(())
+    }
+
+    pub fn on_height(&mut self, origin: u32, height: u64) {
+        self.peers.insert(origin, height);
+    }
+
+    pub fn on_headers(&mut self, origin: u32, mut headers: Vec<Header>) -> Result<(), String> {
+        let (start, count) = match self.requests.remove(&origin) {
+            Some((Request::Headers(start, count), _)) => (start, count),
+            Some(request) => {
+                self.requests.insert(origin, request);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        if headers.is_empty() {
+            self.without_history.insert(origin);
+            return Err(format!("origin {} has not the header {}", origin, start));
+        }
+        if start != self.next_header() {
+            return Ok(());
+        }
+        for (i, header) in headers.iter().enumerate() {
+            if header.number() != start + i as u64 {
+                self.remove_peer(origin);
+                return Err(format!("origin {} answers disordered headers", origin));
+            }
+        }
+        // The chain of the peer ends at the last header.
+        if (headers.len() as u64) < count {
+            self.peers.insert(origin, start + headers.len() as u64 - 1);
+        }
+        headers.truncate(count as usize);
+        self.unverified.extend(headers);
+        self.verify_headers().map_err(|e| {
+            self.remove_peer(origin);
+            format!("bad headers from origin {}: {}", origin, e)
+        })
+    }
+
+    // Verify the downloaded headers in order, until the target.
+    fn verify_headers(&mut self) -> Result<(), String> {
+        if let Some((height, hash)) = self.checkpoint {
+            return self.verify_checkpoint_headers(height, hash);
+        }
+        while self.unverified.len() >= 2 && !self.headers_done() {
+            let latest = self.latest_verified();
+            let header = &self.unverified[0];
+            let number = header.number();
+            if number != latest.number() + 1 || latest.hash() != Some(*header.parent_hash()) {
+                self.unverified.clear();
+                return Err(format!("header {} is not continuous", number));
+            }
+            if !header.verify_next(&self.unverified[1], &self.validators) {
+                if self.validators_height == number - 1 {
+                    self.unverified.clear();
+                    return Err(format!("invalid proof of header {}", number));
+                }
+                // Check again with the validators of the parent.
+                self.needs_validators = true;
+                self.proved_validators = None;
+                self.validators_provers.clear();
+                return Ok(());
+            }
+            let header = self.unverified.remove(0);
+            self.verified.push_back(header);
+            if self.verified.len() > SNAPSHOT_HEADERS {
+                self.verified.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    // Check the headers before the checkpoint by their hashes, after all of
+    // them are downloaded.
+    fn verify_checkpoint_headers(&mut self, height: u64, hash: H256) -> Result<(), String> {
+        let start = Self::checkpoint_start(height);
+        if start + (self.unverified.len() as u64) <= height {
+            return Ok(());
+        }
+        let mut expected = hash;
+        let mut mismatched = None;
+        for header in self.unverified.iter().rev() {
+            if header.hash() != Some(expected) {
+                mismatched = Some(header.number());
+                break;
+            }
+            expected = *header.parent_hash();
+        }
+        if let Some(number) = mismatched {
+            self.unverified.clear();
+            return Err(format!("header {} does not match the checkpoint", number));
+        }
+        if start == 1 && self.latest_verified().hash() != Some(expected) {
+            self.unverified.clear();
+            return Err("the genesis does not match the checkpoint".to_owned());
+        }
+        if start > 1 {
+            self.verified.clear();
+        }
+        self.verified.extend(self.unverified.drain(..));
+        Ok(())
+    }
+
+    pub fn on_validators(
+        &mut self,
+        origin: u32,
+        height: u64,
+        proofs: &[StateProof],
+    ) -> Result<(), String> {
+        match self.requests.get(&origin) {
+            Some((Request::Validators(asked), _)) if *asked == height => {
+                self.requests.remove(&origin);
+            }
+            _ => return Ok(()),
+        }
+        if proofs.is_empty() {
+            self.without_history.insert(origin);
+            return Err(format!("origin {} has not the state {}", origin, height));
+        }
+        let latest = self.latest_verified();
+        if latest.number() != height || !self.needs_validators {
+            return Ok(());
+        }
+        // The state root is not signed by the validators before the change,
+        // so a peer proving other validators may be honest.
+        let validators = match node_manager::verify_validators(*latest.state_root(), proofs) {
+            Ok(ref validators)
+                if self
+                    .proved_validators
+                    .as_ref()
+                    .map(|proved| proved != validators)
+                    .unwrap_or(false) =>
+            {
+                self.is_contested = true;
+                return Err(format!(
+                    "origin {} proves other validators after block {}",
+                    origin, height
+                ));
+            }
+            Ok(validators) => validators,
+            Err(e) => {
+                self.is_contested = true;
+                return Err(format!("bad validators from origin {}: {:?}", origin, e));
+            }
+        };
+        self.validators_provers.insert(origin);
+        if self.validators_provers.len() < VALIDATORS_QUORUM {
+            self.proved_validators = Some(validators);
+            return Ok(());
+        }
+        info!(
+            "fast sync: validators {:?} after block {}",
+            validators, height
+        );
+        self.validators = validators;
+        self.validators_height = height;
+        self.needs_validators = false;
+        self.proved_validators = None;
+        self.validators_provers.clear();
+        self.verify_headers()
+    }
+
+    pub fn on_nodes(&mut self, origin: u32, nodes: Vec<Bytes>) -> Result<(), String> {
+        let hashes = match self.requests.remove(&origin) {
+            Some((Request::Nodes(hashes), _)) => hashes,
+            Some(request) => {
+                self.requests.insert(origin, request);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        let sync = match self.sync {
+            Some(ref mut sync) => sync,
+            None => return Ok(()),
+        };
+        // The peer may have pruned the state.
+        if nodes.is_empty() {
+            sync.retry(&hashes);
+            self.peers.remove(&origin);
+            self.is_target_pruned = true;
+            return Err(format!("origin {} has not the state", origin));
+        }
+        if let Err(e) = sync.import(&self.state_db, &hashes, nodes) {
+            self.peers.remove(&origin);
+            return Err(format!("bad nodes from origin {}: {}", origin, e));
+        }
+        Ok(())
+    }
+
+    pub fn on_receipts(
+        &mut self,
+        origin: u32,
+        receipts: Vec<(H256, BlockReceipts)>,
+    ) -> Result<(), String> {
+        let hashes = match self.requests.remove(&origin) {
+            Some((Request::Receipts(hashes), _)) => hashes,
+            Some(request) => {
+                self.requests.insert(origin, request);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        for (hash, block_receipts) in receipts {
+            let is_valid = hashes.contains(&hash)
+                && self
+                    .missing_receipts
+                    .get(&hash)
+                    .map(|(root, _)| *root == block_receipts.receipts_root())
+                    .unwrap_or(true);
+            if !is_valid {
+                self.remove_peer(origin);
+                return Err(format!(
+                    "bad receipts of block {:?} from origin {}",
+                    hash, origin
+                ));
+            }
+            if self.missing_receipts.remove(&hash).is_some() {
+                self.receipts.push((hash, block_receipts));
+            }
+        }
+        // The peer has pruned the others.
+        for hash in hashes {
+            if let Some((_, without)) = self.missing_receipts.get_mut(&hash) {
+                without.insert(origin);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FastSync, DISCOVER_TIME, VALIDATORS_QUORUM};
+    use crate::core::libexecutor::state_sync::{read_nodes, StateSyncMessage};
+    use crate::core::TrieDB;
+    use crate::tests::helpers::{generate_proof, generate_signer};
+    use crate::types::block_receipts::BlockReceipts;
+    use crate::types::header::Header;
+    use crate::types::node_manager;
+    use crate::types::receipt::Receipt;
+    use crate::types::state_proof::StateProof;
+    use crate::types::state_sync::SNAPSHOT_HEADERS;
+    use cita_crypto::{CreateKey, KeyPair, Signer};
+    use cita_database::{Config, RocksDB, NUM_COLUMNS};
+    use cita_types::{Address, H256, U256};
+    use cita_vm::state::{MemoryDB, State, StateObjectInfo};
+    use hashable::Hashable;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    const PEER: u32 = 1;
+
+    fn generate_fast_sync(
+        genesis: Header,
+        validators: Vec<Address>,
+        checkpoint: Option<(u64, H256)>,
+        path: &str,
+    ) -> FastSync {
+        let config = Config::with_category_num(NUM_COLUMNS);
+        let db = Arc::new(RocksDB::open(path, &config).unwrap());
+        FastSync::new(
+            genesis,
+            validators,
+            checkpoint,
+            TrieDB::new(db),
+            Instant::now(),
+        )
+    }
+
+    // The headers from the genesis, the block `n` signed by `signer(n)` and
+    // with the state root `state_root(n)`.
+    fn generate_headers<S, R>(count: u64, signer: S, state_root: R) -> Vec<Header>
+    where
+        S: Fn(u64) -> Signer,
+        R: Fn(u64) -> H256,
+    {
+        let empty_root = BlockReceipts::new(Vec::new()).receipts_root();
+        let mut genesis = Header::default();
+        genesis.set_receipts_root(empty_root);
+        genesis.rehash();
+        let mut headers = vec![genesis];
+        for number in 1..count {
+            let parent = headers.last().unwrap();
+            let proposal = parent.proposal_protobuf().crypt_hash();
+            let mut header = Header::default();
+            header.set_number(number);
+            header.set_parent_hash(parent.hash().unwrap());
+            header.set_state_root(state_root(number));
+            header.set_receipts_root(empty_root);
+            header.set_proof(generate_proof(signer(number - 1), number - 1, proposal));
+            header.rehash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    // A state with the validators in `NodeManager`.
+    fn generate_state(db: Arc<MemoryDB>, validators: &[Address]) -> State<MemoryDB> {
+        let address = node_manager::contract_address();
+        let mut state = State::new(db).unwrap();
+        state.new_contract(&address, U256::from(0), U256::from(1), vec![1]);
+        let keys = node_manager::validators_keys(validators.len() as u64);
+        state
+            .set_storage(&address, keys[0], H256::from(validators.len() as u64))
+            .unwrap();
+        for (key, validator) in keys[1..].iter().zip(validators.iter()) {
+            state
+                .set_storage(&address, *key, H256::from(*validator))
+                .unwrap();
+        }
+        state.commit().unwrap();
+        state
+    }
+
+    // Answer the requests as the peers having all the blocks and the state.
+    fn answer(
+        fast_sync: &mut FastSync,
+        headers: &[Header],
+        state: &mut State<MemoryDB>,
+        db: &MemoryDB,
+        now: Instant,
+    ) -> Result<Vec<StateSyncMessage>, String> {
+        let requests = fast_sync.requests(now)?;
+        let mut asked = Vec::new();
+        for (origin, request) in requests {
+            let origin = origin.unwrap_or(PEER);
+            match request.clone() {
+                StateSyncMessage::GetHeaders(start, count) => {
+                    let end = (start + count).min(headers.len() as u64);
+                    fast_sync.on_headers(origin, headers[start as usize..end as usize].to_vec())?;
+                }
+                StateSyncMessage::GetValidators(height) => {
+                    let address = node_manager::contract_address();
+                    let count = U256::from(
+                        state
+                            .get_storage(&address, &node_manager::nodes_key())
+                            .unwrap(),
+                    );
+                    let proofs: Vec<StateProof> = node_manager::validators_keys(count.low_u64())
+                        .iter()
+                        .map(|key| StateProof::prove(state, &address, key).unwrap())
+                        .collect();
+                    fast_sync.on_validators(origin, height, &proofs)?;
+                }
+                StateSyncMessage::GetNodes(hashes) => {
+                    let nodes = read_nodes(db, &hashes);
+                    fast_sync.on_nodes(origin, nodes)?;
+                }
+                _ => {}
+            }
+            asked.push(request);
+        }
+        Ok(asked)
+    }
+
+    #[test]
+    fn test_fast_sync() {
+        let first = generate_signer();
+        let privkey = *KeyPair::gen_keypair().privkey();
+        let second = Signer::from(privkey);
+        // The validators are changed in the block 600.
+        let db = Arc::new(MemoryDB::new(false));
+        let mut state = generate_state(Arc::clone(&db), &[second.address]);
+        let headers = generate_headers(
+            1001,
+            |number| {
+                if number <= 600 {
+                    generate_signer()
+                } else {
+                    Signer::from(privkey)
+                }
+            },
+            |number| {
+                if number < 600 {
+                    H256::from(1)
+                } else {
+                    state.root
+                }
+            },
+        );
+
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            vec![first.address],
+            None,
+            "test-rocksdb/fast_sync",
+        );
+        let now = Instant::now() + DISCOVER_TIME;
+        for peer in 1..=VALIDATORS_QUORUM as u32 {
+            fast_sync.on_height(peer, 1000);
+        }
+        let mut asked = Vec::new();
+        while !fast_sync.is_complete() {
+            let answered = answer(&mut fast_sync, &headers, &mut state, &db, now).unwrap();
+            assert!(!answered.is_empty());
+            asked.extend(answered);
+        }
+
+        // The best block is not the target, for its proof is in the next one.
+        let target = fast_sync.target().unwrap();
+        assert_eq!(target.number(), 999);
+        assert_eq!(target.hash(), headers[999].hash());
+        let target_headers = fast_sync.target_headers();
+        assert_eq!(target_headers.len(), SNAPSHOT_HEADERS);
+        assert_eq!(target_headers[0].number(), 1000 - SNAPSHOT_HEADERS as u64);
+        // The validators are carried forward once, proved by enough peers.
+        assert_eq!(
+            asked
+                .iter()
+                .filter(|request| **request == StateSyncMessage::GetValidators(600))
+                .count(),
+            VALIDATORS_QUORUM
+        );
+        assert_eq!(fast_sync.validators, vec![second.address]);
+        assert!(fast_sync.imported() > 0);
+    }
+
+    #[test]
+    fn test_invalid_proof() {
+        let privkey = *KeyPair::gen_keypair().privkey();
+        let headers = generate_headers(1001, |_| Signer::from(privkey), |_| H256::from(1));
+        let db = Arc::new(MemoryDB::new(false));
+        let mut state = generate_state(Arc::clone(&db), &[]);
+
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            vec![generate_signer().address],
+            None,
+            "test-rocksdb/fast_sync_invalid_proof",
+        );
+        let now = Instant::now() + DISCOVER_TIME;
+        fast_sync.on_height(PEER, 1000);
+        assert!(answer(&mut fast_sync, &headers, &mut state, &db, now).is_err());
+        assert!(fast_sync.target().is_none());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let db = Arc::new(MemoryDB::new(false));
+        let mut state = generate_state(Arc::clone(&db), &[]);
+        // The proofs are not checked before the checkpoint.
+        let privkey = *KeyPair::gen_keypair().privkey();
+        let headers = generate_headers(1001, |_| Signer::from(privkey), |_| state.root);
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            Vec::new(),
+            Some((999, headers[999].hash().unwrap())),
+            "test-rocksdb/fast_sync_checkpoint",
+        );
+        let now = Instant::now() + DISCOVER_TIME;
+        fast_sync.on_height(PEER, 1000);
+        while !fast_sync.is_complete() {
+            let answered = answer(&mut fast_sync, &headers, &mut state, &db, now).unwrap();
+            assert!(!answered.is_empty());
+        }
+        let target_headers = fast_sync.target_headers();
+        assert_eq!(target_headers.len(), SNAPSHOT_HEADERS);
+        assert_eq!(target_headers[0].number(), 1000 - SNAPSHOT_HEADERS as u64);
+        assert_eq!(fast_sync.target().unwrap().hash(), headers[999].hash());
+
+        // The headers not matching the checkpoint.
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            Vec::new(),
+            Some((999, H256::from(1))),
+            "test-rocksdb/fast_sync_bad_checkpoint",
+        );
+        fast_sync.on_height(PEER, 1000);
+        assert!(answer(&mut fast_sync, &headers, &mut state, &db, now).is_err());
+        assert!(fast_sync.target().is_none());
+    }
+
+    #[test]
+    fn test_contested_validators() {
+        let privkey = *KeyPair::gen_keypair().privkey();
+        let headers = generate_headers(
+            1001,
+            |number| {
+                if number <= 1 {
+                    generate_signer()
+                } else {
+                    Signer::from(privkey)
+                }
+            },
+            |_| H256::from(1),
+        );
+        let db = Arc::new(MemoryDB::new(false));
+        let mut state = generate_state(Arc::clone(&db), &[Signer::from(privkey).address]);
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            vec![generate_signer().address],
+            None,
+            "test-rocksdb/fast_sync_contested_validators",
+        );
+        let now = Instant::now() + DISCOVER_TIME;
+        for peer in 1..=VALIDATORS_QUORUM as u32 {
+            fast_sync.on_height(peer, 1000);
+        }
+        // The validators are changed in the block 1, but the proofs do not
+        // match its state root.
+        assert!(answer(&mut fast_sync, &headers, &mut state, &db, now).is_ok());
+        assert!(answer(&mut fast_sync, &headers, &mut state, &db, now).is_err());
+        assert!(fast_sync.should_give_up(now));
+    }
+
+    #[test]
+    fn test_median_height() {
+        let headers = generate_headers(10, |_| generate_signer(), |_| H256::from(1));
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            vec![generate_signer().address],
+            None,
+            "test-rocksdb/fast_sync_median_height",
+        );
+        let start = Instant::now();
+        fast_sync.on_height(1, 1200);
+        fast_sync.on_height(2, 5000);
+        fast_sync.on_height(3, 3000);
+        fast_sync.on_height(4, ::std::u64::MAX);
+
+        // Wait for the heights of peers.
+        let requests = fast_sync.requests(start).unwrap();
+        assert_eq!(requests, vec![(None, StateSyncMessage::GetHeight)]);
+
+        let now = start + DISCOVER_TIME;
+        let requests = fast_sync.requests(now).unwrap();
+        assert_eq!(fast_sync.target_height, Some(2999));
+        assert_eq!(
+            requests,
+            vec![(Some(1), StateSyncMessage::GetHeaders(1, 256))]
+        );
+        assert!(!fast_sync.should_give_up(now));
+
+        // The chain of the peer ends before the target.
+        fast_sync.on_headers(1, headers[1..].to_vec()).unwrap();
+        assert_eq!(fast_sync.peers[&1], 9);
+        fast_sync.peers.remove(&2);
+        fast_sync.peers.remove(&3);
+        fast_sync.peers.remove(&4);
+        fast_sync.requests(now).unwrap();
+        assert_eq!(fast_sync.target_height, None);
+    }
+
+    #[test]
+    fn test_short_chain() {
+        let headers = generate_headers(1, |_| generate_signer(), |_| H256::from(1));
+        let mut fast_sync = generate_fast_sync(
+            headers[0].clone(),
+            vec![generate_signer().address],
+            None,
+            "test-rocksdb/fast_sync_short_chain",
+        );
+        let now = Instant::now() + DISCOVER_TIME;
+        fast_sync.on_height(1, 999);
+        fast_sync.requests(now).unwrap();
+        assert!(fast_sync.should_give_up(now));
+    }
+
+    #[test]
+    fn test_receipts() {
+        let mut fast_sync = generate_fast_sync(
+            Header::default(),
+            Vec::new(),
+            None,
+            "test-rocksdb/fast_sync_receipts",
+        );
+        let block_receipts = BlockReceipts::new(vec![Receipt::default()]);
+        let hash = H256::from(1);
+        let now = Instant::now();
+        fast_sync.on_height(1, 1000);
+        fast_sync.on_height(2, 1000);
+        fast_sync
+            .missing_receipts
+            .insert(hash, (block_receipts.receipts_root(), Default::default()));
+        fast_sync
+            .requests
+            .insert(1, (super::Request::Receipts(vec![hash]), now));
+        fast_sync
+            .requests
+            .insert(2, (super::Request::Receipts(vec![hash]), now));
+
+        // Receipts not matching the header.
+        let other = BlockReceipts::new(Vec::new());
+        assert!(fast_sync.on_receipts(1, vec![(hash, other)]).is_err());
+        assert!(!fast_sync.peers.contains_key(&1));
+        // Pruned by the peer.
+        fast_sync.on_receipts(2, Vec::new()).unwrap();
+        assert_eq!(
+            fast_sync.missing_receipts[&hash]
+                .1
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![&2]
+        );
+
+        fast_sync
+            .requests
+            .insert(3, (super::Request::Receipts(vec![hash]), now));
+        fast_sync
+            .on_receipts(3, vec![(hash, block_receipts.clone())])
+            .unwrap();
+        assert!(fast_sync.missing_receipts.is_empty());
+        assert_eq!(fast_sync.receipts(), &[(hash, block_receipts)][..]);
+    }
+}
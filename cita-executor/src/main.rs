@@ -44,6 +44,18 @@
 //!     | executor | Executor  | Auth      | Miscellaneous  |
 //!     | executor | Executor  | Auth      | BlackList      |
 //!     | executor | Executor  | Chain     | StateSignal    |
+//!     | executor | Executor  | Chain     | SyncResponse   |
+//!
+//! 3. Trace calls
+//!
+//!     libproto has no message type for them, they use plain keys: executor
+//!     subscribes `chain.trace_request` and replies on `executor.trace_response`.
+//!
+//! 4. Fast sync
+//!
+//!     Plain keys too: executor subscribes `network.state_sync`, sends to peers
+//!     on `executor.state_sync` and `executor.receipts_request`, and publishes
+//!     the downloaded receipts to chain on `executor.snapshot_receipts`.
 //!
 //! ### Key behavior
 //!
@@ -88,6 +100,8 @@ extern crate cita_database as cita_db;
 #[cfg(test)]
 extern crate hashable;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate libproto;
 #[macro_use]
 extern crate cita_logger as logger;
@@ -96,17 +110,23 @@ extern crate serde_derive;
 #[macro_use]
 extern crate util;
 
+use crate::core::journaldb::{JournalDBType, DEFAULT_PRUNING_HISTORY};
 use crate::core::libexecutor::executor::Executor;
 use crate::postman::Postman;
 use cita_directories::DataPath;
+use cita_metrics::MetricsConfig;
+use cita_types::{clean_0x, H256};
 use clap::App;
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use pubsub::channel;
 use pubsub::start_pubsub;
+use std::str::FromStr;
 use std::thread;
 use util::set_panic_handler;
 
 mod backlogs;
+mod fast_sync;
+mod metrics;
 mod postman;
 #[cfg(test)]
 mod tests;
@@ -117,9 +137,25 @@ include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
 pub struct Options {
     prooftype: u8,
     journaldb_type: String,
+    #[serde(default = "default_pruning_history")]
+    pruning_history: u64,
     genesis_path: String,
     statedb_cache_size: usize,
     eth_compatibility: bool,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    fast_sync: bool,
+    // A trusted block to fast sync to, needed if too few peers can prove the
+    // changes of the validators
+    #[serde(default)]
+    fast_sync_checkpoint: Option<Checkpoint>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Checkpoint {
+    height: u64,
+    hash: String,
 }
 
 impl Options {
@@ -127,15 +163,41 @@ impl Options {
         Options {
             prooftype: 2,
             journaldb_type: String::from("archive"),
+            pruning_history: DEFAULT_PRUNING_HISTORY,
             genesis_path: String::from("genesis.json"),
             statedb_cache_size: 5 * 1024 * 1024,
             eth_compatibility: false,
+            metrics: MetricsConfig::default(),
+            fast_sync: false,
+            fast_sync_checkpoint: None,
         }
     }
 
     pub fn load(path: &str) -> Self {
         parse_config!(Options, path)
     }
+
+    pub fn journaldb_type(&self) -> JournalDBType {
+        JournalDBType::new(&self.journaldb_type, self.pruning_history).unwrap_or_else(|| {
+            panic!(
+                "unknown journaldb_type {}, should be archive or pruning",
+                self.journaldb_type
+            )
+        })
+    }
+
+    pub fn fast_sync_checkpoint(&self) -> Option<(u64, H256)> {
+        self.fast_sync_checkpoint.as_ref().map(|checkpoint| {
+            let hash = H256::from_str(clean_0x(&checkpoint.hash)).unwrap_or_else(|_| {
+                panic!("invalid hash {} of fast_sync_checkpoint", checkpoint.hash)
+            });
+            (checkpoint.height, hash)
+        })
+    }
+}
+
+fn default_pruning_history() -> u64 {
+    DEFAULT_PRUNING_HISTORY
 }
 
 fn main() {
@@ -158,6 +220,8 @@ fn main() {
     info!("Version: {}", get_build_info_str(true));
     info!("Config: {:?}", options);
 
+    cita_metrics::start_server(options.metrics);
+
     // start pubsub thread
     let (forward_req_sender, forward_req_receiver) = channel::unbounded();
     let (forward_resp_sender, forward_resp_receiver) = channel::unbounded();
@@ -167,22 +231,20 @@ fn main() {
     let (fsm_resp_sender, fsm_resp_receiver) = crossbeam_channel::unbounded();
     let (command_req_sender, command_req_receiver) = crossbeam_channel::bounded(0);
     let (command_resp_sender, command_resp_receiver) = crossbeam_channel::bounded(0);
-    start_pubsub(
-        "executor",
-        routing_key!([
-            Chain >> Request,
-            Chain >> RichStatus,
-            Chain >> StateSignal,
-            Chain >> LocalSync,
-            Consensus >> BlockWithProof,
-            Consensus >> SignedProposal,
-            Net >> SyncResponse,
-            Snapshot >> SnapshotReq,
-            Auth >> MiscellaneousReq,
-        ]),
-        forward_req_sender,
-        forward_resp_receiver,
-    );
+    let mut keys = routing_key!([
+        Chain >> Request,
+        Chain >> RichStatus,
+        Chain >> StateSignal,
+        Chain >> LocalSync,
+        Consensus >> BlockWithProof,
+        Consensus >> SignedProposal,
+        Net >> SyncResponse,
+        Snapshot >> SnapshotReq,
+        Auth >> MiscellaneousReq,
+    ]);
+    keys.push(postman::TRACE_REQUEST.to_owned());
+    keys.push(postman::NET_STATE_SYNC.to_owned());
+    start_pubsub("executor", keys, forward_req_sender, forward_resp_receiver);
 
     // start threads to forward messages between mpsc::channel and crosebeam::channel
     thread::spawn(move || loop {
@@ -214,6 +276,8 @@ fn main() {
             command_req_receiver.clone(),
             command_resp_sender.clone(),
             options.eth_compatibility,
+            options.journaldb_type(),
+            options.statedb_cache_size,
         );
         let current_height = executor.get_current_height();
         let current_hash = executor.get_current_hash();
@@ -232,6 +296,9 @@ fn main() {
             command_req_sender.clone(),
             command_resp_receiver.clone(),
         );
+        if options.fast_sync {
+            postman.enable_fast_sync(options.fast_sync_checkpoint());
+        }
         postman.do_loop();
 
         handle.join().expect(
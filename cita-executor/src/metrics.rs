@@ -0,0 +1,52 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::core::CacheStats;
+use cita_metrics::{histogram, int_counter, int_gauge, Histogram, IntCounter, IntGauge};
+
+lazy_static! {
+    pub static ref BLOCK_EXECUTION_SECONDS: Histogram = histogram(
+        "executor_block_execution_seconds",
+        "Time of executing a block"
+    );
+    pub static ref BACKLOG_DEPTH: IntGauge = int_gauge(
+        "executor_backlog_depth",
+        "Number of blocks waiting in the backlogs"
+    );
+    pub static ref EXECUTED_HEIGHT: IntGauge =
+        int_gauge("executor_height", "Height of the latest executed block");
+    pub static ref STATE_SYNC_NODES: IntGauge = int_gauge(
+        "executor_state_sync_nodes",
+        "Number of state nodes downloaded by fast sync"
+    );
+    pub static ref STATEDB_CACHE_HITS: IntCounter = int_counter(
+        "executor_statedb_cache_hits_total",
+        "Number of trie nodes read from the statedb cache"
+    );
+    pub static ref STATEDB_CACHE_MISSES: IntCounter = int_counter(
+        "executor_statedb_cache_misses_total",
+        "Number of trie nodes read from the database"
+    );
+    pub static ref STATEDB_CACHE_BYTES: IntGauge = int_gauge(
+        "executor_statedb_cache_bytes",
+        "Bytes of the trie nodes in the statedb cache"
+    );
+}
+
+/// Export the statedb cache, whose counters only grow.
+pub fn set_statedb_cache(stats: CacheStats) {
+    STATEDB_CACHE_HITS.inc_by(stats.hits as i64 - STATEDB_CACHE_HITS.get());
+    STATEDB_CACHE_MISSES.inc_by(stats.misses as i64 - STATEDB_CACHE_MISSES.get());
+    STATEDB_CACHE_BYTES.set(stats.size as i64);
+}
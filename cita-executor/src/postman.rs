@@ -15,33 +15,69 @@
 use crate::core::contracts::solc::sys_config::ChainId;
 use crate::core::libexecutor::block::{ClosedBlock, OpenBlock};
 use crate::core::libexecutor::call_request::CallRequest;
+use crate::core::libexecutor::state_sync::StateSyncMessage;
+use crate::core::tracer::{TraceOptions, TransactionTrace};
 use crate::core::tx_gas_schedule::TxGasSchedule;
+use crate::types::block::{Block, BlockBody};
 use crate::types::block_number::{BlockTag, Tag};
 use crate::types::errors::ReceiptError;
+use crate::types::node_manager;
+use crate::types::state_proof::StateProof;
+use crate::types::state_sync::ServeLimit;
+use cita_metrics::elapsed_secs;
 use cita_types::U256;
-use cita_types::{Address, H256};
-use crossbeam_channel::{Receiver, Sender};
+use cita_types::{clean_0x, Address, H256};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use error::ErrorCode;
-use jsonrpc_types::rpc_types::{BlockNumber, CountOrCode};
+use jsonrpc_types::rpc_types::{BlockNumber, CountOrCode, Data};
 use libproto::auth::Miscellaneous;
 use libproto::blockchain::{RichStatus, StateSignal};
 use libproto::request::Request_oneof_req as Request;
 use libproto::router::{MsgType, RoutingKey, SubModules};
-use libproto::{request, response, Message};
+use libproto::{
+    request, response, Block as ProtoBlock, Message, MsgClass, OperateType, SyncResponse,
+};
 use libproto::{TryFrom, TryInto};
-use serde_json;
+use serde_json::{self, json, Value};
 use std::convert::Into;
+use std::str::FromStr;
+use std::time::Instant;
 use std::u8;
 
 use crate::core::libexecutor::blacklist::BlackList;
 use crate::core::libexecutor::command;
 use crate::core::libexecutor::lru_cache::LRUCache;
 
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 use super::backlogs::{wrap_height, Backlogs};
+use super::fast_sync::{FastSync, FAST_SYNC_TICK};
+use super::metrics;
 use cita_vm::state::StateObjectInfo;
 
+/// The trace calls forwarded by chain, libproto has no message type for them.
+pub const TRACE_REQUEST: &str = "chain.trace_request";
+/// The trace replies to JSON-RPC.
+const TRACE_RESPONSE: &str = "executor.trace_response";
+/// The fast sync messages from the executors and chains of peers.
+pub const NET_STATE_SYNC: &str = "network.state_sync";
+/// The fast sync messages to the executors of peers.
+const STATE_SYNC: &str = "executor.state_sync";
+/// The receipts requests to the chains of peers.
+const RECEIPTS_REQUEST: &str = "executor.receipts_request";
+/// The receipts downloaded by fast sync, saved by chain.
+const SNAPSHOT_RECEIPTS: &str = "executor.snapshot_receipts";
+
+/// The call of `traceCall`.
+#[derive(Debug, Deserialize)]
+struct TraceCall {
+    #[serde(default)]
+    from: Option<Data>,
+    to: Data,
+    #[serde(default)]
+    data: Option<Data>,
+}
+
 pub struct Postman {
     backlogs: Backlogs,
     black_list_cache: RwLock<LRUCache<u64, Address>>,
@@ -51,6 +87,13 @@ pub struct Postman {
     fsm_resp_receiver: Receiver<ClosedBlock>,
     command_req_sender: Sender<command::Command>,
     command_resp_receiver: Receiver<command::CommandResp>,
+    // The height and start time of the block in executing
+    executing: Option<(u64, Instant)>,
+    fast_sync_enabled: bool,
+    // The height and the hash of a trusted block to fast sync to
+    fast_sync_checkpoint: Option<(u64, H256)>,
+    // Bytes served to the fast sync of peers
+    serve_limit: ServeLimit,
 }
 
 impl Postman {
@@ -74,14 +117,36 @@ impl Postman {
             fsm_resp_receiver,
             command_req_sender,
             command_resp_receiver,
+            executing: None,
+            fast_sync_enabled: false,
+            fast_sync_checkpoint: None,
+            serve_limit: ServeLimit::default(),
         }
     }
 
+    /// Download the state of a recent block from peers, instead of executing
+    /// all the blocks, if nothing is executed yet. The block of the
+    /// checkpoint is downloaded if given.
+    pub fn enable_fast_sync(&mut self, checkpoint: Option<(u64, H256)>) {
+        self.fast_sync_enabled = true;
+        self.fast_sync_checkpoint = checkpoint;
+    }
+
     pub fn do_loop(&mut self) {
         // 1. broadcast current state toward cita-chain
         self.bootstrap_broadcast();
 
-        // 2. listen and handle messages
+        // 2. jump to a recent block of peers if it is a new node
+        if self.fast_sync_enabled && self.get_current_height() == 0 {
+            if let Err(rollback_id) = self.fast_sync() {
+                self.close(rollback_id);
+                return;
+            }
+            // Ask the blocks dropped during fast sync again.
+            self.signal_to_chain();
+        }
+
+        // 3. listen and handle messages
         loop {
             match self.recv() {
                 (None, None) | (Some(_), Some(_)) => return,
@@ -98,6 +163,7 @@ impl Postman {
                     self.execute_next_block();
                 }
             }
+            metrics::BACKLOG_DEPTH.set(self.backlogs.pending_len() as i64);
         }
     }
 
@@ -165,12 +231,29 @@ impl Postman {
     fn handle_fsm_response(&mut self, closed_block: ClosedBlock) {
         let height = closed_block.number();
         info!("postman receive {}-th ClosedBlock from executor", height);
+        if let Some((executing_height, start)) = self.executing.take() {
+            if executing_height == height {
+                metrics::BLOCK_EXECUTION_SECONDS.observe(elapsed_secs(start));
+            }
+        }
         self.backlogs.insert_closed(height, closed_block);
     }
 
     fn handle_mq_message(&mut self, key: &str, msg_vec: Vec<u8>) -> Result<(), BlockTag> {
         let mut msg = Message::try_from(msg_vec).unwrap();
         trace!("receive {} from RabbitMQ", key);
+        if key == TRACE_REQUEST {
+            if let Some(query) = msg.take_raw_bytes() {
+                self.reply_trace(&query);
+            }
+            return Ok(());
+        }
+        if key == NET_STATE_SYNC {
+            if let Some(message) = Self::take_state_sync_message(&mut msg) {
+                self.reply_state_sync(message, msg.get_origin());
+            }
+            return Ok(());
+        }
         match RoutingKey::from(key) {
             routing_key!(Auth >> MiscellaneousReq) => {
                 self.reply_auth_miscellaneous();
@@ -209,6 +292,239 @@ impl Postman {
         Ok(())
     }
 
+    // Return `Err` to restart at the downloaded block, or `Ok` to execute from the genesis.
+    fn fast_sync(&mut self) -> Result<(), BlockTag> {
+        let genesis = command::headers(&self.command_req_sender, &self.command_resp_receiver, 0, 1)
+            .pop()
+            .expect("the genesis is saved by executor; qed");
+        let validators = self
+            .backlogs
+            .get_completed_result(0)
+            .expect("loaded by bootstrap_broadcast; qed")
+            .get_config()
+            .get_validators()
+            .iter()
+            .map(|v| Address::from_slice(&v[..]))
+            .collect();
+        let state_db = command::state_db(&self.command_req_sender, &self.command_resp_receiver);
+        let mut fast_sync = FastSync::new(
+            genesis,
+            validators,
+            self.fast_sync_checkpoint,
+            state_db,
+            Instant::now(),
+        );
+        info!("fast sync: start");
+
+        loop {
+            let now = Instant::now();
+            if fast_sync.should_give_up(now) {
+                info!("fast sync: give up, execute from the genesis");
+                return Ok(());
+            }
+            match fast_sync.requests(now) {
+                Ok(requests) => {
+                    for (origin, message) in requests {
+                        match origin {
+                            Some(origin) => {
+                                self.send_state_sync(&message, OperateType::Single, origin)
+                            }
+                            None => self.send_state_sync(&message, OperateType::Broadcast, 0),
+                        };
+                    }
+                }
+                Err(e) => {
+                    error!("fast sync: {}, execute from the genesis", e);
+                    return Ok(());
+                }
+            }
+            if fast_sync.is_complete() {
+                return self.finish_fast_sync(&fast_sync);
+            }
+
+            let (key, msg_vec) = match self.mq_req_receiver.recv_timeout(FAST_SYNC_TICK) {
+                Ok(mq_req) => mq_req,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(BlockTag::Height(::std::usize::MAX as u64));
+                }
+            };
+            if key == NET_STATE_SYNC {
+                let mut msg = Message::try_from(msg_vec).unwrap();
+                let origin = msg.get_origin();
+                let result = match Self::take_state_sync_message(&mut msg) {
+                    Some(StateSyncMessage::Height(height)) => {
+                        fast_sync.on_height(origin, height);
+                        Ok(())
+                    }
+                    Some(StateSyncMessage::Headers(headers)) => {
+                        fast_sync.on_headers(origin, headers)
+                    }
+                    Some(StateSyncMessage::Validators(height, proofs)) => {
+                        fast_sync.on_validators(origin, height, &proofs)
+                    }
+                    Some(StateSyncMessage::Nodes(nodes)) => {
+                        let result = fast_sync.on_nodes(origin, nodes);
+                        metrics::STATE_SYNC_NODES.set(fast_sync.imported() as i64);
+                        result
+                    }
+                    Some(StateSyncMessage::Receipts(receipts)) => {
+                        fast_sync.on_receipts(origin, receipts)
+                    }
+                    Some(message) => {
+                        self.reply_state_sync(message, origin);
+                        Ok(())
+                    }
+                    None => Ok(()),
+                };
+                if let Err(e) = result {
+                    warn!("fast sync: {}", e);
+                }
+                continue;
+            }
+            match RoutingKey::from(key.as_str()) {
+                // Blocks are executed after the state is downloaded.
+                routing_key!(Consensus >> SignedProposal)
+                | routing_key!(Consensus >> BlockWithProof)
+                | routing_key!(Net >> SyncResponse)
+                | routing_key!(Chain >> LocalSync) => {}
+                _ => self.handle_mq_message(key.as_str(), msg_vec)?,
+            }
+        }
+    }
+
+    fn finish_fast_sync(&self, fast_sync: &FastSync) -> Result<(), BlockTag> {
+        let target = fast_sync
+            .target()
+            .cloned()
+            .expect("the state of target is downloaded; qed");
+        let height = target.number();
+        info!(
+            "fast sync: {} state nodes and {} block receipts of block {} are downloaded",
+            fast_sync.imported(),
+            fast_sync.receipts().len(),
+            height
+        );
+
+        // Save the reference counts of the state before the headers, like a block.
+        fast_sync
+            .state_db()
+            .commit_journal(height)
+            .expect("Commit state journal error.");
+        command::import_headers(
+            &self.command_req_sender,
+            &self.command_resp_receiver,
+            fast_sync.target_headers(),
+        );
+
+        // cita-chain saves the receipts, and jumps to the block too, whose
+        // body is not downloaded.
+        let receipts = StateSyncMessage::Receipts(fast_sync.receipts().to_vec());
+        let msg = Message::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(receipts.to_bytes()),
+        );
+        self.response_mq(SNAPSHOT_RECEIPTS.to_owned(), msg.try_into().unwrap());
+
+        let block = Block {
+            header: target,
+            body: BlockBody::default(),
+        };
+        let mut sync_response = SyncResponse::new();
+        sync_response.mut_blocks().push(block.protobuf());
+        let msg: Message = sync_response.into();
+        self.response_mq(
+            routing_key!(Executor >> SyncResponse).into(),
+            msg.try_into().unwrap(),
+        );
+
+        // Restart from the block, as rolling back to it.
+        Err(BlockTag::Height(height))
+    }
+
+    fn take_state_sync_message(msg: &mut Message) -> Option<StateSyncMessage> {
+        let bytes = msg.take_raw_bytes()?;
+        StateSyncMessage::from_bytes(&bytes)
+            .map_err(|e| warn!("invalid state sync message: {}", e))
+            .ok()
+    }
+
+    // Serve the fast sync of peers, in the limit of bytes.
+    fn reply_state_sync(&mut self, message: StateSyncMessage, origin: u32) {
+        if !self.serve_limit.allow(origin, Instant::now()) {
+            debug!(
+                "drop the state sync request of origin {} over the limit",
+                origin
+            );
+            return;
+        }
+        let reply = match message {
+            StateSyncMessage::GetHeight => StateSyncMessage::Height(self.get_current_height()),
+            StateSyncMessage::GetHeaders(start, count) => {
+                StateSyncMessage::Headers(command::headers(
+                    &self.command_req_sender,
+                    &self.command_resp_receiver,
+                    start,
+                    count,
+                ))
+            }
+            StateSyncMessage::GetValidators(height) => {
+                StateSyncMessage::Validators(height, self.validators_proof(height))
+            }
+            StateSyncMessage::GetNodes(hashes) => StateSyncMessage::Nodes(command::state_nodes(
+                &self.command_req_sender,
+                &self.command_resp_receiver,
+                hashes,
+            )),
+            _ => return,
+        };
+        let size = self.send_state_sync(&reply, OperateType::Single, origin);
+        self.serve_limit.add(origin, size);
+    }
+
+    // The proofs of the validators in the state of the height, none if the
+    // state is pruned.
+    fn validators_proof(&self, height: u64) -> Vec<StateProof> {
+        let mut state = match command::state_at(
+            &self.command_req_sender,
+            &self.command_resp_receiver,
+            BlockTag::Height(height),
+        ) {
+            Some(state) => state,
+            None => return Vec::new(),
+        };
+        let address = node_manager::contract_address();
+        let count = match state.get_storage(&address, &node_manager::nodes_key()) {
+            Ok(count) => U256::from(count).low_u64(),
+            Err(_) => return Vec::new(),
+        };
+        node_manager::validators_keys(count)
+            .iter()
+            .map(|key| StateProof::prove(&state, &address, key))
+            .collect::<Result<_, _>>()
+            .unwrap_or_default()
+    }
+
+    // Returns the size of the message.
+    fn send_state_sync(
+        &self,
+        message: &StateSyncMessage,
+        operate: OperateType,
+        origin: u32,
+    ) -> usize {
+        // The receipts are served by the chains of peers.
+        let key = match message {
+            StateSyncMessage::GetReceipts(_) => RECEIPTS_REQUEST,
+            _ => STATE_SYNC,
+        };
+        let bytes = message.to_bytes();
+        let size = bytes.len();
+        let msg = Message::init(operate, origin, MsgClass::RawBytes(bytes));
+        self.response_mq(key.to_owned(), msg.try_into().unwrap());
+        size
+    }
+
     // cita-chain broadcast StateSignal to indicate its state. So we could figure out
     // which blocks cita-chain lack of, then re-send the lacking blocks to cita-chain.
     fn reply_chain_state_signal(&self, state_signal: &StateSignal) -> Result<(), BlockTag> {
@@ -337,6 +653,7 @@ impl Postman {
             Ok(closed_block) => {
                 trace!("postman notice executor to grow up to {}", next_height);
                 self.pub_black_list(&closed_block);
+                let state_db = Arc::clone(&closed_block.state.db);
                 let executed_result = command::grow(
                     &self.command_req_sender,
                     &self.command_resp_receiver,
@@ -344,6 +661,8 @@ impl Postman {
                 );
                 self.backlogs
                     .insert_completed_result(next_height, executed_result);
+                metrics::EXECUTED_HEIGHT.set(next_height as i64);
+                metrics::set_statedb_cache(state_db.cache_stats());
                 self.send_executed_info_to_chain(next_height).unwrap();
             }
             Err(reason) => trace!("{}", reason),
@@ -355,6 +674,7 @@ impl Postman {
         match self.backlogs.ready(next_height) {
             Ok(open_block) => {
                 trace!("postman send {}-th block to executor", next_height);
+                self.executing = Some((next_height, Instant::now()));
                 let _ = self.fsm_req_sender.send(open_block.clone());
             }
             Err(reason) => trace!("{}", reason),
@@ -645,16 +965,15 @@ impl Postman {
                             block_id.into(),
                         )
                         .and_then(|state| {
-                            state
-                                .get_storage_proof(
-                                    &Address::from(state_info.get_address()),
-                                    &H256::from(state_info.get_position()),
-                                )
-                                .ok()
+                            StateProof::prove(
+                                &state,
+                                &Address::from(state_info.get_address()),
+                                &H256::from(state_info.get_position()),
+                            )
+                            .ok()
                         }) {
-                            Some(state_proof_bs) => {
-                                let buf: Vec<u8> = state_proof_bs.into_iter().flatten().collect();
-                                response.set_state_proof(buf);
+                            Some(state_proof) => {
+                                response.set_state_proof(state_proof.to_bytes());
                             }
                             None => {
                                 response.set_code(ErrorCode::query_error());
@@ -726,6 +1045,98 @@ impl Postman {
         self.backlogs.get_current_height()
     }
 
+    fn reply_trace(&self, query: &[u8]) {
+        let query: Value = match serde_json::from_slice(query) {
+            Ok(query) => query,
+            Err(e) => {
+                warn!("receive invalid trace query: {:?}", e);
+                return;
+            }
+        };
+        let result = match query["method"].as_str() {
+            Some("traceTransaction") => self.trace_transaction(&query),
+            Some("traceCall") => self.trace_call(&query["params"]),
+            _ => Err(format!("unknown trace method {}", query["method"])),
+        };
+        let reply = match result {
+            Ok(trace) => json!({"request_id": query["request_id"], "result": trace.to_json()}),
+            Err(error) => json!({"request_id": query["request_id"], "error": error}),
+        };
+        let msg = Message::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(reply.to_string().into_bytes()),
+        );
+        self.response_mq(TRACE_RESPONSE.to_owned(), msg.try_into().unwrap());
+    }
+
+    // The params are the transaction hash and the options, chain adds the
+    // block and the receipt of the transaction.
+    fn trace_transaction(&self, query: &Value) -> Result<TransactionTrace, String> {
+        let params = &query["params"];
+        let hash = params[0]
+            .as_str()
+            .ok_or_else(|| "missing transaction hash".to_owned())?;
+        let hash = H256::from_str(clean_0x(hash)).map_err(|_| format!("invalid hash {}", hash))?;
+        let block: Data = serde_json::from_value(query["block"].clone())
+            .map_err(|e| format!("invalid block: {}", e))?;
+        let block: Vec<u8> = block.into();
+        let block =
+            ProtoBlock::try_from(&block[..]).map_err(|e| format!("invalid block: {:?}", e))?;
+        let receipt = &query["receipt"];
+        let quota_used = receipt["quotaUsed"]
+            .as_str()
+            .and_then(|quota| U256::from_str(clean_0x(quota)).ok())
+            .ok_or_else(|| format!("invalid receipt {}", receipt))?;
+        let failed = receipt["failed"]
+            .as_bool()
+            .ok_or_else(|| format!("invalid receipt {}", receipt))?;
+        let trace = command::trace_transaction(
+            &self.command_req_sender,
+            &self.command_resp_receiver,
+            OpenBlock::from(block),
+            hash,
+            TraceOptions::from_json(&params[1]),
+        )?;
+        trace.verify(Some((quota_used, failed)))?;
+        Ok(trace)
+    }
+
+    // The params are the call, the block number and the options, the last
+    // two are optional.
+    fn trace_call(&self, params: &Value) -> Result<TransactionTrace, String> {
+        let call: TraceCall = serde_json::from_value(params[0].clone())
+            .map_err(|e| format!("invalid call: {}", e))?;
+        let block_tag = match params[1] {
+            Value::Null => BlockTag::Tag(Tag::Latest),
+            ref block_id => serde_json::from_value::<BlockNumber>(block_id.clone())
+                .map_err(|e| format!("invalid block number: {}", e))?
+                .into(),
+        };
+        let address = |data: Data| {
+            let data: Vec<u8> = data.into();
+            if data.len() == 20 {
+                Ok(Address::from_slice(&data))
+            } else {
+                Err(format!("invalid address length {}", data.len()))
+            }
+        };
+        let request = CallRequest {
+            from: call.from.map(address).transpose()?,
+            to: address(call.to)?,
+            data: call.data.map(Into::into),
+        };
+        let trace = command::trace_call(
+            &self.command_req_sender,
+            &self.command_resp_receiver,
+            request,
+            block_tag,
+            TraceOptions::from_json(&params[2]),
+        )?;
+        trace.verify(None)?;
+        Ok(trace)
+    }
+
     fn response_mq(&self, key: String, message: Vec<u8>) {
         trace!("send {} into RabbitMQ", key);
         let _ = self.mq_resp_sender.send((key, message));
@@ -771,6 +1182,56 @@ mod tests {
         );
     }
 
+    fn trace_reply(receiver: &Receiver<(String, Vec<u8>)>) -> Value {
+        let (key, message) = receiver.recv().unwrap();
+        assert_eq!(key, TRACE_RESPONSE);
+        let body = Message::try_from(message)
+            .unwrap()
+            .take_raw_bytes()
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_reply_trace() {
+        let mut postman = helpers::generate_postman(0, H256::from(0));
+        let (mq_resp_sender, mq_resp_receiver) = crossbeam_channel::unbounded();
+        let (command_req_sender, command_req_receiver) = crossbeam_channel::bounded(0);
+        let (command_resp_sender, command_resp_receiver) = crossbeam_channel::bounded(0);
+        postman.mq_resp_sender = mq_resp_sender;
+        postman.command_req_sender = command_req_sender;
+        postman.command_resp_receiver = command_resp_receiver;
+
+        postman.reply_trace(br#"{"request_id":"1","method":"traceCall","params":[{"to":"0x01"}]}"#);
+        let reply = trace_reply(&mq_resp_receiver);
+        assert_eq!(reply["request_id"], "1");
+        assert_eq!(reply["error"], "invalid address length 1");
+
+        ::std::thread::spawn(move || match command_req_receiver.recv().unwrap() {
+            command::Command::TraceCall(request, block_tag, options) => {
+                assert_eq!(request.to, Address::from(2));
+                assert_eq!(block_tag, BlockTag::Height(3));
+                assert!(!options.steps);
+                command_resp_sender
+                    .send(command::CommandResp::TraceCall(Ok(
+                        TransactionTrace::default(),
+                    )))
+                    .unwrap();
+            }
+            _ => panic!("received should be Command::TraceCall"),
+        });
+        let to = format!("{:#x}", Address::from(2));
+        let query = json!({
+            "request_id": "2",
+            "method": "traceCall",
+            "params": [{"to": to}, "0x3", {"disableSteps": true}],
+        });
+        postman.reply_trace(query.to_string().as_bytes());
+        let reply = trace_reply(&mq_resp_receiver);
+        assert_eq!(reply["request_id"], "2");
+        assert_eq!(reply["result"]["call"], Value::Null);
+    }
+
     #[test]
     fn test_bootstrap_broadcast_at_3th() {
         let mut postman = helpers::generate_postman(3, H256::from(0));
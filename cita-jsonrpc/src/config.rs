@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::limiter::LimitConfig;
+use cita_metrics::MetricsConfig;
 use std::convert::Into;
 use ws::Settings;
 
@@ -22,6 +24,8 @@ pub struct Config {
     pub http_config: HttpConfig,
     pub ws_config: WsConfig,
     pub new_tx_flow_config: NewTxFlowConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 impl Config {
@@ -42,6 +46,12 @@ pub struct WsConfig {
     pub thread_number: usize,
     pub listen_ip: String,
     pub listen_port: String,
+    /// Subscriptions a connection can hold at most.
+    #[serde(default = "default_max_subscriptions")]
+    pub max_subscriptions: usize,
+    /// Seconds to wait for the reply of a pool, logs or trace call.
+    #[serde(default = "default_ws_timeout")]
+    pub timeout: u64,
 
     max_connections: usize,
     queue_size: usize,
@@ -100,6 +110,14 @@ impl Into<Settings> for WsConfig {
     }
 }
 
+fn default_max_subscriptions() -> usize {
+    32
+}
+
+fn default_ws_timeout() -> u64 {
+    60
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HttpConfig {
     pub enable: bool,
@@ -108,4 +126,6 @@ pub struct HttpConfig {
     pub listen_port: String,
     pub timeout: u64,
     pub allow_origin: Option<String>,
+    #[serde(default)]
+    pub limit: LimitConfig,
 }
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::subscription::Notify;
 use futures::sync::oneshot;
 use jsonrpc_types::rpc_request::RequestInfo;
 use jsonrpc_types::rpc_response::Output;
@@ -28,17 +29,40 @@ pub enum TransferType {
     HTTP((RequestInfo, oneshot::Sender<Output>)),
     /// websocket output sender
     WEBSOCKET((RequestInfo, ws::Sender)),
+    /// internal request issued for websocket subscriptions
+    SUBSCRIPTION((RequestInfo, Notify)),
 }
 
 pub type RpcMap = Arc<Mutex<HashMap<Vec<u8>, TransferType>>>;
 pub type ReqSender = Mutex<Sender<(String, ProtoRequest)>>;
 
+/// The trace calls, libproto has no message type for them.
+pub const TRACE_REQUEST: &str = "jsonrpc.trace_request";
+/// The trace replies of executor.
+pub const TRACE_RESPONSE: &str = "executor.trace_response";
+/// The logs calls, answered by chain.
+pub const LOGS_REQUEST: &str = "jsonrpc.logs_request";
+/// The replies of chain to the logs calls, and to the trace calls it can not
+/// forward to executor.
+pub const CHAIN_RESPONSE: &str = "chain.raw_response";
+/// The peer management calls, only served on the admin endpoint.
+pub const PEER_REQUEST: &str = "jsonrpc.peer_request";
+/// The peer management replies of network.
+pub const PEER_RESPONSE: &str = "network.peer_response";
+/// The hashes of the transactions auth adds to its pool.
+pub const PENDING_TXS: &str = "auth.pending_txs";
+
 pub fn select_topic(method: &str) -> String {
     match method {
         "peerCount" => routing_key!(Jsonrpc >> RequestNet).into(),
         "peersInfo" => routing_key!(Jsonrpc >> RequestPeersInfo).into(),
         "sendRawTransaction" | "sendTransaction" => routing_key!(Jsonrpc >> RequestNewTx).into(),
         "getVersion" | "estimateQuota" => routing_key!(Jsonrpc >> RequestRpc).into(),
+        "getPoolStatus" | "getPoolContent" | "getPoolTransaction" => {
+            routing_key!(Jsonrpc >> RawBytes).into()
+        }
+        "getLogsPage" => LOGS_REQUEST.to_owned(),
+        "traceTransaction" | "traceCall" => TRACE_REQUEST.to_owned(),
         _ => routing_key!(Jsonrpc >> Request).into(),
     }
 }
@@ -55,6 +79,18 @@ mod test {
             "jsonrpc.request_new_tx".to_string()
         );
         assert_eq!(select_topic("blockNumber"), "jsonrpc.request".to_string());
+        assert_eq!(
+            select_topic("getPoolStatus"),
+            "jsonrpc.raw_bytes".to_string()
+        );
+        assert_eq!(
+            select_topic("getLogsPage"),
+            "jsonrpc.logs_request".to_string()
+        );
+        assert_eq!(
+            select_topic("traceCall"),
+            "jsonrpc.trace_request".to_string()
+        );
         assert_eq!(
             select_topic("getBlockByNumber"),
             "jsonrpc.request".to_string()
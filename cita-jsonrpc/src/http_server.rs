@@ -12,47 +12,64 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cita_metrics::elapsed_secs;
 use futures::future::{self as future, Future};
 use hyper::header::{
     HeaderMap as Headers, HeaderName, HeaderValue, ACCEPT, ACCESS_CONTROL_ALLOW_HEADERS,
     ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
     CONTENT_TYPE, ORIGIN, USER_AGENT,
 };
+use hyper::server::conn::AddrStream;
 use hyper::service::{MakeService, Service};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use jsonrpc_types::{rpc_request::RpcRequest as JsonrpcRequest, rpc_types::Id as RpcId};
+use jsonrpc_types::{
+    rpc_request::{PartialRequest, RpcRequest as JsonrpcRequest},
+    rpc_types::Id as RpcId,
+};
 use libproto::request::Request as ProtoRequest;
 use pubsub::channel::Sender;
-use std::net::{SocketAddr, TcpListener};
+use serde_json::Value;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use util::Mutex;
 
-use crate::extractor::FutExtractor;
+use crate::extractor::{Extractor, FutExtractor};
 use crate::helper::{ReqSender, RpcMap};
 use crate::http_header::{Origin, CONTENT_TYPE_JSON_STR, CONTENT_TYPE_PLAIN_TEXT_STR};
-use crate::mq_publisher::{AccessLog as MQAccessLog, MQRequest, Publisher, TimeoutPublisher};
+use crate::limiter::{parse_calls, Limiter, Rejection};
+use crate::metrics;
+use crate::mq_publisher::{
+    AccessLog as MQAccessLog, BatchCall, HybridRequest, MQRequest, Publisher, TimeoutPublisher,
+};
 use crate::response::{HyperResponseExt, IntoResponse};
+use crate::service_error::ServiceError;
+use crate::txpool::{PoolCall, PoolQueryMap};
 
 const TCP_BACKLOG: i32 = 1024;
 const CORS_CACHE: u32 = 86_400u32;
 
+type ServiceFuture = Box<dyn Future<Item = Response<Body>, Error = ServiceError> + Send>;
+
 struct Inner {
     pub tx: ReqSender,
     pub responses: RpcMap,
+    pub pool_queries: PoolQueryMap,
     pub timeout: Duration,
     pub http_headers: Headers,
+    pub limiter: Arc<Limiter>,
 }
 
 pub struct Jsonrpc {
     inner: Arc<Inner>,
+    remote_ip: IpAddr,
 }
 
 pub struct JsonrpcMakeService {
     inner: Arc<Inner>,
 }
 
-impl<Ctx> MakeService<Ctx> for JsonrpcMakeService {
+impl<'a> MakeService<&'a AddrStream> for JsonrpcMakeService {
     type ReqBody = Body;
     type ResBody = Body;
     type Error = hyper::Error;
@@ -60,9 +77,10 @@ impl<Ctx> MakeService<Ctx> for JsonrpcMakeService {
     type Future = Box<dyn Future<Item = Self::Service, Error = Self::Error> + Send>;
     type MakeError = hyper::Error;
 
-    fn make_service(&mut self, _: Ctx) -> Self::Future {
+    fn make_service(&mut self, conn: &'a AddrStream) -> Self::Future {
         Box::new(future::ok(Jsonrpc {
             inner: Arc::clone(&self.inner),
+            remote_ip: conn.remote_addr().ip(),
         }))
     }
 }
@@ -157,30 +175,192 @@ impl Service for Jsonrpc {
     fn call(&mut self, http_req: Request<Self::ReqBody>) -> Self::Future {
         let sender = { self.inner.tx.lock().clone() };
         let responses = Arc::clone(&self.inner.responses);
+        let pool_queries = Arc::clone(&self.inner.pool_queries);
         let timeout = self.inner.timeout;
         let http_headers = self.inner.http_headers.clone();
+        let inner = Arc::clone(&self.inner);
+        let remote_ip = self.remote_ip;
+        let api_key = http_req
+            .headers()
+            .get(self.inner.limiter.api_key_header())
+            .and_then(|key| key.to_str().ok())
+            .map(ToOwned::to_owned);
 
         let http_path = http_req.uri().path().to_owned();
         let mut access_log = AccessLog::new(http_req.method(), &http_path, &http_headers);
 
         match (http_req.method(), http_path.as_ref()) {
             (&Method::POST, "/") => {
-                let fut_resp = FutExtractor::<JsonrpcRequest>::extract_from(http_req)
-                    .and_then(FutExtractor::<MQRequest>::extract_from)
+                use futures::Stream;
+
+                let fut_resp = http_req
+                    .into_body()
+                    .concat2()
+                    .map_err(ServiceError::BodyConcatError)
                     .and_then({
                         let headers = http_headers.clone();
 
-                        move |mq_req| {
-                            // logging
-                            access_log.set_rpc_info(RpcAccessLog::from(mq_req.access_log()));
-                            info!("{}", access_log);
+                        move |chunk| -> ServiceFuture {
+                            let body = match serde_json::from_slice::<Value>(&chunk) {
+                                Ok(body) => body,
+                                Err(e) => {
+                                    let err = ServiceError::JsonrpcSerdeError(e);
+                                    return Box::new(future::err::<Response<Body>, _>(err));
+                                }
+                            };
+
+                            if inner.limiter.is_enabled() {
+                                let (methods, id) = parse_calls(&body);
+                                let checked = inner.limiter.check(
+                                    remote_ip,
+                                    api_key.as_ref().map(String::as_str),
+                                    &methods,
+                                );
+                                if let Err(rejection) = checked {
+                                    info!("{}, rejected={}", access_log, rejection.reason());
+                                    metrics::RPC_REJECTED_TOTAL
+                                        .with_label_values(&[rejection.reason()])
+                                        .inc();
+                                    let resp = Response::default()
+                                        .with_headers(headers)
+                                        .with_body(Body::from(rejection.failure(id)));
+                                    return Box::new(future::ok(resp));
+                                }
+                            }
+
+                            // transaction pool calls are answered by auth directly
+                            if let Some(call) = PoolCall::from_value(&body) {
+                                info!("{}, rpc-method={}", access_log, call.method);
+                                let method = call.method.clone();
+                                let start = Instant::now();
+                                let fut_resp =
+                                    pool_queries.query_http(call, timeout).map(move |reply| {
+                                        metrics::RPC_LATENCY_SECONDS
+                                            .with_label_values(&[&method])
+                                            .observe(elapsed_secs(start));
+                                        Response::default()
+                                            .with_headers(headers)
+                                            .with_body(Body::from(reply.to_string()))
+                                    });
+                                return Box::new(fut_resp);
+                            }
+
+                            // a batch containing pool calls is answered call by call
+                            let is_mixed = body.as_array().map_or(false, |calls| {
+                                calls.iter().any(|c| PoolCall::from_value(c).is_some())
+                            });
+                            if is_mixed {
+                                let calls = match mixed_batch_calls(body) {
+                                    Ok(calls) => calls,
+                                    Err(e) => return Box::new(future::err::<Response<Body>, _>(e)),
+                                };
+                                access_log.set_rpc_info(RpcAccessLog::Batch(BatchRpcAccessLog {
+                                    count: Some(calls.len()),
+                                }));
+                                info!("{}", access_log);
+
+                                let timeout_responses = Arc::clone(&responses);
+                                let pulibsher = Publisher::new(responses, sender, headers);
+                                let pulibsher =
+                                    TimeoutPublisher::new(pulibsher, timeout, timeout_responses);
+
+                                let start = Instant::now();
+                                let fut_resp = pulibsher.publish_mixed(calls, &pool_queries).then(
+                                    move |resp| {
+                                        metrics::RPC_LATENCY_SECONDS
+                                            .with_label_values(&["batch"])
+                                            .observe(elapsed_secs(start));
+                                        resp
+                                    },
+                                );
+                                return Box::new(fut_resp);
+                            }
+
+                            let fut_resp = future::result(
+                                serde_json::from_value::<JsonrpcRequest>(body)
+                                    .map_err(ServiceError::JsonrpcSerdeError),
+                            )
+                            .and_then(FutExtractor::<MQRequest>::extract_from)
+                            .and_then(move |mq_req| {
+                                // logging
+                                let mq_access_log = mq_req.access_log();
+                                let method = mq_access_log.method().to_owned();
+                                access_log.set_rpc_info(RpcAccessLog::from(mq_access_log));
+                                info!("{}", access_log);
+
+                                let timeout_responses = Arc::clone(&responses);
+                                let pulibsher = Publisher::new(responses, sender, headers);
+                                let pulibsher =
+                                    TimeoutPublisher::new(pulibsher, timeout, timeout_responses);
+
+                                let start = Instant::now();
+                                pulibsher.publish(mq_req).then(move |resp| {
+                                    metrics::RPC_LATENCY_SECONDS
+                                        .with_label_values(&[&method])
+                                        .observe(elapsed_secs(start));
+                                    resp
+                                })
+                            });
+                            Box::new(fut_resp)
+                        }
+                    })
+                    .then(move |resp| match resp {
+                        Ok(resp) => Ok(resp),
+                        Err(err) => Ok(err.into_response(http_headers.clone())),
+                    });
+
+                Box::new(fut_resp)
+            }
+            // The peer management calls, for the clients with an admin API key only.
+            (&Method::POST, "/admin") => {
+                use futures::Stream;
+
+                info!("{}", access_log);
+                if !inner.limiter.is_admin(api_key.as_ref().map(String::as_str)) {
+                    let rejection = Rejection::Unauthorized;
+                    metrics::RPC_REJECTED_TOTAL
+                        .with_label_values(&[rejection.reason()])
+                        .inc();
+                    let resp = Response::default()
+                        .with_headers(http_headers)
+                        .with_body(Body::from(rejection.failure(Value::Null)));
+                    return Box::new(future::ok(resp));
+                }
 
-                            let timeout_responses = Arc::clone(&responses);
-                            let pulibsher = Publisher::new(responses, sender, headers);
-                            let pulibsher =
-                                TimeoutPublisher::new(pulibsher, timeout, timeout_responses);
+                let fut_resp = http_req
+                    .into_body()
+                    .concat2()
+                    .map_err(ServiceError::BodyConcatError)
+                    .and_then({
+                        let headers = http_headers.clone();
 
-                            pulibsher.publish(mq_req)
+                        move |chunk| -> ServiceFuture {
+                            let body = match serde_json::from_slice::<Value>(&chunk) {
+                                Ok(body) => body,
+                                Err(e) => {
+                                    let err = ServiceError::JsonrpcSerdeError(e);
+                                    return Box::new(future::err::<Response<Body>, _>(err));
+                                }
+                            };
+
+                            let call = match PoolCall::admin_from_value(&body) {
+                                Some(call) => call,
+                                None => {
+                                    let (methods, id) = parse_calls(&body);
+                                    let rejection = Rejection::MethodDenied(methods.concat());
+                                    let resp = Response::default()
+                                        .with_headers(headers)
+                                        .with_body(Body::from(rejection.failure(id)));
+                                    return Box::new(future::ok(resp));
+                                }
+                            };
+                            let fut_resp =
+                                pool_queries.query_http(call, timeout).map(move |reply| {
+                                    Response::default()
+                                        .with_headers(headers)
+                                        .with_body(Body::from(reply.to_string()))
+                                });
+                            Box::new(fut_resp)
                         }
                     })
                     .then(move |resp| match resp {
@@ -208,6 +388,24 @@ impl Service for Jsonrpc {
     }
 }
 
+/// Split a batch into the pool calls and the calls published to the MQ.
+fn mixed_batch_calls(body: Value) -> Result<Vec<BatchCall>, ServiceError> {
+    let calls = match body {
+        Value::Array(calls) => calls,
+        _ => return Ok(Vec::new()),
+    };
+    calls
+        .into_iter()
+        .map(|call| match PoolCall::from_value(&call) {
+            Some(call) => Ok(BatchCall::Pool(call)),
+            None => serde_json::from_value::<PartialRequest>(call)
+                .map_err(ServiceError::JsonrpcSerdeError)
+                .and_then(Extractor::<HybridRequest>::extract_from)
+                .map(BatchCall::MQ),
+        })
+        .collect()
+}
+
 fn handle_preflighted(mut headers: Headers) -> Headers {
     use crate::http_header::{HeaderMapExt, X_REQUESTED_WITH_STR};
 
@@ -236,8 +434,10 @@ impl Server {
         addr: &SocketAddr,
         tx: Sender<(String, ProtoRequest)>,
         responses: RpcMap,
+        pool_queries: PoolQueryMap,
         timeout: u64,
         allow_origin: &Option<String>,
+        limiter: Arc<Limiter>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let listener = listener_from_socket_addr(&addr)?;
         let addr = listener.local_addr()?;
@@ -253,8 +453,10 @@ impl Server {
             inner: Arc::new(Inner {
                 tx: Mutex::new(tx),
                 responses,
+                pool_queries,
                 timeout,
                 http_headers,
+                limiter,
             }),
         };
 
@@ -293,6 +495,8 @@ pub fn listener_from_socket_addr(addr: &SocketAddr) -> std::io::Result<TcpListen
 mod integration_test {
     use super::*;
     use crate::helper::TransferType;
+    use crate::limiter::LimitConfig;
+    use crate::txpool::PoolQueries;
     use futures::{sync::oneshot, Stream};
     use jsonrpc_proto::response::OutputExt;
     use jsonrpc_types;
@@ -333,7 +537,18 @@ mod integration_test {
             .name(format!("test-server-{}", Uuid::new_v4()))
             .spawn(move || {
                 let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
-                let server = Server::create(&addr, tx, responses, timeout, &allow_origin).unwrap();
+                let (pub_tx, _pub_rx) = channel::unbounded();
+                let pool_queries = Arc::new(PoolQueries::new(pub_tx));
+                let server = Server::create(
+                    &addr,
+                    tx,
+                    responses,
+                    pool_queries,
+                    timeout,
+                    &allow_origin,
+                    Arc::new(Limiter::new(LimitConfig::default())),
+                )
+                .unwrap();
 
                 let addr = server.local_addr();
                 addr_tx.send((addr, shutdown_tx)).unwrap();
@@ -402,6 +617,7 @@ mod integration_test {
                                     .unwrap(),
                             );
                         }
+                        TransferType::SUBSCRIPTION(_) => {}
                     }
                 } else {
                     warn!("receive lost request_id {:?}", content.request_id);
@@ -572,4 +788,25 @@ mod integration_test {
         drop(client);
         receiver.join().unwrap();
     }
+
+    #[test]
+    fn test_mixed_batch_calls() {
+        let body = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "peerCount", "params": []},
+            {"jsonrpc": "2.0", "id": 2, "method": "getPoolStatus", "params": []},
+        ]);
+        let calls = mixed_batch_calls(body).unwrap();
+        assert_eq!(calls.len(), 2);
+        match calls[0] {
+            BatchCall::MQ(ref req) => assert_eq!(req.json_req.get_method(), "peerCount"),
+            BatchCall::Pool(_) => panic!("peerCount is not a pool call"),
+        }
+        match calls[1] {
+            BatchCall::Pool(ref call) => assert_eq!(call.method, "getPoolStatus"),
+            BatchCall::MQ(_) => panic!("getPoolStatus is a pool call"),
+        }
+
+        let body = json!([{"jsonrpc": "2.0", "id": 1, "method": "getPoolStatus"}, {"id": 2}]);
+        assert!(mixed_batch_calls(body).is_err());
+    }
 }
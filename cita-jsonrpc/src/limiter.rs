@@ -0,0 +1,398 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access control of the HTTP and WebSocket JSON-RPC servers.
+//!
+//! A client is identified by its API key if sent, or else by its IP address.
+//! The limits are configured in `http_config`, and a WebSocket client is
+//! checked against the same buckets, with the API key of its handshake, for
+//! every message it sends.
+//! Every client has a token bucket for all its calls, and one more for each
+//! method with a limit of its own, e.g.
+//!
+//! ```toml
+//! [http_config.limit]
+//! enable = true
+//! rate = { rate = 50.0, burst = 100 }
+//! deny_methods = ["getPoolContent"]
+//! api_keys = ["secret"]
+//! admin_api_keys = ["admin-secret"]
+//! [http_config.limit.method_rates]
+//! call = { rate = 5.0, burst = 10 }
+//! getLogs = { rate = 1.0, burst = 5 }
+//! ```
+//!
+//! The peer management calls are only served on `/admin`, to the clients with
+//! one of `admin_api_keys`, even if the limits are not enabled.
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use util::Mutex;
+
+const DEFAULT_API_KEY_HEADER: &str = "x-api-key";
+// Buckets of the clients idle for so long are full again, and can be dropped.
+const BUCKET_IDLE: Duration = Duration::from_secs(600);
+const MAX_BUCKETS: usize = 100_000;
+
+const ERR_UNAUTHORIZED: i64 = -32003;
+const ERR_METHOD_DENIED: i64 = -32004;
+const ERR_RATE_LIMITED: i64 = -32005;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateConfig {
+    /// Tokens added per second.
+    pub rate: f64,
+    /// Capacity of the bucket.
+    pub burst: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LimitConfig {
+    pub enable: bool,
+    /// Limit of all the calls of a client.
+    pub rate: Option<RateConfig>,
+    /// Limits of the calls of a client to the methods.
+    pub method_rates: HashMap<String, RateConfig>,
+    /// Only these methods are served if not empty.
+    pub allow_methods: Vec<String>,
+    pub deny_methods: Vec<String>,
+    pub api_key_header: String,
+    pub api_keys: Vec<String>,
+    /// Reject the clients without a valid API key.
+    pub require_api_key: bool,
+    /// Keys of the clients allowed to call `/admin`, which is closed if empty.
+    pub admin_api_keys: Vec<String>,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            enable: false,
+            rate: None,
+            method_rates: HashMap::new(),
+            allow_methods: Vec::new(),
+            deny_methods: Vec::new(),
+            api_key_header: DEFAULT_API_KEY_HEADER.to_owned(),
+            api_keys: Vec::new(),
+            require_api_key: false,
+            admin_api_keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    Unauthorized,
+    MethodDenied(String),
+    RateLimited,
+}
+
+impl Rejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::Unauthorized => "unauthorized",
+            Rejection::MethodDenied(_) => "method_denied",
+            Rejection::RateLimited => "rate_limited",
+        }
+    }
+
+    /// The JSON-RPC failure of the call with the id.
+    pub fn failure(&self, id: Value) -> String {
+        let (code, message) = match self {
+            Rejection::Unauthorized => (ERR_UNAUTHORIZED, "Invalid or missing API key".to_owned()),
+            Rejection::MethodDenied(method) => (
+                ERR_METHOD_DENIED,
+                format!("Method {} is not allowed", method),
+            ),
+            Rejection::RateLimited => (ERR_RATE_LIMITED, "Rate limit exceeded".to_owned()),
+        };
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": code,
+                "message": message,
+            },
+        })
+        .to_string()
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &RateConfig, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(config.burst),
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, config: &RateConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * config.rate).min(f64::from(config.burst));
+        self.last = now;
+    }
+}
+
+pub struct Limiter {
+    config: LimitConfig,
+    api_keys: HashSet<String>,
+    // keyed by client and method, the method is empty for all the calls
+    buckets: Mutex<HashMap<(String, String), TokenBucket>>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitConfig) -> Self {
+        let api_keys = config.api_keys.iter().cloned().collect();
+        Limiter {
+            config,
+            api_keys,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enable
+    }
+
+    pub fn api_key_header(&self) -> &str {
+        &self.config.api_key_header
+    }
+
+    pub fn is_admin(&self, api_key: Option<&str>) -> bool {
+        api_key.map_or(false, |key| {
+            self.config.admin_api_keys.iter().any(|admin| admin == key)
+        })
+    }
+
+    /// Check the calls of a request, and take the tokens if all of them pass.
+    pub fn check(
+        &self,
+        ip: IpAddr,
+        api_key: Option<&str>,
+        methods: &[String],
+    ) -> Result<(), Rejection> {
+        if !self.config.enable {
+            return Ok(());
+        }
+        let client = match api_key {
+            Some(key) if self.api_keys.contains(key) => format!("key:{}", key),
+            Some(_) => return Err(Rejection::Unauthorized),
+            None if self.config.require_api_key => return Err(Rejection::Unauthorized),
+            None => format!("ip:{}", ip),
+        };
+
+        for method in methods {
+            let allowed =
+                self.config.allow_methods.is_empty() || self.config.allow_methods.contains(method);
+            if !allowed || self.config.deny_methods.contains(method) {
+                return Err(Rejection::MethodDenied(method.clone()));
+            }
+        }
+
+        let mut needed: Vec<(String, &RateConfig, f64)> = Vec::new();
+        if let Some(ref rate) = self.config.rate {
+            needed.push((String::new(), rate, methods.len() as f64));
+        }
+        for method in methods {
+            if let Some(rate) = self.config.method_rates.get(method) {
+                match needed.iter_mut().find(|entry| entry.0 == *method) {
+                    Some((_, _, count)) => *count += 1.0,
+                    None => needed.push((method.clone(), rate, 1.0)),
+                }
+            }
+        }
+        if needed.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() > MAX_BUCKETS {
+            evict_buckets(&mut buckets, now);
+        }
+        for (method, rate, count) in &needed {
+            let bucket = buckets
+                .entry((client.clone(), method.clone()))
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            bucket.refill(rate, now);
+            if bucket.tokens < *count {
+                return Err(Rejection::RateLimited);
+            }
+        }
+        for (method, _, count) in needed {
+            if let Some(bucket) = buckets.get_mut(&(client.clone(), method)) {
+                bucket.tokens -= count;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Drop the idle buckets, and the least recently used ones if all of them are
+/// busy, leaving room for a tenth of `MAX_BUCKETS` new ones.
+fn evict_buckets(buckets: &mut HashMap<(String, String), TokenBucket>, now: Instant) {
+    buckets.retain(|_, bucket| now.duration_since(bucket.last) < BUCKET_IDLE);
+    let keep = MAX_BUCKETS - MAX_BUCKETS / 10;
+    if buckets.len() > keep {
+        let mut lasts: Vec<Instant> = buckets.values().map(|bucket| bucket.last).collect();
+        lasts.sort_unstable();
+        let oldest = lasts[buckets.len() - keep - 1];
+        buckets.retain(|_, bucket| bucket.last > oldest);
+    }
+}
+
+/// The methods and the id of a parsed JSON-RPC request, the id is null for a batch.
+pub fn parse_calls(body: &Value) -> (Vec<String>, Value) {
+    let method = |call: &Value| {
+        call.get("method")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned()
+    };
+    match body {
+        Value::Array(calls) => (calls.iter().map(method).collect(), Value::Null),
+        call => {
+            let id = call.get("id").cloned().unwrap_or(Value::Null);
+            (vec![method(call)], id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        evict_buckets, parse_calls, LimitConfig, Limiter, RateConfig, Rejection, TokenBucket,
+        MAX_BUCKETS,
+    };
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_limiter() {
+        let mut config = LimitConfig::default();
+        config.enable = true;
+        config.rate = Some(RateConfig {
+            rate: 0.001,
+            burst: 3,
+        });
+        config.method_rates.insert(
+            "call".to_owned(),
+            RateConfig {
+                rate: 0.001,
+                burst: 1,
+            },
+        );
+        config.deny_methods.push("getPoolContent".to_owned());
+        config.api_keys.push("secret".to_owned());
+        let limiter = Limiter::new(config);
+
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let calls = |methods: &[&str]| methods.iter().map(|m| (*m).to_owned()).collect::<Vec<_>>();
+
+        assert_eq!(
+            limiter.check(ip, None, &calls(&["getPoolContent"])),
+            Err(Rejection::MethodDenied("getPoolContent".to_owned()))
+        );
+        assert_eq!(
+            limiter.check(ip, Some("wrong"), &calls(&["blockNumber"])),
+            Err(Rejection::Unauthorized)
+        );
+
+        assert!(limiter.check(ip, None, &calls(&["call"])).is_ok());
+        // The method bucket is empty, and no token of the client is taken.
+        assert_eq!(
+            limiter.check(ip, None, &calls(&["blockNumber", "call"])),
+            Err(Rejection::RateLimited)
+        );
+        assert!(limiter
+            .check(ip, None, &calls(&["blockNumber", "blockNumber"]))
+            .is_ok());
+        assert_eq!(
+            limiter.check(ip, None, &calls(&["blockNumber"])),
+            Err(Rejection::RateLimited)
+        );
+
+        // Another client has buckets of its own.
+        assert!(limiter.check(ip, Some("secret"), &calls(&["call"])).is_ok());
+    }
+
+    #[test]
+    fn test_admin() {
+        let mut config = LimitConfig::default();
+        config.api_keys.push("secret".to_owned());
+        config.admin_api_keys.push("admin".to_owned());
+        let limiter = Limiter::new(config);
+
+        assert!(limiter.is_admin(Some("admin")));
+        assert!(!limiter.is_admin(Some("secret")));
+        assert!(!limiter.is_admin(None));
+        assert!(!Limiter::new(LimitConfig::default()).is_admin(Some("")));
+    }
+
+    #[test]
+    fn test_evict_buckets() {
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        for i in 0..=MAX_BUCKETS {
+            buckets.insert(
+                (format!("ip:{}", i), String::new()),
+                TokenBucket {
+                    tokens: 0.0,
+                    last: start + Duration::from_millis(i as u64),
+                },
+            );
+        }
+
+        // None of them is idle, the oldest ones are dropped.
+        let now = start + Duration::from_secs(200);
+        evict_buckets(&mut buckets, now);
+        assert_eq!(buckets.len(), MAX_BUCKETS - MAX_BUCKETS / 10);
+        assert!(!buckets.contains_key(&("ip:0".to_owned(), String::new())));
+        let newest = (format!("ip:{}", MAX_BUCKETS), String::new());
+        assert!(buckets.contains_key(&newest));
+
+        // The idle ones are dropped first.
+        let now = start + Duration::from_secs(600) + Duration::from_millis(95_000);
+        evict_buckets(&mut buckets, now);
+        assert_eq!(buckets.len(), 5_000);
+        assert!(buckets.contains_key(&newest));
+    }
+
+    #[test]
+    fn test_parse_calls() {
+        let (methods, id) = parse_calls(&json!({"jsonrpc":"2.0","id":7,"method":"call"}));
+        assert_eq!(methods, vec!["call".to_owned()]);
+        assert_eq!(id, 7);
+
+        let (methods, id) = parse_calls(&json!([
+            {"jsonrpc":"2.0","id":1,"method":"call"},
+            {"jsonrpc":"2.0","id":2,"method":"getLogs"}
+        ]));
+        assert_eq!(methods, vec!["call".to_owned(), "getLogs".to_owned()]);
+        assert!(id.is_null());
+
+        assert_eq!(parse_calls(&json!({"id": 1})).0, vec![String::new()]);
+    }
+}
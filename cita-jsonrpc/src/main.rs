@@ -27,6 +27,8 @@
 //!     | jsonrpc | Chain     | Response     |
 //!     | jsonrpc | Executor  | Response     |
 //!     | jsonrpc | Net       | Response     |
+//!     | jsonrpc | Chain     | RichStatus   |
+//!     | jsonrpc | Auth      | RawBytes     |
 //!
 //! 2. Publish channel
 //!
@@ -36,6 +38,16 @@
 //!     | jsonrpc | Jsonrpc   | Chain     | Request           |
 //!     | jsonrpc | Jsonrpc   | Net       | RequestNet        |
 //!     | jsonrpc | jsonrpc   | Net       | RequestPeersInfo  |
+//!     | jsonrpc | Jsonrpc   | Auth      | RawBytes          |
+//!
+//! 3. Trace, logs and peer management calls
+//!
+//!     libproto has no message type for them, they use plain keys: jsonrpc
+//!     publishes `jsonrpc.trace_request` and subscribes `executor.trace_response`,
+//!     publishes `jsonrpc.logs_request` and subscribes `chain.raw_response`,
+//!     publishes `jsonrpc.peer_request` and subscribes `network.peer_response`.
+//!     It subscribes `auth.pending_txs` for the pending transaction
+//!     subscriptions.
 //!
 //! ### Key behavior
 //!
@@ -50,13 +62,15 @@
 //! uuid number and `TransferType`.
 //!
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate libproto;
 #[macro_use]
 extern crate cita_logger as logger;
 #[macro_use]
 extern crate serde_derive;
-#[cfg_attr(test, macro_use)]
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate util;
@@ -67,17 +81,24 @@ mod fdlimit;
 mod helper;
 mod http_header;
 mod http_server;
+mod limiter;
+mod metrics;
 mod mq_handler;
 mod mq_publisher;
 mod response;
 mod service_error;
 mod soliloquy;
+mod subscription;
+mod txpool;
 mod ws_handler;
 
 use crate::config::NewTxFlowConfig;
 use crate::fdlimit::set_fd_limit;
 use crate::http_server::Server;
+use crate::limiter::Limiter;
 use crate::soliloquy::Soliloquy;
+use crate::subscription::Subscriptions;
+use crate::txpool::PoolQueries;
 use crate::ws_handler::WsFactory;
 use clap::App;
 use futures::Future;
@@ -90,7 +111,7 @@ use pubsub::start_pubsub;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use util::{set_panic_handler, Mutex};
 use uuid::Uuid;
 
@@ -117,6 +138,8 @@ fn main() {
     let config = config::Config::new(config_path);
     info!("CITA:jsonrpc config \n {:?}", config);
 
+    cita_metrics::start_server(config.metrics);
+
     //enable HTTP or WebSocket server!
     if !config.ws_config.enable && !config.http_config.enable {
         error!("Please at least enable one of HTTP and WebSocket server!");
@@ -135,17 +158,19 @@ fn main() {
     let (tx, rx) = channel::unbounded();
     let soli_resp_tx = tx_sub.clone();
 
-    start_pubsub(
-        "jsonrpc",
-        routing_key!([
-            Auth >> Response,
-            Chain >> Response,
-            Executor >> Response,
-            Net >> Response,
-        ]),
-        tx_sub,
-        rx_pub,
-    );
+    let mut keys = routing_key!([
+        Auth >> Response,
+        Chain >> Response,
+        Executor >> Response,
+        Net >> Response,
+        Chain >> RichStatus,
+        Auth >> RawBytes,
+    ]);
+    keys.push(helper::TRACE_RESPONSE.to_owned());
+    keys.push(helper::CHAIN_RESPONSE.to_owned());
+    keys.push(helper::PEER_RESPONSE.to_owned());
+    keys.push(helper::PENDING_TXS.to_owned());
+    start_pubsub("jsonrpc", keys, tx_sub, rx_pub);
 
     let backlog_capacity = config.backlog_capacity;
 
@@ -153,7 +178,15 @@ fn main() {
     let responses = Arc::new(Mutex::new(HashMap::with_capacity(backlog_capacity)));
     let http_responses = Arc::clone(&responses);
     let ws_responses = Arc::clone(&responses);
-    let mut mq_handle = mq_handler::MqHandler::new(responses);
+    let subscriptions = Arc::new(Mutex::new(Subscriptions::new(
+        config.ws_config.max_subscriptions,
+    )));
+    let ws_subscriptions = Arc::clone(&subscriptions);
+    let pool_queries = Arc::new(PoolQueries::new(tx_pub.clone()));
+    let http_pool_queries = Arc::clone(&pool_queries);
+    let ws_pool_queries = Arc::clone(&pool_queries);
+    let mut mq_handle =
+        mq_handler::MqHandler::new(responses, subscriptions, pool_queries, tx_relay.clone());
 
     //dispatch
     let tx_flow_config = config.new_tx_flow_config;
@@ -204,15 +237,33 @@ fn main() {
         }
     });
 
+    // The HTTP and WebSocket clients share the buckets of the limits.
+    let limiter = Arc::new(Limiter::new(config.http_config.limit.clone()));
+    let ws_limiter = Arc::clone(&limiter);
+
     //ws
     if config.ws_config.enable {
         let ws_config = config.ws_config.clone();
         let tx = tx_relay.clone();
+        let timeout = Duration::from_secs(ws_config.timeout);
+        let expire_pool_queries = Arc::clone(&ws_pool_queries);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            expire_pool_queries.expire(Instant::now());
+        });
         thread::spawn(move || {
             let url =
                 ws_config.listen_ip.clone() + ":" + &ws_config.listen_port.clone().to_string();
             //let factory = WsFactory::new(ws_responses, tx_pub, 0);
-            let factory = WsFactory::new(ws_responses, tx, 0);
+            let factory = WsFactory::new(
+                ws_responses,
+                ws_subscriptions,
+                ws_pool_queries,
+                tx,
+                0,
+                timeout,
+                ws_limiter,
+            );
             info!("WebSocket Listening on {}", url);
             let mut ws_build = ws::Builder::new();
             ws_build.with_settings(ws_config.into());
@@ -238,9 +289,16 @@ fn main() {
         let _ = thread::Builder::new()
             .name(String::from("http worker"))
             .spawn(move || {
-                let server =
-                    Server::create(&addr, tx_relay, http_responses, timeout, &allow_origin)
-                        .unwrap();
+                let server = Server::create(
+                    &addr,
+                    tx_relay,
+                    http_responses,
+                    http_pool_queries,
+                    timeout,
+                    &allow_origin,
+                    limiter,
+                )
+                .unwrap();
                 let jsonrpc_server = server
                     .jsonrpc()
                     .map_err(|err| eprintln!("server err {}", err));
@@ -0,0 +1,28 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cita_metrics::{histogram_vec, int_counter_vec, HistogramVec, IntCounterVec};
+
+lazy_static! {
+    pub static ref RPC_LATENCY_SECONDS: HistogramVec = histogram_vec(
+        "jsonrpc_request_latency_seconds",
+        "Latency of the HTTP JSON-RPC requests",
+        &["method"]
+    );
+    pub static ref RPC_REJECTED_TOTAL: IntCounterVec = int_counter_vec(
+        "jsonrpc_rejected_requests_total",
+        "JSON-RPC requests and WebSocket messages rejected by the access limits",
+        &["reason"]
+    );
+}
@@ -12,22 +12,40 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::helper::{RpcMap, TransferType};
+use crate::helper::{
+    RpcMap, TransferType, CHAIN_RESPONSE, PEER_RESPONSE, PENDING_TXS, TRACE_RESPONSE,
+};
+use crate::subscription::SubscriptionMap;
+use crate::txpool::PoolQueryMap;
 use jsonrpc_proto::response::OutputExt;
 use jsonrpc_types::rpc_response::Output;
+use libproto::request::Request as ProtoRequest;
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::Message;
 use libproto::TryFrom;
-use serde_json;
+use pubsub::channel::Sender;
+use serde_json::{self, Value};
 
-#[derive(Default)]
 pub struct MqHandler {
     responses: RpcMap,
+    subscriptions: SubscriptionMap,
+    pool_queries: PoolQueryMap,
+    tx: Sender<(String, ProtoRequest)>,
 }
 
 impl MqHandler {
-    pub fn new(responses: RpcMap) -> Self {
-        MqHandler { responses }
+    pub fn new(
+        responses: RpcMap,
+        subscriptions: SubscriptionMap,
+        pool_queries: PoolQueryMap,
+        tx: Sender<(String, ProtoRequest)>,
+    ) -> Self {
+        MqHandler {
+            responses,
+            subscriptions,
+            pool_queries,
+            tx,
+        }
     }
 
     pub fn handle(&mut self, key: &str, body: &[u8]) -> Result<(), ()> {
@@ -37,6 +55,25 @@ impl MqHandler {
             error!("try_from: {:?}", e);
         })?;
 
+        if key == TRACE_RESPONSE || key == CHAIN_RESPONSE || key == PEER_RESPONSE {
+            let reply = msg.take_raw_bytes().ok_or_else(|| {
+                error!("empty {} message", key);
+            })?;
+            self.pool_queries.reply(&reply);
+            return Ok(());
+        }
+
+        if key == PENDING_TXS {
+            let body = msg.take_raw_bytes().ok_or_else(|| {
+                error!("empty {} message", key);
+            })?;
+            let hashes: Vec<Value> = serde_json::from_slice(&body).map_err(|e| {
+                error!("invalid pending transactions: {:?}", e);
+            })?;
+            self.subscriptions.lock().notify_pending_txs(&hashes);
+            return Ok(());
+        }
+
         match RoutingKey::from(key) {
             routing_key!(Auth >> Response)
             | routing_key!(Chain >> Response)
@@ -73,8 +110,36 @@ impl MqHandler {
                             error!("ws: {:?}", e);
                         })?;
                     }
+                    TransferType::SUBSCRIPTION((req_info, notify)) => {
+                        self.subscriptions
+                            .lock()
+                            .notify(notify, Output::from_res_info(content, req_info));
+                    }
                 };
             }
+            routing_key!(Auth >> RawBytes) => {
+                let reply = msg.take_raw_bytes().ok_or_else(|| {
+                    error!("empty pool reply message");
+                })?;
+                self.pool_queries.reply(&reply);
+            }
+            routing_key!(Chain >> RichStatus) => {
+                let rich_status = msg.take_rich_status().ok_or_else(|| {
+                    error!("empty rich status message");
+                })?;
+                let requests = self
+                    .subscriptions
+                    .lock()
+                    .on_new_height(rich_status.get_height());
+                for (topic, req, transfer) in requests {
+                    self.responses
+                        .lock()
+                        .insert(req.request_id.clone(), transfer);
+                    self.tx.send((topic, req)).map_err(|e| {
+                        error!("subscription: {:?}", e);
+                    })?;
+                }
+            }
             _ => {
                 warn!("receive unexpect key {}", key);
             }
@@ -22,11 +22,15 @@ use jsonrpc_types::{
 };
 use libproto::request::Request as ProtoRequest;
 use pubsub::channel::Sender;
+use serde_json::{self, Value};
 use tokio_timer::{clock, Delay};
 
 use crate::helper::{select_topic, RpcMap, TransferType};
-use crate::response::{BatchFutureResponse, PublishFutResponse, SingleFutureResponse};
-use crate::service_error::ServiceError;
+use crate::response::{
+    BatchFutureResponse, HyperResponseExt, PublishFutResponse, SingleFutureResponse,
+};
+use crate::service_error::{timeout_failure, ServiceError};
+use crate::txpool::{PoolCall, PoolQueries};
 type HyperResponse = hyper::Response<hyper::Body>;
 
 #[derive(Debug)]
@@ -41,6 +45,13 @@ pub enum MQRequest {
     Batch(Vec<HybridRequest>),
 }
 
+/// A call of a batch containing pool calls, which are not sent to the MQ.
+#[derive(Debug)]
+pub enum BatchCall {
+    Pool(PoolCall),
+    MQ(HybridRequest),
+}
+
 pub enum AccessLog {
     Single {
         id: JsonrpcId,
@@ -51,6 +62,20 @@ pub enum AccessLog {
     },
 }
 
+impl AccessLog {
+    /// The method label used in metrics.
+    pub fn method(&self) -> &str {
+        match self {
+            AccessLog::Single {
+                method: Some(ref method),
+                ..
+            } => method,
+            AccessLog::Single { method: None, .. } => "unknown",
+            AccessLog::Batch { .. } => "batch",
+        }
+    }
+}
+
 impl MQRequest {
     pub fn access_log(&self) -> AccessLog {
         match self {
@@ -140,6 +165,55 @@ impl TimeoutPublisher {
         }
     }
 
+    /// Publish a batch containing pool calls. Every call is replied or timed
+    /// out on its own, and the replies are kept in the order of the batch.
+    pub fn publish_mixed(
+        mut self,
+        calls: Vec<BatchCall>,
+        pool_queries: &PoolQueries,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = ServiceError> + Send + 'static> {
+        use futures::future::{join_all, Either};
+        use std::sync::Arc;
+
+        type ReplyFuture = Box<dyn Future<Item = Value, Error = ServiceError> + Send + 'static>;
+
+        let timeout = self.timeout;
+        let replies = calls
+            .into_iter()
+            .map(|call| -> ReplyFuture {
+                let req = match call {
+                    BatchCall::Pool(call) => return pool_queries.query_http(call, timeout),
+                    BatchCall::MQ(req) => req,
+                };
+                let req_info = req.json_req.get_info();
+                let request_id = req.proto_req.request_id.clone();
+                let timeout_responses = Arc::clone(&self.timeout_responses);
+                let reply = self
+                    .publisher
+                    .send_request(req)
+                    .select2(Delay::new(clock::now() + timeout))
+                    .then(move |res| match res {
+                        Ok(Either::A((output, _timeout))) => Ok(json_value(&output)),
+                        Ok(Either::B((_reach_timeout, _no_resp))) => {
+                            timeout_responses.lock().remove(&request_id);
+                            Ok(json_value(&timeout_failure(Some(req_info))))
+                        }
+                        Err(_) => Err(ServiceError::InternalServerError),
+                    });
+                Box::new(reply)
+            })
+            .collect::<Vec<ReplyFuture>>();
+
+        let headers = self.publisher.headers.clone();
+        let fut_resp = join_all(replies).map(move |replies| {
+            HyperResponse::default()
+                .with_headers(headers)
+                .with_body(hyper::Body::from(Value::Array(replies).to_string()))
+        });
+
+        Box::new(fut_resp)
+    }
+
     pub fn publish(
         mut self,
         req: MQRequest,
@@ -185,3 +259,10 @@ impl TimeoutPublisher {
         Box::new(fut_resp)
     }
 }
+
+fn json_value<T: serde::Serialize>(reply: &T) -> Value {
+    serde_json::to_value(reply).unwrap_or_else(|e| {
+        error!("serde_json: {}", e);
+        Value::Null
+    })
+}
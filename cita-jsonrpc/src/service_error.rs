@@ -31,6 +31,16 @@ pub enum ServiceError {
     InternalServerError,
 }
 
+/// The failure of a request not replied in time.
+pub fn timeout_failure(req_info: Option<RequestInfo>) -> RpcFailure {
+    let timeout_err =
+        jsonrpc_types::Error::server_error(error::ErrorCode::time_out_error(), MSG_TIMEOUT_RESEND);
+    match req_info {
+        Some(info) => RpcFailure::from_options(info, timeout_err),
+        None => RpcFailure::from(timeout_err),
+    }
+}
+
 impl IntoResponse for ServiceError {
     fn into_response(self, http_headers: Headers) -> Response<Body> {
         let new_response = |status_code: Option<StatusCode>, body: Option<Body>| {
@@ -57,14 +67,7 @@ impl IntoResponse for ServiceError {
                 new_response(None, Some(Body::from(resp_body)))
             }
             ServiceError::MQRpcTimeout(req_info) => {
-                let timeout_err = jsonrpc_types::Error::server_error(
-                    error::ErrorCode::time_out_error(),
-                    MSG_TIMEOUT_RESEND,
-                );
-                let failure = match req_info {
-                    Some(info) => RpcFailure::from_options(info, timeout_err),
-                    None => RpcFailure::from(timeout_err),
-                };
+                let failure = timeout_failure(req_info);
                 let resp_body = serde_json::to_vec(&failure).unwrap_or_else(|e| {
                     error!("serde_json: {}", e);
                     MSG_TIMEOUT_RESEND.as_bytes().to_vec()
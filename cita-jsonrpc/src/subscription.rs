@@ -0,0 +1,402 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebSocket publish/subscribe support.
+//!
+//! A subscription is bound to one WebSocket connection. When chain broadcasts
+//! a new `RichStatus`, internal `getBlockByNumber` / `getLogs` requests are
+//! issued through the normal request pipeline, and their responses are turned
+//! into `subscription` notifications instead of being sent back to a client.
+//!
+//! `newPendingTransactions` notifies the hashes of all the transactions auth
+//! adds to its pool, sent to any jsonrpc instance or received from the
+//! network, which auth publishes on `auth.pending_txs`.
+
+use crate::helper::{select_topic, TransferType};
+use jsonrpc_proto::complete::CompleteInto;
+use jsonrpc_types::rpc_request::{PartialRequest, RequestInfo};
+use jsonrpc_types::rpc_response::Output;
+use jsonrpc_types::ErrorCode;
+use libproto::request::Request as ProtoRequest;
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use util::Mutex;
+use ws;
+
+pub const SUBSCRIBE_METHOD: &str = "subscribe";
+pub const UNSUBSCRIBE_METHOD: &str = "unsubscribe";
+const NOTIFICATION_METHOD: &str = "subscription";
+
+/// Heights that are fetched at most for one `RichStatus`,
+/// when the chain moves forward several blocks at once.
+const MAX_CATCH_UP_HEIGHTS: u64 = 16;
+
+pub type SubscriptionId = String;
+pub type SubscriptionMap = Arc<Mutex<Subscriptions>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionKind {
+    NewHeads,
+    /// The raw `Filter` object, `fromBlock` and `toBlock` are overridden for each block.
+    Logs(Value),
+    /// The transactions added to the pool of auth.
+    NewPendingTransactions,
+}
+
+impl SubscriptionKind {
+    pub fn from_params(params: &[Value]) -> Result<Self, String> {
+        let kind = params
+            .get(0)
+            .and_then(Value::as_str)
+            .ok_or_else(|| "missing subscription type".to_owned())?;
+        match kind {
+            "newHeads" => Ok(SubscriptionKind::NewHeads),
+            "newPendingTransactions" => Ok(SubscriptionKind::NewPendingTransactions),
+            "logs" => {
+                let filter = params.get(1).cloned().unwrap_or_else(|| json!({}));
+                if filter.is_object() {
+                    Ok(SubscriptionKind::Logs(filter))
+                } else {
+                    Err("logs filter should be an object".to_owned())
+                }
+            }
+            _ => Err(format!("unknown subscription type {}", kind)),
+        }
+    }
+}
+
+/// Where the response of an internal request should be delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notify {
+    NewHeads,
+    Logs(SubscriptionId),
+}
+
+struct Subscriber {
+    kind: SubscriptionKind,
+    sender: ws::Sender,
+}
+
+/// A `subscribe` / `unsubscribe` call from a WebSocket client.
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionCall {
+    #[serde(default)]
+    pub jsonrpc: Option<Value>,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+impl SubscriptionCall {
+    /// Only returns the call when its method is handled by the subscription module.
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str::<SubscriptionCall>(text)
+            .ok()
+            .filter(|call| call.method == SUBSCRIBE_METHOD || call.method == UNSUBSCRIBE_METHOD)
+    }
+
+    fn success(&self, result: Value) -> String {
+        json!({
+            "jsonrpc": self.jsonrpc.clone().unwrap_or_else(|| json!("2.0")),
+            "id": self.id,
+            "result": result,
+        })
+        .to_string()
+    }
+
+    fn failure(&self, code: ErrorCode, message: String) -> String {
+        json!({
+            "jsonrpc": self.jsonrpc.clone().unwrap_or_else(|| json!("2.0")),
+            "id": self.id,
+            "error": {
+                "code": code.code(),
+                "message": message,
+            },
+        })
+        .to_string()
+    }
+}
+
+pub struct Subscriptions {
+    /// Subscriptions a connection can hold at most.
+    max_per_connection: usize,
+    next_id: u64,
+    last_height: Option<u64>,
+    subscribers: HashMap<SubscriptionId, Subscriber>,
+}
+
+impl Subscriptions {
+    pub fn new(max_per_connection: usize) -> Self {
+        Subscriptions {
+            max_per_connection,
+            next_id: 0,
+            last_height: None,
+            subscribers: HashMap::new(),
+        }
+    }
+
+    /// Handle a `subscribe` or `unsubscribe` call and return the reply text.
+    pub fn handle_call(&mut self, call: &SubscriptionCall, sender: &ws::Sender) -> String {
+        if call.method == SUBSCRIBE_METHOD {
+            match SubscriptionKind::from_params(&call.params) {
+                Ok(kind) => match self.subscribe(kind, sender.clone()) {
+                    Ok(id) => call.success(json!(id)),
+                    Err(reason) => call.failure(ErrorCode::InvalidRequest, reason),
+                },
+                Err(reason) => call.failure(ErrorCode::InvalidParams, reason),
+            }
+        } else {
+            match call.params.get(0).and_then(Value::as_str) {
+                Some(id) => call.success(json!(self.unsubscribe(id, sender))),
+                None => call.failure(
+                    ErrorCode::InvalidParams,
+                    "missing subscription id".to_owned(),
+                ),
+            }
+        }
+    }
+
+    /// Fails when the connection already holds `max_per_connection` subscriptions.
+    pub fn subscribe(
+        &mut self,
+        kind: SubscriptionKind,
+        sender: ws::Sender,
+    ) -> Result<SubscriptionId, String> {
+        let token = sender.token();
+        let count = self
+            .subscribers
+            .values()
+            .filter(|sub| sub.sender.token() == token)
+            .count();
+        if count >= self.max_per_connection {
+            return Err(format!(
+                "too many subscriptions, at most {} for a connection",
+                self.max_per_connection
+            ));
+        }
+
+        self.next_id += 1;
+        let id = format!("{:#x}", self.next_id);
+        trace!("subscribe {} {:?} token {}", id, kind, sender.token().0);
+        self.subscribers
+            .insert(id.clone(), Subscriber { kind, sender });
+        Ok(id)
+    }
+
+    /// Only the connection which created the subscription can cancel it.
+    pub fn unsubscribe(&mut self, id: &str, sender: &ws::Sender) -> bool {
+        let owned = self
+            .subscribers
+            .get(id)
+            .map(|sub| sub.sender.token() == sender.token())
+            .unwrap_or(false);
+        if owned {
+            self.subscribers.remove(id);
+        }
+        owned
+    }
+
+    /// Drop all subscriptions of a closed connection.
+    pub fn remove_connection(&mut self, sender: &ws::Sender) {
+        let token = sender.token();
+        self.subscribers
+            .retain(|_, sub| sub.sender.token() != token);
+    }
+
+    /// Build the internal requests needed for a new chain height.
+    ///
+    /// Returns `(topic, request, transfer)` tuples, the transfer should be
+    /// registered in the `RpcMap` before the request is published.
+    pub fn on_new_height(&mut self, height: u64) -> Vec<(String, ProtoRequest, TransferType)> {
+        let from = match self.last_height {
+            Some(last) if height <= last => return Vec::new(),
+            Some(last) => {
+                ::std::cmp::max(last + 1, height.saturating_sub(MAX_CATCH_UP_HEIGHTS - 1))
+            }
+            None => height,
+        };
+        self.last_height = Some(height);
+
+        let mut requests = Vec::new();
+        let has_new_heads = self
+            .subscribers
+            .values()
+            .any(|sub| sub.kind == SubscriptionKind::NewHeads);
+        for h in from..=height {
+            let height_hex = format!("{:#x}", h);
+            if has_new_heads {
+                if let Some(req) = internal_request(
+                    "getBlockByNumber",
+                    json!([height_hex, false]),
+                    Notify::NewHeads,
+                ) {
+                    requests.push(req);
+                }
+            }
+            for (id, sub) in &self.subscribers {
+                if let SubscriptionKind::Logs(ref filter) = sub.kind {
+                    let mut filter = filter.clone();
+                    filter["fromBlock"] = json!(height_hex);
+                    filter["toBlock"] = json!(height_hex);
+                    if let Some(req) =
+                        internal_request("getLogs", json!([filter]), Notify::Logs(id.clone()))
+                    {
+                        requests.push(req);
+                    }
+                }
+            }
+        }
+        requests
+    }
+
+    /// Deliver the output of an internal request to the subscribers.
+    pub fn notify(&self, notify: Notify, output: Output) {
+        let result = match serde_json::to_value(&output) {
+            Ok(mut value) => match value.get_mut("result") {
+                Some(result) if !result.is_null() => result.take(),
+                _ => {
+                    warn!("subscription {:?} got no result: {}", notify, value);
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("subscription serde output: {:?}", e);
+                return;
+            }
+        };
+
+        match notify {
+            Notify::NewHeads => {
+                for (id, sub) in &self.subscribers {
+                    if sub.kind == SubscriptionKind::NewHeads {
+                        send_notification(id, sub, &result);
+                    }
+                }
+            }
+            Notify::Logs(id) => {
+                if let (Some(sub), Some(logs)) = (self.subscribers.get(&id), result.as_array()) {
+                    for log in logs {
+                        send_notification(&id, sub, log);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Push the hashes of the transactions added to the pool of auth.
+    pub fn notify_pending_txs(&self, hashes: &[Value]) {
+        for (id, sub) in &self.subscribers {
+            if sub.kind == SubscriptionKind::NewPendingTransactions {
+                for hash in hashes {
+                    send_notification(id, sub, hash);
+                }
+            }
+        }
+    }
+}
+
+fn send_notification(id: &str, sub: &Subscriber, result: &Value) {
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": NOTIFICATION_METHOD,
+        "params": {
+            "subscription": id,
+            "result": result,
+        },
+    });
+    if let Err(e) = sub.sender.send(notification.to_string()) {
+        warn!("send notification to subscription {} failed: {:?}", id, e);
+    }
+}
+
+/// Build a request exactly as if it was sent by a client, so it gets the
+/// same validation and routing as any other JSON-RPC call.
+fn internal_request(
+    method: &str,
+    params: Value,
+    notify: Notify,
+) -> Option<(String, ProtoRequest, TransferType)> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+    serde_json::from_value::<PartialRequest>(body)
+        .map_err(|e| error!("subscription build {} request: {:?}", method, e))
+        .ok()
+        .and_then(|part_req| {
+            let req_info: RequestInfo = part_req.get_info();
+            part_req
+                .complete_and_into_proto()
+                .map(|(full_req, req)| {
+                    let topic = select_topic(&full_req.get_method());
+                    (topic, req, TransferType::SUBSCRIPTION((req_info, notify)))
+                })
+                .map_err(|e| error!("subscription complete {} request: {:?}", method, e))
+                .ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SubscriptionCall, SubscriptionKind};
+
+    #[test]
+    fn test_subscription_kind_from_params() {
+        assert_eq!(
+            SubscriptionKind::from_params(&[json!("newHeads")]),
+            Ok(SubscriptionKind::NewHeads)
+        );
+        assert_eq!(
+            SubscriptionKind::from_params(&[json!("newPendingTransactions")]),
+            Ok(SubscriptionKind::NewPendingTransactions)
+        );
+        assert_eq!(
+            SubscriptionKind::from_params(&[json!("logs")]),
+            Ok(SubscriptionKind::Logs(json!({})))
+        );
+        let filter = json!({"address": "0x0000000000000000000000000000000000000000"});
+        assert_eq!(
+            SubscriptionKind::from_params(&[json!("logs"), filter.clone()]),
+            Ok(SubscriptionKind::Logs(filter))
+        );
+        assert!(SubscriptionKind::from_params(&[json!("logs"), json!(1)]).is_err());
+        assert!(SubscriptionKind::from_params(&[json!("syncing")]).is_err());
+        assert!(SubscriptionKind::from_params(&[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_subscription_call() {
+        let call = SubscriptionCall::parse(
+            r#"{"jsonrpc":"2.0","id":7,"method":"subscribe","params":["newHeads"]}"#,
+        )
+        .unwrap();
+        assert_eq!(call.id, json!(7));
+        assert_eq!(call.params, vec![json!("newHeads")]);
+
+        assert!(SubscriptionCall::parse(
+            r#"{"jsonrpc":"2.0","id":1,"method":"unsubscribe","params":["0x1"]}"#
+        )
+        .is_some());
+        assert!(SubscriptionCall::parse(
+            r#"{"jsonrpc":"2.0","id":1,"method":"blockNumber","params":[]}"#
+        )
+        .is_none());
+        assert!(SubscriptionCall::parse("not json").is_none());
+    }
+}
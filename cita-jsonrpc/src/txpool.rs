@@ -0,0 +1,365 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transaction pool introspection and peer management.
+//!
+//! `getPoolStatus`, `getPoolContent`, `getPoolTransaction`, `getPeers`,
+//! `banPeer`, `unbanPeer`, `getLogsPage`, `traceTransaction` and `traceCall`
+//! are not calls of `jsonrpc-types`, so they are picked out before the normal
+//! request pipeline, and sent as JSON in `RawBytes` messages. The pool calls
+//! are answered by auth, the peer calls by network, the logs calls by chain,
+//! and the trace calls by executor through chain.
+//!
+//! The peer calls are only accepted on the admin endpoint of the HTTP server.
+//!
+//! A batch containing such calls is answered call by call, and the replies
+//! are collected in the order of the batch.
+//!
+//! A call gets a timeout failure when no reply comes in time: a http call
+//! through its own timer, a websocket call by the periodic `expire`.
+
+use crate::helper::{select_topic, PEER_REQUEST};
+use crate::service_error::ServiceError;
+use futures::future::Either;
+use futures::sync::oneshot;
+use futures::Future;
+use jsonrpc_types::ErrorCode;
+use libproto::{Message, MsgClass, OperateType, TryInto};
+use pubsub::channel::Sender;
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::{clock, Delay};
+use util::Mutex;
+use uuid::Uuid;
+use ws;
+
+pub const POOL_METHODS: [&str; 3] = ["getPoolStatus", "getPoolContent", "getPoolTransaction"];
+pub const PEER_METHODS: [&str; 3] = ["getPeers", "banPeer", "unbanPeer"];
+pub const LOGS_METHODS: [&str; 1] = ["getLogsPage"];
+pub const TRACE_METHODS: [&str; 2] = ["traceTransaction", "traceCall"];
+
+const MSG_TIMEOUT_RESEND: &str = "System timeout, please resend.";
+
+pub type PoolQueryMap = Arc<PoolQueries>;
+
+/// A transaction pool call from a client.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PoolCall {
+    #[serde(default)]
+    pub jsonrpc: Option<Value>,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+impl PoolCall {
+    /// Only returns the call when its method is answered by the pool of auth,
+    /// by chain or by executor.
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|call| Self::from_value(&call))
+    }
+
+    /// Same as `parse`, for a request already parsed, or a call of a batch.
+    pub fn from_value(call: &Value) -> Option<Self> {
+        let method = call.get("method").and_then(Value::as_str)?;
+        if POOL_METHODS.contains(&method)
+            || LOGS_METHODS.contains(&method)
+            || TRACE_METHODS.contains(&method)
+        {
+            serde_json::from_value(call.clone()).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Only returns the call when its method is answered by the peer manager
+    /// of network, for the admin endpoint.
+    pub fn admin_from_value(call: &Value) -> Option<Self> {
+        let method = call.get("method").and_then(Value::as_str)?;
+        if PEER_METHODS.contains(&method) {
+            serde_json::from_value(call.clone()).ok()
+        } else {
+            None
+        }
+    }
+
+    fn success(&self, result: Value) -> Value {
+        json!({
+            "jsonrpc": self.jsonrpc.clone().unwrap_or_else(|| json!("2.0")),
+            "id": self.id,
+            "result": result,
+        })
+    }
+
+    fn failure(&self, code: i64, message: &str) -> Value {
+        json!({
+            "jsonrpc": self.jsonrpc.clone().unwrap_or_else(|| json!("2.0")),
+            "id": self.id,
+            "error": {
+                "code": code,
+                "message": message,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PoolQuery<'a> {
+    request_id: &'a str,
+    method: &'a str,
+    params: &'a [Value],
+}
+
+#[derive(Debug, Deserialize)]
+struct PoolReply {
+    request_id: String,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+enum Replier {
+    HTTP(oneshot::Sender<Value>),
+    /// The connection and the deadline of the reply.
+    WEBSOCKET(ws::Sender, Instant),
+}
+
+type PendingQueries = Arc<Mutex<HashMap<String, (PoolCall, Replier)>>>;
+
+/// The pool calls waiting for the replies of auth.
+pub struct PoolQueries {
+    sender: Sender<(String, Vec<u8>)>,
+    pending: PendingQueries,
+}
+
+impl PoolQueries {
+    pub fn new(sender: Sender<(String, Vec<u8>)>) -> Self {
+        PoolQueries {
+            sender,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Query for a http client, a failure is returned after the timeout.
+    pub fn query_http(
+        &self,
+        call: PoolCall,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = Value, Error = ServiceError> + Send + 'static> {
+        let (tx, rx) = oneshot::channel();
+        let failure = call.failure(error::ErrorCode::time_out_error(), MSG_TIMEOUT_RESEND);
+        let request_id = self.send(call, Replier::HTTP(tx));
+        let pending = Arc::clone(&self.pending);
+
+        let fut_resp = rx
+            .select2(Delay::new(clock::now() + timeout))
+            .then(move |res| match res {
+                Ok(Either::A((reply, _timeout))) => Ok(reply),
+                Ok(Either::B((_reach_timeout, _no_reply))) => {
+                    pending.lock().remove(&request_id);
+                    Ok(failure)
+                }
+                Err(_) => Err(ServiceError::InternalServerError),
+            });
+
+        Box::new(fut_resp)
+    }
+
+    /// Query for a websocket client, a failure is sent by `expire`
+    /// after the timeout.
+    pub fn query_ws(&self, call: PoolCall, sender: ws::Sender, timeout: Duration) {
+        self.send(call, Replier::WEBSOCKET(sender, Instant::now() + timeout));
+    }
+
+    /// Send the timeout failure to the websocket queries which are not
+    /// replied before their deadline.
+    pub fn expire(&self, now: Instant) {
+        let mut pending = self.pending.lock();
+        let expired: Vec<String> = pending
+            .iter()
+            .filter_map(|(request_id, (_, replier))| match replier {
+                Replier::WEBSOCKET(_, deadline) if *deadline <= now => Some(request_id.clone()),
+                _ => None,
+            })
+            .collect();
+        for request_id in expired {
+            if let Some((call, Replier::WEBSOCKET(sender, _))) = pending.remove(&request_id) {
+                let failure = call.failure(error::ErrorCode::time_out_error(), MSG_TIMEOUT_RESEND);
+                if let Err(e) = sender.send(failure.to_string()) {
+                    error!("ws: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Forget the queries of a closed websocket connection.
+    pub fn remove_connection(&self, sender: &ws::Sender) {
+        let token = sender.token();
+        self.pending.lock().retain(|_, (_, replier)| match replier {
+            Replier::WEBSOCKET(ws, _) => ws.token() != token,
+            Replier::HTTP(_) => true,
+        });
+    }
+
+    /// Deliver the reply of auth, network, chain or executor to the client.
+    pub fn reply(&self, body: &[u8]) {
+        let reply = match serde_json::from_slice::<PoolReply>(body) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("receive invalid pool reply: {:?}", e);
+                return;
+            }
+        };
+        let (call, replier) = match self.pending.lock().remove(&reply.request_id) {
+            Some(pending) => pending,
+            None => {
+                warn!("receive lost pool reply {}", reply.request_id);
+                return;
+            }
+        };
+
+        let reply = match (reply.result, reply.error) {
+            (_, Some(error)) => call.failure(ErrorCode::InvalidParams.code(), &error),
+            (Some(result), None) => call.success(result),
+            (None, None) => call.success(Value::Null),
+        };
+        match replier {
+            Replier::HTTP(sender) => {
+                let _ = sender.send(reply);
+            }
+            Replier::WEBSOCKET(sender, _) => {
+                if let Err(e) = sender.send(reply.to_string()) {
+                    error!("ws: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn send(&self, call: PoolCall, replier: Replier) -> String {
+        let request_id = Uuid::new_v4().to_string();
+        let topic = if PEER_METHODS.contains(&call.method.as_str()) {
+            PEER_REQUEST.to_owned()
+        } else {
+            select_topic(&call.method)
+        };
+        let query = serde_json::to_vec(&PoolQuery {
+            request_id: &request_id,
+            method: &call.method,
+            params: &call.params,
+        })
+        .unwrap();
+        self.pending
+            .lock()
+            .insert(request_id.clone(), (call, replier));
+
+        let msg = Message::init(OperateType::Single, 0, MsgClass::RawBytes(query));
+        // NOTE: send failure is handled as timeout error
+        let _ = self.sender.send((topic, msg.try_into().unwrap()));
+        request_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PoolCall, PoolQueries};
+    use futures::Future;
+    use libproto::{Message, TryFrom};
+    use pubsub::channel;
+    use serde_json::{self, Value};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_parse() {
+        let call =
+            PoolCall::parse(br#"{"jsonrpc":"2.0","id":1,"method":"getPoolStatus","params":[]}"#)
+                .unwrap();
+        assert_eq!(call.method, "getPoolStatus");
+        assert!(PoolCall::parse(br#"{"jsonrpc":"2.0","id":1,"method":"blockNumber"}"#).is_none());
+        assert!(PoolCall::parse(br#"{"jsonrpc":"2.0","id":1,"method":"banPeer"}"#).is_none());
+        assert!(PoolCall::parse(br#"{"jsonrpc":"2.0","id":1,"method":"getLogsPage"}"#).is_some());
+        assert!(PoolCall::parse(br#"{"jsonrpc":"2.0","id":1,"method":"traceCall"}"#).is_some());
+        assert!(PoolCall::parse(b"[]").is_none());
+    }
+
+    #[test]
+    fn test_admin_call() {
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "banPeer", "params": []});
+        assert_eq!(PoolCall::admin_from_value(&call).unwrap().method, "banPeer");
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "getPoolStatus"});
+        assert!(PoolCall::admin_from_value(&call).is_none());
+
+        let (tx, rx) = channel::unbounded();
+        let queries = PoolQueries::new(tx);
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "getPeers"});
+        let _fut = queries.query_http(
+            PoolCall::admin_from_value(&call).unwrap(),
+            Duration::from_secs(10),
+        );
+        assert_eq!(rx.recv().unwrap().0, "jsonrpc.peer_request");
+    }
+
+    #[test]
+    fn test_query_reply() {
+        let (tx, rx) = channel::unbounded();
+        let queries = PoolQueries::new(tx);
+        let call = PoolCall::parse(
+            br#"{"jsonrpc":"2.0","id":2,"method":"getPoolTransaction","params":["0x01"]}"#,
+        )
+        .unwrap();
+        let fut = queries.query_http(call, Duration::from_secs(10));
+
+        let (key, body) = rx.recv().unwrap();
+        assert_eq!(key, "jsonrpc.raw_bytes");
+        let query: Value =
+            serde_json::from_slice(&Message::try_from(&body).unwrap().take_raw_bytes().unwrap())
+                .unwrap();
+        assert_eq!(query["method"], "getPoolTransaction");
+        assert_eq!(query["params"][0], "0x01");
+
+        let reply = json!({
+            "request_id": query["request_id"],
+            "result": {"status": "unknown"},
+        });
+        queries.reply(reply.to_string().as_bytes());
+
+        let text = fut.wait().unwrap();
+        assert_eq!(text["id"], 2);
+        assert_eq!(text["result"]["status"], "unknown");
+    }
+
+    #[test]
+    fn test_expire_keeps_http_queries() {
+        let (tx, rx) = channel::unbounded();
+        let queries = PoolQueries::new(tx);
+        let call =
+            PoolCall::parse(br#"{"jsonrpc":"2.0","id":3,"method":"getPoolStatus"}"#).unwrap();
+        let fut = queries.query_http(call, Duration::from_secs(10));
+        queries.expire(Instant::now() + Duration::from_secs(3600));
+
+        let (_, body) = rx.recv().unwrap();
+        let query: Value =
+            serde_json::from_slice(&Message::try_from(&body).unwrap().take_raw_bytes().unwrap())
+                .unwrap();
+        let reply = json!({"request_id": query["request_id"], "result": 0});
+        queries.reply(reply.to_string().as_bytes());
+        assert_eq!(fut.wait().unwrap()["result"], 0);
+    }
+}
@@ -13,6 +13,10 @@
 // limitations under the License.
 
 use crate::helper::{select_topic, RpcMap, TransferType};
+use crate::limiter::{parse_calls, Limiter};
+use crate::metrics;
+use crate::subscription::{SubscriptionCall, SubscriptionMap};
+use crate::txpool::{PoolCall, PoolQueryMap};
 use jsonrpc_proto::complete::CompleteInto;
 use jsonrpc_types::rpc_request::{PartialRequest, RequestInfo};
 use jsonrpc_types::rpc_response::RpcFailure;
@@ -20,23 +24,33 @@ use jsonrpc_types::Error;
 use libproto::request::Request as ProtoRequest;
 use num_cpus;
 use pubsub::channel::Sender;
-use serde_json;
+use serde_json::{self, Value};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
+use std::time::Duration;
 use threadpool::ThreadPool;
-use ws::{self as ws, CloseCode, Factory, Handler};
+use ws::{self as ws, CloseCode, Factory, Handler, Handshake};
 
 pub struct WsFactory {
     //TODO 定时清理工作
     responses: RpcMap,
+    subscriptions: SubscriptionMap,
+    pool_queries: PoolQueryMap,
     thread_pool: ThreadPool,
     tx: Sender<(String, ProtoRequest)>,
+    timeout: Duration,
+    limiter: Arc<Limiter>,
 }
 
 impl WsFactory {
     pub fn new(
         responses: RpcMap,
+        subscriptions: SubscriptionMap,
+        pool_queries: PoolQueryMap,
         tx: Sender<(String, ProtoRequest)>,
         thread_num: usize,
+        timeout: Duration,
+        limiter: Arc<Limiter>,
     ) -> WsFactory {
         let thread_number = if thread_num == 0 {
             num_cpus::get()
@@ -46,8 +60,12 @@ impl WsFactory {
         let thread_pool = ThreadPool::with_name("ws_thread_pool".to_string(), thread_number);
         WsFactory {
             responses,
+            subscriptions,
+            pool_queries,
             thread_pool,
             tx,
+            timeout,
+            limiter,
         }
     }
 }
@@ -58,23 +76,76 @@ impl Factory for WsFactory {
         WsHandler {
             sender: ws,
             responses: Arc::clone(&self.responses),
+            subscriptions: Arc::clone(&self.subscriptions),
+            pool_queries: Arc::clone(&self.pool_queries),
             tx: self.tx.clone(),
             thread_pool: self.thread_pool.clone(),
+            timeout: self.timeout,
+            limiter: Arc::clone(&self.limiter),
+            // Set by the handshake.
+            remote_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            api_key: None,
         }
     }
 }
 
 impl Handler for WsHandler {
+    fn on_open(&mut self, shake: Handshake) -> ws::Result<()> {
+        if let Some(addr) = shake.peer_addr {
+            self.remote_ip = addr.ip();
+        }
+        self.api_key = shake
+            .request
+            .header(self.limiter.api_key_header())
+            .and_then(|key| String::from_utf8(key.clone()).ok());
+        Ok(())
+    }
+
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         trace!("Server got message '{}'  post thread_pool deal task ", msg);
         let tx = self.tx.clone();
         let response = Arc::clone(&self.responses);
+        let subscriptions = Arc::clone(&self.subscriptions);
+        let pool_queries = Arc::clone(&self.pool_queries);
         let sender = self.sender.clone();
+        let timeout = self.timeout;
+        let limiter = Arc::clone(&self.limiter);
+        let remote_ip = self.remote_ip;
+        let api_key = self.api_key.clone();
 
         self.thread_pool.execute(move || {
             let mut req_info = RequestInfo::null();
+            let text = msg.into_text().unwrap();
+
+            if limiter.is_enabled() {
+                let (methods, id) = match serde_json::from_str::<Value>(&text) {
+                    Ok(body) => parse_calls(&body),
+                    Err(_) => (Vec::new(), Value::Null),
+                };
+                let checked =
+                    limiter.check(remote_ip, api_key.as_ref().map(String::as_str), &methods);
+                if let Err(rejection) = checked {
+                    info!("ws {} rejected={}", remote_ip, rejection.reason());
+                    metrics::RPC_REJECTED_TOTAL
+                        .with_label_values(&[rejection.reason()])
+                        .inc();
+                    let _ = sender.send(rejection.failure(id));
+                    return;
+                }
+            }
+
+            if let Some(call) = SubscriptionCall::parse(&text) {
+                let reply = subscriptions.lock().handle_call(&call, &sender);
+                let _ = sender.send(reply);
+                return;
+            }
+
+            if let Some(call) = PoolCall::parse(text.as_bytes()) {
+                pool_queries.query_ws(call, sender, timeout);
+                return;
+            }
 
-            let _ = serde_json::from_str::<PartialRequest>(&msg.into_text().unwrap())
+            let _ = serde_json::from_str::<PartialRequest>(&text)
                 .map_err(Error::from)
                 .and_then(|part_req| {
                     req_info = part_req.get_info();
@@ -108,13 +179,21 @@ impl Handler for WsHandler {
             reason,
             self.sender.token().0
         );
+        self.subscriptions.lock().remove_connection(&self.sender);
+        self.pool_queries.remove_connection(&self.sender);
     }
 }
 
 #[derive(Clone)]
 pub struct WsHandler {
     responses: RpcMap,
+    subscriptions: SubscriptionMap,
+    pool_queries: PoolQueryMap,
     thread_pool: ThreadPool,
     sender: ws::Sender,
     tx: Sender<(String, ProtoRequest)>,
+    timeout: Duration,
+    limiter: Arc<Limiter>,
+    remote_ip: IpAddr,
+    api_key: Option<String>,
 }
@@ -0,0 +1,570 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A client that follows the headers of a chain from a trusted checkpoint.
+//!
+//! The proof in header `n + 1` signs the proposal of block `n`, which has
+//! the hash of header `n - 1`. So a header is trusted as a whole once two
+//! more headers are imported.
+//!
+//! For the same reason, the state root of block `n` is only signed with
+//! block `n + 1`, by the authorities after block `n`. So a change of the
+//! authorities can not be proved by the old authorities, and is followed by
+//! trusting a checkpoint after it with the new authorities.
+
+use crate::error::Error;
+use crate::validators::ValidatorSet;
+use cita_types::{Address, H256};
+use hashable::Hashable;
+use libproto::blockchain::ProofType;
+use proof::BftProof;
+use std::cmp;
+use std::collections::BTreeMap;
+use types::header::{BlockNumber, Header};
+use types::node_manager;
+use types::receipt::Receipt;
+use types::state_proof::StateProof;
+use types::transaction::SignedTransaction;
+use types::tx_proof::TxProof;
+
+/// Headers kept by default.
+pub const DEFAULT_MAX_HEADERS: usize = 1024;
+
+fn header_hash(header: &Header) -> H256 {
+    header.hash().unwrap_or_else(|| header.rlp_hash())
+}
+
+pub struct LightClient {
+    validators: ValidatorSet,
+    headers: BTreeMap<BlockNumber, Header>,
+    checkpoint: BlockNumber,
+    max_headers: usize,
+}
+
+impl LightClient {
+    /// Trust the checkpoint header, and the authorities signing the blocks
+    /// from it on.
+    pub fn new(checkpoint: Header, authorities: Vec<Address>) -> Result<Self, Error> {
+        let number = checkpoint.number();
+        let validators = ValidatorSet::new(number, authorities)?;
+        let mut headers = BTreeMap::new();
+        headers.insert(number, checkpoint);
+        Ok(LightClient {
+            validators,
+            headers,
+            checkpoint: number,
+            max_headers: DEFAULT_MAX_HEADERS,
+        })
+    }
+
+    /// Keep at most `max_headers` headers, 0 for no limit.
+    pub fn set_max_headers(&mut self, max_headers: usize) {
+        self.max_headers = max_headers;
+        self.prune();
+    }
+
+    pub fn validators(&self) -> &ValidatorSet {
+        &self.validators
+    }
+
+    pub fn latest_header(&self) -> &Header {
+        self.headers.values().next_back().unwrap()
+    }
+
+    /// The latest height whose header is trusted as a whole.
+    pub fn trusted_height(&self) -> BlockNumber {
+        cmp::max(
+            self.checkpoint,
+            self.latest_header().number().saturating_sub(2),
+        )
+    }
+
+    pub fn trusted_header(&self, number: BlockNumber) -> Result<&Header, Error> {
+        if number > self.trusted_height() {
+            return Err(Error::UntrustedHeight(number));
+        }
+        self.headers
+            .get(&number)
+            .ok_or(Error::UntrustedHeight(number))
+    }
+
+    /// Verify the header against the latest one and import it.
+    pub fn import_header(&mut self, header: Header) -> Result<(), Error> {
+        let latest = self.latest_header();
+        let height = latest.number();
+        if header.number() != height + 1 {
+            return Err(Error::UnexpectedNumber {
+                expected: height + 1,
+                got: header.number(),
+            });
+        }
+        if *header.parent_hash() != header_hash(latest) {
+            return Err(Error::ParentHashMismatch(header.number()));
+        }
+        if header.proof_type() != Some(ProofType::Bft) {
+            return Err(Error::UnsupportedProof(height));
+        }
+        let proof = BftProof::from(header.proof().clone());
+        // The genesis block is not proposed.
+        if height != 0 && latest.proposal_protobuf().crypt_hash() != proof.proposal {
+            return Err(Error::ProposalMismatch(height));
+        }
+        let authorities = self
+            .validators
+            .authorities_at(height)
+            .ok_or(Error::NoAuthorities(height))?;
+        if !proof.check(height as usize, authorities) {
+            return Err(Error::InvalidSignatures(height));
+        }
+
+        trace!("import header {}", header.number());
+        self.headers.insert(header.number(), header);
+        self.prune();
+        Ok(())
+    }
+
+    /// Import the headers in order, and return the number imported.
+    ///
+    /// The headers before the first invalid one are kept.
+    pub fn import_headers<I>(&mut self, headers: I) -> Result<usize, Error>
+    where
+        I: IntoIterator<Item = Header>,
+    {
+        let mut count = 0;
+        for header in headers {
+            self.import_header(header)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Trust a checkpoint header after the current one, and the authorities
+    /// signing the blocks from it on.
+    ///
+    /// The headers after the checkpoint are dropped, and the ones before it
+    /// too unless its parent is imported.
+    pub fn trust_checkpoint(
+        &mut self,
+        checkpoint: Header,
+        authorities: Vec<Address>,
+    ) -> Result<(), Error> {
+        let number = checkpoint.number();
+        if number <= self.checkpoint {
+            return Err(Error::StaleCheckpoint(number));
+        }
+        if let Some(imported) = self.headers.get(&number) {
+            if header_hash(imported) != header_hash(&checkpoint) {
+                return Err(Error::HeaderMismatch(number));
+            }
+        }
+        let has_parent = match self.headers.get(&(number - 1)) {
+            Some(parent) if header_hash(parent) != *checkpoint.parent_hash() => {
+                return Err(Error::ParentHashMismatch(number));
+            }
+            Some(_) => true,
+            None => false,
+        };
+        self.validators.reset(number, authorities)?;
+        if !has_parent {
+            self.headers.clear();
+        }
+        info!("trust checkpoint {}", number);
+        let _ = self.headers.split_off(&number);
+        self.headers.insert(number, checkpoint);
+        self.checkpoint = number;
+        self.prune();
+        Ok(())
+    }
+
+    /// Change the authorities to the validators in the `NodeManager` at the
+    /// trusted height, proved by the proofs of the keys from
+    /// `node_manager::validators_keys`.
+    ///
+    /// The new authorities sign the blocks after the height. A change in a
+    /// block after the checkpoint can not be proved, see `trust_checkpoint`.
+    pub fn change_authorities(
+        &mut self,
+        height: BlockNumber,
+        proofs: &[StateProof],
+    ) -> Result<(), Error> {
+        let state_root = *self.trusted_header(height)?.state_root();
+        let authorities = node_manager::verify_validators(state_root, proofs)?;
+        info!(
+            "authorities change to {:?} after height {}",
+            authorities, height
+        );
+        self.validators.change(height + 1, authorities)
+    }
+
+    fn verify_tx_proof(&self, proof: &TxProof) -> Result<(), Error> {
+        let header = proof.block_header();
+        let number = header.number();
+        // The proof is signed by the proof of the next block.
+        if number >= self.latest_header().number() {
+            return Err(Error::UntrustedHeight(number));
+        }
+        if let Some(imported) = self.headers.get(&number) {
+            if header_hash(imported) != header_hash(header) {
+                return Err(Error::HeaderMismatch(number));
+            }
+        }
+        let authorities = self
+            .validators
+            .authorities_at(number + 1)
+            .ok_or(Error::NoAuthorities(number + 1))?;
+        if proof.verify(authorities) {
+            Ok(())
+        } else {
+            Err(Error::InvalidTxProof(proof.receipt().transaction_hash))
+        }
+    }
+
+    /// Verify a proof from `getTransactionProof`, and return the
+    /// transaction proved.
+    pub fn verify_transaction<'a>(
+        &self,
+        proof: &'a TxProof,
+    ) -> Result<&'a SignedTransaction, Error> {
+        self.verify_tx_proof(proof).map(|_| proof.tx())
+    }
+
+    /// Verify a proof from `getTransactionProof`, and return the receipt
+    /// of the transaction proved.
+    pub fn verify_receipt<'a>(&self, proof: &'a TxProof) -> Result<&'a Receipt, Error> {
+        self.verify_tx_proof(proof).map(|_| proof.receipt())
+    }
+
+    /// Verify a proof from `getStateProof` against the state root of the
+    /// trusted header, and return the value of the key, zero if not set.
+    pub fn verify_state(&self, height: BlockNumber, proof: &StateProof) -> Result<H256, Error> {
+        let state_root = *self.trusted_header(height)?.state_root();
+        Ok(proof.verify_value(state_root)?)
+    }
+
+    fn prune(&mut self) {
+        if self.max_headers == 0 {
+            return;
+        }
+        while self.headers.len() > self.max_headers {
+            let first = *self.headers.keys().next().unwrap();
+            self.headers.remove(&first);
+        }
+        let first = *self.headers.keys().next().unwrap();
+        self.validators.prune(first);
+    }
+}
+
+#[cfg(all(test, feature = "secp256k1"))]
+mod tests {
+    use super::LightClient;
+    use crate::error::Error;
+    use cita_crypto::{PrivKey, Sign, Signature, Signer};
+    use cita_types::{H256, U256};
+    use cita_vm::state::{MemoryDB, State, StateObjectInfo};
+    use hashable::Hashable;
+    use libproto::blockchain::Proof as ProtoProof;
+    use proof::BftProof;
+    use rlp::Encodable;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use types::block_receipts::BlockReceipts;
+    use types::header::Header;
+    use types::node_manager;
+    use types::receipt::Receipt;
+    use types::state_proof::StateProof;
+    use types::transaction::SignedTransaction;
+    use types::tx_proof::TxProof;
+
+    const KEY: &str = "ef98e68db428906d626cd37782cdfb052ac282132beee53a99948738ea553b4a";
+    const OTHER_KEY: &str = "5f0258a4778057a8a7d97809bd209055b2fbafa654ce7d31ec7191066b9225e6";
+
+    fn generate_signer(key: &str) -> Signer {
+        Signer::from(PrivKey::from_str(key).unwrap())
+    }
+
+    fn generate_proof(signer: &Signer, height: u64, proposal: H256) -> ProtoProof {
+        let serialized = bincode::serialize(
+            &(height as usize, 0usize, 0usize, signer.address, proposal),
+            bincode::Infinite,
+        )
+        .unwrap();
+        let signature =
+            Signature::sign(signer.keypair.privkey(), &serialized.crypt_hash()).unwrap();
+        let mut commits = HashMap::new();
+        commits.insert(signer.address, signature);
+        BftProof::new(height as usize, 0, proposal, commits).into()
+    }
+
+    // The next header, with the proof of the parent signed by the signer.
+    fn next_header(parent: &Header, signer: &Signer) -> Header {
+        // The genesis block is not proposed.
+        let proposal = if parent.number() == 0 {
+            H256::default()
+        } else {
+            parent.proposal_protobuf().crypt_hash()
+        };
+        let mut header = Header::default();
+        header.set_number(parent.number() + 1);
+        header.set_parent_hash(parent.hash().unwrap());
+        header.set_proof(generate_proof(signer, parent.number(), proposal));
+        header.rehash();
+        header
+    }
+
+    // The proof of the first receipt in the receipts.
+    fn receipt_proof(receipts: &[Receipt]) -> cita_merklehash::Proof {
+        let hashes = receipts
+            .iter()
+            .map(|receipt| receipt.rlp_bytes().into_vec().crypt_hash())
+            .collect::<Vec<_>>();
+        cita_merklehash::Tree::from_hashes(hashes, cita_merklehash::merge)
+            .get_proof_by_input_index(0)
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_import_header() {
+        let signer = generate_signer(KEY);
+        let genesis = Header::default();
+        let mut client = LightClient::new(genesis.clone(), vec![signer.address]).unwrap();
+
+        let header1 = next_header(&genesis, &signer);
+        let header2 = next_header(&header1, &signer);
+        assert_eq!(
+            client.import_header(header2.clone()),
+            Err(Error::UnexpectedNumber {
+                expected: 1,
+                got: 2
+            })
+        );
+        let mut orphan = header1.clone();
+        orphan.set_parent_hash(H256::from(1));
+        orphan.rehash();
+        assert_eq!(
+            client.import_header(orphan),
+            Err(Error::ParentHashMismatch(1))
+        );
+        assert_eq!(
+            client.import_header(next_header(&genesis, &generate_signer(OTHER_KEY))),
+            Err(Error::InvalidSignatures(0))
+        );
+        client.import_header(header1.clone()).unwrap();
+        assert_eq!(client.trusted_height(), 0);
+
+        // The proof signs another proposal of block 1.
+        let mut header = Header::default();
+        header.set_number(2);
+        header.set_parent_hash(header1.hash().unwrap());
+        header.set_proof(generate_proof(&signer, 1, H256::from(1)));
+        header.rehash();
+        assert_eq!(
+            client.import_header(header),
+            Err(Error::ProposalMismatch(1))
+        );
+
+        let header3 = next_header(&header2, &signer);
+        assert_eq!(client.import_headers(vec![header2, header3]), Ok(2));
+        assert_eq!(client.trusted_height(), 1);
+        assert_eq!(client.trusted_header(1).unwrap(), &header1);
+        assert_eq!(client.trusted_header(2), Err(Error::UntrustedHeight(2)));
+    }
+
+    #[test]
+    fn test_change_authorities() {
+        let signer = generate_signer(KEY);
+        let other = generate_signer(OTHER_KEY);
+
+        // The NodeManager of the genesis lists the other key.
+        let address = node_manager::contract_address();
+        let mut state = State::new(Arc::new(MemoryDB::new(false))).unwrap();
+        state.new_contract(&address, U256::from(0), U256::from(1), vec![1]);
+        let keys = node_manager::validators_keys(1);
+        state.set_storage(&address, keys[0], H256::from(1)).unwrap();
+        state
+            .set_storage(&address, keys[1], H256::from(other.address))
+            .unwrap();
+        state.commit().unwrap();
+        let proofs: Vec<StateProof> = keys
+            .iter()
+            .map(|key| StateProof::prove(&state, &address, key).unwrap())
+            .collect();
+
+        let mut genesis = Header::default();
+        genesis.set_state_root(state.root);
+        genesis.rehash();
+        let mut client = LightClient::new(genesis.clone(), vec![signer.address]).unwrap();
+        assert_eq!(
+            client.change_authorities(1, &proofs),
+            Err(Error::UntrustedHeight(1))
+        );
+        assert!(client.change_authorities(0, &proofs[..1]).is_err());
+        client.change_authorities(0, &proofs).unwrap();
+        assert_eq!(
+            client.validators().authorities_at(1),
+            Some(&[other.address][..])
+        );
+        assert_eq!(
+            client.change_authorities(0, &proofs),
+            Err(Error::StaleAuthorities(1))
+        );
+
+        // Block 0 is signed by the old authorities, block 1 by the new ones.
+        let header1 = next_header(&genesis, &signer);
+        client.import_header(header1.clone()).unwrap();
+        assert_eq!(
+            client.import_header(next_header(&header1, &signer)),
+            Err(Error::InvalidSignatures(1))
+        );
+        client.import_header(next_header(&header1, &other)).unwrap();
+    }
+
+    #[test]
+    fn test_change_authorities_after_checkpoint() {
+        let signer = generate_signer(KEY);
+        let other = generate_signer(OTHER_KEY);
+
+        // The NodeManager lists the other key from block 2.
+        let address = node_manager::contract_address();
+        let mut state = State::new(Arc::new(MemoryDB::new(false))).unwrap();
+        state.new_contract(&address, U256::from(0), U256::from(1), vec![1]);
+        let keys = node_manager::validators_keys(1);
+        state.set_storage(&address, keys[0], H256::from(1)).unwrap();
+        state
+            .set_storage(&address, keys[1], H256::from(other.address))
+            .unwrap();
+        state.commit().unwrap();
+        let proofs: Vec<StateProof> = keys
+            .iter()
+            .map(|key| StateProof::prove(&state, &address, key).unwrap())
+            .collect();
+
+        let genesis = Header::default();
+        let mut client = LightClient::new(genesis.clone(), vec![signer.address]).unwrap();
+        let header1 = next_header(&genesis, &signer);
+        let mut header2 = next_header(&header1, &signer);
+        header2.set_state_root(state.root);
+        header2.rehash();
+        let header3 = next_header(&header2, &signer);
+        let header4 = next_header(&header3, &other);
+        assert_eq!(
+            client.import_headers(vec![header1.clone(), header2, header3.clone()]),
+            Ok(3)
+        );
+        // Block 3 is signed by the new authorities, so block 2 is not trusted.
+        assert_eq!(
+            client.import_header(header4.clone()),
+            Err(Error::InvalidSignatures(3))
+        );
+        assert_eq!(
+            client.change_authorities(2, &proofs),
+            Err(Error::UntrustedHeight(2))
+        );
+
+        let mut other_header = header3.clone();
+        other_header.set_timestamp(1);
+        other_header.rehash();
+        assert_eq!(
+            client.trust_checkpoint(other_header, vec![other.address]),
+            Err(Error::HeaderMismatch(3))
+        );
+        client
+            .trust_checkpoint(header3, vec![other.address])
+            .unwrap();
+        assert_eq!(
+            client.trust_checkpoint(header1, vec![other.address]),
+            Err(Error::StaleCheckpoint(1))
+        );
+        client.import_header(header4).unwrap();
+        assert_eq!(
+            client.verify_state(2, &proofs[1]),
+            Ok(H256::from(other.address))
+        );
+    }
+
+    #[test]
+    fn test_verify_tx_proof() {
+        let signer = generate_signer(KEY);
+        let genesis = Header::default();
+        let mut client = LightClient::new(genesis.clone(), vec![signer.address]).unwrap();
+
+        let tx = SignedTransaction::default();
+        let receipt = Receipt::new(
+            None,
+            U256::from(21000),
+            vec![],
+            None,
+            U256::zero(),
+            tx.calc_transaction_hash(),
+        );
+        let receipts = vec![receipt.clone(), Receipt::default()];
+        let mut header1 = next_header(&genesis, &signer);
+        header1.set_receipts_root(BlockReceipts::new(receipts.clone()).receipts_root());
+        header1.rehash();
+        let header2 = next_header(&header1, &signer);
+        let header3 = next_header(&header2, &signer);
+        let proof = TxProof::new(
+            tx.clone(),
+            receipt.clone(),
+            receipt_proof(&receipts),
+            header1.clone(),
+            header2.proposal(),
+            header3.proof().clone(),
+        );
+
+        client.import_header(header1.clone()).unwrap();
+        // Block 1 is signed by header 2.
+        assert_eq!(
+            client.verify_receipt(&proof).err(),
+            Some(Error::UntrustedHeight(1))
+        );
+        client.import_header(header2.clone()).unwrap();
+        assert_eq!(client.verify_transaction(&proof), Ok(&tx));
+        assert_eq!(client.verify_receipt(&proof), Ok(&receipt));
+
+        // Signed by another key.
+        let forged = TxProof::new(
+            tx.clone(),
+            receipt.clone(),
+            receipt_proof(&receipts),
+            header1.clone(),
+            header2.proposal(),
+            next_header(&header2, &generate_signer(OTHER_KEY))
+                .proof()
+                .clone(),
+        );
+        assert_eq!(
+            client.verify_receipt(&forged).err(),
+            Some(Error::InvalidTxProof(receipt.transaction_hash))
+        );
+
+        // Block 1 is not the imported one.
+        let mut other_header = header1.clone();
+        other_header.set_timestamp(1);
+        other_header.rehash();
+        let forged = TxProof::new(
+            tx,
+            receipt,
+            receipt_proof(&receipts),
+            other_header,
+            header2.proposal(),
+            header3.proof().clone(),
+        );
+        assert_eq!(
+            client.verify_receipt(&forged).err(),
+            Some(Error::HeaderMismatch(1))
+        );
+    }
+}
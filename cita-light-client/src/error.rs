@@ -0,0 +1,94 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cita_types::H256;
+use rlp::DecoderError;
+use std::fmt;
+use types::header::BlockNumber;
+use types::state_proof::StateProofError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The data from RPC is not a hex string.
+    InvalidHex(String),
+    Decode(DecoderError),
+    /// A header is not the next one of the latest header.
+    UnexpectedNumber {
+        expected: BlockNumber,
+        got: BlockNumber,
+    },
+    ParentHashMismatch(BlockNumber),
+    /// The proof in the next header is not for the proposal of the block.
+    ProposalMismatch(BlockNumber),
+    UnsupportedProof(BlockNumber),
+    InvalidSignatures(BlockNumber),
+    /// The header of the height is not imported, or not signed yet.
+    UntrustedHeight(BlockNumber),
+    /// A proof is of another block than the imported header.
+    HeaderMismatch(BlockNumber),
+    NoAuthorities(BlockNumber),
+    /// Authorities should change after the last change.
+    StaleAuthorities(BlockNumber),
+    /// A new checkpoint should be after the current one.
+    StaleCheckpoint(BlockNumber),
+    InvalidTxProof(H256),
+    InvalidStateProof(StateProofError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidHex(data) => write!(f, "invalid hex data {}", data),
+            Error::Decode(e) => write!(f, "decode error: {:?}", e),
+            Error::UnexpectedNumber { expected, got } => {
+                write!(f, "expect header {}, got {}", expected, got)
+            }
+            Error::ParentHashMismatch(number) => {
+                write!(f, "parent hash of header {} is wrong", number)
+            }
+            Error::ProposalMismatch(number) => {
+                write!(f, "proof is not for the proposal of block {}", number)
+            }
+            Error::UnsupportedProof(number) => {
+                write!(f, "proof of block {} is not a BFT proof", number)
+            }
+            Error::InvalidSignatures(number) => {
+                write!(f, "signatures of block {} are invalid", number)
+            }
+            Error::UntrustedHeight(number) => write!(f, "header {} is not trusted", number),
+            Error::HeaderMismatch(number) => {
+                write!(f, "proof is of another header {}", number)
+            }
+            Error::NoAuthorities(number) => write!(f, "no authorities at height {}", number),
+            Error::StaleAuthorities(number) => {
+                write!(f, "authorities change at {} is stale", number)
+            }
+            Error::StaleCheckpoint(number) => write!(f, "checkpoint {} is stale", number),
+            Error::InvalidTxProof(hash) => write!(f, "proof of transaction {:?} is invalid", hash),
+            Error::InvalidStateProof(e) => write!(f, "invalid state proof: {:?}", e),
+        }
+    }
+}
+
+impl From<DecoderError> for Error {
+    fn from(e: DecoderError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<StateProofError> for Error {
+    fn from(e: StateProofError) -> Self {
+        Error::InvalidStateProof(e)
+    }
+}
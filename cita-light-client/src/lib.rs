@@ -0,0 +1,50 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of CITA data without a full node.
+//!
+//! The `LightClient` starts from a trusted header and the authorities of
+//! that height. It verifies the headers from `getBlockHeader` one by one
+//! with the BFT proofs in them, and follows the changes of the authorities
+//! proved by the storage of the `NodeManager` contract of the chain. With
+//! the trusted headers, it verifies:
+//!
+//! - transactions and receipts with the proofs from `getTransactionProof`,
+//! - account storage with the proofs from `getStateProof`.
+
+#[macro_use]
+extern crate cita_logger as logger;
+
+extern crate common_types as types;
+
+pub mod client;
+pub mod error;
+pub mod validators;
+
+pub use crate::client::LightClient;
+pub use crate::error::Error;
+pub use crate::validators::ValidatorSet;
+
+use cita_types::clean_0x;
+use rlp::{Decodable, UntrustedRlp};
+use rustc_hex::FromHex;
+
+/// Decode the hex data from RPC, such as a header, a transaction proof or a
+/// state proof.
+pub fn decode_hex<T: Decodable>(data: &str) -> Result<T, Error> {
+    let bytes: Vec<u8> = clean_0x(data)
+        .from_hex()
+        .map_err(|_| Error::InvalidHex(data.to_owned()))?;
+    Ok(UntrustedRlp::new(&bytes).as_val()?)
+}
@@ -0,0 +1,117 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The authorities signing the blocks, which change at some heights.
+
+use crate::error::Error;
+use cita_types::Address;
+use std::collections::BTreeMap;
+use types::header::BlockNumber;
+
+#[derive(Debug, Clone)]
+pub struct ValidatorSet {
+    /// The first height signed by the authorities, and the authorities.
+    changes: BTreeMap<BlockNumber, Vec<Address>>,
+}
+
+impl ValidatorSet {
+    pub fn new(height: BlockNumber, authorities: Vec<Address>) -> Result<Self, Error> {
+        if authorities.is_empty() {
+            return Err(Error::NoAuthorities(height));
+        }
+        let mut changes = BTreeMap::new();
+        changes.insert(height, authorities);
+        Ok(ValidatorSet { changes })
+    }
+
+    /// The authorities signing the block of the height.
+    pub fn authorities_at(&self, height: BlockNumber) -> Option<&[Address]> {
+        self.changes
+            .range(..=height)
+            .next_back()
+            .map(|(_, authorities)| &authorities[..])
+    }
+
+    /// The height of the last change.
+    pub fn last_change(&self) -> BlockNumber {
+        self.changes.keys().next_back().cloned().unwrap_or(0)
+    }
+
+    /// The authorities sign the blocks from the height on.
+    pub fn change(&mut self, from: BlockNumber, authorities: Vec<Address>) -> Result<(), Error> {
+        if authorities.is_empty() {
+            return Err(Error::NoAuthorities(from));
+        }
+        if from <= self.last_change() {
+            return Err(Error::StaleAuthorities(from));
+        }
+        if self.authorities_at(from) != Some(&authorities[..]) {
+            self.changes.insert(from, authorities);
+        }
+        Ok(())
+    }
+
+    /// The authorities sign the blocks from the height on, instead of the
+    /// ones changed from it on.
+    pub fn reset(&mut self, from: BlockNumber, authorities: Vec<Address>) -> Result<(), Error> {
+        if authorities.is_empty() {
+            return Err(Error::NoAuthorities(from));
+        }
+        let _ = self.changes.split_off(&from);
+        self.changes.insert(from, authorities);
+        Ok(())
+    }
+
+    /// Forget the changes not needed by the blocks from the height on.
+    pub fn prune(&mut self, height: BlockNumber) {
+        let first = match self.changes.range(..=height).next_back() {
+            Some((first, _)) => *first,
+            None => return,
+        };
+        self.changes = self.changes.split_off(&first);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ValidatorSet;
+    use crate::error::Error;
+    use cita_types::Address;
+
+    #[test]
+    fn test_validator_changes() {
+        let a = vec![Address::from(1)];
+        let b = vec![Address::from(2), Address::from(3)];
+        let mut validators = ValidatorSet::new(10, a.clone()).unwrap();
+        assert_eq!(validators.authorities_at(9), None);
+        assert_eq!(validators.authorities_at(10), Some(&a[..]));
+
+        validators.change(20, b.clone()).unwrap();
+        assert_eq!(validators.authorities_at(19), Some(&a[..]));
+        assert_eq!(validators.authorities_at(100), Some(&b[..]));
+        assert_eq!(
+            validators.change(15, a.clone()),
+            Err(Error::StaleAuthorities(15))
+        );
+        assert_eq!(validators.change(30, vec![]), Err(Error::NoAuthorities(30)));
+
+        validators.prune(25);
+        assert_eq!(validators.authorities_at(19), None);
+        assert_eq!(validators.authorities_at(25), Some(&b[..]));
+
+        validators.reset(20, a.clone()).unwrap();
+        assert_eq!(validators.authorities_at(25), Some(&a[..]));
+        assert_eq!(validators.reset(30, vec![]), Err(Error::NoAuthorities(30)));
+    }
+}
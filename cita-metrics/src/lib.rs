@@ -0,0 +1,196 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus metrics of the micro-services.
+//!
+//! Every service registers its metrics into the default registry, and
+//! exports them at `http://<listen_ip>:<port>/metrics` when enabled in its
+//! config file:
+//!
+//! ```toml
+//! [metrics]
+//! enable = true
+//! listen_ip = "127.0.0.1"
+//! port = 9101
+//! ```
+//!
+//! `listen_ip` is `127.0.0.1` by default, set it to `0.0.0.0` to be scraped
+//! from other hosts. The requests are served one by one, a client which is
+//! too slow to send its request or to read the response is dropped after
+//! `IO_TIMEOUT`.
+
+#[macro_use]
+extern crate cita_logger as logger;
+#[macro_use]
+extern crate serde_derive;
+
+pub use prometheus::{Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+
+use prometheus::{Encoder, HistogramOpts, Opts, TextEncoder};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Read and write timeout of a metrics request.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enable: bool,
+    pub listen_ip: IpAddr,
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enable: false,
+            listen_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 9100,
+        }
+    }
+}
+
+pub fn int_gauge(name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).expect("create metric");
+    prometheus::register(Box::new(gauge.clone())).expect("register metric");
+    gauge
+}
+
+pub fn int_gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    let gauge = IntGaugeVec::new(Opts::new(name, help), labels).expect("create metric");
+    prometheus::register(Box::new(gauge.clone())).expect("register metric");
+    gauge
+}
+
+pub fn int_counter(name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).expect("create metric");
+    prometheus::register(Box::new(counter.clone())).expect("register metric");
+    counter
+}
+
+pub fn int_counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("create metric");
+    prometheus::register(Box::new(counter.clone())).expect("register metric");
+    counter
+}
+
+pub fn histogram(name: &str, help: &str) -> Histogram {
+    let histogram = Histogram::with_opts(HistogramOpts::new(name, help)).expect("create metric");
+    prometheus::register(Box::new(histogram.clone())).expect("register metric");
+    histogram
+}
+
+pub fn histogram_vec(name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let histogram =
+        HistogramVec::new(HistogramOpts::new(name, help), labels).expect("create metric");
+    prometheus::register(Box::new(histogram.clone())).expect("register metric");
+    histogram
+}
+
+/// Seconds elapsed since the instant, for observing histograms.
+pub fn elapsed_secs(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+}
+
+/// Start a thread serving the metrics if enabled.
+pub fn start_server(config: MetricsConfig) {
+    if !config.enable {
+        return;
+    }
+    let addr = SocketAddr::new(config.listen_ip, config.port);
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("metrics server bind {} failed: {:?}", addr, e);
+            return;
+        }
+    };
+    info!("metrics server listening on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve(stream) {
+                        warn!("metrics server response failed: {:?}", e);
+                    }
+                }
+                Err(e) => warn!("metrics server accept failed: {:?}", e),
+            }
+        }
+    });
+}
+
+fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    stream.write_all(&response(&request))?;
+    stream.flush()
+}
+
+fn response(request: &str) -> Vec<u8> {
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut body) {
+                Ok(()) => ("200 OK", encoder.format_type().to_owned(), body),
+                Err(e) => (
+                    "500 Internal Server Error",
+                    "text/plain".to_owned(),
+                    format!("{:?}", e).into_bytes(),
+                ),
+            }
+        }
+        _ => (
+            "404 Not Found",
+            "text/plain".to_owned(),
+            b"not found".to_vec(),
+        ),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend(body);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{int_counter, response};
+
+    #[test]
+    fn test_response() {
+        let counter = int_counter("test_requests_total", "test requests");
+        counter.inc();
+
+        let ok = String::from_utf8(response("GET /metrics HTTP/1.1\r\n\r\n")).unwrap();
+        assert!(ok.starts_with("HTTP/1.1 200 OK"));
+        assert!(ok.contains("test_requests_total 1"));
+
+        let not_found = String::from_utf8(response("GET / HTTP/1.1\r\n\r\n")).unwrap();
+        assert!(not_found.starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
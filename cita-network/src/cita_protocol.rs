@@ -37,26 +37,36 @@ use std::str;
 /// | Address                | u8;20                    |
 /// | Length of Key          | u8                       |
 /// | TTL                    | u8                       |
-/// | Reserved               | u8;2                     |
+/// | Flags                  | u16                      |
 /// +------------------------+--------------------------+
 /// | Key                    | bytes of a str           |
 /// +------------------------+--------------------------+
 /// | Message                | a serialize data         |
 /// +------------------------+--------------------------+
 ///
+/// The message is compressed by snappy if `FLAG_COMPRESSED` is set. A node
+/// sets `FLAG_ACCEPT_COMPRESSED` in its init message if it can decompress
+/// messages, and only such peers are sent compressed messages. The old nodes
+/// send 0 in the flags, which were reserved.
 
 // Start of network messages.
 const NETMSG_START: u64 = 0xDEAD_BEEF_0000_0000;
 
 /// According to CITA frame, defines its frame header length as:
 /// "Symbol for Start" + "Length of Full Payload" + "Version"+
-///  "Address"+ "Length of Key"+"TTL"+"Reserved",
+///  "Address"+ "Length of Key"+"TTL"+"Flags",
 /// And this will consume "4 + 4 + 8 + 20 + 1 + 1+ 2" fixed-lengths of the frame.
 pub const CITA_FRAME_HEADER_LEN: usize = 4 + 4 + 8 + 20 + 1 + 1 + 2;
 pub const HEAD_VERSION_OFFSET: usize = 4 + 4;
 pub const HEAD_ADDRESS_OFFSET: usize = 4 + 4 + 8;
 pub const HEAD_KEY_LEN_OFFSET: usize = 4 + 4 + 8 + 20;
 pub const HEAD_TTL_OFFSET: usize = 4 + 4 + 8 + 20 + 1;
+pub const HEAD_FLAGS_OFFSET: usize = 4 + 4 + 8 + 20 + 1 + 1;
+
+/// The message is compressed by snappy.
+pub const FLAG_COMPRESSED: u16 = 0x0001;
+/// The sender can decompress messages, only set in init messages.
+pub const FLAG_ACCEPT_COMPRESSED: u16 = 0x0002;
 
 pub const DEFAULT_TTL_NUM: u8 = 0;
 pub const CONSENSUS_TTL_NUM: u8 = 9;
@@ -69,6 +79,7 @@ pub struct NetMessageUnit {
     pub addr: Address,
     pub version: u64,
     pub ttl: u8,
+    pub flags: u16,
 }
 
 impl NetMessageUnit {
@@ -79,8 +90,29 @@ impl NetMessageUnit {
             addr,
             version,
             ttl,
+            flags: 0,
         }
     }
+
+    /// The message with its data compressed, if the data is not shorter than
+    /// `threshold` and gets shorter by compression.
+    pub fn compress(&self, threshold: usize) -> Option<NetMessageUnit> {
+        if self.data.len() < threshold || self.flags & FLAG_COMPRESSED != 0 {
+            return None;
+        }
+        let data = snappy::compress(&self.data);
+        if data.len() >= self.data.len() {
+            return None;
+        }
+        Some(NetMessageUnit {
+            key: self.key.clone(),
+            data,
+            addr: self.addr,
+            version: self.version,
+            ttl: self.ttl,
+            flags: self.flags | FLAG_COMPRESSED,
+        })
+    }
 }
 
 impl Default for NetMessageUnit {
@@ -91,6 +123,7 @@ impl Default for NetMessageUnit {
             addr: Address::zero(),
             version: 0,
             ttl: DEFAULT_TTL_NUM,
+            flags: 0,
         }
     }
 }
@@ -123,7 +156,7 @@ pub fn pubsub_message_to_network_message(info: &NetMessageUnit) -> Option<Bytes>
     buf.put(info.addr.to_vec());
     buf.put_u8(length_key as u8);
     buf.put_u8(info.ttl);
-    buf.put_u16_be(0);
+    buf.put_u16_be(info.flags);
 
     buf.put(info.key.as_bytes());
     buf.put_slice(&info.data);
@@ -131,7 +164,12 @@ pub fn pubsub_message_to_network_message(info: &NetMessageUnit) -> Option<Bytes>
     Some(buf.into())
 }
 
-pub fn network_message_to_pubsub_message(buf: &mut BytesMut) -> Option<NetMessageUnit> {
+/// The message of a frame, whose data is decompressed if it was compressed.
+/// A compressed message longer than `max_data_len` is rejected.
+pub fn network_message_to_pubsub_message(
+    buf: &mut BytesMut,
+    max_data_len: usize,
+) -> Option<NetMessageUnit> {
     if buf.len() < CITA_FRAME_HEADER_LEN {
         return None;
     }
@@ -153,6 +191,7 @@ pub fn network_message_to_pubsub_message(buf: &mut BytesMut) -> Option<NetMessag
 
     let length_key = head_buf[HEAD_KEY_LEN_OFFSET] as usize;
     let ttl = head_buf[HEAD_TTL_OFFSET];
+    let mut flags = NetworkEndian::read_u16(&head_buf[HEAD_FLAGS_OFFSET..]);
     if length_key == 0 {
         error!("[CitaProtocol] Network message key is empty.");
         return None;
@@ -178,12 +217,43 @@ pub fn network_message_to_pubsub_message(buf: &mut BytesMut) -> Option<NetMessag
     if length_full == length_key {
         warn!("[CitaProtocol] Network message is empty.");
     }
+
+    let data = if flags & FLAG_COMPRESSED != 0 {
+        flags &= !FLAG_COMPRESSED;
+        match snappy::decompressed_len(&buf) {
+            Ok(len) if len <= max_data_len => {}
+            Ok(len) => {
+                error!(
+                    "[CitaProtocol] Network message {} is too long {} when decompressed.",
+                    key, len
+                );
+                return None;
+            }
+            Err(e) => {
+                error!("[CitaProtocol] Network message {} is broken: {:?}.", key, e);
+                return None;
+            }
+        }
+        match snappy::decompress(&buf) {
+            Ok(data) => data,
+            Err(e) => {
+                error!(
+                    "[CitaProtocol] Network message {} decompress error: {:?}.",
+                    key, e
+                );
+                return None;
+            }
+        }
+    } else {
+        buf.to_vec()
+    };
     Some(NetMessageUnit {
         key,
-        data: buf.to_vec(),
+        data,
         addr,
         version,
         ttl,
+        flags,
     })
 }
 
@@ -191,13 +261,16 @@ pub fn network_message_to_pubsub_message(buf: &mut BytesMut) -> Option<NetMessag
 mod test {
     use super::{
         network_message_to_pubsub_message, pubsub_message_to_network_message, NetMessageUnit,
+        FLAG_COMPRESSED,
     };
     use bytes::BytesMut;
 
+    const MAX_DATA_LEN: usize = 1024;
+
     #[test]
     fn convert_empty_message() {
         let buf = pubsub_message_to_network_message(&NetMessageUnit::default());
-        let pub_msg_opt = network_message_to_pubsub_message(&mut BytesMut::new());
+        let pub_msg_opt = network_message_to_pubsub_message(&mut BytesMut::new(), MAX_DATA_LEN);
         assert!(pub_msg_opt.is_none());
         assert!(buf.is_none());
     }
@@ -211,10 +284,33 @@ mod test {
         msg.data = data.clone();
 
         let buf = pubsub_message_to_network_message(&msg).unwrap();
-        let pub_msg_opt = network_message_to_pubsub_message(&mut buf.try_mut().unwrap());
+        let pub_msg_opt =
+            network_message_to_pubsub_message(&mut buf.try_mut().unwrap(), MAX_DATA_LEN);
         assert!(pub_msg_opt.is_some());
         let info = pub_msg_opt.unwrap();
         assert_eq!(key, info.key);
         assert_eq!(data, info.data);
     }
+
+    #[test]
+    fn convert_compressed_messages() {
+        let mut msg = NetMessageUnit::default();
+        msg.key = "this-is-the-key".to_string();
+        msg.data = vec![7; 512];
+        // Too short to be compressed
+        assert!(msg.compress(1024).is_none());
+
+        let compressed = msg.compress(256).unwrap();
+        assert_eq!(compressed.flags, FLAG_COMPRESSED);
+        assert!(compressed.data.len() < msg.data.len());
+
+        let buf = pubsub_message_to_network_message(&compressed).unwrap();
+        let info = network_message_to_pubsub_message(&mut BytesMut::from(buf.clone()), 512)
+            .expect("decompressed");
+        assert_eq!(info.data, msg.data);
+        assert_eq!(info.flags, 0);
+
+        // Longer than the limit when decompressed
+        assert!(network_message_to_pubsub_message(&mut BytesMut::from(buf), 511).is_none());
+    }
 }
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cita_metrics::MetricsConfig;
 use cita_types::{clean_0x, Address};
+use crypto::PrivKey;
 use serde_derive::Deserialize;
 use std::fs::File;
 use std::io::Read;
@@ -26,6 +28,19 @@ pub struct NetConfig {
     pub max_connects: Option<usize>,
     pub enable_tls: Option<bool>,
     pub enable_discovery: Option<bool>,
+    pub metrics: Option<MetricsConfig>,
+    // Path of the file to save known peers and bans, `peers.json` by default
+    pub peer_store: Option<String>,
+    // Only accept the peers whose addresses are in the node list of chain or in `allowlist`,
+    // the address of a peer is checked against its TLS key, which is signed by the node key
+    pub enable_allowlist: Option<bool>,
+    pub allowlist: Option<Vec<String>>,
+    // Compress the messages not shorter than `compression_threshold` bytes to
+    // the peers accepting it, enabled by default
+    pub enable_compression: Option<bool>,
+    pub compression_threshold: Option<usize>,
+    // Frames longer than this are rejected and their senders are penalized
+    pub max_frame_size: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -60,10 +75,31 @@ impl AddressConfig {
     }
 }
 
+pub struct PrivkeyConfig {
+    pub privkey: Option<PrivKey>,
+}
+
+impl PrivkeyConfig {
+    pub fn new(path: &str) -> Self {
+        let mut buffer = String::new();
+        let privkey = match File::open(path).and_then(|mut f| f.read_to_string(&mut buffer)) {
+            Ok(_) => PrivKey::from_str(clean_0x(buffer.trim())).ok(),
+            Err(_) => None,
+        };
+        if privkey.is_none() {
+            info!("[Config] Cannot load private key file, the TLS key will not be signed.");
+        }
+
+        PrivkeyConfig { privkey }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::NetConfig;
+    use super::{NetConfig, PrivkeyConfig};
+    use crypto::PrivKey;
     use std::io::Write;
+    use std::str::FromStr;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -73,6 +109,9 @@ mod tests {
         enable_tls = true
         max_connects = 4
         id_card = 9
+        enable_allowlist = true
+        allowlist = ["0x0000000000000000000000000000000000000001"]
+        max_frame_size = 1048576
         [[peers]]
             ip = "127.0.0.1"
             port = 4001
@@ -80,6 +119,8 @@ mod tests {
         [[peers]]
             ip = "127.0.0.1"
             port = 4002
+        [metrics]
+            enable = true
         "#;
 
         let mut tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
@@ -92,5 +133,27 @@ mod tests {
         assert_eq!(config.enable_tls, Some(true));
         assert_eq!(config.peers.unwrap().len(), 2);
         assert_eq!(config.enable_discovery, None);
+        assert_eq!(config.peer_store, None);
+        assert_eq!(config.enable_allowlist, Some(true));
+        assert_eq!(config.allowlist.unwrap().len(), 1);
+        assert_eq!(config.enable_compression, None);
+        assert_eq!(config.max_frame_size, Some(1_048_576));
+        let metrics = config.metrics.unwrap();
+        assert!(metrics.enable);
+        assert_eq!(metrics.listen_ip.to_string(), "127.0.0.1");
+        assert_eq!(metrics.port, 9100);
+    }
+
+    #[test]
+    fn test_privkey() {
+        let mut tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
+        let key = "5f0258a4778057a8a7d97809bd209055b2fbafa654ce7d31ec7191066b9225e6";
+        tmp_file
+            .write_all(format!("0x{}\n", key).as_bytes())
+            .unwrap();
+        let config = PrivkeyConfig::new(tmp_file.path().to_str().unwrap());
+        assert_eq!(config.privkey, PrivKey::from_str(key).ok());
+
+        assert_eq!(PrivkeyConfig::new("not_exist").privkey, None::<PrivKey>);
     }
 }
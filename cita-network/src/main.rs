@@ -46,6 +46,18 @@
 //!     | network           | Net       | Auth                | BlockTxn              |
 //!     | network           | Net       | Auth                | GetBlockTxn           |
 //!
+//! 3. Peer management calls
+//!
+//!     libproto has no message type for them, they use plain keys: network
+//!     subscribes `jsonrpc.peer_request` and replies on `network.peer_response`.
+//!
+//! 4. Fast sync
+//!
+//!     Plain keys too: network sends `executor.state_sync`,
+//!     `executor.receipts_request` and `chain.receipts_response` to peers, and
+//!     publishes the messages of peers on `network.state_sync`, except the
+//!     receipts requests on `network.receipts_request`.
+//!
 //! ### p2p binary protocol
 //! | Start      | Full length | Key length | Key value      | Message value    |
 //! | ---------- | ----------- | ---------- | -------------- | ---------------- |
@@ -78,28 +90,37 @@
 
 #[macro_use]
 extern crate cita_logger as logger;
+#[macro_use]
+extern crate lazy_static;
 
 #[macro_use]
 extern crate util;
+extern crate cita_crypto as crypto;
 pub mod cita_protocol;
 pub mod config;
+mod metrics;
 pub mod mq_agent;
 pub mod network;
 pub mod node_manager;
 pub mod p2p_protocol;
+pub mod peer_store;
+pub mod sync_scheduler;
 pub mod synchronizer;
 
-use crate::config::{AddressConfig, NetConfig};
+use crate::config::{AddressConfig, NetConfig, PrivkeyConfig};
 use crate::mq_agent::MqAgent;
 use crate::network::Network;
 use crate::node_manager::{NodesManager, DEFAULT_PORT};
 use crate::p2p_protocol::{
-    node_discovery::create_discovery_meta, transfer::create_transfer_meta, SHandle,
+    node_discovery::create_discovery_meta, transfer::create_transfer_meta,
+    transfer::MAX_FRAME_LENGTH, SHandle,
 };
 use crate::synchronizer::Synchronizer;
 use clap::App;
+use crypto::{Sign, Signature};
 use dotenv;
 use futures::prelude::*;
+use hashable::Hashable;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use std::path::Path;
 use std::sync::mpsc::channel;
@@ -121,6 +142,7 @@ fn main() {
         .args_from_usage(
             "-c, --config=[FILE] 'Sets a custom config file'
                         -a, --address=[FILE] 'Sets an address file'
+                        -p, --private_key=[FILE] 'Sets a private key file, used to sign the TLS key'
                         -s, --stdout 'Log to console'",
         )
         .get_matches();
@@ -153,12 +175,26 @@ fn main() {
     let config = NetConfig::new(&config_file);
     debug!("Network config is {:?}", config_file);
 
+    cita_metrics::start_server(config.metrics.unwrap_or_default());
+
     let addr_path = matches.value_of("address").unwrap_or("address");
     let own_addr = AddressConfig::new(&addr_path);
     debug!("Node address is {:?}", own_addr.addr);
+    let privkey_path = matches.value_of("private_key").unwrap_or("privkey");
+    let own_privkey = PrivkeyConfig::new(&privkey_path);
     // End init config
 
+    // The address in the init message of a peer is checked against the signature
+    // of its TLS key, so the allowlist and the bans can not be passed by claiming
+    // another address.
+    let verify_peer_key = config.enable_allowlist.unwrap_or(false);
+    if verify_peer_key && own_privkey.privkey.is_none() {
+        error!("Allowlist needs the private key of the node!");
+        std::process::exit(2);
+    }
+
     let mut nodes_mgr = NodesManager::from_config(config.clone(), own_addr.addr);
+    nodes_mgr.set_verify_peer_key(verify_peer_key);
     let mut mq_agent = MqAgent::default();
     let mut synchronizer_mgr = Synchronizer::new(mq_agent.client(), nodes_mgr.client());
     let mut network_mgr = Network::new(
@@ -169,8 +205,18 @@ fn main() {
     mq_agent.set_nodes_mgr_client(nodes_mgr.client());
     mq_agent.set_network_client(network_mgr.client());
 
-    let transfer_meta =
-        create_transfer_meta(network_mgr.client(), nodes_mgr.client(), own_addr.addr);
+    // Frames over the limit are refused by the codec of transfer.
+    let max_frame_size = config
+        .max_frame_size
+        .unwrap_or(MAX_FRAME_LENGTH)
+        .min(MAX_FRAME_LENGTH);
+    let transfer_meta = create_transfer_meta(
+        network_mgr.client(),
+        nodes_mgr.client(),
+        own_addr.addr,
+        max_frame_size,
+        verify_peer_key,
+    );
     let mut service_cfg = ServiceBuilder::default()
         .insert_protocol(transfer_meta)
         .forever(true);
@@ -189,10 +235,24 @@ fn main() {
         });
     }
 
-    if config.enable_tls.unwrap_or(false) {
-        service_cfg = service_cfg.key_pair(SecioKeyPair::secp256k1_generated());
+    if config.enable_tls.unwrap_or(false) || verify_peer_key {
+        // The TLS key is bound to the node address by a signature of the node key,
+        // which is sent to the peers in the init message.
+        let key_pair = SecioKeyPair::secp256k1_generated();
+        if let Some(ref privkey) = own_privkey.privkey {
+            let hash = key_pair.to_public_key().inner_ref().crypt_hash();
+            match Signature::sign(privkey, &hash) {
+                Ok(signature) => nodes_mgr.set_key_proof(signature.to_vec()),
+                Err(e) => {
+                    error!("Cannot sign the TLS key: {:?}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        service_cfg = service_cfg.key_pair(key_pair);
     }
-    let mut service = service_cfg.build(SHandle::new(nodes_mgr.client()));
+    let mut service =
+        service_cfg.build(SHandle::new(nodes_mgr.client(), synchronizer_mgr.client()));
 
     let addr = format!("/ip4/0.0.0.0/tcp/{}", config.port.unwrap_or(DEFAULT_PORT));
     let _ = service.listen(addr.parse().unwrap());
@@ -0,0 +1,38 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cita_metrics::{int_counter, int_gauge, IntCounter, IntGauge};
+
+lazy_static! {
+    pub static ref CONNECTED_PEERS: IntGauge =
+        int_gauge("network_connected_peers", "Number of connected peers");
+    pub static ref KNOWN_PEERS: IntGauge =
+        int_gauge("network_known_peers", "Number of known peer addresses");
+    pub static ref CURRENT_HEIGHT: IntGauge =
+        int_gauge("network_current_height", "Height of the local chain");
+    pub static ref GLOBAL_HEIGHT: IntGauge =
+        int_gauge("network_global_height", "Highest height announced by peers");
+    pub static ref SYNC_LAG: IntGauge = int_gauge(
+        "network_sync_lag",
+        "Blocks the local chain is behind the peers"
+    );
+    pub static ref SYNC_RANGES_IN_FLIGHT: IntGauge = int_gauge(
+        "network_sync_ranges_in_flight",
+        "Block ranges requested from peers but not answered"
+    );
+    pub static ref COMPRESSED_BYTES_SAVED: IntCounter = int_counter(
+        "network_compressed_bytes_saved_total",
+        "Bytes saved by compressing the messages sent to peers"
+    );
+}
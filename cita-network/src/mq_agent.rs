@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::network::{send_message, LocalMessage, NetworkClient};
+use crate::network::{
+    send_message, LocalMessage, NetworkClient, PEER_REQUEST, RECEIPTS_REQUEST, RECEIPTS_RESPONSE,
+    STATE_SYNC,
+};
 use crate::node_manager::NodesManagerClient;
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::routing_key;
@@ -56,19 +59,24 @@ impl MqAgent {
             crx_pub_consensus,
         );
 
-        // Chain, JSON-RPC and Snapshot use a common channel
+        // Chain, JSON-RPC, Executor and Snapshot use a common channel
         let (ctx_sub_other_modules, crx_sub_other_modules) = unbounded();
         let (ctx_pub_other_modules, crx_pub_other_modules) = unbounded();
+        let mut keys = routing_key!([
+            Chain >> Status,
+            Chain >> RichStatus,
+            Chain >> SyncResponse,
+            Jsonrpc >> RequestNet,
+            Jsonrpc >> RequestPeersInfo,
+            Snapshot >> SnapshotReq
+        ]);
+        keys.push(PEER_REQUEST.to_owned());
+        keys.push(STATE_SYNC.to_owned());
+        keys.push(RECEIPTS_REQUEST.to_owned());
+        keys.push(RECEIPTS_RESPONSE.to_owned());
         start_pubsub(
             "network",
-            routing_key!([
-                Chain >> Status,
-                Chain >> RichStatus,
-                Chain >> SyncResponse,
-                Jsonrpc >> RequestNet,
-                Jsonrpc >> RequestPeersInfo,
-                Snapshot >> SnapshotReq
-            ]),
+            keys,
             ctx_sub_other_modules,
             crx_pub_other_modules,
         );
@@ -171,12 +179,25 @@ impl MqAgentClient {
         }
     }
 
+    /// Forward the fast sync messages of peers to executor and chain.
+    pub fn forward_state_sync(&self, msg: PubMessage) {
+        if let Err(e) = self.pub_other_modules.send((msg.key, msg.data)) {
+            warn!("[MqAgent] Forward state sync message failed: {:?}", e);
+        }
+    }
+
     pub fn send_peer_count(&self, msg: PubMessage) {
         if let Err(e) = self.pub_other_modules.send((msg.key, msg.data)) {
             warn!("[MqAgent] Send peer count failed: {:?}", e);
         }
     }
 
+    pub fn send_peer_admin_reply(&self, msg: PubMessage) {
+        if let Err(e) = self.pub_other_modules.send((msg.key, msg.data)) {
+            warn!("[MqAgent] Send peer admin reply failed: {:?}", e);
+        }
+    }
+
     pub fn send_snapshot_resp(&self, msg: PubMessage) {
         if let Err(e) = self.pub_other_modules.send((msg.key, msg.data)) {
             warn!("[MqAgent] Send snapshot response failed: {:?}", e);
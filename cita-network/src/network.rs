@@ -15,7 +15,7 @@
 use crate::mq_agent::{MqAgentClient, PubMessage};
 use crate::node_manager::{
     BroadcastReq, DealRichStatusReq, GetPeerCountReq, GetPeersInfoReq, NodesManagerClient,
-    SingleTxReq,
+    PeerAdminReq, SingleTxReq,
 };
 use crate::synchronizer::{SynchronizerClient, SynchronizerMessage};
 use jsonrpc_types::rpc_types::PeersInfo;
@@ -26,7 +26,8 @@ use libproto::snapshot::{Cmd, Resp, SnapshotResp};
 use libproto::{Message as ProtoMessage, OperateType, Response};
 use libproto::{TryFrom, TryInto};
 use pubsub::channel::{unbounded, Receiver, Sender};
-use serde_json;
+use serde_derive::Deserialize;
+use serde_json::{self, Value};
 use std::iter::FromIterator;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -109,6 +110,31 @@ impl NetworkMessage {
         }
     }
 }
+
+/// The peer management calls of JSON-RPC, libproto has no message type for them.
+pub const PEER_REQUEST: &str = "jsonrpc.peer_request";
+/// The replies of the peer management calls.
+pub const PEER_RESPONSE: &str = "network.peer_response";
+
+/// The fast sync messages of executor to peers.
+pub const STATE_SYNC: &str = "executor.state_sync";
+/// The receipts requests of executor to the chains of peers.
+pub const RECEIPTS_REQUEST: &str = "executor.receipts_request";
+/// The receipts of chain to the executors of peers.
+pub const RECEIPTS_RESPONSE: &str = "chain.receipts_response";
+/// The fast sync messages of peers to executor.
+const NET_STATE_SYNC: &str = "network.state_sync";
+/// The receipts requests of peers to chain.
+const NET_RECEIPTS_REQUEST: &str = "network.receipts_request";
+
+#[derive(Debug, Deserialize)]
+struct PeerAdminCall {
+    request_id: String,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
 pub struct LocalMessage {
     key: String,
     data: Vec<u8>,
@@ -120,6 +146,20 @@ impl LocalMessage {
     }
 
     pub fn handle(self, service: &mut Network) {
+        if self.key == PEER_REQUEST {
+            if !service.is_pause.load(Ordering::SeqCst) {
+                self.reply_peer_admin(&self.data, service);
+            }
+            return;
+        }
+        if self.key == STATE_SYNC || self.key == RECEIPTS_REQUEST || self.key == RECEIPTS_RESPONSE {
+            if !service.is_pause.load(Ordering::SeqCst) {
+                let msg = ProtoMessage::try_from(&self.data).unwrap();
+                send_message(&service.nodes_mgr_client, self.key, msg);
+            }
+            return;
+        }
+
         let rt_key = RoutingKey::from(&self.key);
         trace!("[Network] Receive Message from Local/{}", self.key);
 
@@ -241,6 +281,28 @@ impl LocalMessage {
         }
     }
 
+    // The reply is sent by the node manager, so the network thread is not blocked.
+    fn reply_peer_admin(&self, data: &[u8], service: &mut Network) {
+        let call = match ProtoMessage::try_from(data)
+            .ok()
+            .and_then(|mut msg| msg.take_raw_bytes())
+            .and_then(|body| serde_json::from_slice::<PeerAdminCall>(&body).ok())
+        {
+            Some(call) => call,
+            None => {
+                warn!("[Network] Receive unexpected peer admin data");
+                return;
+            }
+        };
+
+        service.nodes_mgr_client.peer_admin(PeerAdminReq::new(
+            call.request_id,
+            call.method,
+            call.params,
+            service.mq_client.clone(),
+        ));
+    }
+
     fn snapshot_req(&self, data: &[u8], service: &mut Network) {
         let mut msg = ProtoMessage::try_from(data).unwrap();
         let req = msg.take_snapshot_req().unwrap();
@@ -295,6 +357,25 @@ impl RemoteMessage {
     }
 
     pub fn handle(self, service: &mut Network) {
+        if self.key == PEER_REQUEST {
+            if !service.is_pause.load(Ordering::SeqCst) {
+                self.reply_peer_admin(&self.data, service);
+            }
+            return;
+        }
+        if self.key == STATE_SYNC || self.key == RECEIPTS_REQUEST || self.key == RECEIPTS_RESPONSE {
+            if !service.is_pause.load(Ordering::SeqCst) {
+                let key = if self.key == RECEIPTS_REQUEST {
+                    NET_RECEIPTS_REQUEST
+                } else {
+                    NET_STATE_SYNC
+                };
+                let msg = PubMessage::new(key.to_owned(), self.data);
+                service.mq_client.forward_state_sync(msg);
+            }
+            return;
+        }
+
         let rt_key = RoutingKey::from(&self.key);
         trace!("[Network] Receive Message from Remote/{}", self.key);
 
@@ -14,22 +14,32 @@
 
 use crate::cita_protocol::{
     pubsub_message_to_network_message, NetMessageUnit, CONSENSUS_STR, CONSENSUS_TTL_NUM,
+    FLAG_ACCEPT_COMPRESSED,
 };
 use crate::config::NetConfig;
+use crate::metrics;
+use crate::mq_agent::{MqAgentClient, PubMessage};
+use crate::network::PEER_RESPONSE;
 use crate::p2p_protocol::transfer::TRANSFER_PROTOCOL_ID;
+use crate::peer_store::{
+    unix_now, BanRecord, IpBanRecord, PeerRecord, PeerStore, PeerStoreData, ScoreEvent,
+    DEFAULT_PEER_STORE, MAX_SCORE_HISTORY,
+};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use cita_types::Address;
+use cita_types::{clean_0x, Address};
 use fnv::FnvHashMap as HashMap;
-use libproto::{Message as ProtoMessage, TryInto};
+use libproto::{Message as ProtoMessage, MsgClass, OperateType, TryInto};
 use notify::DebouncedEvent;
 use pubsub::channel::{select, tick, unbounded, Receiver, Sender};
 use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
 use std::sync::mpsc::Receiver as StdReceiver;
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     convert::Into,
     io::Cursor,
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
     time::{Duration, Instant},
 };
 use tentacle::{
@@ -42,6 +52,9 @@ pub const DEFAULT_MAX_CONNECTS: usize = 666;
 pub const DEFAULT_MAX_KNOWN_ADDRS: usize = 1000;
 pub const DEFAULT_PORT: usize = 4000;
 pub const CHECK_CONNECTED_NODES: Duration = Duration::from_secs(3);
+pub const SAVE_PEERS_INTERVAL: Duration = Duration::from_secs(60);
+// Messages shorter than this are not worth compressing.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
 
 // Score uses to manage known_nodes list. If a node has too low score, do not dial it again.
 // Maybe some complex algorithm can be designed later. But for now, just keeps as simple as below:
@@ -68,6 +81,20 @@ pub const DIALED_ERROR_SCORE: i32 = 25;
 // A node is dialed error by client, should need DIALED_ERROR_SCORE each time.
 pub const KEEP_ON_LINE_SCORE: i32 = 5;
 
+// Penalties for misbehaving sessions. A session which has got BAN_PENALTY is
+// disconnected, and banned for DEFAULT_BAN_SECS: by its node address if the
+// addresses are verified against the TLS keys, or else by its IP address, as
+// an unverified node address can be claimed by anyone.
+pub const INVALID_MESSAGE_PENALTY: i32 = 20;
+pub const BAD_SYNC_RESPONSE_PENALTY: i32 = 10;
+pub const OVERSIZED_FRAME_PENALTY: i32 = 50;
+pub const BAN_PENALTY: i32 = 100;
+pub const DEFAULT_BAN_SECS: u64 = 3600;
+
+pub const GET_PEERS: &str = "getPeers";
+pub const BAN_PEER: &str = "banPeer";
+pub const UNBAN_PEER: &str = "unbanPeer";
+
 #[derive(Debug, PartialEq)]
 pub enum NodeSource {
     FromConfig,
@@ -84,6 +111,13 @@ pub struct NodeStatus {
     // connected yet.
     pub session_id: Option<SessionId>,
     pub node_src: NodeSource,
+
+    // last_seen: Seconds since the epoch when the node was online, 0 for never connected.
+    pub last_seen: u64,
+    // history: Recent penalties of the node.
+    pub history: VecDeque<ScoreEvent>,
+    // peer_key: Node address the node has shown in its `init message`, 'None' for unknown.
+    pub peer_key: Option<Address>,
 }
 
 impl NodeStatus {
@@ -92,8 +126,49 @@ impl NodeStatus {
             score,
             session_id,
             node_src,
+            last_seen: 0,
+            history: VecDeque::new(),
+            peer_key: None,
+        }
+    }
+
+    pub fn from_record(record: PeerRecord) -> Self {
+        let node_src = if record.from_config {
+            NodeSource::FromConfig
+        } else {
+            NodeSource::FromDiscovery
+        };
+        NodeStatus {
+            score: record.score,
+            session_id: None,
+            node_src,
+            last_seen: record.last_seen,
+            history: record.history.into_iter().collect(),
+            peer_key: None,
         }
     }
+
+    pub fn to_record(&self, addr: SocketAddr) -> PeerRecord {
+        PeerRecord {
+            addr,
+            score: self.score,
+            from_config: self.node_src == NodeSource::FromConfig,
+            last_seen: self.last_seen,
+            history: self.history.iter().cloned().collect(),
+        }
+    }
+
+    pub fn penalize(&mut self, penalty: i32, reason: &str) {
+        self.score -= penalty;
+        if self.history.len() >= MAX_SCORE_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(ScoreEvent {
+            time: unix_now(),
+            delta: -penalty,
+            reason: reason.to_owned(),
+        });
+    }
 }
 
 #[derive(Debug)]
@@ -177,6 +252,37 @@ impl ConsensusNodeTopology {
     //pub fn consensus_threshold_linked(&self) -> bool {self.consensus_threshold_linked}
 }
 
+/// The node addresses allowed to join the network in a permissioned chain.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    // Addresses in config file
+    static_nodes: BTreeSet<Address>,
+    // Consensus nodes from the latest rich status of chain
+    chain_nodes: BTreeSet<Address>,
+}
+
+impl Allowlist {
+    pub fn new(static_nodes: BTreeSet<Address>) -> Self {
+        Allowlist {
+            static_nodes,
+            chain_nodes: BTreeSet::default(),
+        }
+    }
+
+    pub fn contains(&self, addr: &Address) -> bool {
+        self.static_nodes.contains(addr) || self.chain_nodes.contains(addr)
+    }
+
+    /// Returns whether the node list of chain is changed.
+    pub fn update_chain_nodes(&mut self, nodes: BTreeSet<Address>) -> bool {
+        if nodes == self.chain_nodes {
+            return false;
+        }
+        self.chain_nodes = nodes;
+        true
+    }
+}
+
 pub struct NodesManager {
     known_addrs: HashMap<SocketAddr, NodeStatus>,
     config_addrs: BTreeMap<String, Option<SocketAddr>>,
@@ -200,6 +306,25 @@ pub struct NodesManager {
 
     dialing_node: Option<SocketAddr>,
     self_addr: Option<SocketAddr>,
+
+    banned_nodes: HashMap<Address, BanRecord>,
+    banned_ips: HashMap<IpAddr, IpBanRecord>,
+    // The node addresses in the init messages are checked against the TLS keys
+    verify_peer_key: bool,
+    // Signature of the TLS key made by the node key, sent in the init message
+    key_proof: Vec<u8>,
+    // Penalties of the misbehaving sessions
+    session_penalties: BTreeMap<SessionId, i32>,
+    peer_store: Option<PeerStore>,
+    last_saved: Instant,
+
+    // None for accepting all nodes
+    allowlist: Option<Allowlist>,
+
+    enable_compression: bool,
+    compression_threshold: usize,
+    // Sessions whose peers accept compressed messages
+    compression_sessions: BTreeSet<SessionId>,
 }
 
 impl NodesManager {
@@ -226,6 +351,17 @@ impl NodesManager {
             gossip_key_version: HashMap::default(),
             self_version: 0,
             consensus_topology: ConsensusNodeTopology::new(peer_key),
+            banned_nodes: HashMap::default(),
+            banned_ips: HashMap::default(),
+            verify_peer_key: false,
+            key_proof: Vec::new(),
+            session_penalties: BTreeMap::default(),
+            peer_store: None,
+            last_saved: Instant::now(),
+            allowlist: None,
+            enable_compression: true,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            compression_sessions: BTreeSet::new(),
         }
     }
 
@@ -247,9 +383,292 @@ impl NodesManager {
         } else {
             warn!("[NodeManager] Does not set any peers in config file!");
         }
+
+        if cfg.enable_allowlist.unwrap_or(false) {
+            let mut static_nodes = BTreeSet::new();
+            for addr in cfg.allowlist.unwrap_or_default() {
+                match Address::from_str(clean_0x(&addr)) {
+                    Ok(addr) => {
+                        static_nodes.insert(addr);
+                    }
+                    Err(_) => warn!("[NodeManager] Invalid address {} in allowlist.", addr),
+                }
+            }
+            info!(
+                "[NodeManager] Allowlist enabled, static nodes: {:?}",
+                static_nodes
+            );
+            node_mgr.allowlist = Some(Allowlist::new(static_nodes));
+        }
+
+        node_mgr.enable_compression = cfg.enable_compression.unwrap_or(true);
+        node_mgr.compression_threshold = cfg
+            .compression_threshold
+            .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD);
+
+        let peer_store = PeerStore::new(
+            &cfg.peer_store
+                .unwrap_or_else(|| DEFAULT_PEER_STORE.to_owned()),
+        );
+        node_mgr.load_peers(peer_store.load());
+        node_mgr.peer_store = Some(peer_store);
         node_mgr
     }
 
+    // The nodes in config file are added by translate_address, so only the
+    // discovered ones are loaded.
+    fn load_peers(&mut self, data: PeerStoreData) {
+        let now = unix_now();
+        for record in data.peers {
+            if record.from_config || self.known_addrs.len() >= DEFAULT_MAX_KNOWN_ADDRS {
+                continue;
+            }
+            self.known_addrs
+                .insert(record.addr, NodeStatus::from_record(record));
+        }
+        for ban in data.bans {
+            if ban.until > now {
+                self.banned_nodes.insert(ban.address, ban);
+            }
+        }
+        for ban in data.ip_bans {
+            if ban.until > now {
+                self.banned_ips.insert(ban.ip, ban);
+            }
+        }
+        info!(
+            "[NodeManager] Load {} known addresses and {} bans from peer store.",
+            self.known_addrs.len(),
+            self.banned_nodes.len() + self.banned_ips.len()
+        );
+    }
+
+    pub fn save_peers(&mut self) {
+        let data = PeerStoreData {
+            peers: self
+                .known_addrs
+                .iter()
+                .map(|(addr, status)| status.to_record(*addr))
+                .collect(),
+            bans: self.banned_nodes.values().cloned().collect(),
+            ip_bans: self.banned_ips.values().cloned().collect(),
+        };
+        if let Some(ref store) = self.peer_store {
+            store.save(&data);
+        }
+        self.last_saved = Instant::now();
+    }
+
+    pub fn is_allowed(&self, peer_key: &Address) -> bool {
+        self.allowlist
+            .as_ref()
+            .map(|allowlist| allowlist.contains(peer_key))
+            .unwrap_or(true)
+    }
+
+    /// Send the message to the target sessions, compressed to the ones whose
+    /// peers accept it.
+    fn send_net_message(&mut self, target: TargetSession, info: &NetMessageUnit) {
+        let compressed = if self.enable_compression && !self.compression_sessions.is_empty() {
+            info.compress(self.compression_threshold)
+        } else {
+            None
+        };
+        let mut frames = Vec::new();
+        match compressed {
+            None => frames.push((target, info)),
+            Some(ref compressed) => {
+                let ids: Vec<SessionId> = match target {
+                    TargetSession::Single(id) => vec![id],
+                    TargetSession::Multi(ids) => ids,
+                    TargetSession::All => self
+                        .connected_addrs
+                        .keys()
+                        .chain(self.pending_connected_addrs.keys())
+                        .cloned()
+                        .collect(),
+                };
+                let (compressed_ids, plain_ids): (Vec<SessionId>, Vec<SessionId>) = ids
+                    .into_iter()
+                    .partition(|id| self.compression_sessions.contains(id));
+                metrics::COMPRESSED_BYTES_SAVED.inc_by(
+                    ((info.data.len() - compressed.data.len()) * compressed_ids.len()) as i64,
+                );
+                if !compressed_ids.is_empty() {
+                    frames.push((TargetSession::Multi(compressed_ids), compressed));
+                }
+                if !plain_ids.is_empty() {
+                    frames.push((TargetSession::Multi(plain_ids), info));
+                }
+            }
+        }
+
+        if let Some(ref mut ctrl) = self.service_ctrl {
+            for (target, info) in frames {
+                if let Some(buf) = pubsub_message_to_network_message(info) {
+                    let _ = ctrl.filter_broadcast(target, TRANSFER_PROTOCOL_ID, buf);
+                }
+            }
+        }
+    }
+
+    /// Disconnect the connected peers which are not allowed any more.
+    fn disconnect_disallowed(&mut self) {
+        let sessions: Vec<SessionId> = self
+            .connected_peer_keys
+            .iter()
+            .filter(|(peer_key, _)| !self.is_allowed(peer_key))
+            .map(|(_, session_id)| *session_id)
+            .collect();
+        if let Some(ref mut ctrl) = self.service_ctrl {
+            for session_id in sessions {
+                info!(
+                    "[NodeManager] Disconnect session [{:?}] not in allowlist.",
+                    session_id
+                );
+                let _ = ctrl.disconnect(session_id);
+            }
+        }
+    }
+
+    pub fn set_verify_peer_key(&mut self, verify_peer_key: bool) {
+        self.verify_peer_key = verify_peer_key;
+    }
+
+    pub fn set_key_proof(&mut self, key_proof: Vec<u8>) {
+        self.key_proof = key_proof;
+    }
+
+    pub fn is_banned(&self, peer_key: &Address) -> bool {
+        self.banned_nodes
+            .get(peer_key)
+            .map(|ban| ban.until > unix_now())
+            .unwrap_or(false)
+    }
+
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        self.banned_ips
+            .get(ip)
+            .map(|ban| ban.until > unix_now())
+            .unwrap_or(false)
+    }
+
+    // A node is known to be banned by its address only after it has shown
+    // its node address once.
+    fn is_banned_node(&self, addr: &SocketAddr, node_status: &NodeStatus) -> bool {
+        self.is_ip_banned(&addr.ip())
+            || node_status
+                .peer_key
+                .map(|peer_key| self.is_banned(&peer_key))
+                .unwrap_or(false)
+    }
+
+    /// Ban the node address, and disconnect its session.
+    pub fn ban(&mut self, peer_key: Address, secs: u64, reason: &str) {
+        warn!(
+            "[NodeManager] Ban {:?} for {} seconds: {}",
+            peer_key, secs, reason
+        );
+        self.banned_nodes.insert(
+            peer_key,
+            BanRecord {
+                address: peer_key,
+                until: unix_now() + secs,
+                reason: reason.to_owned(),
+            },
+        );
+        if let Some(session_id) = self.connected_peer_keys.get(&peer_key).cloned() {
+            if let Some(ref mut ctrl) = self.service_ctrl {
+                let _ = ctrl.disconnect(session_id);
+            }
+        }
+        self.save_peers();
+    }
+
+    /// Ban the IP address, and disconnect all its sessions.
+    pub fn ban_ip(&mut self, ip: IpAddr, secs: u64, reason: &str) {
+        warn!(
+            "[NodeManager] Ban IP {} for {} seconds: {}",
+            ip, secs, reason
+        );
+        self.banned_ips.insert(
+            ip,
+            IpBanRecord {
+                ip,
+                until: unix_now() + secs,
+                reason: reason.to_owned(),
+            },
+        );
+        let sessions: Vec<SessionId> = self
+            .connected_addrs
+            .iter()
+            .filter(|(_, addr)| addr.conn_addr.ip() == ip)
+            .map(|(session_id, _)| *session_id)
+            .chain(
+                self.pending_connected_addrs
+                    .iter()
+                    .filter(|(_, info)| info.addr.ip() == ip)
+                    .map(|(session_id, _)| *session_id),
+            )
+            .collect();
+        if let Some(ref mut ctrl) = self.service_ctrl {
+            for session_id in sessions {
+                let _ = ctrl.disconnect(session_id);
+            }
+        }
+        self.save_peers();
+    }
+
+    pub fn unban_ip(&mut self, ip: &IpAddr) -> bool {
+        let banned = self.banned_ips.remove(ip).is_some();
+        if banned {
+            info!("[NodeManager] Unban IP {}", ip);
+            self.save_peers();
+        }
+        banned
+    }
+
+    pub fn unban(&mut self, peer_key: &Address) -> bool {
+        let banned = self.banned_nodes.remove(peer_key).is_some();
+        if banned {
+            info!("[NodeManager] Unban {:?}", peer_key);
+            self.save_peers();
+        }
+        banned
+    }
+
+    fn expire_bans(&mut self) {
+        let now = unix_now();
+        self.banned_nodes.retain(|_, ban| ban.until > now);
+        self.banned_ips.retain(|_, ban| ban.until > now);
+    }
+
+    fn peers_detail(&self) -> Value {
+        let peers: Vec<Value> = self
+            .known_addrs
+            .iter()
+            .map(|(addr, status)| {
+                json!({
+                    "address": addr.to_string(),
+                    "score": status.score,
+                    "connected": status.session_id.is_some(),
+                    "source": format!("{:?}", status.node_src),
+                    "lastSeen": status.last_seen,
+                    "peerKey": status.peer_key,
+                    "banned": self.is_banned_node(addr, status),
+                    "history": status.history,
+                })
+            })
+            .collect();
+        let bans: Vec<&BanRecord> = self.banned_nodes.values().collect();
+        let ip_bans: Vec<&IpBanRecord> = self.banned_ips.values().collect();
+        json!({
+            "peers": peers,
+            "bans": bans,
+            "ipBans": ip_bans,
+        })
+    }
+
     pub fn notify_config_change(
         rx: StdReceiver<DebouncedEvent>,
         node_client: NodesManagerClient,
@@ -300,7 +719,13 @@ impl NodesManager {
                     }
                 }
                 recv(self.check_connected_nodes) -> _ => {
+                    self.expire_bans();
                     self.dial_nodes();
+                    metrics::CONNECTED_PEERS.set(self.connected_addrs.len() as i64);
+                    metrics::KNOWN_PEERS.set(self.known_addrs.len() as i64);
+                    if self.last_saved.elapsed() >= SAVE_PEERS_INTERVAL {
+                        self.save_peers();
+                    }
                 }
             }
         }
@@ -322,9 +747,15 @@ impl NodesManager {
 
         // If connected node has not reach MAX, select a node from known_addrs to dial.
         if self.connected_addrs.len() < self.max_connects {
-            let mut socks: Vec<_> = self.known_addrs.keys().cloned().collect();
+            let mut socks: Vec<_> = self
+                .known_addrs
+                .iter()
+                .filter(|(addr, status)| !self.is_banned_node(addr, status))
+                .map(|(addr, _)| *addr)
+                .collect();
             thread_rng().shuffle(&mut socks);
 
+            let now = unix_now();
             for key in socks {
                 let value = self.known_addrs.get_mut(&key).unwrap();
                 // Node has been connected
@@ -334,6 +765,7 @@ impl NodesManager {
                         key, session_id
                     );
 
+                    value.last_seen = now;
                     // Node keep on line, reward KEEP_ON_LINE_SCORE.
                     value.score = if (value.score + KEEP_ON_LINE_SCORE) > FULL_SCORE {
                         FULL_SCORE as i32
@@ -503,6 +935,14 @@ impl NodesManagerClient {
         self.send_req(NodesManagerMessage::ModifiedConfigPeers(req));
     }
 
+    pub fn penalize(&self, req: PenalizeReq) {
+        self.send_req(NodesManagerMessage::Penalize(req));
+    }
+
+    pub fn peer_admin(&self, req: PeerAdminReq) {
+        self.send_req(NodesManagerMessage::PeerAdmin(req));
+    }
+
     pub fn deal_rich_status(&self, req: DealRichStatusReq) {
         self.send_req(NodesManagerMessage::DealRichStatus(req));
     }
@@ -535,6 +975,8 @@ pub enum NodesManagerMessage {
     GetPeersInfo(GetPeersInfoReq),
     ModifiedConfigPeers(ModifiedConfigPeersReq),
     DealRichStatus(DealRichStatusReq),
+    Penalize(PenalizeReq),
+    PeerAdmin(PeerAdminReq),
 }
 
 impl NodesManagerMessage {
@@ -556,6 +998,8 @@ impl NodesManagerMessage {
             NodesManagerMessage::ModifiedConfigPeers(req) => req.handle(service),
             NodesManagerMessage::RetransNetMsg(req) => req.handle(service),
             NodesManagerMessage::DealRichStatus(req) => req.handle(service),
+            NodesManagerMessage::Penalize(req) => req.handle(service),
+            NodesManagerMessage::PeerAdmin(req) => req.handle(service),
         }
     }
 }
@@ -564,6 +1008,9 @@ impl NodesManagerMessage {
 pub struct InitMsg {
     pub chain_id: u64,
     pub peer_key: Address,
+    // Signature of the TLS key of the session made by the key of `peer_key`,
+    // empty if the node has no TLS key or no private key
+    pub key_proof: Vec<u8>,
 }
 
 impl Into<Vec<u8>> for InitMsg {
@@ -576,6 +1023,7 @@ impl Into<Vec<u8>> for InitMsg {
 
         out.extend_from_slice(&chain_id_data);
         out.extend_from_slice(&key_data);
+        out.extend_from_slice(&self.key_proof);
         out
     }
 }
@@ -586,9 +1034,14 @@ impl From<Vec<u8>> for InitMsg {
         chain_id_data.copy_from_slice(&data[..8]);
         let mut chain_id_data = Cursor::new(chain_id_data);
         let chain_id = chain_id_data.read_u64::<BigEndian>().unwrap();
-        let peer_key = Address::from_slice(&data[8..]);
+        let peer_key = Address::from_slice(&data[8..28]);
+        let key_proof = data[28..].to_vec();
 
-        InitMsg { chain_id, peer_key }
+        InitMsg {
+            chain_id,
+            peer_key,
+            key_proof,
+        }
     }
 }
 
@@ -596,14 +1049,21 @@ pub struct AddConnectedNodeReq {
     session_id: SessionId,
     ty: SessionType,
     init_msg: InitMsg,
+    accept_compressed: bool,
 }
 
 impl AddConnectedNodeReq {
-    pub fn new(session_id: SessionId, ty: SessionType, init_msg: InitMsg) -> Self {
+    pub fn new(
+        session_id: SessionId,
+        ty: SessionType,
+        init_msg: InitMsg,
+        accept_compressed: bool,
+    ) -> Self {
         AddConnectedNodeReq {
             session_id,
             ty,
             init_msg,
+            accept_compressed,
         }
     }
 
@@ -653,6 +1113,33 @@ impl AddConnectedNodeReq {
                     let _ = ctrl.disconnect(self.session_id);
                 }
             }
+        } else if service.is_banned(&self.init_msg.peer_key) {
+            info!(
+                "[NodeManager] Refuse Session [{:?}] of banned node: {:?}",
+                self.session_id, self.init_msg.peer_key
+            );
+            if let Some(session_info) = service.pending_connected_addrs.remove(&self.session_id) {
+                if let Some(ref mut node_status) = service.known_addrs.get_mut(&session_info.addr) {
+                    node_status.peer_key = Some(self.init_msg.peer_key);
+                }
+            }
+            if let Some(ref mut ctrl) = service.service_ctrl {
+                let _ = ctrl.disconnect(self.session_id);
+            }
+        } else if !service.is_allowed(&self.init_msg.peer_key) {
+            // The address has been checked against the TLS key of the session in transfer.
+            warn!(
+                "[NodeManager] Session [{:?}] of {:?} is not in allowlist, disconnect it.",
+                self.session_id, self.init_msg.peer_key
+            );
+            if let Some(session_info) = service.pending_connected_addrs.remove(&self.session_id) {
+                if let Some(ref mut node_status) = service.known_addrs.get_mut(&session_info.addr) {
+                    node_status.penalize(DIALED_ERROR_SCORE, "not in allowlist");
+                }
+            }
+            if let Some(ref mut ctrl) = service.service_ctrl {
+                let _ = ctrl.disconnect(self.session_id);
+            }
         } else {
             // Found a successful connection after exchanging `init message`.
             // FIXME: If have reached to max_connects, disconnected this node.
@@ -673,10 +1160,16 @@ impl AddConnectedNodeReq {
                     {
                         node_status.session_id = Some(self.session_id);
                         node_status.score += SUCCESS_DIALING_SCORE;
+                        node_status.last_seen = unix_now();
+                        node_status.peer_key = Some(self.init_msg.peer_key);
                     }
                 }
             }
 
+            if self.accept_compressed && service.enable_compression {
+                service.compression_sessions.insert(self.session_id);
+            }
+
             // Add connected peer keys
             // Because AddRepeatedNodeReq maybe already did above action
             let _ = service
@@ -721,11 +1214,15 @@ impl NetworkInitReq {
         let init_msg = InitMsg {
             chain_id: 0,
             peer_key,
+            key_proof: service.key_proof.clone(),
         };
 
         let mut msg_unit = NetMessageUnit::default();
         msg_unit.key = "network.init".to_string();
         msg_unit.data = init_msg.into();
+        if service.enable_compression {
+            msg_unit.flags = FLAG_ACCEPT_COMPRESSED;
+        }
 
         if let Some(buf) = pubsub_message_to_network_message(&msg_unit) {
             if let Some(ref mut ctrl) = service.service_ctrl {
@@ -804,6 +1301,7 @@ impl AddRepeatedNodeReq {
         if let Some(ref mut node_status) = service.known_addrs.get_mut(&self.addr) {
             node_status.session_id = Some(self.session_id);
             node_status.score += SUCCESS_DIALING_SCORE;
+            node_status.last_seen = unix_now();
 
             if let Some(session_info) = service.pending_connected_addrs.remove(&self.session_id) {
                 let _ = service.connected_addrs.insert(
@@ -840,7 +1338,12 @@ impl GetRandomNodesReq {
     }
 
     pub fn handle(self, service: &mut NodesManager) {
-        let mut addrs: Vec<_> = service.known_addrs.keys().cloned().collect();
+        let mut addrs: Vec<_> = service
+            .known_addrs
+            .iter()
+            .filter(|(addr, status)| !service.is_banned_node(addr, status))
+            .map(|(addr, _)| *addr)
+            .collect();
         thread_rng().shuffle(&mut addrs);
         addrs.truncate(self.num);
 
@@ -869,6 +1372,16 @@ impl PendingConnectedNodeReq {
     }
 
     pub fn handle(self, service: &mut NodesManager) {
+        if service.is_ip_banned(&self.addr.ip()) {
+            info!(
+                "[NodeManager] Refuse Session [{:?}] of banned IP, address: {:?}",
+                self.session_id, self.addr
+            );
+            if let Some(ref mut ctrl) = service.service_ctrl {
+                let _ = ctrl.disconnect(self.session_id);
+            }
+            return;
+        }
         if service.connected_addrs.len() >= service.max_connects {
             // Has reached to max connects, refuse this connection
             info!(
@@ -902,6 +1415,8 @@ impl DelConnectedNodeReq {
 
     pub fn handle(self, service: &mut NodesManager) {
         info!("[NodeManager] Disconnected session [{:?}]", self.session_id);
+        service.session_penalties.remove(&self.session_id);
+        service.compression_sessions.remove(&self.session_id);
 
         if let Some(addr) = service.connected_addrs.remove(&self.session_id) {
             let trans_addr = addr.trans_addr.unwrap_or(addr.conn_addr);
@@ -997,12 +1512,7 @@ impl RetransNetMsgReq {
                 self.msg_unit.ttl = 0;
             }
 
-            if let Some(buf) = pubsub_message_to_network_message(&self.msg_unit) {
-                if let Some(ref mut ctrl) = service.service_ctrl {
-                    let _ =
-                        ctrl.filter_broadcast(TargetSession::Multi(ids), TRANSFER_PROTOCOL_ID, buf);
-                }
-            }
+            service.send_net_message(TargetSession::Multi(ids), &self.msg_unit);
         }
     }
 }
@@ -1027,9 +1537,164 @@ impl DealRichStatusReq {
             .map(|node| Address::from_slice(node))
             .collect();
 
+        let mut nodes: BTreeSet<Address> = rich_status
+            .get_nodes()
+            .iter()
+            .map(|node| Address::from_slice(node))
+            .collect();
+        nodes.extend(validators.iter().cloned());
+
         service
             .consensus_topology
             .update_validators(rich_status.get_height(), validators);
+
+        let changed = service
+            .allowlist
+            .as_mut()
+            .map(|allowlist| allowlist.update_chain_nodes(nodes))
+            .unwrap_or(false);
+        if changed {
+            service.disconnect_disallowed();
+        }
+    }
+}
+
+pub struct PenalizeReq {
+    session_id: SessionId,
+    penalty: i32,
+    reason: String,
+}
+
+impl PenalizeReq {
+    pub fn new(session_id: SessionId, penalty: i32, reason: &str) -> Self {
+        PenalizeReq {
+            session_id,
+            penalty,
+            reason: reason.to_owned(),
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let (conn_addr, trans_addr) = match service.connected_addrs.get(&self.session_id) {
+            Some(addr) => (addr.conn_addr, addr.trans_addr),
+            None => return,
+        };
+        warn!(
+            "[NodeManager] Penalize session [{:?}] of {:?} by {}: {}",
+            self.session_id, conn_addr, self.penalty, self.reason
+        );
+
+        if let Some(node_status) =
+            trans_addr.and_then(|trans_addr| service.known_addrs.get_mut(&trans_addr))
+        {
+            node_status.penalize(self.penalty, &self.reason);
+        }
+
+        let total = {
+            let total = service
+                .session_penalties
+                .entry(self.session_id)
+                .or_insert(0);
+            *total += self.penalty;
+            *total
+        };
+        if total >= BAN_PENALTY {
+            // An unverified node address may be claimed by another node, which
+            // would get banned instead.
+            let peer_key = service
+                .connected_peer_keys
+                .iter()
+                .find(|(_, &session_id)| session_id == self.session_id)
+                .map(|(peer_key, _)| *peer_key)
+                .filter(|_| service.verify_peer_key);
+            match peer_key {
+                Some(peer_key) => service.ban(peer_key, DEFAULT_BAN_SECS, &self.reason),
+                None => service.ban_ip(conn_addr.ip(), DEFAULT_BAN_SECS, &self.reason),
+            }
+        }
+    }
+}
+
+/// Peer management calls from JSON-RPC: `getPeers`, `banPeer [address, seconds]`
+/// and `unbanPeer [address]`, the address is a node address or an IP address.
+/// The reply is sent to JSON-RPC directly.
+pub struct PeerAdminReq {
+    request_id: String,
+    method: String,
+    params: Vec<Value>,
+    mq_client: MqAgentClient,
+}
+
+impl PeerAdminReq {
+    pub fn new(
+        request_id: String,
+        method: String,
+        params: Vec<Value>,
+        mq_client: MqAgentClient,
+    ) -> Self {
+        PeerAdminReq {
+            request_id,
+            method,
+            params,
+            mq_client,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let reply = match self.call(service) {
+            Ok(result) => json!({"request_id": self.request_id, "result": result}),
+            Err(error) => json!({"request_id": self.request_id, "error": error}),
+        };
+        let msg = ProtoMessage::init(
+            OperateType::Single,
+            0,
+            MsgClass::RawBytes(reply.to_string().into_bytes()),
+        );
+        match msg.try_into() {
+            Ok(data) => self
+                .mq_client
+                .send_peer_admin_reply(PubMessage::new(PEER_RESPONSE.to_owned(), data)),
+            Err(e) => warn!("[NodeManager] Encode peer admin reply failed : {:?}", e),
+        }
+    }
+
+    fn call(&self, service: &mut NodesManager) -> Result<Value, String> {
+        match self.method.as_str() {
+            GET_PEERS => Ok(service.peers_detail()),
+            BAN_PEER => {
+                let secs = match self.params.get(1) {
+                    Some(secs) => secs
+                        .as_u64()
+                        .ok_or_else(|| "invalid ban seconds".to_owned())?,
+                    None => DEFAULT_BAN_SECS,
+                };
+                match self.ip() {
+                    Some(ip) => service.ban_ip(ip, secs, "manual"),
+                    None => service.ban(self.peer_key()?, secs, "manual"),
+                }
+                Ok(Value::Bool(true))
+            }
+            UNBAN_PEER => match self.ip() {
+                Some(ip) => Ok(Value::Bool(service.unban_ip(&ip))),
+                None => Ok(Value::Bool(service.unban(&self.peer_key()?))),
+            },
+            method => Err(format!("unknown method {}", method)),
+        }
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        self.params
+            .get(0)
+            .and_then(Value::as_str)
+            .and_then(|ip| ip.parse().ok())
+    }
+
+    fn peer_key(&self) -> Result<Address, String> {
+        self.params
+            .get(0)
+            .and_then(Value::as_str)
+            .and_then(|addr| Address::from_str(clean_0x(addr)).ok())
+            .ok_or_else(|| "invalid node address".to_owned())
     }
 }
 
@@ -1066,11 +1731,7 @@ impl BroadcastReq {
             info.ttl = CONSENSUS_TTL_NUM;
         }
 
-        if let Some(buf) = pubsub_message_to_network_message(&info) {
-            if let Some(ref mut ctrl) = service.service_ctrl {
-                let _ = ctrl.filter_broadcast(TargetSession::All, TRANSFER_PROTOCOL_ID, buf);
-            }
-        }
+        service.send_net_message(TargetSession::All, &info);
     }
 }
 
@@ -1097,11 +1758,7 @@ impl SingleTxReq {
         msg_unit.key = self.key;
         msg_unit.data = self.msg.try_into().unwrap();
 
-        if let Some(buf) = pubsub_message_to_network_message(&msg_unit) {
-            if let Some(ref mut ctrl) = service.service_ctrl {
-                let _ = ctrl.send_message_to(dst, TRANSFER_PROTOCOL_ID, buf);
-            }
-        }
+        service.send_net_message(TargetSession::Single(dst), &msg_unit);
     }
 }
 
@@ -1210,3 +1867,61 @@ impl ModifiedConfigPeersReq {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Allowlist, NodesManager, PenalizeReq, TransformAddr, BAN_PENALTY, INVALID_MESSAGE_PENALTY,
+    };
+    use cita_types::Address;
+    use std::collections::BTreeSet;
+    use tentacle::SessionId;
+
+    #[test]
+    fn test_allowlist() {
+        let static_node = Address::from(1);
+        let chain_node = Address::from(2);
+        let mut allowlist = Allowlist::new(vec![static_node].into_iter().collect());
+        assert!(allowlist.contains(&static_node));
+        assert!(!allowlist.contains(&chain_node));
+
+        let nodes: BTreeSet<Address> = vec![chain_node].into_iter().collect();
+        assert!(allowlist.update_chain_nodes(nodes.clone()));
+        assert!(!allowlist.update_chain_nodes(nodes));
+        assert!(allowlist.contains(&static_node));
+        assert!(allowlist.contains(&chain_node));
+
+        // The node removed from chain is not allowed any more.
+        assert!(allowlist.update_chain_nodes(BTreeSet::new()));
+        assert!(!allowlist.contains(&chain_node));
+    }
+
+    #[test]
+    fn test_penalize_ban() {
+        let session_id = SessionId::from(1);
+        let peer_key = Address::from(1);
+        let addr = "127.0.0.2:4000".parse().unwrap();
+        let connect = |mgr: &mut NodesManager| {
+            mgr.connected_addrs
+                .insert(session_id, TransformAddr::new(addr, None));
+            mgr.connected_peer_keys.insert(peer_key, session_id);
+        };
+
+        // The claimed node address is not verified, the IP is banned.
+        let mut mgr = NodesManager::new(Address::from(9));
+        connect(&mut mgr);
+        PenalizeReq::new(session_id, INVALID_MESSAGE_PENALTY, "invalid message").handle(&mut mgr);
+        assert!(!mgr.is_ip_banned(&addr.ip()));
+        PenalizeReq::new(session_id, BAN_PENALTY, "invalid message").handle(&mut mgr);
+        assert!(mgr.is_ip_banned(&addr.ip()));
+        assert!(!mgr.is_banned(&peer_key));
+        assert!(mgr.unban_ip(&addr.ip()));
+
+        let mut mgr = NodesManager::new(Address::from(9));
+        mgr.set_verify_peer_key(true);
+        connect(&mut mgr);
+        PenalizeReq::new(session_id, BAN_PENALTY, "invalid message").handle(&mut mgr);
+        assert!(mgr.is_banned(&peer_key));
+        assert!(!mgr.is_ip_banned(&addr.ip()));
+    }
+}
@@ -14,8 +14,10 @@
 
 use crate::node_manager::{
     AddRepeatedNodeReq, ConnectedSelfReq, DelConnectedNodeReq, DialedErrorReq, NodesManagerClient,
-    PendingConnectedNodeReq,
+    PenalizeReq, PendingConnectedNodeReq, OVERSIZED_FRAME_PENALTY,
 };
+use crate::p2p_protocol::transfer::TRANSFER_PROTOCOL_ID;
+use crate::synchronizer::SynchronizerClient;
 use tentacle::{
     context::ServiceContext,
     error,
@@ -30,11 +32,15 @@ pub mod transfer;
 // This handle will be shared with all protocol
 pub struct SHandle {
     nodes_mgr_client: NodesManagerClient,
+    sync_client: SynchronizerClient,
 }
 
 impl SHandle {
-    pub fn new(nodes_mgr_client: NodesManagerClient) -> Self {
-        SHandle { nodes_mgr_client }
+    pub fn new(nodes_mgr_client: NodesManagerClient, sync_client: SynchronizerClient) -> Self {
+        SHandle {
+            nodes_mgr_client,
+            sync_client,
+        }
     }
 }
 
@@ -85,6 +91,11 @@ impl ServiceHandle for SHandle {
                     "[P2pProtocol] Protocol Error, stream id: {:?}, protocol id: {:?}, error: {:?}",
                     id, proto_id, error
                 );
+                // The codec of transfer refuses the frames over the limit.
+                if proto_id == TRANSFER_PROTOCOL_ID {
+                    let req = PenalizeReq::new(id, OVERSIZED_FRAME_PENALTY, "transfer codec error");
+                    self.nodes_mgr_client.penalize(req);
+                }
             }
             ServiceError::ProtocolSelectError {
                 proto_name,
@@ -104,6 +115,8 @@ impl ServiceHandle for SHandle {
                 );
                 let req = DelConnectedNodeReq::new(session_context.id);
                 self.nodes_mgr_client.del_connected_node(req);
+                self.sync_client
+                    .handle_peer_disconnected(session_context.id);
             }
 
             ServiceError::MuxerError {
@@ -153,6 +166,8 @@ impl ServiceHandle for SHandle {
             ServiceEvent::SessionClose { session_context } => {
                 let req = DelConnectedNodeReq::new(session_context.id);
                 self.nodes_mgr_client.del_connected_node(req);
+                self.sync_client
+                    .handle_peer_disconnected(session_context.id);
             }
             ServiceEvent::ListenClose { address } => {
                 warn!("ListenClose, address {:?}", address);
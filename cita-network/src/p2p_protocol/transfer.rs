@@ -12,17 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cita_protocol::network_message_to_pubsub_message;
+use crate::cita_protocol::{
+    network_message_to_pubsub_message, CITA_FRAME_HEADER_LEN, FLAG_ACCEPT_COMPRESSED,
+};
 use crate::network::{NetworkClient, RemoteMessage};
 use crate::node_manager::{
-    AddConnectedNodeReq, InitMsg, NetworkInitReq, NodesManagerClient, RetransNetMsgReq,
+    AddConnectedNodeReq, InitMsg, NetworkInitReq, NodesManagerClient, PenalizeReq,
+    RetransNetMsgReq, BAN_PENALTY, INVALID_MESSAGE_PENALTY, OVERSIZED_FRAME_PENALTY,
 };
 use bytes::BytesMut;
 use cita_types::Address;
+use crypto::{pubkey_to_address, Sign, Signature, SIGNATURE_BYTES_LEN};
+use hashable::Hashable;
 use libproto::{Message as ProtoMessage, TryFrom, TryInto};
 use tentacle::{
     builder::MetaBuilder,
     context::{ProtocolContext, ProtocolContextMutRef},
+    secio::PublicKey,
     service::{ProtocolHandle, ProtocolMeta},
     traits::ServiceProtocol,
     ProtocolId, SessionId,
@@ -32,8 +38,10 @@ use tokio::codec::length_delimited::LengthDelimitedCodec;
 // Quota (1 byte) = 200,
 // Max 20 block in one transfer.
 // 512M can support BQL set to 2 ** 32 - 1
-pub const MAX_FRAME_LENGTH: usize = 512 * 1024 * 1204;
+pub const MAX_FRAME_LENGTH: usize = 512 * 1024 * 1024;
 pub const TRANSFER_PROTOCOL_ID: ProtocolId = ProtocolId::new(1);
+// Chain id (u64) and peer key (Address), followed by an optional key proof (Signature)
+const INIT_MSG_LENGTH: usize = 8 + 20;
 
 struct TransferProtocol {
     proto_id: ProtocolId,
@@ -41,6 +49,9 @@ struct TransferProtocol {
     network_client: NetworkClient,
     nodes_mgr_client: NodesManagerClient,
     self_address: Address,
+    max_frame_size: usize,
+    // Check the address in the init message against the signature of the TLS key of the session
+    verify_peer_key: bool,
 }
 
 impl ServiceProtocol for TransferProtocol {
@@ -78,12 +89,56 @@ impl ServiceProtocol for TransferProtocol {
     }
 
     fn received(&mut self, env: ProtocolContextMutRef, data: bytes::Bytes) {
+        if data.len() > self.max_frame_size {
+            warn!(
+                "[Transfer] Receive oversized frame {} > {} from session {}!",
+                data.len(),
+                self.max_frame_size,
+                env.session.id
+            );
+            self.penalize(env.session.id, OVERSIZED_FRAME_PENALTY, "oversized frame");
+            return;
+        }
         let mut data = BytesMut::from(data);
 
-        if let Some(mut info) = network_message_to_pubsub_message(&mut data) {
+        // A compressed message is not larger than a frame when decompressed.
+        let max_data_len = self.max_frame_size.saturating_sub(CITA_FRAME_HEADER_LEN);
+        if let Some(mut info) = network_message_to_pubsub_message(&mut data, max_data_len) {
             if info.key.eq(&"network.init".to_string()) {
+                if info.data.len() != INIT_MSG_LENGTH
+                    && info.data.len() != INIT_MSG_LENGTH + SIGNATURE_BYTES_LEN
+                {
+                    warn!("[Transfer] Receive invalid init message!");
+                    self.penalize(
+                        env.session.id,
+                        INVALID_MESSAGE_PENALTY,
+                        "invalid init message",
+                    );
+                    return;
+                }
+                let accept_compressed = info.flags & FLAG_ACCEPT_COMPRESSED != 0;
                 let msg = InitMsg::from(info.data);
-                let req = AddConnectedNodeReq::new(env.session.id, env.session.ty, msg);
+                if self.verify_peer_key {
+                    let key_address = env
+                        .session
+                        .remote_pubkey
+                        .as_ref()
+                        .and_then(|pubkey| key_proof_address(pubkey, &msg.key_proof));
+                    if key_address != Some(msg.peer_key) {
+                        warn!(
+                            "[Transfer] Session {} claims address {:?}, but its key is signed by {:?}!",
+                            env.session.id, msg.peer_key, key_address
+                        );
+                        self.penalize(env.session.id, BAN_PENALTY, "forged init message");
+                        return;
+                    }
+                }
+                let req = AddConnectedNodeReq::new(
+                    env.session.id,
+                    env.session.ty,
+                    msg,
+                    accept_compressed,
+                );
                 self.nodes_mgr_client.add_connected_node(req);
                 return;
             }
@@ -94,7 +149,14 @@ impl ServiceProtocol for TransferProtocol {
             }
 
             let sid = env.session.id;
-            let mut msg = ProtoMessage::try_from(&info.data).unwrap();
+            let mut msg = match ProtoMessage::try_from(&info.data) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("[Transfer] Receive invalid message {}: {:?}", info.key, e);
+                    self.penalize(sid, INVALID_MESSAGE_PENALTY, "invalid message");
+                    return;
+                }
+            };
             msg.set_origin(sid.value() as u32);
             self.network_client
                 .handle_remote_message(RemoteMessage::new(
@@ -110,20 +172,42 @@ impl ServiceProtocol for TransferProtocol {
             }
         } else {
             warn!("[Transfer] Cannot convert network message to pubsub message!");
+            self.penalize(env.session.id, INVALID_MESSAGE_PENALTY, "malformed frame");
         }
     }
 }
 
+impl TransferProtocol {
+    fn penalize(&self, session_id: SessionId, penalty: i32, reason: &str) {
+        let req = PenalizeReq::new(session_id, penalty, reason);
+        self.nodes_mgr_client.penalize(req);
+    }
+}
+
+/// The node address which signed a TLS key, the signature is sent in the init message.
+fn key_proof_address(pubkey: &PublicKey, key_proof: &[u8]) -> Option<Address> {
+    if key_proof.len() != SIGNATURE_BYTES_LEN {
+        return None;
+    }
+    let signature = Signature::from(key_proof);
+    signature
+        .recover(&pubkey.inner_ref().crypt_hash())
+        .ok()
+        .map(|pubkey| pubkey_to_address(&pubkey))
+}
+
 pub fn create_transfer_meta(
     network_client: NetworkClient,
     nodes_mgr_client: NodesManagerClient,
     self_address: Address,
+    max_frame_size: usize,
+    verify_peer_key: bool,
 ) -> ProtocolMeta {
     MetaBuilder::default()
         .id(TRANSFER_PROTOCOL_ID)
-        .codec(|| {
+        .codec(move || {
             let mut lcodec = LengthDelimitedCodec::new();
-            lcodec.set_max_frame_length(MAX_FRAME_LENGTH);
+            lcodec.set_max_frame_length(max_frame_size);
             Box::new(lcodec)
         })
         .service_handle(move || {
@@ -133,6 +217,8 @@ pub fn create_transfer_meta(
                 network_client: network_client.clone(),
                 nodes_mgr_client: nodes_mgr_client.clone(),
                 self_address,
+                max_frame_size,
+                verify_peer_key,
             });
             ProtocolHandle::Callback(handle)
         })
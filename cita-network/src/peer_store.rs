@@ -0,0 +1,160 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Persisted peers and bans.
+//!
+//! The known peers, with their scores, and the banned node addresses are saved
+//! to a JSON file and loaded at startup, so a restarted node keeps the peers
+//! it has learned and does not redial the bad ones.
+
+use cita_types::Address;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_PEER_STORE: &str = "peers.json";
+// Score changes kept for each peer.
+pub const MAX_SCORE_HISTORY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreEvent {
+    pub time: u64,
+    pub delta: i32,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub addr: SocketAddr,
+    pub score: i32,
+    pub from_config: bool,
+    /// Seconds since the epoch, 0 for never connected.
+    pub last_seen: u64,
+    pub history: Vec<ScoreEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BanRecord {
+    pub address: Address,
+    /// Seconds since the epoch.
+    pub until: u64,
+    pub reason: String,
+}
+
+/// A ban of the sessions from an IP address, for the nodes whose addresses
+/// are not verified.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IpBanRecord {
+    pub ip: IpAddr,
+    /// Seconds since the epoch.
+    pub until: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerStoreData {
+    pub peers: Vec<PeerRecord>,
+    pub bans: Vec<BanRecord>,
+    #[serde(default)]
+    pub ip_bans: Vec<IpBanRecord>,
+}
+
+pub struct PeerStore {
+    path: PathBuf,
+}
+
+impl PeerStore {
+    pub fn new(path: &str) -> Self {
+        PeerStore {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Nothing is loaded if the file is missing or broken.
+    pub fn load(&self) -> PeerStoreData {
+        if !self.path.exists() {
+            return PeerStoreData::default();
+        }
+        File::open(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                warn!("[PeerStore] Load peers from {:?} failed: {}", self.path, e);
+                PeerStoreData::default()
+            })
+    }
+
+    pub fn save(&self, data: &PeerStoreData) {
+        // Write to a temporary file first, so a crash can not leave a broken store.
+        let tmp_path = self.path.with_extension("tmp");
+        let ret = File::create(&tmp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(BufWriter::new(f), data).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = ret {
+            warn!("[PeerStore] Save peers to {:?} failed: {}", self.path, e);
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BanRecord, IpBanRecord, PeerRecord, PeerStore, PeerStoreData, ScoreEvent};
+    use cita_types::Address;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let store = PeerStore::new(path.to_str().unwrap());
+        assert_eq!(store.load(), PeerStoreData::default());
+
+        let data = PeerStoreData {
+            peers: vec![PeerRecord {
+                addr: "127.0.0.1:4000".parse().unwrap(),
+                score: 80,
+                from_config: false,
+                last_seen: 1_500_000_000,
+                history: vec![ScoreEvent {
+                    time: 1_500_000_000,
+                    delta: -20,
+                    reason: "invalid message".to_owned(),
+                }],
+            }],
+            bans: vec![BanRecord {
+                address: Address::from(1),
+                until: 1_500_003_600,
+                reason: "manual".to_owned(),
+            }],
+            ip_bans: vec![IpBanRecord {
+                ip: "127.0.0.2".parse().unwrap(),
+                until: 1_500_003_600,
+                reason: "invalid message".to_owned(),
+            }],
+        };
+        store.save(&data);
+        assert_eq!(store.load(), data);
+    }
+}
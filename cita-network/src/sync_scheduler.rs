@@ -0,0 +1,316 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Plan the block download of the synchronizer.
+//!
+//! The missing heights are split into ranges of `SYNC_STEP` blocks, and the
+//! ranges are spread over all the peers which have announced those heights.
+//! A peer is chosen by its ranges in flight, then by its time outs, and then
+//! by its throughput. A range not answered in time is dropped, so it is
+//! requested again from another peer.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+pub const SYNC_STEP: u64 = 20;
+// Ranges in flight of one peer
+pub const MAX_RANGES_PER_PEER: usize = 2;
+pub const RANGE_TIME_OUT: Duration = Duration::from_secs(9);
+
+#[derive(Debug)]
+struct SyncRange {
+    end: u64,
+    origin: u32,
+    sent: Instant,
+}
+
+#[derive(Debug, Default)]
+struct PeerStats {
+    blocks: u64,
+    busy: Duration,
+    // Time outs since the last answer
+    time_outs: u32,
+}
+
+impl PeerStats {
+    // Blocks per second
+    fn throughput(&self) -> f64 {
+        let secs = self.busy.as_secs() as f64 + f64::from(self.busy.subsec_millis()) / 1000.0;
+        if secs > 0.0 {
+            self.blocks as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncScheduler {
+    // Latest height announced by each peer
+    peer_heights: BTreeMap<u32, u64>,
+    peer_stats: BTreeMap<u32, PeerStats>,
+    // Ranges requested but not answered, keyed by their start heights
+    ranges: BTreeMap<u64, SyncRange>,
+}
+
+impl SyncScheduler {
+    pub fn update_peer(&mut self, origin: u32, height: u64) {
+        let peer_height = self.peer_heights.entry(origin).or_insert(0);
+        if *peer_height < height {
+            *peer_height = height;
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.ranges.len()
+    }
+
+    fn is_in_flight(&self, height: u64) -> bool {
+        self.ranges
+            .range(..=height)
+            .next_back()
+            .map(|(_, range)| range.end >= height)
+            .unwrap_or(false)
+    }
+
+    fn load(&self, origin: u32) -> usize {
+        self.ranges
+            .values()
+            .filter(|range| range.origin == origin)
+            .count()
+    }
+
+    fn choose_peer(&self, height: u64) -> Option<(u32, u64)> {
+        self.peer_heights
+            .iter()
+            .filter(|(_, peer_height)| **peer_height >= height)
+            .map(|(origin, peer_height)| (*origin, *peer_height, self.load(*origin)))
+            .filter(|(_, _, load)| *load < MAX_RANGES_PER_PEER)
+            .min_by(|a, b| {
+                let stats_a = self.peer_stats.get(&a.0);
+                let stats_b = self.peer_stats.get(&b.0);
+                let time_outs = |stats: Option<&PeerStats>| stats.map(|s| s.time_outs).unwrap_or(0);
+                let throughput =
+                    |stats: Option<&PeerStats>| stats.map(|s| s.throughput()).unwrap_or(0.0);
+                a.2.cmp(&b.2)
+                    .then(time_outs(stats_a).cmp(&time_outs(stats_b)))
+                    .then(
+                        throughput(stats_b)
+                            .partial_cmp(&throughput(stats_a))
+                            .unwrap_or(std::cmp::Ordering::Equal),
+                    )
+            })
+            .map(|(origin, peer_height, _)| (origin, peer_height))
+    }
+
+    /// Plan the requests of the heights from `start` to `limit`, which are
+    /// neither received nor in flight.
+    pub fn schedule<F>(
+        &mut self,
+        start: u64,
+        limit: u64,
+        now: Instant,
+        is_received: F,
+    ) -> Vec<(Vec<u64>, u32)>
+    where
+        F: Fn(u64) -> bool,
+    {
+        let mut requests = Vec::new();
+        let mut height = start;
+        while height <= limit {
+            if is_received(height) || self.is_in_flight(height) {
+                height += 1;
+                continue;
+            }
+            let (origin, peer_height) = match self.choose_peer(height) {
+                Some(peer) => peer,
+                None => break,
+            };
+            let mut end = (height + SYNC_STEP - 1).min(limit).min(peer_height);
+            if let Some((next_start, _)) = self.ranges.range(height..).next() {
+                end = end.min(*next_start - 1);
+            }
+
+            let heights: Vec<u64> = (height..=end).filter(|h| !is_received(*h)).collect();
+            self.ranges.insert(
+                height,
+                SyncRange {
+                    end,
+                    origin,
+                    sent: now,
+                },
+            );
+            requests.push((heights, origin));
+            height = end + 1;
+        }
+        requests
+    }
+
+    /// The blocks of the heights are received from the peer.
+    pub fn received(&mut self, origin: u32, heights: &[u64], now: Instant) {
+        let done: Vec<u64> = self
+            .ranges
+            .iter()
+            .filter(|(start, range)| {
+                range.origin == origin && heights.iter().any(|h| *h >= **start && *h <= range.end)
+            })
+            .map(|(start, _)| *start)
+            .collect();
+        for start in done {
+            if let Some(range) = self.ranges.remove(&start) {
+                let stats = self
+                    .peer_stats
+                    .entry(origin)
+                    .or_insert_with(PeerStats::default);
+                stats.blocks += heights
+                    .iter()
+                    .filter(|h| **h >= start && **h <= range.end)
+                    .count() as u64;
+                stats.busy += now.duration_since(range.sent);
+                stats.time_outs = 0;
+            }
+        }
+    }
+
+    /// Drop the ranges not answered in time, returns whether any is dropped.
+    pub fn check_time_out(&mut self, now: Instant) -> bool {
+        let expired: Vec<u64> = self
+            .ranges
+            .iter()
+            .filter(|(_, range)| now.duration_since(range.sent) >= RANGE_TIME_OUT)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in &expired {
+            if let Some(range) = self.ranges.remove(start) {
+                warn!(
+                    "sync: range {}-{} of origin {} is timed out",
+                    start, range.end, range.origin
+                );
+                self.peer_stats
+                    .entry(range.origin)
+                    .or_insert_with(PeerStats::default)
+                    .time_outs += 1;
+            }
+        }
+        !expired.is_empty()
+    }
+
+    /// Forget a disconnected peer, its ranges in flight are requested again
+    /// from the other peers.
+    pub fn remove_peer(&mut self, origin: u32) {
+        self.peer_heights.remove(&origin);
+        self.peer_stats.remove(&origin);
+        let starts: Vec<u64> = self
+            .ranges
+            .iter()
+            .filter(|(_, range)| range.origin == origin)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in starts {
+            self.ranges.remove(&start);
+        }
+    }
+
+    /// Drop the ranges below the height, which are already in the chain.
+    pub fn prune(&mut self, height: u64) {
+        let starts: Vec<u64> = self
+            .ranges
+            .iter()
+            .filter(|(_, range)| range.end < height)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in starts {
+            self.ranges.remove(&start);
+        }
+    }
+
+    /// Drop all the ranges in flight to restart the download.
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyncScheduler, RANGE_TIME_OUT, SYNC_STEP};
+    use std::time::Instant;
+
+    #[test]
+    fn test_schedule() {
+        let mut scheduler = SyncScheduler::default();
+        let now = Instant::now();
+        scheduler.update_peer(1, 100);
+        scheduler.update_peer(2, 30);
+
+        let requests = scheduler.schedule(1, 100, now, |h| h == 5);
+        // Peer 2 only has the first 30 blocks, and peer 1 has two ranges at most.
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].1, 1);
+        assert_eq!(requests[0].0.len() as u64, SYNC_STEP - 1);
+        assert!(!requests[0].0.contains(&5));
+        assert_eq!(requests[1], ((21..=30).collect(), 2));
+        assert_eq!(requests[2], ((31..=50).collect(), 1));
+        assert_eq!(scheduler.in_flight(), 3);
+
+        // Nothing more before any answer.
+        assert!(scheduler.schedule(1, 100, now, |_| false).is_empty());
+
+        let (heights, origin) = requests[0].clone();
+        scheduler.received(origin, &heights, now);
+        assert_eq!(scheduler.in_flight(), 2);
+        assert_eq!(
+            scheduler.schedule(1, 100, now, |h| h <= 20),
+            vec![((51..=70).collect(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_retry_on_time_out() {
+        let mut scheduler = SyncScheduler::default();
+        let now = Instant::now();
+        scheduler.update_peer(1, 10);
+
+        let requests = scheduler.schedule(1, 10, now, |_| false);
+        assert_eq!(requests, vec![((1..=10).collect(), 1)]);
+        assert!(!scheduler.check_time_out(now));
+        assert!(scheduler.check_time_out(now + RANGE_TIME_OUT));
+
+        // A peer without time out is preferred.
+        scheduler.update_peer(2, 10);
+        let requests = scheduler.schedule(1, 10, now + RANGE_TIME_OUT, |_| false);
+        assert_eq!(requests, vec![((1..=10).collect(), 2)]);
+    }
+
+    #[test]
+    fn test_remove_peer() {
+        let mut scheduler = SyncScheduler::default();
+        let now = Instant::now();
+        scheduler.update_peer(1, 10);
+        scheduler.update_peer(2, 10);
+        assert_eq!(scheduler.schedule(1, 10, now, |_| false).len(), 1);
+
+        scheduler.remove_peer(1);
+        scheduler.remove_peer(2);
+        assert_eq!(scheduler.in_flight(), 0);
+        assert!(scheduler.peer_heights.is_empty());
+        assert!(scheduler.peer_stats.is_empty());
+        assert!(scheduler.schedule(1, 10, now, |_| false).is_empty());
+
+        scheduler.update_peer(3, 10);
+        assert_eq!(
+            scheduler.schedule(1, 10, now, |_| false),
+            vec![((1..=10).collect(), 3)]
+        );
+    }
+}
@@ -12,23 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::metrics;
 use crate::mq_agent::{MqAgentClient, PubMessage};
-use crate::node_manager::{BroadcastReq, NodesManagerClient, SingleTxReq};
+use crate::node_manager::{
+    BroadcastReq, NodesManagerClient, PenalizeReq, SingleTxReq, BAD_SYNC_RESPONSE_PENALTY,
+};
+use crate::sync_scheduler::{SyncScheduler, SYNC_STEP};
 use libproto::blockchain::{Block, Status};
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::routing_key;
-use libproto::{Message, OperateType, SyncRequest, SyncResponse};
+use libproto::{Message, MsgClass, OperateType, SyncRequest, SyncResponse};
 use libproto::{TryFrom, TryInto};
 use pubsub::channel::{unbounded, Receiver, Sender};
-use rand::{thread_rng, Rng, ThreadRng};
-use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::collections::BTreeMap;
 use std::convert::Into;
 use std::time::{Duration, Instant};
 use std::u8;
 use tentacle::SessionId;
 
-const SYNC_STEP: u64 = 20;
 const SYNC_TIME_OUT: u64 = 9;
+// Blocks requested ahead of the local chain
+const SYNC_WINDOW: u64 = SYNC_STEP * 10;
+// Interval to check the time out ranges
+const SYNC_TICK: Duration = Duration::from_secs(1);
+// A peer is disconnected, libproto has no message type for it.
+const PEER_DISCONNECTED: &str = "synchronizer.peer_disconnected";
 
 /// Get messages and determine if need to synchronize or broadcast the current node status
 pub struct Synchronizer {
@@ -38,9 +46,8 @@ pub struct Synchronizer {
     global_status: Status,
     sync_end_height: u64, //current_status <= sync_end_status
     is_synchronizing: bool,
-    latest_status_lists: BTreeMap<u64, VecDeque<u32>>,
+    scheduler: SyncScheduler,
     block_lists: BTreeMap<u64, Block>,
-    rand: ThreadRng,
     // Timer for each height processing
     remote_sync_time_out: Instant,
     /// local sync error
@@ -61,11 +68,10 @@ impl Synchronizer {
             nodes_mgr_client,
             current_status: Status::new(),
             global_status: Status::new(),
-            latest_status_lists: BTreeMap::new(),
             sync_end_height: 0,
             is_synchronizing: false,
+            scheduler: SyncScheduler::default(),
             block_lists: BTreeMap::new(),
-            rand: thread_rng(),
             remote_sync_time_out: (Instant::now() - Duration::from_secs(SYNC_TIME_OUT)),
             local_sync_count: 0,
             sync_client: client,
@@ -75,9 +81,10 @@ impl Synchronizer {
 
     pub fn run(&mut self) {
         loop {
-            if let Ok(msg) = self.msg_receiver.recv() {
+            if let Ok(msg) = self.msg_receiver.recv_timeout(SYNC_TICK) {
                 msg.handle(self);
             }
+            self.retry_time_out_ranges();
         }
     }
 
@@ -120,10 +127,9 @@ impl Synchronizer {
             self.remote_sync_time_out = Instant::now();
         }
 
-        self.latest_status_lists = self
-            .latest_status_lists
-            .split_off(&(latest_status.get_height() + 1));
+        self.scheduler.prune(latest_status.get_height() + 1);
         self.current_status = latest_status;
+        self.update_height_metrics();
         self.broadcast_status();
         self.prune_block_list_cache(new_height + 1);
 
@@ -142,6 +148,7 @@ impl Synchronizer {
             if self.block_lists.contains_key(&start_height) && !self.block_lists.is_empty() {
                 self.submit_blocks();
             } else {
+                self.scheduler.clear();
                 self.start_sync_req(start_height);
             }
         } else if new_height < self.sync_end_height {
@@ -160,6 +167,7 @@ impl Synchronizer {
                 // send cache to executor and chain, and clear cache
                 self.local_sync_count = 0;
                 self.block_lists.clear();
+                self.scheduler.clear();
                 self.start_sync_req(new_height + 1);
                 info!("More than 3 times, clear the cache");
             }
@@ -175,11 +183,11 @@ impl Synchronizer {
             // If the block height is equal to the maximum height that has already been synchronized,
             // perform the synchronization operation first to see if it is the latest in the chain
             if self.is_synchronizing {
-                let start = match self.block_lists.iter().last() {
-                    Some((height, _)) => *height + 1,
-                    None => new_height + 1,
-                };
-                self.start_sync_req(start);
+                // The blocks downloaded out of order are waiting for this height.
+                if self.block_lists.contains_key(&(new_height + 1)) {
+                    self.submit_blocks();
+                }
+                self.start_sync_req(new_height + 1);
             }
         } else {
             info!("...Can't reach this");
@@ -200,14 +208,14 @@ impl Synchronizer {
         let current_height = self.current_status.get_height();
         if self.global_status.get_height() < status.get_height() {
             self.global_status = status.clone();
+            self.update_height_metrics();
         }
+        self.scheduler.update_peer(origin, status.get_height());
 
         if status.get_height() < current_height + 1 {
             // The current node is the latest height and does not need to be synchronized
         } else if status.get_height() == current_height + 1 {
             // A node on the chain blocks out, synchronizing the latest block
-            self.add_latest_sync_lists(status.get_height(), origin);
-
             if self.remote_sync_time_out.elapsed().as_secs() > SYNC_TIME_OUT
                 && !self.is_synchronizing
             {
@@ -215,8 +223,6 @@ impl Synchronizer {
             }
         } else {
             // The node is far behind the data on the chain and initiates a synchronization request
-            self.add_latest_sync_lists(status.get_height(), origin);
-
             if self.remote_sync_time_out.elapsed().as_secs() > SYNC_TIME_OUT
                 || !self.is_synchronizing
             {
@@ -229,7 +235,7 @@ impl Synchronizer {
         self.is_synchronizing
     }
 
-    pub fn process_sync(&mut self, mut blocks: SyncResponse) {
+    pub fn process_sync(&mut self, mut blocks: SyncResponse, origin: u32) {
         let blocks = blocks.take_blocks();
         debug!("sync: process_sync: blocks len = {}", blocks.len());
 
@@ -241,74 +247,67 @@ impl Synchronizer {
         }
 
         debug!("sync: process_sync: heights = {:?}", heights);
-        self.submit_blocks();
-    }
+        self.scheduler.received(origin, &heights, Instant::now());
 
-    // Initiate a sync request
-    fn start_sync_req(&mut self, start_height: u64) {
-        debug!(
-            "sync: start_sync_req: start_height = {}, current height = {}",
-            start_height,
-            self.current_status.get_height()
-        );
-        let mut origin = 0;
-        let mut end_height = start_height;
-        let mut is_send = false;
-        let current_height = self.current_status.get_height();
-
-        if let Some((height, origins)) = self
-            .latest_status_lists
-            .iter()
-            .rfind(|&(_, origins)| !origins.is_empty())
-        {
-            debug!(
-                "sync: start_sync_req: height = {}, origins = {:?}",
-                height, origins
-            );
-            if let Some(origins) = self.latest_status_lists.get(height) {
-                if *height > current_height {
-                    origin = origins[self.rand.gen_range(0, origins.len())];
-                    end_height = current_height + SYNC_STEP;
-                    is_send = true;
-                }
-            }
+        // Only the blocks following the chain can be submitted, the others
+        // are kept until the chain reaches them.
+        let next_height = self.current_status.get_height() + 1;
+        if heights.contains(&next_height) || !self.is_synchronizing {
+            self.submit_blocks();
         }
+        self.start_sync_req(next_height);
+    }
 
-        if is_send {
-            self.sync_strategy(start_height, end_height, origin);
+    // Request the ranges of the timed out requests again from other peers.
+    fn retry_time_out_ranges(&mut self) {
+        if self.scheduler.check_time_out(Instant::now()) {
+            self.start_sync_req(self.current_status.get_height() + 1);
         }
     }
 
-    fn sync_strategy(&self, start_height: u64, end_height: u64, origin: u32) {
-        //current height = 155,start_height = 156, end height = 160, to origin = 1
+    // The ranges in flight of the peer are requested from the others.
+    fn peer_disconnected(&mut self, origin: u32) {
+        debug!("sync: origin {} is disconnected", origin);
+        self.scheduler.remove_peer(origin);
+        self.start_sync_req(self.current_status.get_height() + 1);
+    }
+
+    // A peer which sends a bad sync response wastes a round of synchronization.
+    fn penalize(&self, origin: u32, reason: &str) {
+        warn!("sync: bad sync response from origin {}: {}", origin, reason);
+        self.nodes_mgr_client.penalize(PenalizeReq::new(
+            SessionId::from(origin as usize),
+            BAD_SYNC_RESPONSE_PENALTY,
+            reason,
+        ));
+    }
+
+    // Initiate the sync requests of the missing heights from start_height,
+    // spread over the peers at the tip
+    fn start_sync_req(&mut self, start_height: u64) {
+        let current_height = self.current_status.get_height();
+        let limit = self
+            .global_status
+            .get_height()
+            .min(current_height + SYNC_WINDOW);
         debug!(
-            "sync: sync_strategy: current height = {}, \
-             start_height = {}, \
-             end height = {}, \
-             to origin = {}",
-            self.current_status.get_height(),
-            start_height,
-            end_height,
-            origin
+            "sync: start_sync_req: start_height = {}, current height = {}, limit = {}",
+            start_height, current_height, limit
         );
-        if start_height >= self.current_status.get_height() && start_height <= end_height {
-            let mut start_height = start_height;
-            let mut step_sum = SYNC_STEP;
-            let mut heights = vec![];
-
-            while start_height <= end_height {
-                if step_sum == 0 {
-                    step_sum = SYNC_STEP;
-                    self.send_sync_req(heights, origin);
-                    heights = vec![];
-                }
-                heights.push(start_height);
+        if start_height <= current_height || start_height > limit {
+            return;
+        }
 
-                step_sum -= 1;
-                start_height += 1;
-            }
+        let block_lists = &self.block_lists;
+        let requests = self
+            .scheduler
+            .schedule(start_height, limit, Instant::now(), |height| {
+                block_lists.contains_key(&height)
+            });
+        for (heights, origin) in requests {
             self.send_sync_req(heights, origin);
         }
+        metrics::SYNC_RANGES_IN_FLIGHT.set(self.scheduler.in_flight() as i64);
     }
 
     fn send_sync_req(&self, heights: Vec<u64>, origin: u32) {
@@ -335,6 +334,14 @@ impl Synchronizer {
         }
     }
 
+    fn update_height_metrics(&self) {
+        let current_height = self.current_status.get_height();
+        let global_height = self.global_status.get_height();
+        metrics::CURRENT_HEIGHT.set(current_height as i64);
+        metrics::GLOBAL_HEIGHT.set(global_height as i64);
+        metrics::SYNC_LAG.set(global_height.saturating_sub(current_height) as i64);
+    }
+
     fn broadcast_status(&mut self) {
         debug!(
             "sync: broadcast status {:?}, {:?} to other nodes",
@@ -417,33 +424,6 @@ impl Synchronizer {
         }
     }
 
-    fn add_latest_sync_lists(&mut self, height: u64, origin: u32) {
-        debug!(
-            "sync: add_sync_lists: current height = {}, \
-             from node = {}, \
-             height = {}",
-            self.current_status.get_height(),
-            origin,
-            height
-        );
-        let insert_is_ok = self
-            .latest_status_lists
-            .entry(height)
-            .or_insert_with(VecDeque::new)
-            .iter()
-            .fold(HashSet::new(), |mut set, item| {
-                set.insert(item);
-                set
-            })
-            .insert(&origin);
-        if insert_is_ok {
-            self.latest_status_lists
-                .entry(height)
-                .or_insert_with(VecDeque::new)
-                .push_back(origin);
-        }
-    }
-
     /// Prune block on btreemap
     fn prune_block_list_cache(&mut self, height: u64) {
         self.block_lists = self.block_lists.split_off(&height);
@@ -472,6 +452,18 @@ impl SynchronizerClient {
         self.send_msg(msg);
     }
 
+    pub fn handle_peer_disconnected(&self, session_id: SessionId) {
+        let msg = Message::init(
+            OperateType::Single,
+            session_id.value() as u32,
+            MsgClass::RawBytes(Vec::new()),
+        );
+        self.send_msg(SynchronizerMessage::new(
+            PEER_DISCONNECTED.to_owned(),
+            msg.try_into().unwrap(),
+        ));
+    }
+
     fn send_msg(&self, msg: SynchronizerMessage) {
         match self.sender.try_send(msg) {
             Ok(_) => {
@@ -497,6 +489,11 @@ impl SynchronizerMessage {
     pub fn handle(self, service: &mut Synchronizer) {
         let mut msg = Message::try_from(&self.data).unwrap();
         let origin = msg.get_origin();
+        if self.key == PEER_DISCONNECTED {
+            service.peer_disconnected(origin);
+            return;
+        }
+
         let rt_key = RoutingKey::from(&self.key);
         match rt_key {
             routing_key!(Chain >> Status) => {
@@ -509,11 +506,13 @@ impl SynchronizerMessage {
                     service.update_global_status(&status, origin);
                 };
             }
-            routing_key!(Synchronizer >> SyncResponse) => {
-                if let Some(blocks) = msg.take_sync_response() {
-                    service.process_sync(blocks);
-                };
-            }
+            routing_key!(Synchronizer >> SyncResponse) => match msg.take_sync_response() {
+                Some(ref blocks) if blocks.get_blocks().is_empty() => {
+                    service.penalize(origin, "empty sync response");
+                }
+                Some(blocks) => service.process_sync(blocks, origin),
+                None => service.penalize(origin, "invalid sync response"),
+            },
             _ => {
                 error!("receive: unexpected data key = {:?}", self.key);
             }
@@ -0,0 +1,167 @@
+pragma solidity 0.4.24;
+
+import "./CrossChain.sol";
+
+/// @title Versioned cross-chain messages
+/// @notice Messages may go to any chain whose nodes the ChainManager of the
+/// destination knows: the parent chain, an enabled side chain or a peer
+/// chain set by `setPeerChain`, so sibling side chains talk directly.
+/// Several may be sent in a transaction, with acks and timeouts.
+/// It is a separate base, so the storage of the contracts inheriting
+/// `CrossChain` is not changed.
+contract CrossChainMessenger is CrossChain {
+
+    // Versioned message, see `cita-chain/types/src/cross_chain.rs`.
+    // The nonce counts the messages from this contract to `destContract`.
+    event CrossChainMessage(
+        uint8 version,
+        uint fromChainId,
+        uint toChainId,
+        address destContract,
+        uint64 nonce,
+        uint64 timeoutHeight,
+        uint8 kind,
+        bytes payload
+    );
+    event RecvCrossChainMessage(
+        uint indexed fromChainId,
+        address indexed sender,
+        uint64 nonce,
+        bool timedOut
+    );
+
+    uint8 constant MESSAGE_VERSION = 1;
+    uint8 constant MESSAGE_DATA = 0;
+    // The payload of an ack is `abi.encode(uint64 nonce, bool success, bytes result)`.
+    uint8 constant MESSAGE_ACK = 1;
+
+    // toChainId => destContract => nonce
+    mapping(uint => mapping(address => uint64)) messageSendNonces;
+    // fromChainId => sender => nonce
+    mapping(uint => mapping(address => uint64)) messageRecvNonces;
+
+    function getMessageSendNonce(uint toChainId, address destContract)
+        public
+        view
+        returns (uint64)
+    {
+        return messageSendNonces[toChainId][destContract];
+    }
+
+    // The relayer relays the message with this nonce next.
+    function getMessageRecvNonce(uint fromChainId, address sender)
+        public
+        view
+        returns (uint64)
+    {
+        return messageRecvNonces[fromChainId][sender];
+    }
+
+    // The message should be received before `timeoutHeight` of the
+    // destination chain, 0 for never.
+    function sendMessage(
+        uint toChainId,
+        address destContract,
+        uint64 timeoutHeight,
+        uint8 kind,
+        bytes memory payload
+    ) internal {
+        uint64 nonce = messageSendNonces[toChainId][destContract];
+        emit CrossChainMessage(
+            MESSAGE_VERSION,
+            getFromChainId(),
+            toChainId,
+            destContract,
+            nonce,
+            timeoutHeight,
+            kind,
+            payload
+        );
+        messageSendNonces[toChainId][destContract] = nonce + 1;
+    }
+
+    // The relayer calls this with the proof of the next message from
+    // `sender` of chain `fromChainId`. It should call `verifyMessage`.
+    function recvCrossChainMessage(
+        uint fromChainId,
+        address sender,
+        bytes txProof
+    ) public;
+
+    // Verify the next message from `sender` of chain `fromChainId` in the
+    // proof. A timed out message is received too, with `timedOut` set, so
+    // the channel goes on, and a timed out data message is acked to the
+    // source here with `success` false. The contract should not ack it again.
+    function verifyMessage(
+        uint fromChainId,
+        address sender,
+        bytes memory txProof,
+        uint256 payloadSize
+    ) internal returns (
+        bool timedOut,
+        uint8 kind,
+        bytes memory payload
+    ) {
+        address contractAddr = crossChainVerifyAddr;
+        bytes4 nativeFunc = bytes4(
+            keccak256("verifyMessage(uint256,address,uint64,bytes)")
+        );
+        uint64 recvNonce = messageRecvNonces[fromChainId][sender];
+        // bytes len + bytes
+        uint txProofSize = 0x20 + txProof.length / 0x20 * 0x20;
+        if (txProof.length % 0x20 != 0) {
+            txProofSize += 0x20;
+        }
+        // bool + uint8 + bytes pos + bytes len + bytes
+        uint outSize = 0x80 + payloadSize / 0x20 * 0x20;
+        if (payloadSize % 0x20 != 0) {
+            outSize += 0x20;
+        }
+        // solium-disable-next-line security/no-inline-assembly
+        assembly {
+            let ptr := mload(0x40)
+            mstore(ptr, nativeFunc)
+            mstore(add(ptr, 0x04), fromChainId)
+            mstore(add(ptr, 0x24), sender)
+            mstore(add(ptr, 0x44), recvNonce)
+            mstore(add(ptr, 0x64), 0x80)
+            // copy txproof bytes
+            let ptrL := add(ptr, 0x84)
+            for {
+                    let txProofL := txProof
+                    let txProofR := add(txProof, txProofSize)
+                }
+                lt(txProofL, txProofR)
+                {
+                    txProofL := add(txProofL, 0x20)
+                    ptrL := add(ptrL, 0x20)
+                }
+                {
+                mstore(ptrL, mload(txProofL))
+            }
+            let inSize := sub(ptrL, ptr)
+            switch call(100000, contractAddr, 0, ptr, inSize, ptr, outSize)
+            case 0 { revert(0, 0) }
+            default {
+                // The output is trusted only if it fits the buffer.
+                if lt(returndatasize, 0x80) { revert(0, 0) }
+                if gt(mload(add(ptr, 0x60)), payloadSize) { revert(0, 0) }
+                timedOut := mload(ptr)
+                kind := mload(add(ptr, 0x20))
+                payload := add(ptr, 0x60)
+                mstore(0x40, add(ptr, outSize))
+            }
+        }
+        emit RecvCrossChainMessage(fromChainId, sender, recvNonce, timedOut);
+        messageRecvNonces[fromChainId][sender] = recvNonce + 1;
+        if (timedOut && kind == MESSAGE_DATA) {
+            sendMessage(
+                fromChainId,
+                sender,
+                0,
+                MESSAGE_ACK,
+                abi.encode(recvNonce, false, new bytes(0))
+            );
+        }
+    }
+}
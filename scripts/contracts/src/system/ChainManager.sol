@@ -18,6 +18,10 @@ contract ChainManager is Error {
     // Stores `ChainInfo` struct for each chain.
     mapping(uint => ChainInfo) public sideChains;
 
+    // Nodes of the chains which are neither the parent chain nor a side
+    // chain, as the sibling side chains, whose messages are received directly.
+    mapping(uint => address[]) peerChainNodes;
+
     // Default: Unknown
     enum ChainStatus { Unknown, Disable, Enable }
 
@@ -92,11 +96,36 @@ contract ChainManager is Error {
             sideChains[sideChainId].status == ChainStatus.Unknown,
             "ChainStatus not the same witch sideChainStatus."
         );
+        require(
+            peerChainNodes[sideChainId].length == 0,
+            "A peer chain should not be a side chain."
+        );
         sideChains[sideChainId] = ChainInfo(ChainStatus.Disable, addrs);
         // TODO A sorted array can search data more fast.
         //      And we can remove duplicated data, simply.
     }
 
+    // @notice Register the nodes of a peer chain.
+    function setPeerChain(uint peerChainId, address[] addrs)
+        public
+    {
+        require(addrs.length > 0, "The length should larger than zero.");
+        uint myChainId = getChainId();
+        require(
+            myChainId != peerChainId,
+            "ChainId should not equal to peerChainId."
+        );
+        require(
+            parentChainId != peerChainId,
+            "The parent chain should not be a peer chain."
+        );
+        require(
+            sideChains[peerChainId].status == ChainStatus.Unknown,
+            "A side chain should not be a peer chain."
+        );
+        peerChainNodes[peerChainId] = addrs;
+    }
+
     function enableSideChain(uint id)
         public
         hasSideChain(id)
@@ -247,6 +276,9 @@ contract ChainManager is Error {
         // Is it a enabled side chain?
         } else if (sideChains[id].status == ChainStatus.Enable) {
             return sideChains[id].nodes;
+        // Is it a peer chain?
+        } else if (peerChainNodes[id].length > 0) {
+            return peerChainNodes[id];
         } else {
             // Returns an empty array;
             return ;
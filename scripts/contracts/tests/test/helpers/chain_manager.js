@@ -34,6 +34,15 @@ const disableSideChain = async (id, _sender = superAdmin) => {
   return contract.methods.disableSideChain(id).send(param);
 };
 
+// setPeerChain
+const setPeerChain = async (id, address, _sender = superAdmin) => {
+  param = await genTxParams(_sender);
+  return contract.methods.setPeerChain(
+    id,
+    address,
+  ).send(param);
+};
+
 // getChainId
 const getChainId = () => contract.methods.getChainId().call('pending');
 
@@ -47,6 +56,7 @@ module.exports = {
   newSideChain,
   enableSideChain,
   disableSideChain,
+  setPeerChain,
   getChainId,
   getParentChainId,
   getAuthorities,
@@ -0,0 +1,144 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The block file.
+//!
+//! A block file starts with `MAGIC`, followed by the blocks in height order.
+//! Each block is a protobuf `BlockWithProof`, prefixed by its length as a
+//! big endian u32.
+
+use libproto::{BlockWithProof, TryFrom, TryInto};
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 8] = b"CITABLK1";
+// A block larger than this is taken as a broken file.
+const MAX_RECORD_LENGTH: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum BlockFileError {
+    Io(io::Error),
+    BadMagic,
+    Decode(String),
+}
+
+impl From<io::Error> for BlockFileError {
+    fn from(err: io::Error) -> Self {
+        BlockFileError::Io(err)
+    }
+}
+
+pub struct BlockFileWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> BlockFileWriter<W> {
+    pub fn new(mut inner: W) -> Result<Self, BlockFileError> {
+        inner.write_all(MAGIC)?;
+        Ok(BlockFileWriter { inner })
+    }
+
+    pub fn write(&mut self, block: BlockWithProof) -> Result<(), BlockFileError> {
+        let bytes: Vec<u8> = block
+            .try_into()
+            .map_err(|e| BlockFileError::Decode(format!("{:?}", e)))?;
+        self.inner.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, BlockFileError> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+pub struct BlockFileReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> BlockFileReader<R> {
+    pub fn new(mut inner: R) -> Result<Self, BlockFileError> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(BlockFileError::BadMagic);
+        }
+        Ok(BlockFileReader { inner })
+    }
+
+    /// The next block, `None` at the end of the file.
+    pub fn read(&mut self) -> Result<Option<BlockWithProof>, BlockFileError> {
+        let mut len = [0u8; 4];
+        // A clean end of file is only allowed between the blocks.
+        match self.inner.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => self.inner.read_exact(&mut len[1..])?,
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_RECORD_LENGTH {
+            return Err(BlockFileError::Decode(format!(
+                "block of {} bytes is too large",
+                len
+            )));
+        }
+        let mut bytes = vec![0u8; len];
+        self.inner.read_exact(&mut bytes)?;
+        BlockWithProof::try_from(&bytes[..])
+            .map(Some)
+            .map_err(|e| BlockFileError::Decode(format!("{:?}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockFileError, BlockFileReader, BlockFileWriter};
+    use libproto::BlockWithProof;
+    use std::io::Cursor;
+
+    fn block(height: u64) -> BlockWithProof {
+        let mut block = BlockWithProof::new();
+        block.mut_blk().mut_header().set_height(height);
+        block.mut_blk().mut_header().set_timestamp(height * 3000);
+        block
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut writer = BlockFileWriter::new(Vec::new()).unwrap();
+        for height in 1..=3 {
+            writer.write(block(height)).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = BlockFileReader::new(Cursor::new(bytes.clone())).unwrap();
+        for height in 1..=3 {
+            assert_eq!(reader.read().unwrap(), Some(block(height)));
+        }
+        assert_eq!(reader.read().unwrap(), None);
+
+        // A truncated block is an error rather than the end of the file.
+        let mut reader = BlockFileReader::new(Cursor::new(&bytes[..bytes.len() - 1])).unwrap();
+        reader.read().unwrap();
+        reader.read().unwrap();
+        match reader.read() {
+            Err(BlockFileError::Io(_)) => {}
+            ret => panic!("unexpected result {:?}", ret),
+        }
+
+        match BlockFileReader::new(Cursor::new(b"NOTBLOCK".to_vec())) {
+            Err(BlockFileError::BadMagic) => {}
+            _ => panic!("bad magic is accepted"),
+        }
+    }
+}
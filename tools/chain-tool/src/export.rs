@@ -0,0 +1,66 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::block_file::BlockFileWriter;
+use chain_core::libchain::chain::Chain;
+use libproto::BlockWithProof;
+use std::io::Write;
+
+/// Export the blocks from `from` to `to` with their proofs, returns the
+/// number of blocks exported.
+///
+/// The genesis block is never exported, the importer builds it from the
+/// genesis file.
+pub fn export<W: Write>(
+    chain: &Chain,
+    from: u64,
+    to: Option<u64>,
+    writer: &mut BlockFileWriter<W>,
+) -> Result<u64, String> {
+    let current_height = chain.get_current_height();
+    let from = from.max(1);
+    let to = to.unwrap_or(current_height);
+    if to > current_height {
+        return Err(format!(
+            "height {} is above the current height {}",
+            to, current_height
+        ));
+    }
+
+    let mut count = 0;
+    for height in from..=to {
+        let block = chain
+            .block_by_height(height)
+            .ok_or_else(|| format!("block {} is missing or pruned", height))?;
+        // The proof of a block is in the next header, except the latest one.
+        let proof = if height == current_height {
+            chain.current_block_poof()
+        } else {
+            chain.get_block_proof_by_height(height)
+        }
+        .ok_or_else(|| format!("proof of block {} is missing", height))?;
+
+        let mut proof_blk = BlockWithProof::new();
+        proof_blk.set_blk(block.protobuf());
+        proof_blk.set_proof(proof);
+        writer
+            .write(proof_blk)
+            .map_err(|e| format!("write block {} error: {:?}", height, e))?;
+        count += 1;
+        if count % 1000 == 0 {
+            info!("exported {} blocks, at height {}", count, height);
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,313 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::block_file::BlockFileReader;
+use chain_core::libchain::chain::Chain;
+use cita_types::{Address, H256};
+use core_executor::libexecutor::command::Commander;
+use core_executor::libexecutor::executor::Executor;
+use core_executor::libexecutor::fsm::FSM;
+use hashable::Hashable;
+use libproto::{BlockWithProof, Proof};
+use proof::BftProof;
+use std::io::Read;
+use types::block::OpenBlock;
+use types::header::Header;
+
+/// Execute the blocks of a block file, and save them to the chain as the
+/// chain service does.
+pub struct Importer {
+    executor: Executor,
+    chain: Chain,
+}
+
+impl Importer {
+    pub fn new(executor: Executor, chain: Chain) -> Result<Self, String> {
+        // A new chain has not even the genesis block.
+        if chain.get_current_height() == 0 && chain.block_hash_by_height(0).is_none() {
+            let executed_result = executor.executed_result_by_height(0);
+            chain.set_db_result(&executed_result, &OpenBlock::default());
+        }
+        if chain.get_current_height() != executor.get_current_height() {
+            return Err(format!(
+                "chain is at height {}, but executor is at height {}",
+                chain.get_current_height(),
+                executor.get_current_height()
+            ));
+        }
+        Ok(Importer { executor, chain })
+    }
+
+    /// Import all the blocks of the file, returns the number of blocks
+    /// imported. The blocks already in the chain are skipped.
+    pub fn import<R: Read>(&mut self, reader: &mut BlockFileReader<R>) -> Result<u64, String> {
+        let mut count = 0;
+        while let Some(proof_blk) = reader
+            .read()
+            .map_err(|e| format!("read block file error: {:?}", e))?
+        {
+            if self.import_block(proof_blk)? {
+                count += 1;
+                if count % 1000 == 0 {
+                    info!(
+                        "imported {} blocks, at height {}",
+                        count,
+                        self.executor.get_current_height()
+                    );
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn import_block(&mut self, mut proof_blk: BlockWithProof) -> Result<bool, String> {
+        let proto_block = proof_blk.take_blk();
+        let proof = proof_blk.take_proof();
+        let height = proto_block.get_header().get_height();
+        let current_height = self.executor.get_current_height();
+        if height <= current_height {
+            return Ok(false);
+        }
+        if height != current_height + 1 {
+            return Err(format!(
+                "block {} is not next to the current height {}",
+                height, current_height
+            ));
+        }
+        if !proto_block.check_hash() {
+            return Err(format!("transactions root of block {} is wrong", height));
+        }
+
+        let state_root = H256::from_slice(proto_block.get_header().get_state_root());
+        let receipts_root = H256::from_slice(proto_block.get_header().get_receipts_root());
+        let block = OpenBlock::from(proto_block);
+        if *block.parent_hash() != self.executor.get_current_hash() {
+            return Err(format!("parent hash of block {} is wrong", height));
+        }
+        self.verify_proof(&block, &proof)?;
+
+        let closed_block = self.executor.into_fsm(block.clone());
+        if *closed_block.state_root() != state_root {
+            return Err(format!(
+                "state root of block {} is {:?} after execution, but {:?} in the file",
+                height,
+                closed_block.state_root(),
+                state_root
+            ));
+        }
+        if *closed_block.receipts_root() != receipts_root {
+            return Err(format!(
+                "receipts root of block {} is {:?} after execution, but {:?} in the file",
+                height,
+                closed_block.receipts_root(),
+                receipts_root
+            ));
+        }
+        let executed_result = self.executor.grow(&closed_block);
+
+        self.chain.set_block_body(height, &block);
+        self.chain.set_db_result(&executed_result, &block);
+        self.chain.set_max_store_height(height);
+        self.chain.save_current_block_poof(&proof);
+        Ok(true)
+    }
+
+    // The block is signed by the validators of its parent.
+    fn verify_proof(&self, block: &OpenBlock, proof: &Proof) -> Result<(), String> {
+        let height = block.number();
+        let bft_proof = BftProof::from(proof.clone());
+        if bft_proof.height as u64 != height {
+            return Err(format!(
+                "proof of block {} is for height {}",
+                height, bft_proof.height
+            ));
+        }
+        let proposal = Header::new(block.header.clone())
+            .proposal_protobuf()
+            .crypt_hash();
+        if proposal != bft_proof.proposal {
+            return Err(format!("proof of block {} is for another proposal", height));
+        }
+        let validators: Vec<Address> = self
+            .executor
+            .executed_result_by_height(height - 1)
+            .get_config()
+            .get_validators()
+            .iter()
+            .map(|v| Address::from_slice(&v[..]))
+            .collect();
+        if !bft_proof.check(height as usize, &validators) {
+            return Err(format!("signatures of block {} are invalid", height));
+        }
+        Ok(())
+    }
+
+    pub fn current_height(&self) -> u64 {
+        self.executor.get_current_height()
+    }
+
+    pub fn close(mut self) {
+        self.executor.close();
+    }
+}
+
+// The keys are of the genesis built by scripts/config_tool.
+#[cfg(all(test, feature = "secp256k1"))]
+mod tests {
+    use super::Importer;
+    use crate::block_file::{BlockFileReader, BlockFileWriter};
+    use crate::export::export;
+    use crate::STATEDB_CACHE_SIZE;
+    use chain_core::libchain::chain::{Chain, Config as ChainConfig};
+    use cita_crypto::{PrivKey, Sign, Signature, Signer};
+    use cita_database::{Config as DatabaseConfig, RocksDB, NUM_COLUMNS};
+    use cita_types::H256;
+    use core_executor::journaldb::JournalDBType;
+    use core_executor::libexecutor::executor::Executor;
+    use hashable::Hashable;
+    use libproto::Proof;
+    use proof::BftProof;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use types::block::OpenBlock;
+    use types::header::Header;
+
+    const GENESIS: &str = "../../scripts/config_tool/genesis/genesis.json";
+    // The only validator in the genesis.
+    const VALIDATOR_KEY: &str = "5f0258a4778057a8a7d97809bd209055b2fbafa654ce7d31ec7191066b9225e6";
+    const OTHER_KEY: &str = "ef98e68db428906d626cd37782cdfb052ac282132beee53a99948738ea553b4a";
+
+    fn generate_importer(dir: &TempDir) -> Importer {
+        let (_fsm_req_sender, fsm_req_receiver) = crossbeam_channel::unbounded();
+        let (fsm_resp_sender, _fsm_resp_receiver) = crossbeam_channel::unbounded();
+        let (_command_req_sender, command_req_receiver) = crossbeam_channel::unbounded();
+        let (command_resp_sender, _command_resp_receiver) = crossbeam_channel::unbounded();
+        let executor = Executor::init(
+            GENESIS,
+            dir.path().to_str().unwrap().to_owned(),
+            fsm_req_receiver,
+            fsm_resp_sender,
+            command_req_receiver,
+            command_resp_sender,
+            false,
+            JournalDBType::Archive,
+            STATEDB_CACHE_SIZE,
+        );
+        let db_config = DatabaseConfig::with_category_num(NUM_COLUMNS);
+        let db = RocksDB::open(dir.path().join("chain").to_str().unwrap(), &db_config).unwrap();
+        let chain = Chain::init_chain(Arc::new(db), ChainConfig::default());
+        Importer::new(executor, chain).unwrap()
+    }
+
+    fn generate_proof(key: &str, height: u64, proposal: H256) -> Proof {
+        let privkey = PrivKey::from_str(key).unwrap();
+        let signer = Signer::from(privkey);
+        let serialized = bincode::serialize(
+            &(height as usize, 0usize, 0usize, signer.address, proposal),
+            bincode::Infinite,
+        )
+        .unwrap();
+        let signature = Signature::sign(&privkey, &serialized.crypt_hash()).unwrap();
+        let mut commits = HashMap::new();
+        commits.insert(signer.address, signature);
+        BftProof::new(height as usize, 0, proposal, commits).into()
+    }
+
+    // Execute and save the next block signed by the key, as the chain and
+    // executor services do, returns its proof.
+    fn grow(importer: &mut Importer, key: &str, parent_proof: Proof) -> Proof {
+        let height = importer.current_height() + 1;
+        let mut proto_block = libproto::Block::new();
+        let transactions_root = proto_block.get_body().transactions_root().to_vec();
+        let header = proto_block.mut_header();
+        header.set_transactions_root(transactions_root);
+        header.set_prevhash(importer.executor.get_current_hash().to_vec());
+        header.set_height(height);
+        header.set_timestamp(1_543_976_147_000 + height * 3000);
+        header.set_proof(parent_proof);
+        let block = OpenBlock::from(proto_block);
+        let proposal = Header::new(block.header.clone())
+            .proposal_protobuf()
+            .crypt_hash();
+        let proof = generate_proof(key, height, proposal);
+
+        let closed_block = importer.executor.into_fsm(block.clone());
+        let executed_result = importer.executor.grow(&closed_block);
+        importer.chain.set_block_body(height, &block);
+        importer.chain.set_db_result(&executed_result, &block);
+        importer.chain.set_max_store_height(height);
+        importer.chain.save_current_block_poof(&proof);
+        proof
+    }
+
+    // Export the blocks of a source chain, whose block `n` is signed by
+    // `key(n)`.
+    fn export_blocks<F: Fn(u64) -> &'static str>(count: u64, key: F) -> (Vec<u8>, H256) {
+        let dir = TempDir::new("chain_tool_source").unwrap();
+        let mut source = generate_importer(&dir);
+        let mut proof = Proof::new();
+        for height in 1..=count {
+            proof = grow(&mut source, key(height), proof);
+        }
+        let mut writer = BlockFileWriter::new(Vec::new()).unwrap();
+        assert_eq!(export(&source.chain, 1, None, &mut writer), Ok(count));
+        let hash = source.chain.block_hash_by_height(count).unwrap();
+        source.close();
+        (writer.finish().unwrap(), hash)
+    }
+
+    #[test]
+    fn test_export_import() {
+        let (bytes, hash) = export_blocks(5, |_| VALIDATOR_KEY);
+
+        let dir = TempDir::new("chain_tool_target").unwrap();
+        let mut importer = generate_importer(&dir);
+        let mut reader = BlockFileReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(importer.import(&mut reader), Ok(5));
+        assert_eq!(importer.current_height(), 5);
+        assert_eq!(importer.chain.get_current_height(), 5);
+        assert_eq!(importer.chain.block_hash_by_height(5), Some(hash));
+        // The proofs are exported again.
+        let mut writer = BlockFileWriter::new(Vec::new()).unwrap();
+        assert_eq!(export(&importer.chain, 1, None, &mut writer), Ok(5));
+        assert_eq!(writer.finish().unwrap(), bytes);
+
+        // The blocks imported are skipped.
+        let mut reader = BlockFileReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(importer.import(&mut reader), Ok(0));
+        importer.close();
+    }
+
+    #[test]
+    fn test_import_invalid_proof() {
+        // The block 3 is not signed by the validators of the block 2.
+        let (bytes, _) = export_blocks(4, |height| {
+            if height == 3 {
+                OTHER_KEY
+            } else {
+                VALIDATOR_KEY
+            }
+        });
+
+        let dir = TempDir::new("chain_tool_invalid").unwrap();
+        let mut importer = generate_importer(&dir);
+        let mut reader = BlockFileReader::new(Cursor::new(bytes)).unwrap();
+        assert!(importer.import(&mut reader).is_err());
+        assert_eq!(importer.current_height(), 2);
+        importer.close();
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Offline export and import of the blocks.
+//!
+//! The tool works on the databases of a stopped node, without the message
+//! bus and the other services.
+//!
+//! - `export` reads the blocks with their proofs from the chain database and
+//!   writes them to a block file.
+//! - `import` verifies the proofs of the blocks in a block file, executes
+//!   them and saves the results to the executor and the chain databases.
+//! - `check` checks the chain and the executor databases, and with
+//!   `--repair` rebuilds the transaction indexes and the log blooms of the
+//!   chain.
+
+#[macro_use]
+extern crate cita_logger as logger;
+#[macro_use]
+extern crate util;
+
+extern crate common_types as types;
+
+use crate::block_file::{BlockFileReader, BlockFileWriter};
+use crate::import::Importer;
+use chain_core::libchain::chain::{Chain, Config as ChainConfig};
+use chain_core::libchain::integrity;
+use cita_database::{Config as DatabaseConfig, RocksDB, NUM_COLUMNS};
+use cita_directories::DataPath;
+use clap::{App, ArgMatches, SubCommand};
+use core_executor::journaldb::{JournalDBType, DEFAULT_PRUNING_HISTORY};
+use core_executor::libexecutor::executor::Executor;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::Arc;
+use util::set_panic_handler;
+
+mod block_file;
+mod export;
+mod import;
+
+const STATEDB_CACHE_SIZE: usize = 5 * 1024 * 1024;
+
+fn main() {
+    micro_service_init!("cita-chain-tool", "CITA:chain-tool", true);
+
+    let matches = App::new("chain-tool")
+        .version("0.1")
+        .author("Rivtower")
+        .about("Export and import the blocks of a stopped CITA node")
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the blocks with their proofs to a file")
+                .args_from_usage(
+                    "-o, --output=<FILE> 'The block file to write'
+                     --from=[HEIGHT] 'The first block, 1 by default'
+                     --to=[HEIGHT] 'The last block, the latest one by default'
+                     --chain-db=[PATH] 'The chain database'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Verify and execute the blocks of a file")
+                .args_from_usage(
+                    "-i, --input=<FILE> 'The block file to read'
+                     -g, --genesis=[FILE] 'The genesis file, genesis.json by default'
+                     --chain-db=[PATH] 'The chain database'
+                     --executor-data=[PATH] 'The data directory of executor'
+                     --journaldb=[TYPE] 'archive or pruning, archive by default'
+                     --eth-compatibility 'The eth_compatibility of executor'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check the chain and the executor databases")
+                .args_from_usage(
+                    "--chain-db=[PATH] 'The chain database'
+                     --executor-data=[PATH] 'The data directory of executor'
+                     --repair 'Rebuild the transaction indexes and the log blooms of the chain'",
+                ),
+        )
+        .get_matches();
+
+    let ret = match matches.subcommand() {
+        ("export", Some(m)) => run_export(m),
+        ("import", Some(m)) => run_import(m),
+        ("check", Some(m)) => run_check(m),
+        _ => Err(matches.usage().to_owned()),
+    };
+    if let Err(e) = ret {
+        error!("{}", e);
+        ::std::process::exit(1);
+    }
+}
+
+fn open_db(path: &str) -> Result<RocksDB, String> {
+    let db_config = DatabaseConfig::with_category_num(NUM_COLUMNS);
+    RocksDB::open(path, &db_config).map_err(|e| format!("open database {} error: {:?}", path, e))
+}
+
+fn open_chain_db(m: &ArgMatches) -> Result<RocksDB, String> {
+    let path = m
+        .value_of("chain-db")
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(DataPath::nosql_path);
+    open_db(&path)
+}
+
+fn executor_data_path(m: &ArgMatches) -> String {
+    m.value_of("executor-data")
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(DataPath::root_node_path)
+}
+
+fn open_chain(m: &ArgMatches) -> Result<Chain, String> {
+    let db = open_chain_db(m)?;
+    Ok(Chain::init_chain(Arc::new(db), ChainConfig::default()))
+}
+
+fn parse_height(m: &ArgMatches, name: &str) -> Result<Option<u64>, String> {
+    m.value_of(name)
+        .map(|s| {
+            s.parse::<u64>()
+                .map_err(|e| format!("invalid height {}: {}", s, e))
+        })
+        .transpose()
+}
+
+fn run_export(m: &ArgMatches) -> Result<(), String> {
+    let output = m.value_of("output").unwrap();
+    let from = parse_height(m, "from")?.unwrap_or(1);
+    let to = parse_height(m, "to")?;
+    let chain = open_chain(m)?;
+
+    let file = File::create(output).map_err(|e| format!("create {} error: {}", output, e))?;
+    let mut writer = BlockFileWriter::new(BufWriter::new(file)).map_err(|e| format!("{:?}", e))?;
+    let count = export::export(&chain, from, to, &mut writer)?;
+    writer.finish().map_err(|e| format!("{:?}", e))?;
+    info!("exported {} blocks to {}", count, output);
+    Ok(())
+}
+
+fn run_import(m: &ArgMatches) -> Result<(), String> {
+    let input = m.value_of("input").unwrap();
+    let genesis_path = m.value_of("genesis").unwrap_or("genesis.json");
+    let data_path = executor_data_path(m);
+    let journaldb = m.value_of("journaldb").unwrap_or("archive");
+    let journaldb_type = JournalDBType::new(journaldb, DEFAULT_PRUNING_HISTORY)
+        .ok_or_else(|| format!("unknown journaldb type {}", journaldb))?;
+
+    let file = File::open(input).map_err(|e| format!("open {} error: {}", input, e))?;
+    let mut reader = BlockFileReader::new(BufReader::new(file)).map_err(|e| format!("{:?}", e))?;
+
+    // The executor is driven directly, the channels are never used.
+    let (_fsm_req_sender, fsm_req_receiver) = crossbeam_channel::unbounded();
+    let (fsm_resp_sender, _fsm_resp_receiver) = crossbeam_channel::unbounded();
+    let (_command_req_sender, command_req_receiver) = crossbeam_channel::unbounded();
+    let (command_resp_sender, _command_resp_receiver) = crossbeam_channel::unbounded();
+    let executor = Executor::init(
+        genesis_path,
+        data_path,
+        fsm_req_receiver,
+        fsm_resp_sender,
+        command_req_receiver,
+        command_resp_sender,
+        m.is_present("eth-compatibility"),
+        journaldb_type,
+        STATEDB_CACHE_SIZE,
+    );
+    let chain = open_chain(m)?;
+
+    let mut importer = Importer::new(executor, chain)?;
+    let ret = importer.import(&mut reader);
+    info!("current height is {}", importer.current_height());
+    importer.close();
+    let count = ret?;
+    info!("imported {} blocks from {}", count, input);
+    Ok(())
+}
+
+fn run_check(m: &ArgMatches) -> Result<(), String> {
+    let chain_db = open_chain_db(m)?;
+    let executor_db = open_db(&(executor_data_path(m) + "/statedb"))?;
+
+    let mut report = integrity::check_chain(&chain_db).map_err(|e| format!("{:?}", e))?;
+    if m.is_present("repair") {
+        let count = integrity::rebuild_indexes(&chain_db, 0, report.current_height)
+            .map_err(|e| format!("{:?}", e))?;
+        info!("rebuild the indexes of {} transactions", count);
+        report = integrity::check_chain(&chain_db).map_err(|e| format!("{:?}", e))?;
+    }
+    let mut problems = report.problems;
+    problems.extend(
+        integrity::check_executor(&executor_db, &chain_db, report.current_height)
+            .map_err(|e| format!("{:?}", e))?,
+    );
+
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    if problems.is_empty() {
+        info!("no problem found up to height {}", report.current_height);
+        Ok(())
+    } else {
+        Err(format!("{} problems found", problems.len()))
+    }
+}
@@ -18,32 +18,38 @@ use std::str::FromStr;
 
 pub struct AppArgs {
     pub cfg_file: String,
-    pub chain_id: U256,
-    pub tx_hash: H256,
+    /// Watch the chains and relay until stopped.
+    pub daemon: bool,
+    pub chain_id: Option<U256>,
+    pub tx_hash: Option<H256>,
 }
 
 impl<'a> From<&'a clap::ArgMatches<'a>> for AppArgs {
     fn from(matches: &'a clap::ArgMatches) -> Self {
         let cfg_file = matches.value_of("ConfigFile").unwrap();
-        let chain_id_str = matches.value_of("ChainId").unwrap();
-        let chain_id_str = if chain_id_str.starts_with("0x") {
-            &chain_id_str[2..]
-        } else {
-            chain_id_str
-        };
+        let daemon = matches.is_present("Daemon");
 
-        let chain_id = U256::from_str(chain_id_str).unwrap();
+        let chain_id = matches.value_of("ChainId").map(|chain_id_str| {
+            let chain_id_str = if chain_id_str.starts_with("0x") {
+                &chain_id_str[2..]
+            } else {
+                chain_id_str
+            };
+            U256::from_str(chain_id_str).unwrap()
+        });
 
-        let tx_hash_str = matches.value_of("TxHash").unwrap();
-        let tx_hash_str = if tx_hash_str.starts_with("0x") {
-            &tx_hash_str[2..]
-        } else {
-            tx_hash_str
-        };
-        let tx_hash = H256::from_str(tx_hash_str).unwrap();
+        let tx_hash = matches.value_of("TxHash").map(|tx_hash_str| {
+            let tx_hash_str = if tx_hash_str.starts_with("0x") {
+                &tx_hash_str[2..]
+            } else {
+                tx_hash_str
+            };
+            H256::from_str(tx_hash_str).unwrap()
+        });
         AppArgs {
             cfg_file: cfg_file.to_owned(),
-            chain_id: chain_id.to_owned(),
+            daemon,
+            chain_id,
             tx_hash,
         }
     }
@@ -53,6 +59,7 @@ impl ::std::fmt::Display for AppArgs {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         f.debug_struct("AppArgs")
             .field("cfg_file", &self.cfg_file)
+            .field("daemon", &self.daemon)
             .field("chain_id", &self.chain_id)
             .field("tx_hash", &self.tx_hash)
             .finish()
@@ -65,8 +72,9 @@ pub fn build_commandline<'a>() -> clap::ArgMatches<'a> {
         (author: "Rivtower Technologies")
         (about: "CITA Relay Info Parser by Rust")
         (@arg ConfigFile: -f --config_file +takes_value +required "Input a toml configuration file.")
-        (@arg ChainId: -c --chain_id +takes_value +required "Input a chain id for the transaction hash.")
-        (@arg TxHash: -t --tx_hash +takes_value +required "Input a hex string of the transaction hash.")
+        (@arg Daemon: -d --daemon "Watch the chains in the configuration and relay until stopped.")
+        (@arg ChainId: -c --chain_id +takes_value required_unless[Daemon] "Input a chain id for the transaction hash.")
+        (@arg TxHash: -t --tx_hash +takes_value required_unless[Daemon] "Input a hex string of the transaction hash.")
     ).get_matches();
     trace!("matches = {:?}", matches);
     matches
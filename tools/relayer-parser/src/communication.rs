@@ -18,12 +18,14 @@ use futures::{Future, Sink, Stream};
 use hyper;
 use libproto::TryInto;
 use parking_lot::{Mutex, RwLock};
-use serde_json;
+use serde::de::DeserializeOwned;
+use serde_json::{self, json};
 use std::convert::Into;
 use tokio_core::reactor::{Core, Timeout};
 
 use crate::configuration::UpStream;
-use cita_types::{H256, U256};
+use cita_types::traits::LowerHex;
+use cita_types::{Address, H256, U256};
 use jsonrpc_types::{rpc_request, rpc_types};
 use libproto::blockchain::UnverifiedTransaction;
 
@@ -173,3 +175,101 @@ pub fn cita_send_transaction(
         Err(Error::BadStatus)
     }
 }
+
+#[derive(Debug, Deserialize)]
+struct Reply<T> {
+    pub result: T,
+}
+
+/// Send a request whose params are not in `rpc_request`.
+fn rpc_call<T>(upstream: &UpStream, method: &str, params: serde_json::Value) -> Result<T, Error>
+where
+    T: DeserializeOwned + ::std::fmt::Debug,
+{
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    })
+    .to_string();
+    let rpc_cli = RpcClient::create(upstream);
+    let data = rpc_cli.do_post(&body)?;
+    let reply: Reply<T> = serde_json::from_slice(&data).map_err(|_| {
+        error!(
+            "send {:?} return error: {:?}",
+            &body,
+            ::std::str::from_utf8(&data)
+        );
+        Error::Parse
+    })?;
+    trace!("get reply {:?}.", reply);
+    Ok(reply.result)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogEntry {
+    pub address: Address,
+    pub topics: Vec<H256>,
+    pub data: rpc_types::Data,
+    #[serde(rename = "blockNumber")]
+    pub block_number: U256,
+    #[serde(rename = "transactionHash")]
+    pub transaction_hash: H256,
+}
+
+pub fn cita_get_logs(
+    upstream: &UpStream,
+    from: u64,
+    to: u64,
+    topics: &[H256],
+) -> Result<Vec<LogEntry>, Error> {
+    // Logs whose first topic is any of the topics.
+    let topics: Vec<String> = topics
+        .iter()
+        .map(|topic| format!("0x{}", topic.lower_hex()))
+        .collect();
+    let filter = json!({
+        "fromBlock": format!("0x{:x}", from),
+        "toBlock": format!("0x{:x}", to),
+        "topics": [topics],
+    });
+    rpc_call(upstream, "getLogs", json!([filter]))
+}
+
+pub fn cita_call(upstream: &UpStream, to: Address, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let request = json!({
+        "to": format!("0x{}", to.lower_hex()),
+        "data": rpc_types::Data::from(data),
+    });
+    let result: rpc_types::Data = rpc_call(upstream, "call", json!([request, "latest"]))?;
+    Ok(result.into())
+}
+
+pub fn cita_get_block_header(upstream: &UpStream, height: u64) -> Result<Vec<u8>, Error> {
+    let result: rpc_types::Data = rpc_call(
+        upstream,
+        "getBlockHeader",
+        json!([format!("0x{:x}", height)]),
+    )?;
+    Ok(result.into())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReceiptEntry {
+    /// The error of the transaction on chain.
+    #[serde(rename = "errorMessage")]
+    pub error_message: Option<String>,
+}
+
+/// The receipt of the transaction, or none if it is not in a block yet.
+pub fn cita_get_transaction_receipt(
+    upstream: &UpStream,
+    hash: H256,
+) -> Result<Option<ReceiptEntry>, Error> {
+    rpc_call(
+        upstream,
+        "getTransactionReceipt",
+        json!([format!("0x{}", hash.lower_hex())]),
+    )
+}
@@ -28,18 +28,58 @@ pub struct UpStream {
 struct Chain {
     pub id: U256,
     pub servers: Vec<UpStream>,
+    /// The first block the daemon watches if no progress is saved, the
+    /// latest one by default.
+    #[serde(default)]
+    pub start_height: Option<u64>,
+}
+
+/// Relay the headers of chain `from` to the `ChainManager` of chain `to`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HeaderRelay {
+    pub from: U256,
+    pub to: U256,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// The file saving the watched heights and the relays in progress.
+    pub state_file: String,
+    pub poll_interval: Duration,
+    /// Blocks of a log query.
+    pub scan_blocks: u64,
+    /// Headers sent to a chain in a round.
+    pub headers_per_round: u64,
+    pub header_relays: Vec<HeaderRelay>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            state_file: "relayer-state.json".to_owned(),
+            poll_interval: Duration::from_secs(3),
+            scan_blocks: 1000,
+            headers_per_round: 10,
+            header_relays: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct FileConfig {
     pub private_key: PrivKey,
     pub chains: Vec<Chain>,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pkey: PrivKey,
     servers: HashMap<U256, Vec<UpStream>>,
+    start_heights: HashMap<U256, u64>,
+    daemon: DaemonConfig,
 }
 
 impl FileConfig {
@@ -59,15 +99,38 @@ impl Config {
     pub fn get_private_key(&self) -> &PrivKey {
         &self.pkey
     }
+    pub fn get_chain_ids(&self) -> Vec<U256> {
+        let mut ids: Vec<U256> = self.servers.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+    #[inline]
+    pub fn get_start_height(&self, chain_id: U256) -> Option<u64> {
+        self.start_heights.get(&chain_id).cloned()
+    }
+    #[inline]
+    pub fn get_daemon(&self) -> &DaemonConfig {
+        &self.daemon
+    }
 }
 
 pub fn parse_configfile(path: &str) -> Config {
     let config = FileConfig::load(path);
     let pkey = config.private_key;
+    let start_heights = config
+        .chains
+        .iter()
+        .filter_map(|c| c.start_height.map(|height| (c.id, height)))
+        .collect();
     let servers = config
         .chains
         .into_iter()
         .map(|c| (c.id, c.servers))
         .collect();
-    Config { pkey, servers }
+    Config {
+        pkey,
+        servers,
+        start_heights,
+        daemon: config.daemon,
+    }
 }
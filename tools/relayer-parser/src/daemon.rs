@@ -0,0 +1,679 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The daemon mode of the relayer.
+//!
+//! Each round it
+//!
+//! - watches the new blocks of the chains for `SendCrossChain` events and
+//!   `CrossChainMessage` messages, and queues them in the state file,
+//! - relays the queued events in the order of the receiving nonce of each
+//!   channel, and resends the ones not received in time,
+//! - relays the headers expected by the `ChainManager` of the chains in
+//!   `header_relays`.
+
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cita_types::{Address, H160, H256, U256};
+use core::cross_chain::{CrossChainMessage, CROSS_CHAIN_MESSAGE_TOPIC};
+use core::libchain::chain::RelayInfo;
+use core::log::Log;
+use core::reserved_addresses;
+use util::sha3;
+
+use crate::communication::{self, Error};
+use crate::configuration::{Config, HeaderRelay, UpStream};
+use crate::state::{PendingRelay, RelayState};
+use crate::transaction::{self, VALID_UNTIL_BLOCKS};
+
+const SEND_CROSS_CHAIN_EVENT: &str = "SendCrossChain(uint256,uint256,address,bytes4,uint64)";
+/// The proof of a transaction needs the two blocks after it.
+const PROOF_BLOCKS: u64 = 2;
+/// The longest wait in seconds before retrying a failed relay.
+const MAX_RETRY_DELAY: u64 = 300;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The events of a channel are received in the order of their nonces.
+///
+/// A `SendCrossChain` event goes to the channel of the destination contract,
+/// a message to the channel of the sender and the destination contract.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Channel {
+    version: u8,
+    to_chain_id: U256,
+    dest_contract: Address,
+    from_chain_id: U256,
+    sender: Address,
+}
+
+impl Channel {
+    fn of(relay: &PendingRelay) -> Self {
+        if relay.version == 0 {
+            Channel {
+                version: 0,
+                to_chain_id: relay.to_chain_id,
+                dest_contract: relay.dest_contract,
+                from_chain_id: U256::zero(),
+                sender: Address::default(),
+            }
+        } else {
+            Channel {
+                version: relay.version,
+                to_chain_id: relay.to_chain_id,
+                dest_contract: relay.dest_contract,
+                from_chain_id: relay.from_chain_id,
+                sender: relay.sender,
+            }
+        }
+    }
+}
+
+fn decode_u64(output: &[u8]) -> Result<u64, String> {
+    if output.len() < 32 {
+        Err(format!("invalid uint output {:?}", output))
+    } else {
+        Ok(U256::from(&output[..32]).low_u64())
+    }
+}
+
+/// The blocks to watch in a round, from the next height, or the latest
+/// block with proofs if no height is saved or configured, to at most
+/// `scan_blocks` blocks later.
+fn scan_range(next_height: Option<u64>, latest: u64, scan_blocks: u64) -> Option<(u64, u64)> {
+    let safe = latest.checked_sub(PROOF_BLOCKS)?;
+    let from = next_height.unwrap_or(safe);
+    if from > safe {
+        return None;
+    }
+    Some((from, cmp::min(safe, from + cmp::max(scan_blocks, 1) - 1)))
+}
+
+/// Forget the relays of the channel received by the destination contract,
+/// returns whether any is forgotten.
+fn forget_received(relays: &mut Vec<PendingRelay>, channel: Channel, recv_nonce: u64) -> bool {
+    let before = relays.len();
+    relays.retain(|r| {
+        let received = Channel::of(r) == channel && r.cross_chain_nonce < recv_nonce;
+        if received {
+            info!(
+                "relay of {:?} with nonce {} is received",
+                r.tx_hash, r.cross_chain_nonce
+            );
+        }
+        !received
+    });
+    relays.len() != before
+}
+
+/// The relay of the channel received next, whatever the order it is queued.
+fn next_relay(relays: &[PendingRelay], channel: Channel, recv_nonce: u64) -> Option<usize> {
+    relays
+        .iter()
+        .position(|r| Channel::of(r) == channel && r.cross_chain_nonce == recv_nonce)
+}
+
+/// Whether to send the relay now: it is not sent, or the transaction sent is
+/// not valid any more, and it is not waiting to retry after a failure.
+fn ready_to_send(relay: &PendingRelay, height: u64, now: u64) -> bool {
+    let waiting = match relay.sent {
+        Some((_, sent_at)) => height <= sent_at + VALID_UNTIL_BLOCKS,
+        None => false,
+    };
+    !waiting && now >= relay.next_attempt
+}
+
+/// The wait in seconds before retrying a relay failed `retries` times.
+fn retry_delay(poll_interval: u64, retries: u32) -> u64 {
+    cmp::min(poll_interval << cmp::min(retries, 8), MAX_RETRY_DELAY)
+}
+
+/// The headers sent to a chain in the last round.
+struct SentHeaders {
+    last: u64,
+    sent_at: u64,
+    /// The numbers of the headers sent, and the hashes of the transactions.
+    txs: Vec<(u64, H256)>,
+}
+
+pub struct Daemon<'a> {
+    cfg: &'a Config,
+    state: RelayState,
+    headers: HashMap<(U256, U256), SentHeaders>,
+    event_topic: H256,
+    chain_manager: H160,
+}
+
+impl<'a> Daemon<'a> {
+    pub fn new(cfg: &'a Config) -> Result<Self, String> {
+        let path = &cfg.get_daemon().state_file;
+        let state = RelayState::load(path)
+            .map_err(|e| format!("load state from {} failed: {}", path, e))?;
+        info!("load {} relays from {}", state.relays.len(), path);
+        Ok(Daemon {
+            cfg,
+            state,
+            headers: HashMap::new(),
+            event_topic: H256::from_slice(&sha3::keccak256(SEND_CROSS_CHAIN_EVENT.as_bytes())),
+            chain_manager: H160::from_str(reserved_addresses::CHAIN_MANAGER).unwrap(),
+        })
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            self.round();
+            thread::sleep(self.cfg.get_daemon().poll_interval);
+        }
+    }
+
+    fn round(&mut self) {
+        for chain_id in self.cfg.get_chain_ids() {
+            if let Err(e) = self.scan(chain_id) {
+                warn!("watch chain {} failed: {}", chain_id, e);
+            }
+        }
+        self.relay_transactions();
+        for header_relay in self.cfg.get_daemon().header_relays.clone() {
+            if let Err(e) = self.relay_headers(&header_relay) {
+                warn!(
+                    "relay headers of chain {} to chain {} failed: {}",
+                    header_relay.from, header_relay.to, e
+                );
+            }
+        }
+    }
+
+    fn save(&self) {
+        let path = &self.cfg.get_daemon().state_file;
+        if let Err(e) = self.state.save(path) {
+            error!("save state to {} failed: {}", path, e);
+        }
+    }
+
+    /// Try the servers of the chain one by one.
+    fn request<T, F>(&self, chain_id: U256, f: F) -> Result<T, String>
+    where
+        F: Fn(&UpStream) -> Result<T, Error>,
+    {
+        let servers = self
+            .cfg
+            .get_servers(chain_id)
+            .ok_or_else(|| format!("no servers of chain {}", chain_id))?;
+        let mut last_error = None;
+        for upstream in servers.iter() {
+            match f(upstream) {
+                Ok(ret) => return Ok(ret),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(format!(
+            "all servers failed, the last error {:?}",
+            last_error
+        ))
+    }
+
+    fn block_number(&self, chain_id: U256) -> Result<u64, String> {
+        self.request(chain_id, communication::cita_block_number)
+            .map(|height| height.low_u64())
+    }
+
+    fn scan(&mut self, chain_id: U256) -> Result<(), String> {
+        let latest = self.block_number(chain_id)?;
+        let next_height = self
+            .state
+            .next_height(chain_id)
+            .or_else(|| self.cfg.get_start_height(chain_id));
+        let (from, to) = match scan_range(next_height, latest, self.cfg.get_daemon().scan_blocks) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        let topics = [self.event_topic, *CROSS_CHAIN_MESSAGE_TOPIC];
+        let logs = self.request(chain_id, |upstream| {
+            communication::cita_get_logs(upstream, from, to, &topics)
+        })?;
+        trace!(
+            "found {} events of chain {} in [{}, {}]",
+            logs.len(),
+            chain_id,
+            from,
+            to
+        );
+
+        for log in logs {
+            let tx_hash = log.transaction_hash;
+            let relay = if log.topics.first() == Some(&*CROSS_CHAIN_MESSAGE_TOPIC) {
+                let log = Log {
+                    address: log.address,
+                    topics: log.topics,
+                    data: log.data.into(),
+                };
+                CrossChainMessage::from_log(&log)
+                    .filter(|message| message.from_chain_id == chain_id)
+                    .map(|message| PendingRelay {
+                        version: message.version,
+                        from_chain_id: chain_id,
+                        sender: message.sender,
+                        tx_hash,
+                        to_chain_id: message.to_chain_id,
+                        dest_contract: message.dest_contract,
+                        cross_chain_nonce: message.nonce,
+                        sent: None,
+                        retries: 0,
+                        next_attempt: 0,
+                    })
+            } else {
+                let data: Vec<u8> = log.data.into();
+                RelayInfo::from_log_data(&data)
+                    .filter(|info| info.from_chain_id == chain_id)
+                    .map(|info| PendingRelay {
+                        version: 0,
+                        from_chain_id: chain_id,
+                        sender: Address::default(),
+                        tx_hash,
+                        to_chain_id: info.to_chain_id,
+                        dest_contract: info.dest_contract,
+                        cross_chain_nonce: info.cross_chain_nonce,
+                        sent: None,
+                        retries: 0,
+                        next_attempt: 0,
+                    })
+            };
+            let relay = match relay {
+                Some(relay) => relay,
+                None => {
+                    warn!(
+                        "invalid cross chain event in {:?} of block {}",
+                        tx_hash, log.block_number
+                    );
+                    continue;
+                }
+            };
+            // Kept in the queue until the servers are configured.
+            if self.cfg.get_servers(relay.to_chain_id).is_none() {
+                warn!(
+                    "no servers of chain {} to relay the event in {:?}",
+                    relay.to_chain_id, tx_hash
+                );
+            }
+            let (to_chain_id, nonce) = (relay.to_chain_id, relay.cross_chain_nonce);
+            if self.state.add_relay(relay) {
+                info!(
+                    "queue relay of {:?} to chain {} with nonce {}",
+                    tx_hash, to_chain_id, nonce
+                );
+            }
+        }
+        self.state.set_next_height(chain_id, to + 1);
+        self.save();
+        Ok(())
+    }
+
+    fn relay_transactions(&mut self) {
+        let channels: BTreeSet<Channel> = self
+            .state
+            .relays
+            .iter()
+            .map(Channel::of)
+            .filter(|channel| self.cfg.get_servers(channel.to_chain_id).is_some())
+            .collect();
+        for channel in channels {
+            if let Err(e) = self.relay_to(channel) {
+                warn!(
+                    "relay to {:?} of chain {} failed: {}",
+                    channel.dest_contract, channel.to_chain_id, e
+                );
+            }
+        }
+    }
+
+    /// The nonce of the next event the destination contract receives in the
+    /// channel.
+    fn recv_nonce(&self, channel: Channel) -> Result<u64, String> {
+        let data = if channel.version == 0 {
+            transaction::encode_call("getCrossChainRecvNonce()", &[])
+        } else {
+            transaction::encode_call(
+                "getMessageRecvNonce(uint256,address)",
+                &[
+                    ethabi::Token::Uint(channel.from_chain_id.into()),
+                    ethabi::Token::Address(channel.sender.into()),
+                ],
+            )
+        };
+        let output = self.request(channel.to_chain_id, |upstream| {
+            communication::cita_call(upstream, channel.dest_contract, data.clone())
+        })?;
+        decode_u64(&output)
+    }
+
+    /// Relay the event whose nonce is the receiving nonce of the channel, and
+    /// forget the ones received.
+    fn relay_to(&mut self, channel: Channel) -> Result<(), String> {
+        let recv_nonce = self.recv_nonce(channel)?;
+        let height = self.block_number(channel.to_chain_id)?;
+
+        let mut changed = forget_received(&mut self.state.relays, channel, recv_nonce);
+
+        let now = unix_now();
+        match next_relay(&self.state.relays, channel, recv_nonce) {
+            Some(index) => {
+                let relay = self.state.relays[index].clone();
+                if ready_to_send(&relay, height, now) {
+                    let sent = if relay.version == 0 {
+                        self.send_relay(&relay)
+                    } else {
+                        self.send_message(&relay, height)
+                    };
+                    let relay = &mut self.state.relays[index];
+                    match sent {
+                        Ok(hash) => {
+                            info!(
+                                "send relay of {:?} with nonce {} in {:?}",
+                                relay.tx_hash, relay.cross_chain_nonce, hash
+                            );
+                            if relay.sent.is_some() {
+                                relay.retries += 1;
+                            }
+                            relay.sent = Some((hash, height));
+                        }
+                        Err(e) => {
+                            relay.retries += 1;
+                            let delay = retry_delay(
+                                self.cfg.get_daemon().poll_interval.as_secs(),
+                                relay.retries,
+                            );
+                            relay.next_attempt = now + delay;
+                            warn!(
+                                "relay of {:?} failed {} times, retry in {}s: {}",
+                                relay.tx_hash, relay.retries, delay, e
+                            );
+                        }
+                    }
+                    changed = true;
+                }
+            }
+            None => {
+                let waiting = self.state.relays.iter().any(|r| Channel::of(r) == channel);
+                if waiting {
+                    warn!(
+                        "no event with nonce {} to {:?} of chain {}",
+                        recv_nonce, channel.dest_contract, channel.to_chain_id
+                    );
+                }
+            }
+        }
+        if changed {
+            self.save();
+        }
+        Ok(())
+    }
+
+    fn send_relay(&self, relay: &PendingRelay) -> Result<H256, String> {
+        let servers = self
+            .cfg
+            .get_servers(relay.from_chain_id)
+            .ok_or_else(|| format!("no servers of chain {}", relay.from_chain_id))?;
+        let tx_proof_rlp = crate::fetch_txproof(&servers[..], relay.tx_hash)
+            .ok_or_else(|| format!("get proof of {:?} failed", relay.tx_hash))?;
+        let relay_info = crate::deconstruct_txproof(&tx_proof_rlp[..])
+            .ok_or_else(|| format!("no relay info in proof of {:?}", relay.tx_hash))?;
+        let to_servers = self
+            .cfg
+            .get_servers(relay_info.to_chain_id)
+            .ok_or_else(|| format!("no servers of chain {}", relay_info.to_chain_id))?;
+        crate::relay_transaction(
+            &to_servers[..],
+            self.cfg.get_private_key(),
+            &tx_proof_rlp[..],
+            &relay_info,
+        )
+        .ok_or_else(|| "send relay transaction failed".to_owned())
+    }
+
+    /// Call `recvCrossChainMessage(uint256,address,bytes)` of the destination
+    /// contract with the proof of the message.
+    fn send_message(&self, relay: &PendingRelay, height: u64) -> Result<H256, String> {
+        let servers = self
+            .cfg
+            .get_servers(relay.from_chain_id)
+            .ok_or_else(|| format!("no servers of chain {}", relay.from_chain_id))?;
+        let tx_proof_rlp = crate::fetch_txproof(&servers[..], relay.tx_hash)
+            .ok_or_else(|| format!("get proof of {:?} failed", relay.tx_hash))?;
+        let data = transaction::encode_call(
+            "recvCrossChainMessage(uint256,address,bytes)",
+            &[
+                ethabi::Token::Uint(relay.from_chain_id.into()),
+                ethabi::Token::Address(relay.sender.into()),
+                ethabi::Token::Bytes(tx_proof_rlp),
+            ],
+        );
+        let utx = transaction::construct_call_transaction(
+            self.cfg.get_private_key(),
+            relay.dest_contract,
+            data,
+            relay.to_chain_id,
+            U256::from(height),
+        );
+        self.request(relay.to_chain_id, |upstream| {
+            communication::cita_send_transaction(upstream, &utx)
+        })
+    }
+
+    /// Send the headers expected by the `ChainManager` of chain `to`, unless
+    /// the ones sent are still pending.
+    ///
+    /// The `ChainManager` verifies a header in a call, so each header is sent
+    /// in its own transaction. The expected header is sent again at once if
+    /// its transaction failed on chain.
+    fn relay_headers(&mut self, header_relay: &HeaderRelay) -> Result<(), String> {
+        let (from, to) = (header_relay.from, header_relay.to);
+        let data = transaction::encode_call(
+            "getExpectedBlockNumber(uint256)",
+            &[ethabi::Token::Uint(from.into())],
+        );
+        let chain_manager = self.chain_manager;
+        let output = self.request(to, |upstream| {
+            communication::cita_call(upstream, chain_manager, data.clone())
+        })?;
+        let expected = decode_u64(&output)?;
+        let latest = self.block_number(from)?;
+        let height = self.block_number(to)?;
+
+        if let Some(sent) = self.headers.get(&(from, to)) {
+            if expected <= sent.last && height <= sent.sent_at + VALID_UNTIL_BLOCKS {
+                match self.header_error(to, sent, expected)? {
+                    Some(e) => warn!(
+                        "header {} of chain {} failed in chain {}: {}",
+                        expected, from, to, e
+                    ),
+                    None => return Ok(()),
+                }
+            }
+        }
+        if expected > latest {
+            return Ok(());
+        }
+        let last = cmp::min(
+            latest,
+            expected + cmp::max(self.cfg.get_daemon().headers_per_round, 1) - 1,
+        );
+        let mut txs = Vec::new();
+        let mut result = Ok(());
+        for number in expected..=last {
+            match self.send_header(from, to, number, height) {
+                Ok(hash) => {
+                    trace!("send header {} of chain {} in {:?}", number, from, hash);
+                    txs.push((number, hash));
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        // Wait for the headers sent, even if not all of them are sent.
+        if let Some(&(last, _)) = txs.last() {
+            info!(
+                "send headers [{}, {}] of chain {} to chain {}",
+                expected, last, from, to
+            );
+            self.headers.insert(
+                (from, to),
+                SentHeaders {
+                    last,
+                    sent_at: height,
+                    txs,
+                },
+            );
+        }
+        result
+    }
+
+    fn send_header(&self, from: U256, to: U256, number: u64, height: u64) -> Result<H256, String> {
+        let header = self.request(from, |upstream| {
+            communication::cita_get_block_header(upstream, number)
+        })?;
+        let data = transaction::encode_call(
+            "verifyBlockHeader(uint256,bytes)",
+            &[
+                ethabi::Token::Uint(from.into()),
+                ethabi::Token::Bytes(header),
+            ],
+        );
+        let utx = transaction::construct_call_transaction(
+            self.cfg.get_private_key(),
+            self.chain_manager,
+            data,
+            to,
+            U256::from(height),
+        );
+        self.request(to, |upstream| {
+            communication::cita_send_transaction(upstream, &utx)
+        })
+    }
+
+    /// The error of the transaction sending the header, if it failed.
+    fn header_error(
+        &self,
+        to: U256,
+        sent: &SentHeaders,
+        number: u64,
+    ) -> Result<Option<String>, String> {
+        let hash = match sent.txs.iter().find(|tx| tx.0 == number) {
+            Some(tx) => tx.1,
+            None => return Ok(None),
+        };
+        let receipt = self.request(to, |upstream| {
+            communication::cita_get_transaction_receipt(upstream, hash)
+        })?;
+        Ok(receipt.and_then(|receipt| receipt.error_message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        forget_received, next_relay, ready_to_send, retry_delay, scan_range, Channel,
+        MAX_RETRY_DELAY,
+    };
+    use crate::state::PendingRelay;
+    use cita_types::{Address, H256, U256};
+
+    fn relay(dest_contract: u64, nonce: u64) -> PendingRelay {
+        PendingRelay {
+            version: 0,
+            from_chain_id: U256::from(3),
+            sender: Address::default(),
+            tx_hash: H256::from(dest_contract * 100 + nonce),
+            to_chain_id: U256::from(4),
+            dest_contract: Address::from(dest_contract),
+            cross_chain_nonce: nonce,
+            sent: None,
+            retries: 0,
+            next_attempt: 0,
+        }
+    }
+
+    #[test]
+    fn test_scan_range() {
+        // No block has the proofs yet.
+        assert_eq!(scan_range(None, 1, 10), None);
+        // From the latest block with the proofs.
+        assert_eq!(scan_range(None, 100, 10), Some((98, 98)));
+        assert_eq!(scan_range(Some(50), 100, 10), Some((50, 59)));
+        assert_eq!(scan_range(Some(95), 100, 10), Some((95, 98)));
+        assert_eq!(scan_range(Some(50), 100, 0), Some((50, 50)));
+        assert_eq!(scan_range(Some(99), 100, 10), None);
+    }
+
+    #[test]
+    fn test_relay_order() {
+        // Queued out of order.
+        let mut relays = vec![relay(5, 2), relay(5, 0), relay(6, 0), relay(5, 1)];
+        let channel = Channel::of(&relays[0]);
+        let other = Channel::of(&relays[2]);
+        assert_ne!(channel, other);
+
+        assert_eq!(next_relay(&relays, channel, 0), Some(1));
+        assert!(forget_received(&mut relays, channel, 1));
+        assert_eq!(relays.len(), 3);
+        assert!(!forget_received(&mut relays, channel, 1));
+        assert_eq!(
+            relays[next_relay(&relays, channel, 1).unwrap()],
+            relay(5, 1)
+        );
+        assert_eq!(next_relay(&relays, channel, 3), None);
+        // Other channels are not touched.
+        assert_eq!(next_relay(&relays, other, 0), Some(1));
+
+        // A message of the same destination is of another channel.
+        let mut message = relay(5, 1);
+        message.version = 1;
+        message.sender = Address::from(7);
+        assert_ne!(Channel::of(&message), channel);
+        relays.push(message.clone());
+        assert!(forget_received(&mut relays, channel, 3));
+        assert_eq!(relays, vec![relay(6, 0), message]);
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut r = relay(5, 0);
+        assert!(ready_to_send(&r, 10, 1000));
+
+        // Wait for the transaction sent until it is not valid.
+        r.sent = Some((H256::from(1), 10));
+        assert!(!ready_to_send(&r, 110, 1000));
+        assert!(ready_to_send(&r, 111, 1000));
+
+        // Wait to retry after a failure.
+        r.next_attempt = 1006;
+        assert!(!ready_to_send(&r, 111, 1005));
+        assert!(ready_to_send(&r, 111, 1006));
+
+        assert_eq!(retry_delay(3, 1), 6);
+        assert_eq!(retry_delay(3, 3), 24);
+        assert_eq!(retry_delay(3, 7), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(1, 100), 256);
+    }
+}
@@ -22,6 +22,8 @@ extern crate cita_logger as logger;
 mod arguments;
 mod communication;
 mod configuration;
+mod daemon;
+mod state;
 mod transaction;
 
 use cita_crypto::PrivKey;
@@ -31,6 +33,7 @@ use libproto::blockchain::UnverifiedTransaction;
 
 use arguments::{build_commandline, parse_arguments};
 use configuration::{parse_configfile, UpStream};
+use daemon::Daemon;
 
 fn main() {
     logger::init();
@@ -39,6 +42,18 @@ fn main() {
     let args = parse_arguments(&matches);
     let cfg = parse_configfile(&args.cfg_file);
 
+    if args.daemon {
+        match Daemon::new(&cfg) {
+            Ok(mut daemon) => daemon.run(),
+            Err(e) => {
+                error!("start daemon failed: {}", e);
+                ::std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let (chain_id, tx_hash) = (args.chain_id.unwrap(), args.tx_hash.unwrap());
+
     let mut retcode = 1;
     // Get servers list from the config file by the input chain id.
     // Try to get transaction proof from servers in server list.
@@ -47,8 +62,8 @@ fn main() {
     // The chain id of to-chain is in the tx proof.
     // Relay the transaction to each server in to-chain servers list, until succeed.
     let _ = cfg
-        .get_servers(args.chain_id)
-        .and_then(|servers| fetch_txproof(&servers[..], args.tx_hash))
+        .get_servers(chain_id)
+        .and_then(|servers| fetch_txproof(&servers[..], tx_hash))
         .and_then(|tx_proof_rlp| {
             deconstruct_txproof(&tx_proof_rlp[..]).map(|relay_info| (tx_proof_rlp, relay_info))
         })
@@ -0,0 +1,181 @@
+// Copyright Rivtower Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The progress of the daemon, saved to a file after each change, so the
+//! events found are relayed after a restart.
+
+use cita_types::{Address, H256, U256};
+use serde_json;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanProgress {
+    pub chain_id: U256,
+    /// The next block to watch.
+    pub next_height: u64,
+}
+
+/// A `SendCrossChain` event, or a `CrossChainMessage` of `version`, to relay.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingRelay {
+    /// 0 for a `SendCrossChain` event.
+    #[serde(default)]
+    pub version: u8,
+    pub from_chain_id: U256,
+    /// The contract sending the message.
+    #[serde(default)]
+    pub sender: Address,
+    pub tx_hash: H256,
+    pub to_chain_id: U256,
+    pub dest_contract: Address,
+    pub cross_chain_nonce: u64,
+    /// The hash of the relay transaction sent, and the height of the
+    /// destination chain then.
+    pub sent: Option<(H256, u64)>,
+    pub retries: u32,
+    /// Unix time in seconds of the next attempt after a failure.
+    pub next_attempt: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RelayState {
+    pub scanned: Vec<ScanProgress>,
+    pub relays: Vec<PendingRelay>,
+}
+
+impl RelayState {
+    /// Load the state saved, or an empty state if no file is saved yet.
+    pub fn load(path: &str) -> io::Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(RelayState::default());
+        }
+        let file = fs::File::open(path)?;
+        let reader = io::BufReader::new(file);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Write to a temporary file and rename it, so the state file is never
+    /// half written.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn next_height(&self, chain_id: U256) -> Option<u64> {
+        self.scanned
+            .iter()
+            .find(|p| p.chain_id == chain_id)
+            .map(|p| p.next_height)
+    }
+
+    pub fn set_next_height(&mut self, chain_id: U256, next_height: u64) {
+        match self.scanned.iter_mut().find(|p| p.chain_id == chain_id) {
+            Some(progress) => progress.next_height = next_height,
+            None => self.scanned.push(ScanProgress {
+                chain_id,
+                next_height,
+            }),
+        }
+    }
+
+    /// Queue the relay, unless it is queued already.
+    pub fn add_relay(&mut self, relay: PendingRelay) -> bool {
+        // A transaction may send several messages, from several contracts,
+        // each counting the nonces of its own channels.
+        if self.relays.iter().any(|r| {
+            r.version == relay.version
+                && r.from_chain_id == relay.from_chain_id
+                && r.sender == relay.sender
+                && r.tx_hash == relay.tx_hash
+                && r.to_chain_id == relay.to_chain_id
+                && r.dest_contract == relay.dest_contract
+                && r.cross_chain_nonce == relay.cross_chain_nonce
+        }) {
+            false
+        } else {
+            self.relays.push(relay);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PendingRelay, RelayState};
+    use cita_types::{Address, H256, U256};
+    use serde_json;
+
+    fn relay(nonce: u64) -> PendingRelay {
+        PendingRelay {
+            version: 0,
+            from_chain_id: U256::from(3),
+            sender: Address::default(),
+            tx_hash: H256::from(nonce + 100),
+            to_chain_id: U256::from(4),
+            dest_contract: Address::from(5),
+            cross_chain_nonce: nonce,
+            sent: None,
+            retries: 0,
+            next_attempt: 0,
+        }
+    }
+
+    #[test]
+    fn test_relay_state() {
+        let mut state = RelayState::default();
+        assert_eq!(state.next_height(U256::from(3)), None);
+        state.set_next_height(U256::from(3), 10);
+        state.set_next_height(U256::from(3), 20);
+        assert_eq!(state.next_height(U256::from(3)), Some(20));
+
+        assert!(state.add_relay(relay(0)));
+        assert!(state.add_relay(relay(1)));
+        assert!(!state.add_relay(relay(0)));
+        let mut message = relay(0);
+        message.version = 1;
+        message.cross_chain_nonce = 1;
+        assert!(state.add_relay(message));
+        state.relays[1].sent = Some((H256::from(1), 30));
+
+        let data = serde_json::to_vec(&state).unwrap();
+        let loaded: RelayState = serde_json::from_slice(&data).unwrap();
+        assert_eq!(loaded.scanned, state.scanned);
+        assert_eq!(loaded.relays, state.relays);
+    }
+
+    #[test]
+    fn test_relay_messengers() {
+        let mut state = RelayState::default();
+        let message = |sender: u64| {
+            let mut message = relay(0);
+            message.version = 1;
+            message.sender = Address::from(sender);
+            message
+        };
+        // Two messengers send the same nonce to the same contract in one
+        // transaction.
+        assert!(state.add_relay(message(6)));
+        assert!(state.add_relay(message(7)));
+        assert!(!state.add_relay(message(7)));
+        // A `SendCrossChain` event of the same transaction.
+        let mut event = relay(0);
+        event.sender = Address::from(6);
+        assert!(state.add_relay(event));
+        assert_eq!(state.relays.len(), 3);
+    }
+}
@@ -17,6 +17,10 @@ use ethabi;
 use cita_crypto::PrivKey;
 use cita_types::{H160, H256, U256};
 use libproto::blockchain::{Transaction, UnverifiedTransaction};
+use util::sha3;
+
+/// Blocks a relay transaction is valid for.
+pub const VALID_UNTIL_BLOCKS: u64 = 100;
 
 pub fn construct_transaction(
     pkey: &PrivKey,
@@ -30,6 +34,24 @@ pub fn construct_transaction(
     sign(pkey, dest_contract, code, chain_id, height)
 }
 
+/// Construct a transaction calling a contract with the encoded data.
+pub fn construct_call_transaction(
+    pkey: &PrivKey,
+    contract: H160,
+    data: Vec<u8>,
+    chain_id: U256,
+    height: U256,
+) -> UnverifiedTransaction {
+    sign(pkey, contract, data, chain_id, height)
+}
+
+/// Encode the call of a function, such as `getExpectedBlockNumber(uint256)`.
+pub fn encode_call(signature: &str, tokens: &[ethabi::Token]) -> Vec<u8> {
+    let mut data = sha3::keccak256(signature.as_bytes())[..4].to_vec();
+    data.extend(ethabi::encode(tokens));
+    data
+}
+
 #[inline]
 fn encode(dest_hasher: [u8; 4], tx_proof_rlp: &[u8]) -> Vec<u8> {
     trace!("encode dest_hasher {:?}", dest_hasher);
@@ -55,7 +77,7 @@ fn sign(
     let mut tx = Transaction::new();
     tx.set_data(code);
     tx.set_to_v1(addr.to_vec());
-    tx.set_valid_until_block(height.low_u64() + 100);
+    tx.set_valid_until_block(height.low_u64() + VALID_UNTIL_BLOCKS);
     tx.set_quota(1_000_000);
     tx.set_chain_id_v1(H256::from(chain_id).to_vec());
     tx.set_version(2);